@@ -0,0 +1,66 @@
+use std::net::IpAddr;
+
+use chrono::prelude::*;
+use tokio::io::AsyncWriteExt;
+
+use crate::AppState;
+
+/// Something a client did that an operator may want to ban it for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    /// A token, signature or TOTP code that didn't check out, or an attempt
+    /// while locked out.
+    AuthFailure,
+    /// The client was locked out after failing too often.
+    Ban,
+    /// A client certificate no token is mapped to.
+    UnknownCert,
+}
+
+impl Event {
+    /// The name events are logged under. These never change, so filters
+    /// written against them keep matching.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::AuthFailure => "auth_failure",
+            Self::Ban => "ban",
+            Self::UnknownCert => "unknown_cert",
+        }
+    }
+}
+
+/// Logs `event` from `ip`, with `details` as `key=value` pairs, to the
+/// regular log under the `abuse` target and appended to `ABUSE_LOG` when
+/// it's set. Lines there look like
+///
+/// ```text
+/// 2024-01-01T00:00:00Z smolpaste[1234]: auth_failure from 192.0.2.1 outcome=failure token=abcd
+/// ```
+///
+/// so a fail2ban filter only needs `^\S+ smolpaste\[\d+\]: \S+ from <HOST>`.
+/// The file is reopened for every line, which lets it be rotated underneath.
+pub async fn log(state: &AppState, ip: IpAddr, event: Event, details: &[(&str, &str)]) {
+    let mut message = format!("{} from {}", event.as_str(), ip);
+    for (key, value) in details {
+        message.push_str(&format!(" {}={}", key, sanitize(value)));
+    }
+    tracing::warn!(target: "abuse", "{}", message);
+
+    let path = match state.config().abuse_log.clone() {
+        Some(p) => p,
+        None => return,
+    };
+    let line = format!("{} smolpaste[{}]: {}\n", Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true), std::process::id(), message);
+    let res = async {
+        let mut file = tokio::fs::OpenOptions::new().create(true).append(true).open(&path).await?;
+        file.write_all(line.as_bytes()).await
+    }.await;
+    if let Err(e) = res {
+        tracing::error!("Couldn't write to {}: {}", path, e);
+    }
+}
+
+/// Keeps a value to one word, so it can't break up the line or fake another.
+fn sanitize(value: &str) -> String {
+    value.chars().map(|c| if c.is_whitespace() || c.is_control() { '_' } else { c }).collect()
+}
@@ -0,0 +1,150 @@
+use std::{net::{IpAddr, SocketAddr}, str::FromStr, sync::Arc, time::Duration};
+
+use axum::{
+    extract::{ConnectInfo, Path, Query, State},
+    http::{header, Method, StatusCode},
+    response::Response,
+    Extension, Json,
+};
+use chrono::prelude::*;
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+
+use crate::{auth::authenticate_client, namespace::RequestNamespace, tls::ClientCert, AppState};
+
+/// Most accesses listed at once.
+const MAX_LIMIT: u32 = 1000;
+
+/// Whether downloads of pastes are logged for their owners, and what's kept
+/// of the downloader's address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessLog {
+    Off,
+    /// Only when a paste was downloaded.
+    WithoutAddresses,
+    /// A keyed hash of the address, so repeat downloaders can be told
+    /// apart. It's keyed with `SESSION_SECRET`, so without one hashes only
+    /// match until a restart.
+    Hashed,
+    Full,
+}
+
+impl FromStr for AccessLog {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(Self::Off),
+            "none" => Ok(Self::WithoutAddresses),
+            "hashed" => Ok(Self::Hashed),
+            "full" => Ok(Self::Full),
+            _ => anyhow::bail!("unknown access log mode {:?}, expected off, none, hashed or full", s),
+        }
+    }
+}
+
+/// Logs a `method` request from `ip` that was served the paste stored as
+/// `filename` with `response`, when `ACCESS_LOG` is on. Only the start of a
+/// download counts, not `HEAD` requests or the ranges that follow, and the
+/// log is written in the background.
+pub fn record(state: &AppState, filename: &str, ip: IpAddr, method: &Method, response: &Response) {
+    let mode = state.config().access_log;
+    if mode == AccessLog::Off || method != Method::GET || !is_start(response) {
+        return;
+    }
+
+    let ip = match mode {
+        AccessLog::Full => Some(ip.to_string()),
+        AccessLog::Hashed => Some(state.session_key.digest(ip.to_string().as_bytes())),
+        _ => None,
+    };
+    let db = state.db.clone();
+    let filename = filename.to_string();
+    tokio::spawn(async move {
+        let res = sqlx::query("INSERT INTO accesses (paste, timestamp, ip) VALUES ($1, $2, $3)")
+        .bind(&filename)
+        .bind(Utc::now().timestamp())
+        .bind(ip)
+        .execute(&db).await;
+        if let Err(e) = res {
+            tracing::error!("Couldn't log an access to {}: {}", filename, e);
+        }
+    });
+}
+
+/// Whether `response` sends the paste from its first byte.
+pub fn is_start(response: &Response) -> bool {
+    match response.status() {
+        StatusCode::OK => true,
+        StatusCode::PARTIAL_CONTENT => response.headers().get(header::CONTENT_RANGE)
+            .and_then(|v| v.to_str().ok())
+            .map_or(false, |r| r.starts_with("bytes 0-")),
+        _ => false,
+    }
+}
+
+/// Forgets accesses older than `retention` and those of pastes that are
+/// gone, returning how many.
+pub async fn prune(db: &SqlitePool, retention: Duration) -> sqlx::Result<u64> {
+    let cutoff = Utc::now().timestamp() - retention.as_secs() as i64;
+    let res = sqlx::query("DELETE FROM accesses WHERE timestamp < $1 OR paste NOT IN (SELECT filename FROM pastes)")
+    .bind(cutoff)
+    .execute(db).await?;
+    Ok(res.rows_affected())
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct AccessParam {
+    token: Option<String>,
+    limit: Option<u32>,
+}
+
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct Access {
+    pub timestamp: i64,
+    /// The address or its hash, depending on `ACCESS_LOG` at the time.
+    pub ip: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Accesses {
+    /// Downloads logged in all, within `ACCESS_LOG_RETENTION_DAYS`.
+    pub count: i64,
+    /// The latest of them, newest first.
+    pub accesses: Vec<Access>,
+}
+
+/// Handles `GET /api/paste/<id>/accesses`, telling the paste's owner when it
+/// was downloaded.
+#[axum::debug_handler]
+pub async fn list(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    cert: Option<Extension<ClientCert>>,
+    namespace: RequestNamespace,
+    Path(id): Path<String>,
+    Query(query): Query<AccessParam>,
+) -> Result<Json<Accesses>, StatusCode> {
+    let user = authenticate_client(&state, addr.ip(), cert.as_deref(), query.token.as_deref()).await?;
+    namespace.check(&user)?;
+
+    let (filename, owner) = sqlx::query_as::<_, (String, Option<i64>)>("SELECT filename, owner FROM pastes WHERE id = $1 AND namespace IS $2")
+    .bind(&id)
+    .bind(namespace.name())
+    .fetch_optional(&state.db).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    .ok_or(StatusCode::NOT_FOUND)?;
+    if !user.can_manage(owner) {
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    let count = sqlx::query_scalar::<_, i64>("SELECT COUNT(*) FROM accesses WHERE paste = $1")
+    .bind(&filename)
+    .fetch_one(&state.db).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let accesses = sqlx::query_as::<_, Access>("SELECT timestamp, ip FROM accesses WHERE paste = $1 ORDER BY timestamp DESC LIMIT $2")
+    .bind(&filename)
+    .bind(query.limit.unwrap_or(100).min(MAX_LIMIT))
+    .fetch_all(&state.db).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Json(Accesses { count, accesses }))
+}
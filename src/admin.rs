@@ -0,0 +1,663 @@
+use std::{collections::HashMap, convert::Infallible, net::SocketAddr, sync::Arc};
+
+use axum::{
+    body::StreamBody,
+    extract::{ConnectInfo, Path, Query, State},
+    http::{header, StatusCode},
+    response::{sse::{Event, KeepAlive, Sse}, IntoResponse, Response},
+    Json,
+};
+use chrono::prelude::*;
+use futures::{Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use tokio_stream::wrappers::{errors::BroadcastStreamRecvError, BroadcastStream};
+use tokio_util::io::ReaderStream;
+
+use crate::{
+    archive,
+    audit,
+    auth::{authenticate_admin, token_hash},
+    db::{self, AuditEntry, AuthAttempt, TokenUsage},
+    jobs::JobStats,
+    maintenance,
+    parts,
+    reload,
+    storage::{self, paste_path_in},
+    totp,
+    versions,
+    AppState,
+};
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ListParam {
+    token: String,
+    totp: Option<String>,
+    limit: Option<u32>,
+}
+
+#[axum::debug_handler]
+pub async fn auth_attempts(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Query(query): Query<ListParam>,
+) -> Result<Json<Vec<AuthAttempt>>, StatusCode> {
+    authenticate_admin(&state, addr.ip(), &query.token, query.totp.as_deref()).await?;
+
+    let attempts = sqlx::query_as::<_, AuthAttempt>("SELECT * FROM auth_attempts ORDER BY id DESC LIMIT $1")
+    .bind(query.limit.unwrap_or(100))
+    .fetch_all(&state.db).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Json(attempts))
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct TokenParam {
+    token: String,
+    /// Current code from the admin's authenticator app, if they enrolled one.
+    totp: Option<String>,
+}
+
+#[axum::debug_handler]
+pub async fn token_usage(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path(id): Path<i64>,
+    Query(query): Query<TokenParam>,
+) -> Result<Json<TokenUsage>, StatusCode> {
+    authenticate_admin(&state, addr.ip(), &query.token, query.totp.as_deref()).await?;
+
+    let exists = sqlx::query_scalar::<_, i64>("SELECT COUNT(*) FROM tokens WHERE rowid = $1")
+    .bind(id)
+    .fetch_one(&state.db).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    if exists == 0 {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    let usage = sqlx::query_as::<_, TokenUsage>("SELECT
+        COUNT(*) AS pastes,
+        COALESCE(SUM(size), 0) AS bytes,
+        COALESCE(SUM(bytes_served), 0) AS bytes_served,
+        (SELECT MAX(timestamp) FROM audit_log WHERE actor = $1) AS last_activity
+        FROM pastes WHERE owner = $1")
+    .bind(id)
+    .fetch_one(&state.db).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Json(usage))
+}
+
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct PurgedPaste {
+    id: String,
+    filename: String,
+    size: i64,
+    #[serde(skip)]
+    storage_prefix: Option<String>,
+}
+
+/// What was erased for a token, so the request can be answered with proof.
+#[derive(Debug, Clone, Serialize)]
+pub struct PurgeReport {
+    token: i64,
+    timestamp: i64,
+    pastes: Vec<PurgedPaste>,
+    versions: u64,
+    collections: u64,
+    download_links: u64,
+    s3_objects: u64,
+    sessions: u64,
+    /// Per-day download counts of its pastes, which outlive the pastes.
+    bandwidth: u64,
+    /// Uploads it started in parts and never completed.
+    multipart_uploads: u64,
+    audit_entries: u64,
+    /// Failed attempts made with the token's prefix.
+    auth_attempts: u64,
+    /// Pastes kept because they're under a legal hold.
+    held: Vec<String>,
+    /// Pastes whose files couldn't be removed from storage.
+    failed: Vec<String>,
+}
+
+/// Erases token `id` and everything tied to it: its pastes and their kept
+/// versions, collections, download links and S3 keys, its sessions, the
+/// bandwidth its pastes used, its unfinished uploads in parts and its audit
+/// entries, all in one transaction. The files are removed afterwards.
+/// Pastes under a legal hold are kept, and listed in the report.
+#[axum::debug_handler]
+pub async fn purge_token(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path(id): Path<i64>,
+    Query(query): Query<TokenParam>,
+) -> Result<Json<PurgeReport>, StatusCode> {
+    let admin = authenticate_admin(&state, addr.ip(), &query.token, query.totp.as_deref()).await?;
+    if admin.id == Some(id) {
+        return Err(StatusCode::CONFLICT);
+    }
+
+    let (report, uploads) = match purge(&state, id).await {
+        Ok(Some(r)) => r,
+        Ok(None) => return Err(StatusCode::NOT_FOUND),
+        Err(e) => {
+            tracing::error!("Couldn't purge token {}: {}", id, e);
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        },
+    };
+
+    let mut failed = Vec::new();
+    for paste in &report.pastes {
+        if let Err(e) = tokio::fs::remove_file(paste_path_in(paste.storage_prefix.as_deref(), &paste.filename)).await {
+            tracing::error!("Couldn't remove {}: {}", paste.filename, e);
+            failed.push(paste.filename.clone());
+        }
+        if let Err(e) = versions::remove_files(&paste.filename).await {
+            tracing::error!("Couldn't remove the versions of {}: {}", paste.filename, e);
+        }
+        state.hooks.delete(&paste.filename).await;
+    }
+    for upload in &uploads {
+        if let Err(e) = tokio::fs::remove_dir_all(parts::upload_dir(upload)).await {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                tracing::error!("Couldn't remove the parts of upload {}: {}", upload, e);
+            }
+        }
+    }
+
+    audit::record(&state, admin.id, addr.ip(), "purge", &id.to_string()).await;
+    tracing::info!("Purged token {} and its {} pastes", id, report.pastes.len());
+
+    Ok(Json(PurgeReport { failed, ..report }))
+}
+
+/// Returns what was erased, along with the ids of the uploads in parts whose
+/// staged parts are left to remove.
+async fn purge(state: &AppState, id: i64) -> sqlx::Result<Option<(PurgeReport, Vec<String>)>> {
+    let mut tx = state.db.begin().await?;
+
+    let value = match sqlx::query_scalar::<_, Option<String>>("DELETE FROM tokens WHERE rowid = $1 RETURNING value")
+    .bind(id)
+    .fetch_optional(&mut *tx).await? {
+        Some(v) => v.unwrap_or_default(),
+        None => return Ok(None),
+    };
+
+    let pastes = sqlx::query_as::<_, PurgedPaste>("DELETE FROM pastes WHERE owner = $1 AND NOT legal_hold RETURNING id, filename, size, storage_prefix")
+    .bind(id)
+    .fetch_all(&mut *tx).await?;
+    let held = sqlx::query_scalar::<_, String>("SELECT filename FROM pastes WHERE owner = $1")
+    .bind(id)
+    .fetch_all(&mut *tx).await?;
+
+    let (mut versions, mut download_links) = (0, 0);
+    for paste in &pastes {
+        versions += sqlx::query("DELETE FROM versions WHERE paste = $1")
+        .bind(&paste.filename)
+        .execute(&mut *tx).await?
+        .rows_affected();
+
+        download_links += sqlx::query("DELETE FROM download_links WHERE paste = $1")
+        .bind(&paste.id)
+        .execute(&mut *tx).await?
+        .rows_affected();
+    }
+
+    let mut deleted = Vec::new();
+    for query in [
+        "DELETE FROM collections WHERE owner = $1",
+        "DELETE FROM s3_objects WHERE owner = $1",
+        "DELETE FROM sessions WHERE token = $1",
+        "DELETE FROM bandwidth WHERE owner = $1",
+        "DELETE FROM multipart_parts WHERE upload IN (SELECT id FROM multipart_uploads WHERE owner = $1)",
+    ] {
+        deleted.push(sqlx::query(query).bind(id).execute(&mut *tx).await?.rows_affected());
+    }
+    let uploads = sqlx::query_scalar::<_, String>("DELETE FROM multipart_uploads WHERE owner = $1 RETURNING id")
+    .bind(id)
+    .fetch_all(&mut *tx).await?;
+
+    // signed requests are logged under the token's row id instead
+    let auth_attempts = sqlx::query("DELETE FROM auth_attempts WHERE token_hash = $1 OR (token_hash IS NULL AND token_prefix = $2)")
+    .bind(token_hash(&value))
+    .bind(id.to_string())
+    .execute(&mut *tx).await?
+    .rows_affected();
+
+    let audit_entries = db::erase_audit_entries(&mut tx, id).await?;
+
+    tx.commit().await?;
+
+    Ok(Some((PurgeReport {
+        token: id,
+        timestamp: Utc::now().timestamp(),
+        pastes,
+        versions,
+        collections: deleted[0],
+        download_links,
+        s3_objects: deleted[1],
+        sessions: deleted[2],
+        bandwidth: deleted[3],
+        multipart_uploads: uploads.len() as u64,
+        audit_entries,
+        auth_attempts,
+        held,
+        failed: Vec::new(),
+    }, uploads)))
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct HoldParam {
+    token: String,
+    totp: Option<String>,
+    /// Paste id.
+    id: String,
+    held: bool,
+}
+
+/// Puts paste `id` under a legal hold, which keeps it from being deleted by
+/// anyone, its owner included, or from expiring, until it's released.
+#[axum::debug_handler]
+pub async fn set_legal_hold(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Query(query): Query<HoldParam>,
+) -> Result<StatusCode, StatusCode> {
+    let admin = authenticate_admin(&state, addr.ip(), &query.token, query.totp.as_deref()).await?;
+
+    let filename = sqlx::query_scalar::<_, String>("UPDATE pastes SET legal_hold = $1 WHERE id = $2 RETURNING filename")
+    .bind(query.held)
+    .bind(&query.id)
+    .fetch_optional(&state.db).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    .ok_or(StatusCode::NOT_FOUND)?;
+
+    let action = if query.held { "legal_hold" } else { "legal_hold_release" };
+    audit::record(&state, admin.id, addr.ip(), action, &filename).await;
+
+    Ok(StatusCode::OK)
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct AuditParam {
+    token: String,
+    totp: Option<String>,
+    limit: Option<u32>,
+    action: Option<String>,
+    /// `jsonl` exports one JSON object per line instead of a JSON array.
+    format: Option<String>,
+}
+
+#[axum::debug_handler]
+pub async fn audit_log(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Query(query): Query<AuditParam>,
+) -> Result<Response, StatusCode> {
+    authenticate_admin(&state, addr.ip(), &query.token, query.totp.as_deref()).await?;
+
+    let entries = sqlx::query_as::<_, AuditEntry>("SELECT * FROM audit_log
+    WHERE $1 IS NULL OR action = $1
+    ORDER BY id DESC LIMIT $2")
+    .bind(&query.action)
+    .bind(query.limit.map(i64::from).unwrap_or(-1))
+    .fetch_all(&state.db).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    match query.format.as_deref() {
+        None | Some("json") => Ok(Json(entries).into_response()),
+        Some("jsonl") => {
+            let mut body = String::new();
+            for entry in &entries {
+                body.push_str(&serde_json::to_string(entry).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?);
+                body.push('\n');
+            }
+            Ok(([(header::CONTENT_TYPE, "application/x-ndjson")], body).into_response())
+        },
+        Some(_) => Err(StatusCode::BAD_REQUEST)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct CertParam {
+    token: String,
+    totp: Option<String>,
+    /// Row id of the token the certificate should authenticate as.
+    id: i64,
+    /// Hex SHA-256 fingerprint of the client certificate, or nothing to unmap it.
+    fingerprint: Option<String>,
+}
+
+#[axum::debug_handler]
+pub async fn map_cert(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Query(query): Query<CertParam>,
+) -> Result<StatusCode, StatusCode> {
+    let admin = authenticate_admin(&state, addr.ip(), &query.token, query.totp.as_deref()).await?;
+
+    let fingerprint = query.fingerprint.map(|f| f.replace(':', "").to_lowercase());
+    let res = sqlx::query("UPDATE tokens SET cert_fingerprint = $1 WHERE rowid = $2")
+    .bind(&fingerprint)
+    .bind(query.id)
+    .execute(&state.db).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    if res.rows_affected() == 0 {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    audit::record(&state, admin.id, addr.ip(), "map_cert", &format!("{}:{}", query.id, fingerprint.unwrap_or_default())).await;
+
+    Ok(StatusCode::OK)
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct BaseUrlParam {
+    token: String,
+    totp: Option<String>,
+    /// Row id of the token whose links should use the URL.
+    id: i64,
+    /// Like `https://i.example.com`, or nothing to go back to the default.
+    base_url: Option<String>,
+}
+
+#[axum::debug_handler]
+pub async fn set_base_url(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Query(query): Query<BaseUrlParam>,
+) -> Result<StatusCode, StatusCode> {
+    let admin = authenticate_admin(&state, addr.ip(), &query.token, query.totp.as_deref()).await?;
+
+    let base_url = query.base_url.map(|u| u.trim_end_matches('/').to_string());
+    if let Some(url) = &base_url {
+        if !(url.starts_with("https://") || url.starts_with("http://")) {
+            return Err(StatusCode::BAD_REQUEST);
+        }
+    }
+
+    let res = sqlx::query("UPDATE tokens SET base_url = $1 WHERE rowid = $2")
+    .bind(&base_url)
+    .bind(query.id)
+    .execute(&state.db).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    if res.rows_affected() == 0 {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    audit::record(&state, admin.id, addr.ip(), "set_base_url", &format!("{}:{}", query.id, base_url.unwrap_or_default())).await;
+
+    Ok(StatusCode::OK)
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct BandwidthQuotaParam {
+    token: String,
+    totp: Option<String>,
+    /// Row id of the token whose pastes the quota covers.
+    id: i64,
+    /// Bytes they may be downloaded per calendar month, or nothing to lift
+    /// the quota.
+    bytes: Option<i64>,
+}
+
+/// Sets how much a token's pastes may be downloaded per month before they're
+/// refused. Takes effect within seconds.
+#[axum::debug_handler]
+pub async fn set_bandwidth_quota(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Query(query): Query<BandwidthQuotaParam>,
+) -> Result<StatusCode, StatusCode> {
+    let admin = authenticate_admin(&state, addr.ip(), &query.token, query.totp.as_deref()).await?;
+    if query.bytes.map_or(false, |b| b < 0) {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let res = sqlx::query("UPDATE tokens SET monthly_bandwidth = $1 WHERE rowid = $2")
+    .bind(query.bytes)
+    .bind(query.id)
+    .execute(&state.db).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    if res.rows_affected() == 0 {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    let quota = query.bytes.map(|b| b.to_string()).unwrap_or_default();
+    audit::record(&state, admin.id, addr.ip(), "set_bandwidth_quota", &format!("{}:{}", query.id, quota)).await;
+
+    Ok(StatusCode::OK)
+}
+
+/// Re-reads the config, like `SIGHUP` does.
+#[axum::debug_handler]
+pub async fn reload_config(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Query(query): Query<TokenParam>,
+) -> Result<StatusCode, StatusCode> {
+    let admin = authenticate_admin(&state, addr.ip(), &query.token, query.totp.as_deref()).await?;
+
+    if let Err(e) = reload::reload(&state).await {
+        tracing::error!("Couldn't reload the configuration: {}", e);
+        return Err(StatusCode::UNPROCESSABLE_ENTITY);
+    }
+    audit::record(&state, admin.id, addr.ip(), "reload_config", "").await;
+
+    Ok(StatusCode::OK)
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ReadOnlyParam {
+    token: String,
+    totp: Option<String>,
+    enabled: bool,
+}
+
+/// Turns read-only mode on or off until the next restart, or a reload that
+/// changes `READ_ONLY`.
+#[axum::debug_handler]
+pub async fn set_read_only(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Query(query): Query<ReadOnlyParam>,
+) -> Result<StatusCode, StatusCode> {
+    let admin = authenticate_admin(&state, addr.ip(), &query.token, query.totp.as_deref()).await?;
+
+    maintenance::set_read_only(&state, query.enabled);
+    audit::record(&state, admin.id, addr.ip(), "set_read_only", &query.enabled.to_string()).await;
+
+    Ok(StatusCode::OK)
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ArchiveParam {
+    token: String,
+    totp: Option<String>,
+    /// `zip` (the default) or `tar`.
+    format: Option<String>,
+}
+
+#[axum::debug_handler]
+pub async fn archive_all(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Query(query): Query<ArchiveParam>,
+) -> Result<Response, StatusCode> {
+    let admin = authenticate_admin(&state, addr.ip(), &query.token, query.totp.as_deref()).await?;
+
+    let members = sqlx::query_as::<_, archive::Member>("SELECT filename, timestamp, storage_prefix FROM pastes ORDER BY timestamp")
+    .fetch_all(&state.db).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    audit::record(&state, admin.id, addr.ip(), "archive_all", &members.len().to_string()).await;
+
+    archive::stream(members, query.format.as_deref(), "smolpaste")
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct EventsParam {
+    token: String,
+    totp: Option<String>,
+    /// Comma-separated actions to receive, all of them by default.
+    actions: Option<String>,
+}
+
+/// Streams audit events as they happen over SSE.
+#[axum::debug_handler]
+pub async fn events(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Query(query): Query<EventsParam>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, StatusCode> {
+    authenticate_admin(&state, addr.ip(), &query.token, query.totp.as_deref()).await?;
+
+    let actions: Option<Vec<String>> = query.actions.map(|a| a.split(',').map(str::to_string).collect());
+    let events = BroadcastStream::new(state.events.subscribe()).filter_map(move |res| {
+        let event = match res {
+            Ok(e) if actions.as_ref().map_or(true, |a| a.contains(&e.action)) => {
+                Event::default().event(&e.action).json_data(&e).ok()
+            },
+            Ok(_) => None,
+            Err(BroadcastStreamRecvError::Lagged(n)) => Some(Event::default().event("lagged").data(n.to_string())),
+        };
+        futures::future::ready(event.map(Ok))
+    });
+
+    Ok(Sse::new(events).keep_alive(KeepAlive::default()))
+}
+
+#[axum::debug_handler]
+pub async fn jobs(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Query(query): Query<TokenParam>,
+) -> Result<Json<HashMap<&'static str, JobStats>>, StatusCode> {
+    authenticate_admin(&state, addr.ip(), &query.token, query.totp.as_deref()).await?;
+    Ok(Json(state.jobs.snapshot()))
+}
+
+/// Streams a consistent copy of the database, taken with `VACUUM INTO` so
+/// writes can carry on while it's being made.
+#[axum::debug_handler]
+pub async fn db_backup(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Query(query): Query<TokenParam>,
+) -> Result<Response, StatusCode> {
+    let admin = authenticate_admin(&state, addr.ip(), &query.token, query.totp.as_deref()).await?;
+
+    let config = state.config();
+    let dir = std::path::Path::new(&config.backup_dir);
+    tokio::fs::create_dir_all(dir).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    // named so the backup job's pruning never picks it up
+    let path = dir.join(format!(".download-{}.sqlite", uuid::Uuid::new_v4().simple()));
+    let res = sqlx::query("VACUUM INTO $1")
+    .bind(path.to_string_lossy().into_owned())
+    .execute(&state.db).await;
+
+    if let Err(e) = res {
+        tracing::error!("Couldn't snapshot the database: {}", e);
+        let _ = tokio::fs::remove_file(&path).await;
+        return Err(StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    let opened = tokio::fs::File::open(&path).await;
+    // the open handle keeps the snapshot readable, so it's gone from disk
+    // as soon as the download finishes, however that happens
+    let _ = tokio::fs::remove_file(&path).await;
+    let file = opened.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let len = file.metadata().await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?.len();
+
+    audit::record(&state, admin.id, addr.ip(), "db_backup", &len.to_string()).await;
+
+    let name = format!("smolpaste-{}.sqlite", Utc::now().format("%Y%m%d%H%M%S"));
+    Ok((
+        [
+            (header::CONTENT_TYPE, "application/vnd.sqlite3".to_string()),
+            (header::CONTENT_LENGTH, len.to_string()),
+            (header::CONTENT_DISPOSITION, format!("attachment; filename=\"{}\"", name)),
+        ],
+        StreamBody::new(ReaderStream::with_capacity(file, storage::chunk_size(len))),
+    ).into_response())
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Enrollment {
+    pub secret: String,
+    /// `otpauth://` URI for authenticator apps, usually shown as a QR code.
+    pub uri: String,
+}
+
+/// Starts enrolling the calling admin token in TOTP. The second factor is
+/// only required once a code for it has been confirmed.
+#[axum::debug_handler]
+pub async fn totp_enroll(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Query(query): Query<TokenParam>,
+) -> Result<Json<Enrollment>, StatusCode> {
+    let admin = authenticate_admin(&state, addr.ip(), &query.token, query.totp.as_deref()).await?;
+    // JWT and PASETO admins have nowhere to store a secret
+    let id = admin.id.ok_or(StatusCode::BAD_REQUEST)?;
+
+    let secret = totp::generate_secret();
+    sqlx::query("UPDATE tokens SET totp_pending = $1 WHERE rowid = $2")
+    .bind(&secret)
+    .bind(id)
+    .execute(&state.db).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let uri = totp::uri(&secret, &state.config().site_name, &format!("token-{}", id));
+    Ok(Json(Enrollment { secret, uri }))
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ConfirmParam {
+    token: String,
+    totp: Option<String>,
+    /// Code for the secret being enrolled.
+    code: String,
+}
+
+#[axum::debug_handler]
+pub async fn totp_confirm(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Query(query): Query<ConfirmParam>,
+) -> Result<StatusCode, StatusCode> {
+    let admin = authenticate_admin(&state, addr.ip(), &query.token, query.totp.as_deref()).await?;
+    let id = admin.id.ok_or(StatusCode::BAD_REQUEST)?;
+
+    let pending = sqlx::query_scalar::<_, Option<String>>("SELECT totp_pending FROM tokens WHERE rowid = $1")
+    .bind(id)
+    .fetch_one(&state.db).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    .ok_or(StatusCode::NOT_FOUND)?;
+
+    if !totp::verify(&pending, &query.code, Utc::now().timestamp()) {
+        return Err(StatusCode::UNPROCESSABLE_ENTITY);
+    }
+
+    sqlx::query("UPDATE tokens SET totp_secret = totp_pending, totp_pending = NULL WHERE rowid = $1")
+    .bind(id)
+    .execute(&state.db).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    audit::record(&state, admin.id, addr.ip(), "totp_enable", &id.to_string()).await;
+
+    Ok(StatusCode::OK)
+}
+
+/// Turns the second factor off again, which takes a current code.
+#[axum::debug_handler]
+pub async fn totp_disable(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Query(query): Query<TokenParam>,
+) -> Result<StatusCode, StatusCode> {
+    let admin = authenticate_admin(&state, addr.ip(), &query.token, query.totp.as_deref()).await?;
+    let id = admin.id.ok_or(StatusCode::BAD_REQUEST)?;
+
+    sqlx::query("UPDATE tokens SET totp_secret = NULL, totp_pending = NULL WHERE rowid = $1")
+    .bind(id)
+    .execute(&state.db).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    audit::record(&state, admin.id, addr.ip(), "totp_disable", &id.to_string()).await;
+
+    Ok(StatusCode::OK)
+}
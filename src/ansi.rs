@@ -0,0 +1,177 @@
+use std::fmt::Write;
+
+const ESC: char = '\x1b';
+
+/// The 16 basic terminal colours, roughly as xterm shows them.
+const PALETTE: [&str; 16] = [
+    "#000000", "#cd0000", "#00cd00", "#cdcd00", "#0000ee", "#cd00cd", "#00cdcd", "#e5e5e5",
+    "#7f7f7f", "#ff0000", "#00ff00", "#ffff00", "#5c5cff", "#ff00ff", "#00ffff", "#ffffff",
+];
+
+/// Whether `text` looks like captured terminal output with colours in it.
+pub fn has_escapes(text: &str) -> bool {
+    text.contains("\x1b[")
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct Style {
+    fg: Option<String>,
+    bg: Option<String>,
+    bold: bool,
+    dim: bool,
+    italic: bool,
+    underline: bool,
+    inverse: bool,
+}
+
+impl Style {
+    fn css(&self) -> String {
+        let (fg, bg) = if self.inverse { (&self.bg, &self.fg) } else { (&self.fg, &self.bg) };
+        let mut css = String::new();
+        if let Some(c) = fg {
+            let _ = write!(css, "color:{};", c);
+        }
+        if let Some(c) = bg {
+            let _ = write!(css, "background:{};", c);
+        }
+        if self.bold {
+            css.push_str("font-weight:bold;");
+        }
+        if self.dim {
+            css.push_str("opacity:0.7;");
+        }
+        if self.italic {
+            css.push_str("font-style:italic;");
+        }
+        if self.underline {
+            css.push_str("text-decoration:underline;");
+        }
+        css
+    }
+
+    /// Applies the parameters of one SGR (`ESC [ ... m`) sequence.
+    fn apply(&mut self, params: &str) {
+        let mut codes = params.split(';').map(|p| p.parse::<u16>().unwrap_or(0));
+        while let Some(code) = codes.next() {
+            match code {
+                0 => *self = Self::default(),
+                1 => self.bold = true,
+                2 => self.dim = true,
+                3 => self.italic = true,
+                4 => self.underline = true,
+                7 => self.inverse = true,
+                22 => (self.bold, self.dim) = (false, false),
+                23 => self.italic = false,
+                24 => self.underline = false,
+                27 => self.inverse = false,
+                30..=37 => self.fg = Some(PALETTE[usize::from(code - 30)].to_string()),
+                90..=97 => self.fg = Some(PALETTE[usize::from(code - 90 + 8)].to_string()),
+                40..=47 => self.bg = Some(PALETTE[usize::from(code - 40)].to_string()),
+                100..=107 => self.bg = Some(PALETTE[usize::from(code - 100 + 8)].to_string()),
+                39 => self.fg = None,
+                49 => self.bg = None,
+                38 => self.fg = extended_color(&mut codes),
+                48 => self.bg = extended_color(&mut codes),
+                _ => {},
+            }
+        }
+    }
+}
+
+/// Reads the rest of a `38;5;n` or `38;2;r;g;b` colour.
+fn extended_color(codes: &mut impl Iterator<Item = u16>) -> Option<String> {
+    match codes.next()? {
+        5 => Some(color_256(codes.next()?)),
+        2 => {
+            let (r, g, b) = (codes.next()?, codes.next()?, codes.next()?);
+            Some(format!("#{:02x}{:02x}{:02x}", r.min(255), g.min(255), b.min(255)))
+        },
+        _ => None,
+    }
+}
+
+fn color_256(n: u16) -> String {
+    match n {
+        0..=15 => PALETTE[usize::from(n)].to_string(),
+        16..=231 => {
+            let n = n - 16;
+            let level = |v: u16| if v == 0 { 0 } else { 55 + v * 40 };
+            format!("#{:02x}{:02x}{:02x}", level(n / 36), level(n / 6 % 6), level(n % 6))
+        },
+        _ => {
+            let gray = 8 + (n.min(255) - 232) * 10;
+            format!("#{:02x}{:02x}{:02x}", gray, gray, gray)
+        },
+    }
+}
+
+/// Converts terminal output into one line of HTML per line of `text`, with
+/// colours and text attributes as inline styles. Other escape sequences,
+/// like cursor movement, are dropped.
+pub fn to_html_lines(text: &str) -> Vec<String> {
+    let mut style = Style::default();
+    text.lines().map(|line| render_line(line, &mut style)).collect()
+}
+
+/// Renders `line`, starting in `style` and leaving it as the line ends it,
+/// since colours carry over to the next line.
+fn render_line(line: &str, style: &mut Style) -> String {
+    let mut html = String::new();
+    let mut run = String::new();
+    let mut run_style = style.clone();
+    let mut chars = line.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if c != ESC {
+            run.push(c);
+            continue;
+        }
+
+        match chars.next() {
+            // CSI: parameters, then a final byte telling what they're for
+            Some('[') => {
+                let mut params = String::new();
+                let mut last = None;
+                for c in chars.by_ref() {
+                    if ('\x40'..='\x7e').contains(&c) {
+                        last = Some(c);
+                        break;
+                    }
+                    params.push(c);
+                }
+                if last == Some('m') {
+                    style.apply(&params);
+                }
+            },
+            // OSC, like window titles and hyperlinks, ends with BEL or ESC \
+            Some(']') => {
+                while let Some(c) = chars.next() {
+                    if c == '\x07' || (c == ESC && chars.next_if_eq(&'\\').is_some()) {
+                        break;
+                    }
+                }
+            },
+            _ => {},
+        }
+
+        if *style != run_style {
+            push_run(&mut html, &run, &run_style);
+            run.clear();
+            run_style = style.clone();
+        }
+    }
+    push_run(&mut html, &run, &run_style);
+    html
+}
+
+fn push_run(html: &mut String, text: &str, style: &Style) {
+    if text.is_empty() {
+        return;
+    }
+    let css = style.css();
+    if css.is_empty() {
+        html.push_str(&tera::escape_html(text));
+    } else {
+        let _ = write!(html, "<span style=\"{}\">{}</span>", css, tera::escape_html(text));
+    }
+}
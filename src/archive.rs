@@ -0,0 +1,359 @@
+use std::{
+    fs::File,
+    io::{self, Read},
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use axum::{
+    body::StreamBody,
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+};
+use chrono::prelude::*;
+use tokio::io::{AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio_util::io::ReaderStream;
+use uuid::Uuid;
+
+use crate::{name::PasteName, sniff::{sniff, SNIFF_LEN}, storage::paste_path_in};
+
+/// A file extracted from an archive, staged until it's recorded.
+#[derive(Debug, Clone)]
+pub struct Extracted {
+    pub id: Uuid,
+    pub filename: String,
+    pub size: u64,
+    /// MIME type sniffed from the contents, for entries without an extension.
+    pub mime: Option<&'static str>,
+}
+
+/// Extracts every file in the zip at `path` into the directory `into` under a
+/// name from `fresh_name`, given the entry's name, keeping only the extension of the original name or guessing one
+/// from the contents.
+///
+/// Entries whose names would escape the archive root are rejected outright, and
+/// so are archives with more than `max_entries` files or whose contents inflate
+/// to more than `max_bytes`. On error, everything extracted so far is removed.
+/// This blocks, so call it from `spawn_blocking`.
+pub fn expand_zip(
+    path: &Path,
+    into: &Path,
+    max_entries: usize,
+    max_bytes: u64,
+    fresh_name: impl Fn(&str) -> anyhow::Result<PasteName>,
+) -> anyhow::Result<Vec<Extracted>> {
+    let mut extracted = Vec::new();
+    let res = extract_into(path, into, max_entries, max_bytes, &fresh_name, &mut extracted);
+
+    if res.is_err() {
+        for file in &extracted {
+            let _ = std::fs::remove_file(into.join(&file.filename));
+        }
+    }
+
+    res.map(|_| extracted)
+}
+
+fn extract_into(
+    path: &Path,
+    into: &Path,
+    max_entries: usize,
+    max_bytes: u64,
+    fresh_name: &dyn Fn(&str) -> anyhow::Result<PasteName>,
+    extracted: &mut Vec<Extracted>,
+) -> anyhow::Result<()> {
+    let mut archive = zip::ZipArchive::new(File::open(path)?)?;
+    let mut remaining = max_bytes;
+    std::fs::create_dir_all(into)?;
+
+    for i in 0..archive.len() {
+        let mut entry = archive.by_index(i)?;
+        if entry.is_dir() {
+            continue;
+        }
+
+        let name: PathBuf = entry.enclosed_name()
+            .with_context(|| format!("unsafe entry name {:?}", entry.name()))?
+            .to_owned();
+
+        if extracted.len() >= max_entries {
+            anyhow::bail!("archive has more than {} entries", max_entries);
+        }
+
+        let mut head = Vec::with_capacity(SNIFF_LEN);
+        (&mut entry).take(SNIFF_LEN as u64).read_to_end(&mut head)?;
+
+        let id = Uuid::new_v4();
+        let name = fresh_name(&name.to_string_lossy())?;
+        let (name, mime) = match sniff(&head) {
+            Some((ext, mime)) if !name.has_extension() => match name.with_extension(ext) {
+                Some(n) => (n, Some(mime)),
+                None => (name, None),
+            },
+            _ => (name, None),
+        };
+        let filename = name.into_string();
+
+        let mut file = File::create(into.join(&filename))?;
+        extracted.push(Extracted { id, filename, size: 0, mime });
+
+        // don't trust the sizes in the central directory, count what actually comes out
+        // spelled out, since tokio's AsyncReadExt has the same methods
+        let contents = Read::chain(io::Cursor::new(head), &mut entry);
+        let size = io::copy(&mut Read::take(contents, remaining.saturating_add(1)), &mut file)?;
+        if size > remaining {
+            anyhow::bail!("archive inflates to more than {} bytes", max_bytes);
+        }
+        remaining -= size;
+
+        if let Some(last) = extracted.last_mut() {
+            last.size = size;
+        }
+    }
+
+    Ok(())
+}
+
+/// Sizes and offsets from here up don't fit zip's 32-bit fields, whose
+/// all-ones value means "see the zip64 extra field".
+const ZIP64_LIMIT: u64 = u32::MAX as u64;
+
+/// A paste to be written into an archive.
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct Member {
+    pub filename: String,
+    pub timestamp: i64,
+    pub storage_prefix: Option<String>,
+}
+
+/// Streams `members` as a zip or tar, generated while it's being sent.
+pub fn stream(members: Vec<Member>, format: Option<&str>, name: &str) -> Result<Response, StatusCode> {
+    let (content_type, extension) = match format {
+        None | Some("zip") => ("application/zip", "zip"),
+        Some("tar") => ("application/x-tar", "tar"),
+        Some(_) => return Err(StatusCode::BAD_REQUEST)
+    };
+
+    let (writer, reader) = tokio::io::duplex(64 * 1024);
+    tokio::spawn(async move {
+        let res = match extension {
+            "zip" => write_zip(writer, &members).await,
+            _ => write_tar(writer, &members).await,
+        };
+        if let Err(e) = res {
+            tracing::error!("Couldn't write archive: {}", e);
+        }
+    });
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, content_type.to_string()),
+            (header::CONTENT_DISPOSITION, format!("attachment; filename=\"{}.{}\"", name, extension)),
+        ],
+        StreamBody::new(ReaderStream::new(reader)),
+    ).into_response())
+}
+
+/// Writes `members` as a tar archive, reading each file as it goes.
+pub async fn write_tar<W: AsyncWrite + Unpin>(mut w: W, members: &[Member]) -> io::Result<()> {
+    for member in members {
+        let mut file = tokio::fs::File::open(paste_path_in(member.storage_prefix.as_deref(), &member.filename)).await?;
+        let size = file.metadata().await?.len();
+
+        let mut header = tar::Header::new_ustar();
+        header.set_path(&member.filename)?;
+        header.set_size(size);
+        header.set_mode(0o644);
+        header.set_mtime(member.timestamp.max(0) as u64);
+        header.set_entry_type(tar::EntryType::Regular);
+        header.set_cksum();
+        w.write_all(header.as_bytes()).await?;
+
+        // the file might change size under us, so stick to what the header says
+        let copied = tokio::io::copy(&mut (&mut file).take(size), &mut w).await?;
+        if copied != size {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "paste shrank while archiving"));
+        }
+
+        let padding = (512 - size % 512) % 512;
+        w.write_all(&vec![0; padding as usize]).await?;
+    }
+
+    w.write_all(&[0; 1024]).await?;
+    w.shutdown().await
+}
+
+/// Writes `members` as an uncompressed zip. Sizes and checksums go in data
+/// descriptors after each entry, so nothing has to be known up front and the
+/// output never needs seeking. Pastes, offsets and entry counts too large for
+/// plain zip fields are written with zip64 extensions.
+pub async fn write_zip<W: AsyncWrite + Unpin>(mut w: W, members: &[Member]) -> io::Result<()> {
+    let mut central = Vec::new();
+    let mut offset: u64 = 0;
+    let mut count: u64 = 0;
+
+    for member in members {
+        let mut file = tokio::fs::File::open(paste_path_in(member.storage_prefix.as_deref(), &member.filename)).await?;
+        let size = file.metadata().await?.len();
+        let zip64 = size >= ZIP64_LIMIT;
+        let (time, date) = dos_datetime(member.timestamp);
+        let name = member.filename.as_bytes();
+        // bit 3: sizes in data descriptor, bit 11: UTF-8 names
+        let flags: u16 = 0x0808;
+        let version: u16 = if zip64 { 45 } else { 20 };
+
+        let mut header = Vec::with_capacity(30 + name.len() + 20);
+        header.extend_from_slice(&0x04034b50u32.to_le_bytes());
+        header.extend_from_slice(&version.to_le_bytes());
+        header.extend_from_slice(&flags.to_le_bytes());
+        header.extend_from_slice(&0u16.to_le_bytes());
+        header.extend_from_slice(&time.to_le_bytes());
+        header.extend_from_slice(&date.to_le_bytes());
+        header.extend_from_slice(&0u32.to_le_bytes());
+        if zip64 {
+            // the real sizes follow in the (64-bit) data descriptor
+            header.extend_from_slice(&u32::MAX.to_le_bytes());
+            header.extend_from_slice(&u32::MAX.to_le_bytes());
+            header.extend_from_slice(&(name.len() as u16).to_le_bytes());
+            header.extend_from_slice(&20u16.to_le_bytes());
+            header.extend_from_slice(name);
+            header.extend_from_slice(&0x0001u16.to_le_bytes());
+            header.extend_from_slice(&16u16.to_le_bytes());
+            header.extend_from_slice(&[0; 16]);
+        } else {
+            header.extend_from_slice(&[0; 8]);
+            header.extend_from_slice(&(name.len() as u16).to_le_bytes());
+            header.extend_from_slice(&0u16.to_le_bytes());
+            header.extend_from_slice(name);
+        }
+        w.write_all(&header).await?;
+
+        // the file might change size under us, so stick to what the header
+        // was written for
+        let mut contents = (&mut file).take(size);
+        let mut crc = crc32fast::Hasher::new();
+        let mut copied: u64 = 0;
+        let mut buf = vec![0; 64 * 1024];
+        loop {
+            let n = contents.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            crc.update(&buf[..n]);
+            w.write_all(&buf[..n]).await?;
+            copied += n as u64;
+        }
+        if copied != size {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "paste shrank while archiving"));
+        }
+        let crc = crc.finalize();
+
+        let mut descriptor = Vec::with_capacity(24);
+        descriptor.extend_from_slice(&0x08074b50u32.to_le_bytes());
+        descriptor.extend_from_slice(&crc.to_le_bytes());
+        if zip64 {
+            descriptor.extend_from_slice(&size.to_le_bytes());
+            descriptor.extend_from_slice(&size.to_le_bytes());
+        } else {
+            descriptor.extend_from_slice(&(size as u32).to_le_bytes());
+            descriptor.extend_from_slice(&(size as u32).to_le_bytes());
+        }
+        w.write_all(&descriptor).await?;
+
+        // whatever doesn't fit in the central directory's 32-bit fields goes
+        // in a zip64 extra field instead, in this order
+        let mut extra = Vec::with_capacity(28);
+        if zip64 {
+            extra.extend_from_slice(&size.to_le_bytes());
+            extra.extend_from_slice(&size.to_le_bytes());
+        }
+        if offset >= ZIP64_LIMIT {
+            extra.extend_from_slice(&offset.to_le_bytes());
+        }
+        let size32 = if zip64 { u32::MAX } else { size as u32 };
+        let offset32 = if offset >= ZIP64_LIMIT { u32::MAX } else { offset as u32 };
+        let version: u16 = if extra.is_empty() { 20 } else { 45 };
+
+        central.extend_from_slice(&0x02014b50u32.to_le_bytes());
+        // made by unix, so the file mode below is honoured
+        central.extend_from_slice(&(0x0300 | version).to_le_bytes());
+        central.extend_from_slice(&version.to_le_bytes());
+        central.extend_from_slice(&flags.to_le_bytes());
+        central.extend_from_slice(&0u16.to_le_bytes());
+        central.extend_from_slice(&time.to_le_bytes());
+        central.extend_from_slice(&date.to_le_bytes());
+        central.extend_from_slice(&crc.to_le_bytes());
+        central.extend_from_slice(&size32.to_le_bytes());
+        central.extend_from_slice(&size32.to_le_bytes());
+        central.extend_from_slice(&(name.len() as u16).to_le_bytes());
+        let extra_len = if extra.is_empty() { 0 } else { extra.len() as u16 + 4 };
+        central.extend_from_slice(&extra_len.to_le_bytes());
+        central.extend_from_slice(&[0; 6]);
+        central.extend_from_slice(&(0o100644u32 << 16).to_le_bytes());
+        central.extend_from_slice(&offset32.to_le_bytes());
+        central.extend_from_slice(name);
+        if !extra.is_empty() {
+            central.extend_from_slice(&0x0001u16.to_le_bytes());
+            central.extend_from_slice(&(extra.len() as u16).to_le_bytes());
+            central.extend_from_slice(&extra);
+        }
+
+        offset += header.len() as u64 + size + descriptor.len() as u64;
+        count += 1;
+    }
+
+    let central_offset = offset;
+    let central_size = central.len() as u64;
+    w.write_all(&central).await?;
+
+    let zip64 = count >= u64::from(u16::MAX) || central_size >= ZIP64_LIMIT || central_offset >= ZIP64_LIMIT;
+    if zip64 {
+        let end_offset = central_offset + central_size;
+
+        let mut end64 = Vec::with_capacity(56 + 20);
+        end64.extend_from_slice(&0x06064b50u32.to_le_bytes());
+        // size of the rest of the record
+        end64.extend_from_slice(&44u64.to_le_bytes());
+        end64.extend_from_slice(&0x032du16.to_le_bytes());
+        end64.extend_from_slice(&45u16.to_le_bytes());
+        end64.extend_from_slice(&[0; 8]);
+        end64.extend_from_slice(&count.to_le_bytes());
+        end64.extend_from_slice(&count.to_le_bytes());
+        end64.extend_from_slice(&central_size.to_le_bytes());
+        end64.extend_from_slice(&central_offset.to_le_bytes());
+
+        // the locator, which is how readers find the record above
+        end64.extend_from_slice(&0x07064b50u32.to_le_bytes());
+        end64.extend_from_slice(&0u32.to_le_bytes());
+        end64.extend_from_slice(&end_offset.to_le_bytes());
+        end64.extend_from_slice(&1u32.to_le_bytes());
+        w.write_all(&end64).await?;
+    }
+
+    // fields that didn't fit are saturated, pointing readers at the zip64 record
+    let count16 = if zip64 { u16::MAX } else { count as u16 };
+    let (central_size32, central_offset32) = if zip64 { (u32::MAX, u32::MAX) } else { (central_size as u32, central_offset as u32) };
+
+    let mut end = Vec::with_capacity(22);
+    end.extend_from_slice(&0x06054b50u32.to_le_bytes());
+    end.extend_from_slice(&[0; 4]);
+    end.extend_from_slice(&count16.to_le_bytes());
+    end.extend_from_slice(&count16.to_le_bytes());
+    end.extend_from_slice(&central_size32.to_le_bytes());
+    end.extend_from_slice(&central_offset32.to_le_bytes());
+    end.extend_from_slice(&0u16.to_le_bytes());
+    w.write_all(&end).await?;
+
+    w.shutdown().await
+}
+
+/// MS-DOS time and date, which is what zip stores.
+fn dos_datetime(timestamp: i64) -> (u16, u16) {
+    let dt = Utc.timestamp_opt(timestamp, 0).single().unwrap_or_default();
+    if dt.year() < 1980 {
+        return (0, (1 << 5) | 1);
+    }
+    let time = (dt.hour() << 11) | (dt.minute() << 5) | (dt.second() / 2);
+    let date = (((dt.year() - 1980) as u32) << 9) | (dt.month() << 5) | dt.day();
+    (time as u16, date as u16)
+}
@@ -0,0 +1,36 @@
+use axum::{
+    extract::Path,
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+};
+use rust_embed::RustEmbed;
+
+use crate::session::hex;
+
+/// The web UI's stylesheets, scripts and icons, built into the binary so
+/// there's nothing to deploy next to it.
+#[derive(RustEmbed)]
+#[folder = "static/"]
+struct Static;
+
+/// Serves `/static/<path>`, revalidated by the hash of its contents so a new
+/// build is picked up right away.
+pub async fn serve(Path(path): Path<String>, headers: HeaderMap) -> Result<Response, StatusCode> {
+    let file = Static::get(&path).ok_or(StatusCode::NOT_FOUND)?;
+    let hash = hex(&file.metadata.sha256_hash()[..16]);
+    let etag = format!("\"{}\"", hash);
+
+    if headers.get(header::IF_NONE_MATCH).map_or(false, |v| v.as_bytes() == etag.as_bytes()) {
+        return Ok(StatusCode::NOT_MODIFIED.into_response());
+    }
+
+    let mime = mime_guess::from_path(&path).first_or_octet_stream();
+    Ok((
+        [
+            (header::CONTENT_TYPE, HeaderValue::from_str(mime.as_ref()).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?),
+            (header::ETAG, HeaderValue::from_str(&etag).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?),
+            (header::CACHE_CONTROL, HeaderValue::from_static("public, no-cache")),
+        ],
+        file.data,
+    ).into_response())
+}
@@ -0,0 +1,46 @@
+use std::net::IpAddr;
+
+use chrono::prelude::*;
+use serde::Serialize;
+
+use crate::AppState;
+
+/// A mutating operation, as published to `/admin/events` subscribers.
+#[derive(Debug, Clone, Serialize)]
+pub struct AuditEvent {
+    pub timestamp: i64,
+    pub actor: Option<i64>,
+    pub ip: String,
+    pub action: String,
+    pub target: String,
+}
+
+/// Appends an entry to the audit log and publishes it as an event.
+///
+/// `actor` is the id of the token that performed the action, if any. Failures
+/// are logged rather than returned, so a full disk doesn't turn into failed
+/// uploads.
+pub async fn record(state: &AppState, actor: Option<i64>, ip: IpAddr, action: &str, target: &str) {
+    let event = AuditEvent {
+        timestamp: Utc::now().timestamp(),
+        actor,
+        ip: ip.to_string(),
+        action: action.to_string(),
+        target: target.to_string(),
+    };
+
+    let res = sqlx::query("INSERT INTO audit_log (timestamp, actor, ip, action, target) VALUES ($1, $2, $3, $4, $5)")
+    .bind(event.timestamp)
+    .bind(event.actor)
+    .bind(&event.ip)
+    .bind(&event.action)
+    .bind(&event.target)
+    .execute(&state.db).await;
+
+    if let Err(e) = res {
+        tracing::error!("Couldn't write audit log entry for {} {}: {}", action, target, e);
+    }
+
+    // nobody listening isn't an error
+    let _ = state.events.send(event);
+}
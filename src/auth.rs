@@ -0,0 +1,251 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use axum::http::StatusCode;
+use chrono::prelude::*;
+use sha2::{Digest, Sha256};
+
+use crate::{abuse::{self, Event}, db::TokenInfo, jwt::looks_like_jwt, paseto::looks_like_paseto, session::hex, tls::ClientCert, totp, AppState};
+
+/// Number of token characters kept when logging attempts.
+const TOKEN_PREFIX_LEN: usize = 4;
+
+/// Selects a [`TokenInfo`], applying the namespace's upload limit on top of
+/// the token's own.
+const TOKEN_QUERY: &str = "SELECT tokens.rowid AS id, tokens.scope, tokens.namespace, tokens.base_url, tokens.expires_at,
+    COALESCE(MIN(tokens.max_upload_size, namespaces.max_upload_size), tokens.max_upload_size, namespaces.max_upload_size) AS max_upload_size
+    FROM tokens LEFT JOIN namespaces ON namespaces.name = tokens.namespace
+    WHERE (tokens.expires_at IS NULL OR tokens.expires_at > CAST(strftime('%s', 'now') AS INTEGER))";
+
+#[derive(Debug)]
+struct FailureRecord {
+    count: u32,
+    last: Instant,
+    banned_until: Option<Instant>,
+}
+
+/// Tracks failed authentication attempts per client IP and per token hash,
+/// banning either key with exponential backoff once it fails too often.
+#[derive(Debug, Default)]
+pub struct AuthGuard {
+    failures: Mutex<HashMap<String, FailureRecord>>,
+}
+
+impl AuthGuard {
+    fn banned(&self, keys: &[String]) -> bool {
+        let now = Instant::now();
+        let failures = self.failures.lock().unwrap();
+        keys.iter().any(|k| {
+            failures
+                .get(k)
+                .and_then(|r| r.banned_until)
+                .is_some_and(|until| until > now)
+        })
+    }
+
+    /// Counts a failure against each of `keys`, returning the longest ban it
+    /// led to.
+    fn record_failure(&self, keys: &[String], max_failures: u32, base: Duration, max: Duration) -> Option<Duration> {
+        let now = Instant::now();
+        let mut longest = None;
+        let mut failures = self.failures.lock().unwrap();
+
+        // forget keys that have been quiet for longer than the longest ban
+        failures.retain(|_, r| now.duration_since(r.last) < max || r.banned_until.is_some_and(|u| u > now));
+
+        for key in keys {
+            let record = failures.entry(key.clone()).or_insert(FailureRecord {
+                count: 0,
+                last: now,
+                banned_until: None,
+            });
+            record.count += 1;
+            record.last = now;
+
+            if record.count >= max_failures {
+                let exponent = (record.count - max_failures).min(16);
+                let ban = base.saturating_mul(1 << exponent).min(max);
+                record.banned_until = Some(now + ban);
+                tracing::warn!("Banning {} for {} seconds after {} failed attempts", key, ban.as_secs(), record.count);
+                longest = longest.max(Some(ban));
+            }
+        }
+        longest
+    }
+
+    fn clear(&self, keys: &[String]) {
+        let mut failures = self.failures.lock().unwrap();
+        for key in keys {
+            failures.remove(key);
+        }
+    }
+}
+
+pub fn token_prefix(token: &str) -> String {
+    token.chars().take(TOKEN_PREFIX_LEN).collect()
+}
+
+/// Identifies `token` in the lockout and the attempt log without keeping it.
+/// Unlike its prefix, which many tokens share, failures against one token
+/// can't lock out the others this way.
+pub fn token_hash(token: &str) -> String {
+    hex(&Sha256::digest(token.as_bytes()))
+}
+
+/// Looks up `token`, enforcing the failed-attempt lockout for `ip`.
+pub async fn authenticate(state: &AppState, ip: IpAddr, token: &str) -> Result<TokenInfo, StatusCode> {
+    let hash = token_hash(token);
+    let keys = [format!("ip:{}", ip), format!("token:{}", hash)];
+
+    if state.auth_guard.banned(&keys) {
+        record_attempt(state, ip, &token_prefix(token), Some(&hash), "locked").await;
+        return Err(StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    // PASETOs have three dot-separated parts as well, so they go first
+    let res = match (&state.paseto, &state.jwt) {
+        (Some(paseto), _) if looks_like_paseto(token) => Ok(paseto.verify(token)),
+        (_, Some(jwt)) if looks_like_jwt(token) => Ok(jwt.verify(token).await),
+        _ => sqlx::query_as::<_, TokenInfo>(&format!("{} AND tokens.value = $1", TOKEN_QUERY))
+        .bind(token)
+        .fetch_optional(&state.db).await
+    };
+
+    match res {
+        Ok(Some(info)) => {
+            state.auth_guard.clear(&keys);
+            Ok(info)
+        },
+        Ok(None) => {
+            record_failure(state, ip, &keys).await;
+            record_attempt(state, ip, &token_prefix(token), Some(&hash), "failure").await;
+            Err(StatusCode::UNAUTHORIZED)
+        },
+        Err(_) => Err(StatusCode::INTERNAL_SERVER_ERROR)
+    }
+}
+
+/// Looks up the token with row id `key_id` for a request signed with it,
+/// like S3's, which `verify` checks given the token. The same lockout as for
+/// [`authenticate`] applies.
+pub async fn authenticate_signed(state: &AppState, ip: IpAddr, key_id: &str, verify: impl FnOnce(&str) -> bool) -> Result<TokenInfo, StatusCode> {
+    let keys = [format!("ip:{}", ip), format!("key:{}", key_id)];
+
+    if state.auth_guard.banned(&keys) {
+        record_attempt(state, ip, key_id, None, "locked").await;
+        return Err(StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    let id = key_id.parse::<i64>().ok();
+    let secret = sqlx::query_scalar::<_, Option<String>>("SELECT value FROM tokens WHERE rowid = $1")
+    .bind(id)
+    .fetch_optional(&state.db).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    .flatten();
+
+    let info = match secret {
+        Some(secret) if verify(&secret) => sqlx::query_as::<_, TokenInfo>(&format!("{} AND tokens.rowid = $1", TOKEN_QUERY))
+        .bind(id)
+        .fetch_optional(&state.db).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?,
+        _ => None,
+    };
+
+    match info {
+        Some(info) => {
+            state.auth_guard.clear(&keys);
+            Ok(info)
+        },
+        None => {
+            record_failure(state, ip, &keys).await;
+            record_attempt(state, ip, key_id, None, "failure").await;
+            Err(StatusCode::UNAUTHORIZED)
+        },
+    }
+}
+
+/// Identifies the caller by its client certificate when one is mapped to a
+/// token, falling back to the bearer `token` otherwise.
+pub async fn authenticate_client(state: &AppState, ip: IpAddr, cert: Option<&ClientCert>, token: Option<&str>) -> Result<TokenInfo, StatusCode> {
+    match cert {
+        Some(cert) => {
+            let res = sqlx::query_as::<_, TokenInfo>(&format!("{} AND tokens.cert_fingerprint = $1", TOKEN_QUERY))
+            .bind(&cert.fingerprint)
+            .fetch_optional(&state.db).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+            if let Some(info) = res {
+                return Ok(info);
+            }
+            abuse::log(state, ip, Event::UnknownCert, &[("fingerprint", &cert.fingerprint)]).await;
+        },
+        None if state.config().tls_require_client_cert => return Err(StatusCode::UNAUTHORIZED),
+        None => {}
+    }
+
+    match token {
+        Some(token) => authenticate(state, ip, token).await,
+        None => Err(StatusCode::UNAUTHORIZED)
+    }
+}
+
+/// Like [`authenticate`], but only lets admin tokens through, along with the
+/// current `totp` code for admins who enrolled a second factor.
+pub async fn authenticate_admin(state: &AppState, ip: IpAddr, token: &str, totp: Option<&str>) -> Result<TokenInfo, StatusCode> {
+    let info = authenticate(state, ip, token).await?;
+    if !info.is_admin() {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    check_totp(state, ip, token, &info, totp).await?;
+    Ok(info)
+}
+
+/// Checks the current `totp` code of `info`, which `token` was found to be,
+/// if it enrolled a second factor.
+pub async fn check_totp(state: &AppState, ip: IpAddr, token: &str, info: &TokenInfo, totp: Option<&str>) -> Result<(), StatusCode> {
+    let secret = sqlx::query_scalar::<_, Option<String>>("SELECT totp_secret FROM tokens WHERE rowid = $1")
+    .bind(info.id)
+    .fetch_optional(&state.db).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    .flatten();
+
+    if let Some(secret) = secret {
+        // counted apart from token failures, which a valid token clears
+        let keys = [format!("totp:{}", info.id.unwrap_or_default())];
+        if state.auth_guard.banned(&keys) {
+            record_attempt(state, ip, &token_prefix(token), Some(&token_hash(token)), "totp locked").await;
+            return Err(StatusCode::TOO_MANY_REQUESTS);
+        }
+
+        if !totp.map_or(false, |code| totp::verify(&secret, code, Utc::now().timestamp())) {
+            record_failure(state, ip, &keys).await;
+            record_attempt(state, ip, &token_prefix(token), Some(&token_hash(token)), "totp").await;
+            return Err(StatusCode::UNAUTHORIZED);
+        }
+        state.auth_guard.clear(&keys);
+    }
+    Ok(())
+}
+
+async fn record_failure(state: &AppState, ip: IpAddr, keys: &[String]) {
+    let config = state.config();
+    if let Some(ban) = state.auth_guard.record_failure(keys, config.auth_max_failures, config.auth_ban_base, config.auth_ban_max) {
+        abuse::log(state, ip, Event::Ban, &[("seconds", &ban.as_secs().to_string())]).await;
+    }
+}
+
+async fn record_attempt(state: &AppState, ip: IpAddr, prefix: &str, hash: Option<&str>, outcome: &str) {
+    abuse::log(state, ip, Event::AuthFailure, &[("outcome", outcome), ("token", prefix)]).await;
+
+    let res = sqlx::query("INSERT INTO auth_attempts (ip, token_prefix, token_hash, outcome, timestamp) VALUES ($1, $2, $3, $4, $5)")
+    .bind(ip.to_string())
+    .bind(prefix)
+    .bind(hash)
+    .bind(outcome)
+    .bind(Utc::now().timestamp())
+    .execute(&state.db).await;
+
+    if let Err(e) = res {
+        tracing::error!("Couldn't record authentication attempt: {}", e);
+    }
+}
@@ -0,0 +1,215 @@
+use std::{
+    collections::{HashMap, HashSet},
+    str::FromStr,
+    sync::{Arc, Mutex, RwLock},
+    time::Duration,
+};
+
+use axum::{
+    body::{self, HttpBody, StreamBody},
+    extract::State,
+    http::{header, Method, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use chrono::prelude::*;
+use futures::StreamExt;
+
+use crate::AppState;
+
+/// How often the bytes counted in memory are written out. A crash loses at
+/// most this much of the count.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(10);
+
+/// What downloads of pastes whose owner used up their monthly bandwidth get.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverQuota {
+    /// `429`, with `Retry-After` pointing at the start of next month.
+    TooManyRequests,
+    /// `403`.
+    Forbidden,
+}
+
+impl FromStr for OverQuota {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "429" => Ok(Self::TooManyRequests),
+            "403" => Ok(Self::Forbidden),
+            _ => anyhow::bail!("unknown bandwidth quota status {:?}, expected 429 or 403", s),
+        }
+    }
+}
+
+/// Bytes served per paste, counted in memory so serving doesn't wait on the
+/// database, until they're written to `pastes.bytes_served` and the daily
+/// `bandwidth` table.
+#[derive(Debug, Default)]
+pub struct Bandwidth {
+    /// Shared with the bodies of responses being sent, which count as they go.
+    pending: Arc<Mutex<HashMap<String, u64>>>,
+    /// Tokens that used up their `monthly_bandwidth`, as of the last flush.
+    over_quota: RwLock<HashSet<i64>>,
+}
+
+impl Bandwidth {
+    pub fn add(&self, filename: &str, bytes: u64) {
+        add(&self.pending, filename, bytes);
+    }
+
+    fn take(&self) -> HashMap<String, u64> {
+        std::mem::take(&mut *self.pending.lock().unwrap())
+    }
+}
+
+fn add(pending: &Mutex<HashMap<String, u64>>, filename: &str, bytes: u64) {
+    *pending.lock().unwrap().entry(filename.to_string()).or_default() += bytes;
+}
+
+/// Counts what a successful `response` to a `method` request for `filename`
+/// sends, as it's sent, so downloads cut short only count what went out.
+/// Answers to `HEAD` send nothing.
+pub fn record(state: &AppState, filename: &str, method: &Method, response: Response) -> Response {
+    if method == Method::HEAD || !response.status().is_success() {
+        return response;
+    }
+
+    let pending = state.bandwidth.pending.clone();
+    let filename = filename.to_string();
+    let (parts, body) = response.into_parts();
+    let chunks = futures::stream::unfold(body, |mut body| async move {
+        body.data().await.map(|chunk| (chunk, body))
+    });
+    let counted = chunks.inspect(move |chunk| {
+        if let Ok(bytes) = chunk {
+            add(&pending, &filename, bytes.len() as u64);
+        }
+    });
+    Response::from_parts(parts, body::boxed(StreamBody::new(counted)))
+}
+
+/// Refuses to serve the paste stored as `filename` when its owner is over
+/// their monthly bandwidth quota. Counts lag up to `FLUSH_INTERVAL` behind, so
+/// a quota can be overshot by what's downloaded in that time.
+pub async fn check(state: &AppState, filename: &str) -> Result<(), Response> {
+    if state.bandwidth.over_quota.read().unwrap().is_empty() {
+        return Ok(());
+    }
+
+    let owner = sqlx::query_scalar::<_, Option<i64>>("SELECT owner FROM pastes WHERE filename = $1")
+    .bind(filename)
+    .fetch_optional(&state.db).await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR.into_response())?
+    .flatten();
+    let over = owner.map_or(false, |o| state.bandwidth.over_quota.read().unwrap().contains(&o));
+    if !over {
+        return Ok(());
+    }
+
+    tracing::debug!("Refused {}, its owner is over their bandwidth quota", filename);
+    let message = "The owner of this paste used up their bandwidth for the month\n";
+    Err(match state.config().bandwidth_quota_status {
+        OverQuota::TooManyRequests => {
+            let retry_after = (next_month(Utc::now()) - Utc::now()).num_seconds().max(1).to_string();
+            (StatusCode::TOO_MANY_REQUESTS, [(header::RETRY_AFTER, retry_after)], message).into_response()
+        },
+        OverQuota::Forbidden => (StatusCode::FORBIDDEN, message).into_response(),
+    })
+}
+
+/// Applies bandwidth quotas to pastes served from `/paste`.
+pub async fn enforce<B>(
+    State(state): State<Arc<AppState>>,
+    req: Request<B>,
+    next: Next<B>,
+) -> Response {
+    // appending to or updating a paste isn't a download
+    if req.method() == Method::GET || req.method() == Method::HEAD {
+        // `/<filename>/...` serves parts or versions of the same paste
+        let path = req.uri().path().trim_start_matches('/');
+        let filename = path.split('/').next().unwrap_or(path);
+        if let Err(response) = check(&state, filename).await {
+            return response;
+        }
+    }
+    next.run(req).await
+}
+
+fn month_start(now: DateTime<Utc>) -> DateTime<Utc> {
+    Utc.with_ymd_and_hms(now.year(), now.month(), 1, 0, 0, 0).single().unwrap_or(now)
+}
+
+fn next_month(now: DateTime<Utc>) -> DateTime<Utc> {
+    let (year, month) = if now.month() == 12 { (now.year() + 1, 1) } else { (now.year(), now.month() + 1) };
+    Utc.with_ymd_and_hms(year, month, 1, 0, 0, 0).single().unwrap_or(now)
+}
+
+/// Writes out what was counted every `FLUSH_INTERVAL`.
+pub fn start(state: Arc<AppState>) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(FLUSH_INTERVAL);
+        loop {
+            interval.tick().await;
+            flush(&state).await;
+        }
+    });
+}
+
+async fn flush(state: &AppState) {
+    let pending = state.bandwidth.take();
+    if !pending.is_empty() {
+        if let Err(e) = write(state, &pending).await {
+            tracing::error!("Couldn't record bandwidth for {} pastes: {}", pending.len(), e);
+            // nothing was written, so it's counted again next time
+            for (filename, bytes) in pending {
+                state.bandwidth.add(&filename, bytes);
+            }
+        }
+    }
+
+    // also picks up quotas that changed and the start of a new month
+    match over_quota(state).await {
+        Ok(over) => *state.bandwidth.over_quota.write().unwrap() = over,
+        Err(e) => tracing::error!("Couldn't check bandwidth quotas: {}", e),
+    }
+}
+
+/// Tokens that were served at least their `monthly_bandwidth` this month.
+async fn over_quota(state: &AppState) -> sqlx::Result<HashSet<i64>> {
+    let since = month_start(Utc::now()).format("%Y-%m-%d").to_string();
+    let owners = sqlx::query_scalar::<_, i64>("SELECT bandwidth.owner FROM bandwidth
+        JOIN tokens ON tokens.rowid = bandwidth.owner
+        WHERE tokens.monthly_bandwidth IS NOT NULL AND bandwidth.day >= $1
+        GROUP BY bandwidth.owner HAVING SUM(bandwidth.bytes) >= MAX(tokens.monthly_bandwidth)")
+    .bind(since)
+    .fetch_all(&state.db).await?;
+
+    let over: HashSet<i64> = owners.into_iter().collect();
+    let before = state.bandwidth.over_quota.read().unwrap().clone();
+    for owner in over.difference(&before) {
+        tracing::info!("Token {} used up its bandwidth for the month", owner);
+    }
+    Ok(over)
+}
+
+async fn write(state: &AppState, pending: &HashMap<String, u64>) -> sqlx::Result<()> {
+    let day = Utc::now().format("%Y-%m-%d").to_string();
+    let mut tx = state.db.begin().await?;
+    for (filename, bytes) in pending {
+        let bytes = *bytes as i64;
+        sqlx::query("UPDATE pastes SET bytes_served = bytes_served + $1 WHERE filename = $2")
+        .bind(bytes)
+        .bind(filename)
+        .execute(&mut *tx).await?;
+
+        sqlx::query("INSERT INTO bandwidth (day, paste, owner, bytes)
+        SELECT $1, filename, owner, $2 FROM pastes WHERE filename = $3
+        ON CONFLICT (day, paste) DO UPDATE SET bytes = bytes + excluded.bytes")
+        .bind(&day)
+        .bind(bytes)
+        .bind(filename)
+        .execute(&mut *tx).await?;
+    }
+    tx.commit().await
+}
@@ -0,0 +1,135 @@
+use std::{str::FromStr, sync::Arc};
+
+use axum::{
+    async_trait,
+    extract::{FromRequestParts, Host},
+    http::{request::Parts, StatusCode},
+};
+
+use crate::{namespace::{self, RequestNamespace}, tls::Tls, AppState};
+
+/// The URL the server is reached at, without a trailing slash.
+///
+/// The namespace's base URL or `BASE_URL` when set, otherwise built from the
+/// request's host and `X-Forwarded-Proto`, as long as the host is in
+/// `ALLOWED_HOSTS` or belongs to a namespace. Requests made under a namespace
+/// prefix get it appended.
+#[derive(Debug, Clone)]
+pub struct BaseUrl(pub String);
+
+#[async_trait]
+impl FromRequestParts<Arc<AppState>> for BaseUrl {
+    type Rejection = StatusCode;
+
+    async fn from_request_parts(parts: &mut Parts, state: &Arc<AppState>) -> Result<Self, Self::Rejection> {
+        let RequestNamespace(ns) = RequestNamespace::from_request_parts(parts, state).await?;
+        if let Some(url) = ns.as_ref().and_then(|n| n.base_url.clone()) {
+            return Ok(Self(url));
+        }
+
+        let base = Self::derive(parts, state, ns.is_some()).await?;
+        match namespace::prefix(parts) {
+            Some(name) => Ok(Self(format!("{}/ns/{}", base, name))),
+            None => Ok(Self(base)),
+        }
+    }
+}
+
+impl BaseUrl {
+    /// `namespaced` requests may come in for hosts outside `ALLOWED_HOSTS`,
+    /// since they matched a namespace.
+    async fn derive(parts: &mut Parts, state: &Arc<AppState>, namespaced: bool) -> Result<String, StatusCode> {
+        if let Some(url) = &state.config().base_url {
+            return Ok(url.clone());
+        }
+
+        let Host(host) = Host::from_request_parts(parts, state).await.map_err(|_| StatusCode::BAD_REQUEST)?;
+        if !(namespaced && is_sane(&host)) && !is_allowed(&host, &state.config().allowed_hosts) {
+            tracing::debug!("Rejected request for host {:?}", host);
+            return Err(StatusCode::BAD_REQUEST);
+        }
+
+        let scheme = match parts.headers.get("x-forwarded-proto").and_then(|v| v.to_str().ok()) {
+            Some(p) if p.eq_ignore_ascii_case("https") => "https",
+            Some(p) if p.eq_ignore_ascii_case("http") => "http",
+            Some(_) => return Err(StatusCode::BAD_REQUEST),
+            None if parts.extensions.get::<Tls>().is_some() => "https",
+            None => "http",
+        };
+
+        Ok(format!("{}://{}", scheme, host))
+    }
+}
+
+/// Anything that could break out of the authority part is never fine.
+fn is_sane(host: &str) -> bool {
+    !host.is_empty() && !host.contains(|c: char| c == '/' || c == '@' || c == '\\' || c.is_whitespace())
+}
+
+/// Whether `host` (with an optional port) matches an entry of `allowed`, which
+/// are lowercase hostnames, `*.domain` for any subdomain or `*` for anything.
+fn is_allowed(host: &str, allowed: &[String]) -> bool {
+    if !is_sane(host) {
+        return false;
+    }
+
+    let name = match host.rsplit_once(':') {
+        // bracketed IPv6 addresses have colons of their own
+        Some((name, port)) if !port.contains(']') => name,
+        _ => host,
+    };
+
+    let name = name.to_ascii_lowercase();
+    allowed.iter().any(|a| {
+        a == "*" || *a == name || a.strip_prefix('*').map_or(false, |domain| domain.starts_with('.') && name.ends_with(domain))
+    })
+}
+
+/// The shape of links handed out for pastes, like `{base}/f/{filename}`, for
+/// when a proxy in front rewrites them back to `/paste/<filename>`.
+///
+/// `{base}` is the base URL, `{filename}` the whole name the paste is stored
+/// as, `{stem}` the part of it before the extension and `{ext}` the extension,
+/// empty if there's none. `{filename}` is required, since pastes are only
+/// found by their whole name.
+#[derive(Debug, Clone)]
+pub struct UrlTemplate(String);
+
+impl Default for UrlTemplate {
+    fn default() -> Self {
+        Self("{base}/paste/{filename}".to_string())
+    }
+}
+
+impl FromStr for UrlTemplate {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut rest = s;
+        while let Some(start) = rest.find('{') {
+            let end = rest[start..].find('}')
+                .ok_or_else(|| anyhow::anyhow!("unclosed placeholder in URL template {:?}", s))?;
+            match &rest[start + 1..start + end] {
+                "base" | "filename" | "stem" | "ext" => {},
+                "id" => anyhow::bail!("{{id}} in URL template {:?} is called {{stem}} now, and can't replace {{filename}}", s),
+                other => anyhow::bail!("unknown placeholder {{{}}} in URL template {:?}", other, s),
+            }
+            rest = &rest[start + end + 1..];
+        }
+        if !s.contains("{filename}") {
+            anyhow::bail!("URL template {:?} has to contain {{filename}}", s);
+        }
+        Ok(Self(s.to_string()))
+    }
+}
+
+impl UrlTemplate {
+    /// The link to the paste stored as `filename`, under `base_url`.
+    pub fn render(&self, base_url: &str, filename: &str) -> String {
+        let (stem, ext) = filename.split_once('.').unwrap_or((filename, ""));
+        self.0.replace("{base}", base_url)
+            .replace("{filename}", filename)
+            .replace("{stem}", stem)
+            .replace("{ext}", ext)
+    }
+}
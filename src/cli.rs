@@ -0,0 +1,217 @@
+use std::{collections::HashSet, path::Path};
+
+use chrono::prelude::*;
+use sqlx::SqlitePool;
+
+use crate::{
+    config::Config,
+    db,
+    hooks::Hooks,
+    jobs::{collect_garbage, parse_interval},
+    namespace,
+    replica,
+    session::random_hex,
+    storage,
+};
+
+const USAGE: &str = "usage:
+    smolpaste                      run the server
+    smolpaste token new [--scope upload|admin] [--expires 90d]
+                        [--max-upload-size BYTES] [--namespace NAME]
+                        [--monthly-bandwidth BYTES]
+    smolpaste admin list [--limit N] [--owner TOKEN_ID]
+    smolpaste admin gc
+    smolpaste admin purge-expired
+    smolpaste admin stats
+    smolpaste admin verify
+    smolpaste replica generations
+    smolpaste replica restore PATH [--until UNIX_TIME|RFC3339]
+                        [--generation NAME]";
+
+/// Runs the subcommand in `args`, with the database and storage the server
+/// would use, so it works from cron without going through the API.
+pub async fn run(args: &[String]) -> anyhow::Result<()> {
+    tracing_subscriber::fmt().with_writer(std::io::stderr).init();
+
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    match args.as_slice() {
+        ["token", "new", rest @ ..] => new_token(&connect().await?.0, rest).await,
+        ["admin", "list", rest @ ..] => list(&connect().await?.0, rest).await,
+        ["admin", "gc"] => {
+            let (db, config) = connect().await?;
+            collect_garbage(&db, &config, &Hooks::from_config(&config, &db)).await
+        },
+        ["admin", "purge-expired"] => {
+            let (db, config) = connect().await?;
+            let expired = namespace::expire(&db, &Hooks::from_config(&config, &db)).await?;
+            println!("{} expired pastes removed", expired);
+            Ok(())
+        },
+        ["admin", "stats"] => stats(&connect().await?.0).await,
+        ["admin", "verify"] => verify(&connect().await?.0).await,
+        ["replica", "generations"] => replica::list_generations(&Config::from_env()?).await,
+        ["replica", "restore", target, rest @ ..] => restore(target, rest).await,
+        ["help" | "--help" | "-h"] => {
+            println!("{}", USAGE);
+            Ok(())
+        },
+        _ => anyhow::bail!("unknown command\n{}", USAGE),
+    }
+}
+
+async fn connect() -> anyhow::Result<(SqlitePool, Config)> {
+    let config = Config::from_env()?;
+    let db = crate::connect(&config).await?;
+    db::init_db(&db).await?;
+    Ok((db, config))
+}
+
+/// Pairs up `--flag value` arguments.
+fn flags<'a>(args: &[&'a str]) -> anyhow::Result<Vec<(&'a str, &'a str)>> {
+    args.chunks(2)
+        .map(|pair| match pair {
+            [flag, value] if flag.starts_with("--") => Ok((&flag[2..], *value)),
+            _ => anyhow::bail!("expected --flag value pairs, got {:?}\n{}", pair, USAGE),
+        })
+        .collect()
+}
+
+/// Creates a token and prints it, which is the only time it's shown.
+async fn new_token(db: &SqlitePool, args: &[&str]) -> anyhow::Result<()> {
+    let mut scope = "upload";
+    let mut expires_at = None;
+    let mut max_upload_size = None;
+    let mut namespace = None;
+    let mut monthly_bandwidth = None;
+
+    for (flag, value) in flags(args)? {
+        match flag {
+            "scope" if matches!(value, "upload" | "admin") => scope = value,
+            "scope" => anyhow::bail!("scope must be upload or admin"),
+            "expires" => expires_at = Some(Utc::now().timestamp() + parse_interval(value)?.as_secs() as i64),
+            "max-upload-size" => max_upload_size = Some(value.parse::<i64>()?),
+            "namespace" => namespace = Some(value),
+            "monthly-bandwidth" => monthly_bandwidth = Some(value.parse::<i64>()?),
+            _ => anyhow::bail!("unknown flag --{}\n{}", flag, USAGE),
+        }
+    }
+
+    let token = random_hex();
+    let id = sqlx::query("INSERT INTO tokens (value, created_at, scope, expires_at, max_upload_size, namespace, monthly_bandwidth) VALUES ($1, $2, $3, $4, $5, $6, $7)")
+    .bind(&token)
+    .bind(Utc::now().timestamp())
+    .bind(scope)
+    .bind(expires_at)
+    .bind(max_upload_size)
+    .bind(namespace)
+    .bind(monthly_bandwidth)
+    .execute(db).await?
+    .last_insert_rowid();
+
+    println!("{}", token);
+    // what S3 clients use as their access key id
+    eprintln!("Id {}", id);
+    if let Some(expires_at) = expires_at.and_then(|t| Utc.timestamp_opt(t, 0).single()) {
+        eprintln!("Expires {}", expires_at.to_rfc3339());
+    }
+    Ok(())
+}
+
+/// Prints the newest pastes, one per line: id, filename, size, upload time
+/// and owner.
+async fn list(db: &SqlitePool, args: &[&str]) -> anyhow::Result<()> {
+    let mut limit = 100;
+    let mut owner = None;
+    for (flag, value) in flags(args)? {
+        match flag {
+            "limit" => limit = value.parse::<i64>()?,
+            "owner" => owner = Some(value.parse::<i64>()?),
+            _ => anyhow::bail!("unknown flag --{}\n{}", flag, USAGE),
+        }
+    }
+
+    let rows = sqlx::query_as::<_, (String, String, i64, i64, Option<i64>)>("SELECT id, filename, size, timestamp, owner FROM pastes
+    WHERE $1 IS NULL OR owner = $1
+    ORDER BY timestamp DESC LIMIT $2")
+    .bind(owner)
+    .bind(limit)
+    .fetch_all(db).await?;
+
+    for (id, filename, size, timestamp, owner) in rows {
+        let uploaded = Utc.timestamp_opt(timestamp, 0).single().map(|t| t.to_rfc3339()).unwrap_or_default();
+        let owner = owner.map(|o| o.to_string()).unwrap_or_else(|| "-".to_string());
+        println!("{}\t{}\t{}\t{}\t{}", id, filename, size, uploaded, owner);
+    }
+    Ok(())
+}
+
+/// Restores the replicated database to `target`, without touching the live
+/// one, which the server can then be pointed at.
+async fn restore(target: &str, args: &[&str]) -> anyhow::Result<()> {
+    let mut until = None;
+    let mut generation = None;
+    for (flag, value) in flags(args)? {
+        match flag {
+            "until" => until = Some(match value.parse::<i64>() {
+                Ok(t) => Utc.timestamp_opt(t, 0).single().ok_or_else(|| anyhow::anyhow!("invalid time {}", value))?,
+                Err(_) => DateTime::parse_from_rfc3339(value)?.with_timezone(&Utc),
+            }),
+            "generation" => generation = Some(value),
+            _ => anyhow::bail!("unknown flag --{}\n{}", flag, USAGE),
+        }
+    }
+    replica::restore(&Config::from_env()?, Path::new(target), generation, until).await
+}
+
+async fn stats(db: &SqlitePool) -> anyhow::Result<()> {
+    let (pastes, bytes) = sqlx::query_as::<_, (i64, i64)>("SELECT COUNT(*), COALESCE(SUM(size), 0) FROM pastes")
+    .fetch_one(db).await?;
+    let tokens = sqlx::query_scalar::<_, i64>("SELECT COUNT(*) FROM tokens")
+    .fetch_one(db).await?;
+    let since = Utc::now().timestamp() - 86400;
+    let uploads = sqlx::query_scalar::<_, i64>("SELECT COUNT(*) FROM pastes WHERE timestamp >= $1")
+    .bind(since)
+    .fetch_one(db).await?;
+
+    println!("pastes\t{}", pastes);
+    println!("bytes\t{}", bytes);
+    println!("tokens\t{}", tokens);
+    println!("uploads_24h\t{}", uploads);
+    Ok(())
+}
+
+/// Checks that every paste has its file at the recorded size and that every
+/// file belongs to a paste, failing if anything's off.
+async fn verify(db: &SqlitePool) -> anyhow::Result<()> {
+    let pastes = sqlx::query_as::<_, (String, i64, Option<String>)>("SELECT filename, size, storage_prefix FROM pastes")
+    .fetch_all(db).await?;
+
+    let mut problems = 0;
+    for (filename, size, prefix) in &pastes {
+        match tokio::fs::metadata(storage::paste_path_in(prefix.as_deref(), filename)).await {
+            Ok(m) if m.len() as i64 == *size => {},
+            Ok(m) => {
+                println!("size mismatch\t{}\t{} recorded, {} on disk", filename, size, m.len());
+                problems += 1;
+            },
+            Err(e) => {
+                println!("missing\t{}\t{}", filename, e);
+                problems += 1;
+            },
+        }
+    }
+
+    let known: HashSet<&str> = pastes.iter().map(|(f, _, _)| f.as_str()).collect();
+    for (name, path) in storage::walk().await? {
+        if !known.contains(name.as_str()) {
+            println!("orphaned\t{}\t{}", name, path.display());
+            problems += 1;
+        }
+    }
+
+    if problems > 0 {
+        anyhow::bail!("{} problems in {} pastes", problems, pastes.len());
+    }
+    println!("{} pastes ok", pastes.len());
+    Ok(())
+}
@@ -0,0 +1,116 @@
+use std::{io::Read, path::PathBuf, sync::Arc};
+
+use axum::{
+    body::Body,
+    extract::{Path, State},
+    http::{Request, StatusCode},
+    response::Response,
+};
+use sha2::{Digest, Sha256};
+use sqlx::SqlitePool;
+
+use crate::{config::Config, namespace::RequestNamespace, paste, session::hex, storage, AppState};
+
+/// Shortest hash prefix a paste can be looked up by.
+const MIN_PREFIX_LEN: usize = 8;
+/// Hex digits of the hash put in links, enough to never collide by chance.
+const LINK_LEN: usize = 16;
+
+/// The SHA-256 of the file at `path`, in hex.
+pub async fn hash_file(path: PathBuf) -> std::io::Result<String> {
+    tokio::task::spawn_blocking(move || {
+        let mut file = std::fs::File::open(path)?;
+        let mut hasher = Sha256::new();
+        let mut buf = vec![0; 64 * 1024];
+        loop {
+            match file.read(&mut buf)? {
+                0 => break,
+                n => hasher.update(&buf[..n]),
+            }
+        }
+        Ok(hex(&hasher.finalize()))
+    })
+    .await?
+}
+
+/// Hashes the paste stored as `filename` and records it, returning the hash
+/// when the instance addresses pastes by content. Called again whenever the
+/// contents change.
+pub async fn address(db: &SqlitePool, config: &Config, filename: &str) -> Option<String> {
+    if !config.content_addressing {
+        // still recorded, for verifying the paste later, but nobody's waiting
+        schedule(db, filename);
+        return None;
+    }
+
+    match record(db, filename).await {
+        Ok(hash) => Some(hash),
+        Err(e) => {
+            tracing::warn!("Couldn't hash {}: {}", filename, e);
+            None
+        },
+    }
+}
+
+/// Records the hash of the paste stored as `filename` in the background,
+/// whether or not pastes are addressed by it, so it can be verified later.
+pub fn schedule(db: &SqlitePool, filename: &str) {
+    let db = db.clone();
+    let filename = filename.to_string();
+    tokio::spawn(async move {
+        if let Err(e) = record(&db, &filename).await {
+            tracing::warn!("Couldn't hash {}: {}", filename, e);
+        }
+    });
+}
+
+/// Hashes the paste stored as `filename` and records the hash, unless it was
+/// recorded as it was uploaded and hasn't changed since.
+async fn record(db: &SqlitePool, filename: &str) -> anyhow::Result<String> {
+    let recorded = sqlx::query_scalar::<_, Option<String>>("SELECT sha256 FROM pastes WHERE filename = $1")
+    .bind(filename)
+    .fetch_optional(db).await?
+    .flatten();
+    if let Some(hash) = recorded {
+        return Ok(hash);
+    }
+
+    let hash = hash_file(storage::locate(db, filename).await?).await?;
+    sqlx::query("UPDATE pastes SET sha256 = $1 WHERE filename = $2")
+    .bind(&hash)
+    .bind(filename)
+    .execute(db).await?;
+    Ok(hash)
+}
+
+/// Where the paste with this content `hash` can be found.
+pub fn url(base_url: &str, hash: &str) -> String {
+    format!("{}/p/{}", base_url, &hash[..LINK_LEN.min(hash.len())])
+}
+
+/// Serves `/p/<hash>`, the paste whose contents hash to what starts with
+/// `hash`, so links verify what they point to. Pastes with the same contents
+/// are interchangeable, so whichever is found first is served.
+#[axum::debug_handler]
+pub async fn serve(
+    State(state): State<Arc<AppState>>,
+    namespace: RequestNamespace,
+    Path(hash): Path<String>,
+    req: Request<Body>,
+) -> Result<Response, StatusCode> {
+    let hash = hash.to_ascii_lowercase();
+    if !state.config().content_addressing || hash.len() < MIN_PREFIX_LEN || !hash.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    // every hash with the prefix sorts between it and it followed by `g`
+    let filename = sqlx::query_scalar::<_, String>("SELECT filename FROM pastes
+        WHERE sha256 >= $1 AND sha256 < $1 || 'g' AND namespace IS $2 LIMIT 1")
+    .bind(&hash)
+    .bind(namespace.name())
+    .fetch_optional(&state.db).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    .ok_or(StatusCode::NOT_FOUND)?;
+
+    // through everything `/paste` goes through, as the paste's own bytes
+    Ok(paste::serve(state, &filename, true, req).await)
+}
@@ -0,0 +1,435 @@
+use std::path::PathBuf;
+
+use serde::Serialize;
+use sqlx::{SqliteConnection, SqlitePool};
+use uuid::Uuid;
+
+use crate::storage;
+
+pub async fn init_db(db: &SqlitePool) -> anyhow::Result<()> {
+    sqlx::query("CREATE TABLE IF NOT EXISTS pastes (
+        id TEXT PRIMARY KEY NOT NULL,
+        size INTEGER,
+        filename TEXT,
+        timestamp INTEGER
+    )")
+    .execute(db).await?;
+
+    add_column(db, "pastes", "owner", "INTEGER").await?;
+    add_column(db, "pastes", "bytes_served", "INTEGER NOT NULL DEFAULT 0").await?;
+    add_column(db, "pastes", "collection", "TEXT").await?;
+    add_column(db, "pastes", "mime", "TEXT").await?;
+    add_column(db, "pastes", "unlisted", "INTEGER NOT NULL DEFAULT 0").await?;
+    add_column(db, "pastes", "namespace", "TEXT").await?;
+    add_column(db, "pastes", "version", "INTEGER NOT NULL DEFAULT 1").await?;
+    add_column(db, "pastes", "updated_at", "INTEGER").await?;
+    add_column(db, "pastes", "pdf_pages", "INTEGER").await?;
+    add_column(db, "pastes", "legal_hold", "INTEGER NOT NULL DEFAULT 0").await?;
+    add_column(db, "pastes", "immutable", "INTEGER NOT NULL DEFAULT 0").await?;
+    add_column(db, "pastes", "sha256", "TEXT").await?;
+    add_column(db, "pastes", "metadata", "TEXT").await?;
+    add_column(db, "pastes", "title", "TEXT").await?;
+    add_column(db, "pastes", "recipients", "TEXT").await?;
+    add_column(db, "pastes", "signature", "BLOB").await?;
+    add_column(db, "pastes", "signed_by", "TEXT").await?;
+    add_column(db, "pastes", "verified_at", "INTEGER").await?;
+    add_column(db, "pastes", "corrupted_at", "INTEGER").await?;
+    // the storage prefix of its namespace when it was stored, which is where
+    // it stays if that changes
+    add_column(db, "pastes", "storage_prefix", "TEXT").await?;
+
+    sqlx::query("CREATE INDEX IF NOT EXISTS pastes_sha256 ON pastes (sha256)")
+    .execute(db).await?;
+    // what's stored where, so two uploads can't be given the same name
+    sqlx::query("CREATE UNIQUE INDEX IF NOT EXISTS pastes_filename ON pastes (filename)")
+    .execute(db).await?;
+
+    sqlx::query("CREATE TABLE IF NOT EXISTS versions (
+        paste TEXT NOT NULL,
+        version INTEGER NOT NULL,
+        size INTEGER NOT NULL,
+        timestamp INTEGER NOT NULL,
+        PRIMARY KEY (paste, version)
+    )")
+    .execute(db).await?;
+
+    // pastes removed because they expired, so they can be told apart from
+    // ones that never existed
+    sqlx::query("CREATE TABLE IF NOT EXISTS expired_pastes (
+        filename TEXT PRIMARY KEY NOT NULL,
+        timestamp INTEGER NOT NULL
+    )")
+    .execute(db).await?;
+
+    // what pastes were pinned to IPFS as; rows outlive their paste until the
+    // gc job has unpinned it
+    sqlx::query("CREATE TABLE IF NOT EXISTS ipfs_pins (
+        filename TEXT PRIMARY KEY NOT NULL,
+        cid TEXT NOT NULL
+    )")
+    .execute(db).await?;
+
+    // keys pastes were put as through the S3 API, per token
+    sqlx::query("CREATE TABLE IF NOT EXISTS s3_objects (
+        owner INTEGER NOT NULL,
+        key TEXT NOT NULL,
+        filename TEXT NOT NULL,
+        PRIMARY KEY (owner, key)
+    )")
+    .execute(db).await?;
+
+    sqlx::query("CREATE TABLE IF NOT EXISTS namespaces (
+        name TEXT PRIMARY KEY NOT NULL,
+        host TEXT UNIQUE,
+        base_url TEXT,
+        max_upload_size INTEGER,
+        retention_seconds INTEGER
+    )")
+    .execute(db).await?;
+    add_column(db, "namespaces", "storage_prefix", "TEXT").await?;
+
+    sqlx::query("CREATE TABLE IF NOT EXISTS collections (
+        id TEXT PRIMARY KEY NOT NULL,
+        timestamp INTEGER NOT NULL,
+        owner INTEGER
+    )")
+    .execute(db).await?;
+
+    sqlx::query("CREATE TABLE IF NOT EXISTS download_links (
+        id TEXT PRIMARY KEY NOT NULL,
+        paste TEXT NOT NULL,
+        uses_left INTEGER NOT NULL,
+        created_at INTEGER NOT NULL
+    )")
+    .execute(db).await?;
+
+    sqlx::query("CREATE TABLE IF NOT EXISTS tokens (
+        value TEXT,
+        created_at INTEGER
+)")
+    .execute(db).await?;
+
+    add_column(db, "tokens", "scope", "TEXT NOT NULL DEFAULT 'upload'").await?;
+    add_column(db, "tokens", "max_upload_size", "INTEGER").await?;
+    add_column(db, "tokens", "cert_fingerprint", "TEXT").await?;
+    add_column(db, "tokens", "totp_secret", "TEXT").await?;
+    add_column(db, "tokens", "totp_pending", "TEXT").await?;
+    add_column(db, "tokens", "namespace", "TEXT").await?;
+    add_column(db, "tokens", "base_url", "TEXT").await?;
+    add_column(db, "tokens", "expires_at", "INTEGER").await?;
+    // bytes its pastes may be downloaded per calendar month
+    add_column(db, "tokens", "monthly_bandwidth", "INTEGER").await?;
+
+    sqlx::query("CREATE TABLE IF NOT EXISTS auth_attempts (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        ip TEXT NOT NULL,
+        token_prefix TEXT NOT NULL,
+        outcome TEXT NOT NULL,
+        timestamp INTEGER NOT NULL
+    )")
+    .execute(db).await?;
+    // the prefix is shared by many tokens, so purges go by the whole token
+    add_column(db, "auth_attempts", "token_hash", "TEXT").await?;
+
+    sqlx::query("CREATE TABLE IF NOT EXISTS audit_log (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        timestamp INTEGER NOT NULL,
+        actor INTEGER,
+        ip TEXT NOT NULL,
+        action TEXT NOT NULL,
+        target TEXT NOT NULL
+    )")
+    .execute(db).await?;
+
+    sqlx::query("CREATE TABLE IF NOT EXISTS sessions (
+        id TEXT PRIMARY KEY NOT NULL,
+        token INTEGER,
+        scope TEXT NOT NULL,
+        max_upload_size INTEGER,
+        csrf TEXT NOT NULL,
+        expires_at INTEGER NOT NULL
+    )")
+    .execute(db).await?;
+
+    add_column(db, "sessions", "namespace", "TEXT").await?;
+
+    sqlx::query("CREATE TABLE IF NOT EXISTS multipart_uploads (
+        id TEXT PRIMARY KEY NOT NULL,
+        owner INTEGER,
+        name TEXT NOT NULL,
+        created_at INTEGER NOT NULL
+    )")
+    .execute(db).await?;
+
+    // the size each part was announced with, counted against the upload
+    // limit before it's written
+    sqlx::query("CREATE TABLE IF NOT EXISTS multipart_parts (
+        upload TEXT NOT NULL,
+        part INTEGER NOT NULL,
+        size INTEGER NOT NULL,
+        PRIMARY KEY (upload, part)
+    )")
+    .execute(db).await?;
+
+    sqlx::query("CREATE TABLE IF NOT EXISTS flags (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        filename TEXT NOT NULL,
+        kind TEXT NOT NULL,
+        score REAL,
+        created_at INTEGER NOT NULL,
+        reviewed_at INTEGER,
+        reviewed_by INTEGER
+    )")
+    .execute(db).await?;
+
+    sqlx::query("CREATE TABLE IF NOT EXISTS takedowns (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        filename TEXT NOT NULL,
+        reason TEXT NOT NULL,
+        status INTEGER NOT NULL,
+        actor INTEGER,
+        created_at INTEGER NOT NULL,
+        lifted_at INTEGER,
+        lifted_by INTEGER
+    )")
+    .execute(db).await?;
+
+    sqlx::query("CREATE TABLE IF NOT EXISTS stats_snapshots (
+        timestamp INTEGER NOT NULL,
+        pastes INTEGER NOT NULL,
+        bytes INTEGER NOT NULL
+    )")
+    .execute(db).await?;
+
+    // uploads counted per hour and per day by the `histogram` job
+    sqlx::query("CREATE TABLE IF NOT EXISTS upload_histogram (
+        granularity TEXT NOT NULL,
+        bucket INTEGER NOT NULL,
+        uploads INTEGER NOT NULL,
+        PRIMARY KEY (granularity, bucket)
+    )")
+    .execute(db).await?;
+
+    sqlx::query("CREATE INDEX IF NOT EXISTS audit_log_action ON audit_log (action, timestamp)")
+    .execute(db).await?;
+
+    // bytes served per paste and day, with the owner as of then, so it
+    // outlives the paste
+    sqlx::query("CREATE TABLE IF NOT EXISTS bandwidth (
+        day TEXT NOT NULL,
+        paste TEXT NOT NULL,
+        owner INTEGER,
+        bytes INTEGER NOT NULL,
+        PRIMARY KEY (day, paste)
+    )")
+    .execute(db).await?;
+
+    sqlx::query("CREATE TABLE IF NOT EXISTS accesses (
+        paste TEXT NOT NULL,
+        timestamp INTEGER NOT NULL,
+        ip TEXT
+    )")
+    .execute(db).await?;
+
+    // old names of renamed pastes, which lead to the new ones
+    sqlx::query("CREATE TABLE IF NOT EXISTS redirects (
+        source TEXT PRIMARY KEY NOT NULL,
+        target TEXT NOT NULL,
+        namespace TEXT,
+        created_at INTEGER NOT NULL
+    )")
+    .execute(db).await?;
+
+    sqlx::query("CREATE INDEX IF NOT EXISTS accesses_paste ON accesses (paste, timestamp)")
+    .execute(db).await?;
+
+    // the audit log is append-only
+    sqlx::query("CREATE TRIGGER IF NOT EXISTS audit_log_no_update BEFORE UPDATE ON audit_log
+    BEGIN SELECT RAISE(ABORT, 'audit_log is append-only'); END")
+    .execute(db).await?;
+
+    sqlx::query(AUDIT_LOG_NO_DELETE).execute(db).await?;
+
+    Ok(())
+}
+
+const AUDIT_LOG_NO_DELETE: &str = "CREATE TRIGGER IF NOT EXISTS audit_log_no_delete BEFORE DELETE ON audit_log
+    BEGIN SELECT RAISE(ABORT, 'audit_log is append-only'); END";
+
+/// Removes the audit entries of `actor`, the one exception to the log being
+/// append-only, for erasure requests. Run it in a transaction, so the log is
+/// never left unprotected.
+pub async fn erase_audit_entries(tx: &mut SqliteConnection, actor: i64) -> sqlx::Result<u64> {
+    sqlx::query("DROP TRIGGER audit_log_no_delete").execute(&mut *tx).await?;
+    let erased = sqlx::query("DELETE FROM audit_log WHERE actor = $1")
+    .bind(actor)
+    .execute(&mut *tx).await?
+    .rows_affected();
+    sqlx::query(AUDIT_LOG_NO_DELETE).execute(&mut *tx).await?;
+    Ok(erased)
+}
+
+/// `CREATE TABLE IF NOT EXISTS` won't touch tables created by older versions,
+/// so new columns are added here when they're missing.
+async fn add_column(db: &SqlitePool, table: &str, column: &str, definition: &str) -> anyhow::Result<()> {
+    let exists = sqlx::query_scalar::<_, i32>("SELECT COUNT(*) FROM pragma_table_info($1) WHERE name = $2")
+    .bind(table)
+    .bind(column)
+    .fetch_one(db).await?;
+
+    if exists == 0 {
+        sqlx::query(&format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition))
+        .execute(db).await?;
+    }
+
+    Ok(())
+}
+
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct PasteInfo {
+    pub id: Uuid,
+    /// SQLite only stores signed integers.
+    #[sqlx(try_from = "i64")]
+    pub size: u64,
+    pub filename: String,
+    pub timestamp: i64,
+    /// Row id of the token that uploaded the paste.
+    pub owner: Option<i64>,
+    /// Collection the paste was uploaded as part of, if any.
+    pub collection: Option<Uuid>,
+    /// MIME type sniffed from the contents, when the upload had no extension.
+    pub mime: Option<String>,
+    /// Kept out of search engines.
+    pub unlisted: bool,
+    pub namespace: Option<String>,
+    /// Can't be updated, appended to or renamed.
+    pub immutable: bool,
+    /// Arbitrary JSON object the uploader attached.
+    pub metadata: Option<String>,
+    /// What the uploader called it, shown instead of the filename.
+    pub title: Option<String>,
+    /// Who it's encrypted to, when it's an age file.
+    pub recipients: Option<sqlx::types::Json<Vec<String>>>,
+    /// SHA-256 of the contents, in hex, when it was worked out while they
+    /// were uploaded.
+    pub sha256: Option<String>,
+}
+
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct FileNameWrapper {
+    pub filename: String,
+}
+
+/// A paste along with the storage prefix it was stored under, for finding
+/// its file once its row is gone.
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct StoredPaste {
+    pub filename: String,
+    pub storage_prefix: Option<String>,
+}
+
+impl StoredPaste {
+    pub fn path(&self) -> PathBuf {
+        storage::paste_path_in(self.storage_prefix.as_deref(), &self.filename)
+    }
+}
+
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct TokenInfo {
+    /// `None` for identities that don't come from the tokens table, like JWTs.
+    pub id: Option<i64>,
+    pub scope: String,
+    /// Largest upload this token may make, in bytes, including its
+    /// namespace's limit.
+    pub max_upload_size: Option<i64>,
+    /// Namespace the token belongs to, `None` for the default one.
+    pub namespace: Option<String>,
+    /// Where this token's uploads are linked from, like a vanity domain.
+    pub base_url: Option<String>,
+    /// When the credential stops being valid, as a Unix timestamp, so
+    /// sessions opened with it don't outlive it.
+    pub expires_at: Option<i64>,
+}
+
+impl TokenInfo {
+    pub fn is_admin(&self) -> bool {
+        self.scope == "admin"
+    }
+
+    /// Whether this token may change or remove a paste owned by `owner`.
+    /// Admins may manage any paste, everyone else only the ones their token
+    /// uploaded, so identities without a row id manage none.
+    pub fn can_manage(&self, owner: Option<i64>) -> bool {
+        self.is_admin() || (self.id.is_some() && owner == self.id)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct AuthAttempt {
+    pub id: i64,
+    pub ip: String,
+    pub token_prefix: String,
+    pub outcome: String,
+    pub timestamp: i64,
+}
+
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct AuditEntry {
+    pub id: i64,
+    pub timestamp: i64,
+    pub actor: Option<i64>,
+    pub ip: String,
+    pub action: String,
+    pub target: String,
+}
+
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct TokenUsage {
+    pub pastes: i64,
+    pub bytes: i64,
+    pub bytes_served: i64,
+    pub last_activity: Option<i64>,
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::sqlite::SqlitePoolOptions;
+
+    use super::*;
+
+    /// Sizes past 4 GiB are stored and read back whole.
+    #[tokio::test]
+    async fn paste_size_round_trips_past_4_gib() {
+        // every in-memory connection is a database of its own
+        let db = SqlitePoolOptions::new().max_connections(1).connect("sqlite::memory:").await.unwrap();
+        init_db(&db).await.unwrap();
+
+        let info = PasteInfo {
+            id: Uuid::new_v4(),
+            size: (5 << 30) + 7,
+            filename: "abcd.txt".to_string(),
+            timestamp: 0,
+            owner: None,
+            collection: None,
+            mime: None,
+            unlisted: false,
+            namespace: None,
+            immutable: false,
+            metadata: None,
+            title: None,
+            recipients: None,
+            sha256: None,
+        };
+        crate::paste::insert_paste(&db, &info).await.unwrap();
+
+        // ids are stored as text, which `Uuid` only decodes from blobs
+        let query = "SELECT $1 AS id, size, filename, timestamp, owner, collection, mime, unlisted, namespace,
+            immutable, metadata, title, recipients, sha256 FROM pastes";
+        let read = sqlx::query_as::<_, PasteInfo>(query).bind(info.id).fetch_one(&db).await.unwrap();
+        assert_eq!(read.size, info.size);
+
+        // and a size no u64 holds is refused rather than wrapped around
+        sqlx::query("UPDATE pastes SET size = -1").execute(&db).await.unwrap();
+        assert!(sqlx::query_as::<_, PasteInfo>(query).bind(info.id).fetch_one(&db).await.is_err());
+    }
+}
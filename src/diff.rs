@@ -0,0 +1,165 @@
+use std::{sync::Arc, time::Duration};
+
+use axum::{
+    extract::{Path, Query, State},
+    http::{header, StatusCode},
+    response::{Html, IntoResponse, Response},
+};
+use serde::{Deserialize, Serialize};
+use similar::{DiffTag, TextDiff};
+use tera::Context;
+
+use crate::{name::PasteName, namespace::RequestNamespace, storage, text::is_text, versions, AppState};
+
+/// Pastes larger than this aren't diffed, since both sides are held in memory
+/// and diffing them ties up a worker thread.
+const MAX_DIFF_SIZE: u64 = 1024 * 1024;
+
+/// How long diffing may take before it settles for a coarser diff, which is
+/// still correct but may replace more lines than it had to.
+const DIFF_TIMEOUT: Duration = Duration::from_millis(500);
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct DiffParam {
+    a: String,
+    b: String,
+    /// `unified` (the default) or `html` for a side-by-side view.
+    format: Option<String>,
+}
+
+#[axum::debug_handler]
+pub async fn diff(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<DiffParam>,
+) -> Result<Response, StatusCode> {
+    let (a_name, a) = load(&state, &query.a).await?;
+    let (b_name, b) = load(&state, &query.b).await?;
+
+    let raw_url = format!("/api/diff?a={}&b={}", query.a, query.b);
+    render(&state, query.format.as_deref(), (&a_name, &a), (&b_name, &b), &raw_url)
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct VersionDiffParam {
+    /// Defaults to the version before `to`.
+    from: Option<i64>,
+    /// Defaults to the current version.
+    to: Option<i64>,
+    /// `html` (the default) or `unified`.
+    format: Option<String>,
+}
+
+/// Shows what changed between two versions of a paste.
+#[axum::debug_handler]
+pub async fn versions(
+    State(state): State<Arc<AppState>>,
+    namespace: RequestNamespace,
+    Path(filename): Path<String>,
+    Query(query): Query<VersionDiffParam>,
+) -> Result<Response, StatusCode> {
+    let filename = PasteName::parse(&filename).ok_or(StatusCode::NOT_FOUND)?.into_string();
+    if !namespace.owns(&state, &filename).await? {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    if !is_text(&filename) {
+        return Err(StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    }
+
+    let to = match query.to {
+        Some(v) => v,
+        None => versions::current(&state, &filename).await?,
+    };
+    let from = query.from.unwrap_or(to - 1);
+
+    let a_name = format!("{} v{}", filename, from);
+    let b_name = format!("{} v{}", filename, to);
+    let a = read(&versions::find(&state, &filename, from).await?).await?;
+    let b = read(&versions::find(&state, &filename, to).await?).await?;
+
+    let raw_url = format!("/view/{}/diff?from={}&to={}&format=unified", filename, from, to);
+    render(&state, Some(query.format.as_deref().unwrap_or("html")), (&a_name, &a), (&b_name, &b), &raw_url)
+}
+
+/// Answers with the diff between `a` and `b`, both given as a name and
+/// contents, in the requested `format`.
+fn render(state: &AppState, format: Option<&str>, (a_name, a): (&str, &str), (b_name, b): (&str, &str), raw_url: &str) -> Result<Response, StatusCode> {
+    let diff = TextDiff::configure().timeout(DIFF_TIMEOUT).diff_lines(a, b);
+
+    match format {
+        None | Some("unified") => {
+            let body = diff.unified_diff().header(a_name, b_name).to_string();
+            Ok(([(header::CONTENT_TYPE, "text/x-diff; charset=utf-8")], body).into_response())
+        },
+        Some("html") => Ok(side_by_side(state, &diff, a_name, b_name, raw_url)?.into_response()),
+        Some(_) => Err(StatusCode::BAD_REQUEST)
+    }
+}
+
+async fn load(state: &AppState, id: &str) -> Result<(String, String), StatusCode> {
+    let filename = sqlx::query_scalar::<_, String>("SELECT filename FROM pastes WHERE id = $1")
+    .bind(id)
+    .fetch_optional(&state.db).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    .ok_or(StatusCode::NOT_FOUND)?;
+
+    if !is_text(&filename) {
+        return Err(StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    }
+
+    let path = storage::locate(&state.db, &filename).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let contents = read(&path).await?;
+    Ok((filename, contents))
+}
+
+async fn read(path: &std::path::Path) -> Result<String, StatusCode> {
+    let size = tokio::fs::metadata(&path).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?.len();
+    if size > MAX_DIFF_SIZE {
+        return Err(StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    let bytes = tokio::fs::read(&path).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct Row<'a> {
+    class: &'static str,
+    left_n: Option<usize>,
+    left: &'a str,
+    right_n: Option<usize>,
+    right: &'a str,
+}
+
+fn side_by_side(state: &AppState, diff: &TextDiff<'_, '_, '_, str>, a_name: &str, b_name: &str, raw_url: &str) -> Result<Html<String>, StatusCode> {
+    let old = diff.old_slices();
+    let new = diff.new_slices();
+
+    let mut rows = Vec::new();
+    for op in diff.ops() {
+        let (tag, old_range, new_range) = op.as_tag_tuple();
+        let class = match tag {
+            DiffTag::Equal => "equal",
+            DiffTag::Delete => "delete",
+            DiffTag::Insert => "insert",
+            DiffTag::Replace => "replace",
+        };
+
+        for i in 0..old_range.len().max(new_range.len()) {
+            let left = (i < old_range.len()).then(|| old_range.start + i);
+            let right = (i < new_range.len()).then(|| new_range.start + i);
+            rows.push(Row {
+                class,
+                left_n: left.map(|l| l + 1),
+                left: left.map_or("", |l| old[l].trim_end_matches(['\r', '\n'])),
+                right_n: right.map(|r| r + 1),
+                right: right.map_or("", |r| new[r].trim_end_matches(['\r', '\n'])),
+            });
+        }
+    }
+
+    let mut context = Context::new();
+    context.insert("a", a_name);
+    context.insert("b", b_name);
+    context.insert("rows", &rows);
+    context.insert("raw_url", raw_url);
+    state.templates.render("diff.html", context)
+}
@@ -0,0 +1,132 @@
+use std::{io, path::Path, str::FromStr};
+
+use axum::http::StatusCode;
+use base64::Engine;
+use tokio::io::AsyncReadExt;
+
+use crate::name::PasteName;
+
+/// How an age file starts, binary or armored.
+const MAGIC: &[u8] = b"age-encryption.org/v1\n";
+const ARMOR_BEGIN: &[u8] = b"-----BEGIN AGE ENCRYPTED FILE-----";
+/// Bytes read looking for the end of the header. Every stanza is small, so
+/// this fits far more recipients than anyone sends to.
+const MAX_HEADER_LEN: u64 = 64 * 1024;
+/// Most recipients a paste can be encrypted to on upload.
+const MAX_RECIPIENTS: usize = 32;
+/// Extension of pastes encrypted in the browser, whose key the server never
+/// sees; see `static/e2e.js`.
+const CLIENT_EXTENSION: &str = "e2e";
+
+/// Whether the paste stored as `filename` was encrypted in the browser, so
+/// the viewer has to leave decrypting it to the page.
+pub fn is_client_encrypted(filename: &str) -> bool {
+    filename.rsplit_once('.').map_or(false, |(_, ext)| ext.eq_ignore_ascii_case(CLIENT_EXTENSION))
+}
+
+/// Whether `head`, the first bytes of a file, is an age file.
+pub fn is_age(head: &[u8]) -> bool {
+    head.starts_with(MAGIC) || head.starts_with(ARMOR_BEGIN)
+}
+
+/// Describes who the age file at `path` is encrypted to, one entry per
+/// recipient stanza: its type, plus the key tag for SSH recipients, which
+/// tells which key it is. X25519 stanzas don't say whose they are. Returns
+/// `None` for anything that isn't an age file.
+pub async fn inspect(path: &Path) -> io::Result<Option<Vec<String>>> {
+    let mut head = Vec::new();
+    tokio::fs::File::open(path).await?.take(MAX_HEADER_LEN).read_to_end(&mut head).await?;
+    Ok(stanzas(&head))
+}
+
+fn stanzas(head: &[u8]) -> Option<Vec<String>> {
+    let header = if head.starts_with(ARMOR_BEGIN) { dearmor(head)? } else { head.to_vec() };
+    let rest = header.strip_prefix(MAGIC)?;
+
+    let mut recipients = Vec::new();
+    for line in rest.split(|b| *b == b'\n') {
+        let line = std::str::from_utf8(line).ok()?;
+        if line.starts_with("---") {
+            return Some(recipients);
+        }
+        let mut args = match line.strip_prefix("-> ") {
+            Some(stanza) => stanza.split(' '),
+            // a line of the stanza's body
+            None => continue,
+        };
+        let kind = args.next()?;
+        recipients.push(match (kind, args.next()) {
+            (k, Some(tag)) if k.starts_with("ssh-") => format!("{} {}", k, tag),
+            (k, _) => k.to_string(),
+        });
+    }
+    // the header didn't end within what was read
+    None
+}
+
+/// The start of the binary file an armored one encodes, as far as it's there.
+fn dearmor(head: &[u8]) -> Option<Vec<u8>> {
+    let text = String::from_utf8_lossy(head);
+    let body: String = text.lines()
+        .skip(1)
+        .map(str::trim)
+        .take_while(|l| !l.starts_with("-----"))
+        .collect();
+    // a partial last group of four characters can't be decoded yet
+    let whole = body.len() - body.len() % 4;
+    base64::engine::general_purpose::STANDARD.decode(&body[..whole]).ok()
+}
+
+/// Checks the comma-separated age recipients of an upload asking to be
+/// encrypted, X25519 (`age1...`) or SSH public keys.
+pub fn parse_recipients(raw: &str) -> Result<Vec<String>, StatusCode> {
+    let recipients: Vec<String> = raw.split(',').map(str::trim).filter(|r| !r.is_empty()).map(str::to_string).collect();
+    if recipients.is_empty() || recipients.len() > MAX_RECIPIENTS {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    for recipient in &recipients {
+        recipient_from_str(recipient).map_err(|_| StatusCode::BAD_REQUEST)?;
+    }
+    Ok(recipients)
+}
+
+fn recipient_from_str(s: &str) -> anyhow::Result<Box<dyn age::Recipient + Send>> {
+    if s.starts_with("age1") {
+        return Ok(Box::new(age::x25519::Recipient::from_str(s).map_err(|e| anyhow::anyhow!("{}", e))?));
+    }
+    age::ssh::Recipient::from_str(s)
+        .map(|r| Box::new(r) as Box<dyn age::Recipient + Send>)
+        .map_err(|e| anyhow::anyhow!("invalid recipient: {:?}", e))
+}
+
+/// Encrypts the upload staged at `source`, to be stored as `filename`, to
+/// `recipients`, which were checked with [`parse_recipients`], staging the
+/// result at `target`. It's stored under the same id with a `.age`
+/// extension, so it's never taken for what it was before. Returns its name
+/// and size.
+pub async fn encrypt(filename: &str, source: &Path, target: &Path, recipients: &[String]) -> anyhow::Result<(String, u64)> {
+    let name = PasteName::parse(filename).ok_or_else(|| anyhow::anyhow!("invalid name {}", filename))?;
+    let encrypted = format!("{}.age", name.id());
+    let keys = recipients.iter().map(|r| recipient_from_str(r)).collect::<anyhow::Result<Vec<_>>>()?;
+    // creating the target would empty the source before it's read
+    anyhow::ensure!(source != target, "can't encrypt {} in place", filename);
+
+    let source = source.to_path_buf();
+    let output = tokio::fs::File::create(target).await?.into_std().await;
+    let res = tokio::task::spawn_blocking(move || -> anyhow::Result<u64> {
+        let encryptor = age::Encryptor::with_recipients(keys).ok_or_else(|| anyhow::anyhow!("no recipients"))?;
+        let mut writer = encryptor.wrap_output(io::BufWriter::new(&output))?;
+        io::copy(&mut std::fs::File::open(&source)?, &mut writer)?;
+        writer.finish()?.into_inner().map_err(|e| e.into_error())?;
+        output.sync_data()?;
+        Ok(output.metadata()?.len())
+    }).await?;
+
+    match res {
+        Ok(size) => Ok((encrypted, size)),
+        Err(e) => {
+            let _ = tokio::fs::remove_file(target).await;
+            Err(e)
+        },
+    }
+}
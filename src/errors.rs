@@ -0,0 +1,73 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::State,
+    http::{header, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde_json::json;
+
+use crate::{name::PasteName, AppState};
+
+/// Gives bare error statuses a body: the error page for browsers and a small
+/// JSON object for everything else. Responses that already have one, like
+/// the pages' own 404, are left alone.
+pub async fn error_pages<B>(State(state): State<Arc<AppState>>, req: Request<B>, next: Next<B>) -> Response {
+    let wants_html = req.headers().get(header::ACCEPT)
+        .and_then(|a| a.to_str().ok())
+        .map_or(false, |a| a.contains("text/html"));
+    let paste = paste_name(req.uri().path());
+
+    let response = next.run(req).await;
+    let mut status = response.status();
+    if !(status.is_client_error() || status.is_server_error()) || response.headers().contains_key(header::CONTENT_TYPE) {
+        return response;
+    }
+
+    if status == StatusCode::NOT_FOUND {
+        if let Some(filename) = paste {
+            if was_expired(&state, &filename).await {
+                status = StatusCode::GONE;
+            }
+        }
+    }
+
+    let mut page = if wants_html {
+        state.templates.error(status)
+    } else {
+        (status, Json(json!({ "status": status.as_u16(), "error": message(status) }))).into_response()
+    };
+    // keep things like `Retry-After` and `WWW-Authenticate`
+    for (name, value) in response.headers() {
+        if name != header::CONTENT_LENGTH {
+            page.headers_mut().entry(name).or_insert_with(|| value.clone());
+        }
+    }
+    page
+}
+
+/// What went wrong, in words, for the error page or body.
+pub fn message(status: StatusCode) -> &'static str {
+    match status {
+        StatusCode::NOT_FOUND => "There's nothing here. The paste may have been deleted, or the link is wrong.",
+        StatusCode::GONE => "This paste has expired.",
+        StatusCode::PAYLOAD_TOO_LARGE => "That upload is larger than you're allowed to make.",
+        s => s.canonical_reason().unwrap_or_default(),
+    }
+}
+
+/// The paste a `/paste/<name>` or `/view/<name>` path is about.
+fn paste_name(path: &str) -> Option<String> {
+    let rest = path.strip_prefix("/paste/").or_else(|| path.strip_prefix("/view/"))?;
+    let name = rest.split('/').next().unwrap_or(rest);
+    PasteName::parse(name).map(|n| n.into_string())
+}
+
+async fn was_expired(state: &AppState, filename: &str) -> bool {
+    sqlx::query_scalar::<_, i64>("SELECT COUNT(*) FROM expired_pastes WHERE filename = $1")
+    .bind(filename)
+    .fetch_one(&state.db).await
+    .map_or(false, |n| n > 0)
+}
@@ -0,0 +1,131 @@
+use std::path::Path;
+
+/// Removes EXIF, XMP and similar metadata from the JPEG, PNG or WebP image at
+/// `path`, without re-encoding it. Returns the new size if anything was
+/// removed; other files and images that don't parse are left alone.
+pub async fn strip(path: &Path) -> std::io::Result<Option<u64>> {
+    let data = tokio::fs::read(path).await?;
+    let stripped = match strip_bytes(&data) {
+        Some(s) => s,
+        None => return Ok(None),
+    };
+
+    // written next to the original and renamed over it, so a crash halfway
+    // through can't leave a truncated image behind
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".strip");
+    tokio::fs::write(&tmp, &stripped).await?;
+    tokio::fs::rename(&tmp, path).await?;
+
+    Ok(Some(stripped.len() as u64))
+}
+
+fn strip_bytes(data: &[u8]) -> Option<Vec<u8>> {
+    let out = if data.starts_with(&[0xff, 0xd8]) {
+        strip_jpeg(data)?
+    } else if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+        strip_png(data)?
+    } else if data.len() >= 12 && &data[..4] == b"RIFF" && &data[8..12] == b"WEBP" {
+        strip_webp(data)?
+    } else {
+        return None;
+    };
+    (out.len() != data.len()).then_some(out)
+}
+
+/// Drops APP1 (EXIF, XMP) and APP13 (IPTC) segments, keeping everything from
+/// the start of the scan onwards as it is.
+fn strip_jpeg(data: &[u8]) -> Option<Vec<u8>> {
+    let mut out = data[..2].to_vec();
+    let mut pos = 2;
+
+    loop {
+        if *data.get(pos)? != 0xff {
+            return None;
+        }
+        let marker = *data.get(pos + 1)?;
+        match marker {
+            // padding before a marker
+            0xff => {
+                pos += 1;
+                continue;
+            },
+            // start of scan or end of image, the rest is entropy-coded data
+            0xda | 0xd9 => {
+                out.extend_from_slice(&data[pos..]);
+                return Some(out);
+            },
+            // standalone markers without a length
+            0x01 | 0xd0..=0xd7 => {
+                out.extend_from_slice(&data[pos..pos + 2]);
+                pos += 2;
+                continue;
+            },
+            _ => {},
+        }
+
+        let len = usize::from(u16::from_be_bytes([*data.get(pos + 2)?, *data.get(pos + 3)?]));
+        let end = pos + 2 + len;
+        if len < 2 || end > data.len() {
+            return None;
+        }
+        if marker != 0xe1 && marker != 0xed {
+            out.extend_from_slice(&data[pos..end]);
+        }
+        pos = end;
+    }
+}
+
+/// Drops the `eXIf`, text and timestamp chunks.
+fn strip_png(data: &[u8]) -> Option<Vec<u8>> {
+    let mut out = data[..8].to_vec();
+    let mut pos = 8;
+
+    while pos < data.len() {
+        let len = u32::from_be_bytes(data.get(pos..pos + 4)?.try_into().ok()?) as usize;
+        let kind = data.get(pos + 4..pos + 8)?;
+        // length, type, data and CRC
+        let end = pos.checked_add(12)?.checked_add(len)?;
+        if end > data.len() {
+            return None;
+        }
+        if !matches!(kind, b"eXIf" | b"tEXt" | b"zTXt" | b"iTXt" | b"tIME") {
+            out.extend_from_slice(&data[pos..end]);
+        }
+        pos = end;
+    }
+    Some(out)
+}
+
+/// Drops the `EXIF` and `XMP ` chunks and clears their flags in `VP8X`.
+fn strip_webp(data: &[u8]) -> Option<Vec<u8>> {
+    let mut out = data[..12].to_vec();
+    let mut pos = 12;
+
+    while pos < data.len() {
+        let kind = data.get(pos..pos + 4)?;
+        let len = u32::from_le_bytes(data.get(pos + 4..pos + 8)?.try_into().ok()?) as usize;
+        // chunks are padded to an even length
+        let end = pos.checked_add(8)?.checked_add(len + (len & 1))?.min(data.len());
+        if pos + 8 + len > data.len() {
+            return None;
+        }
+
+        match kind {
+            b"EXIF" | b"XMP " => {},
+            b"VP8X" => {
+                let start = out.len();
+                out.extend_from_slice(&data[pos..end]);
+                if let Some(flags) = out.get_mut(start + 8) {
+                    *flags &= !(0x08 | 0x04);
+                }
+            },
+            _ => out.extend_from_slice(&data[pos..end]),
+        }
+        pos = end;
+    }
+
+    let riff_len = u32::try_from(out.len() - 8).ok()?;
+    out[4..8].copy_from_slice(&riff_len.to_le_bytes());
+    Some(out)
+}
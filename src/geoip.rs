@@ -0,0 +1,85 @@
+use std::{fmt, net::IpAddr};
+
+use axum::{async_trait, http::StatusCode};
+use maxminddb::{geoip2, MaxMindDBError, Reader};
+
+use crate::{config::Config, hooks::{Hook, Upload}};
+
+/// Refuses uploads from countries `UPLOAD_COUNTRIES_BLOCKED` lists, or that
+/// `UPLOAD_COUNTRIES_ALLOWED` doesn't, going by the MaxMind country database
+/// at `GEOIP_DATABASE`. Downloads aren't restricted. With an allowlist,
+/// addresses the database doesn't place anywhere are refused too.
+pub struct CountryFilter {
+    /// `None` when the database couldn't be opened, in which case every
+    /// upload is refused rather than let through unchecked.
+    reader: Option<Reader<Vec<u8>>>,
+    allowed: Vec<String>,
+    blocked: Vec<String>,
+}
+
+impl fmt::Debug for CountryFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CountryFilter")
+            .field("allowed", &self.allowed)
+            .field("blocked", &self.blocked)
+            .finish_non_exhaustive()
+    }
+}
+
+impl CountryFilter {
+    /// The filter `config` asks for, if it restricts any country.
+    pub fn from_config(config: &Config) -> Option<Self> {
+        if config.upload_countries_allowed.is_empty() && config.upload_countries_blocked.is_empty() {
+            return None;
+        }
+
+        let reader = match &config.geoip_database {
+            Some(path) => Reader::open_readfile(path)
+                .map_err(|e| tracing::error!("Couldn't open the GeoIP database {}, refusing all uploads: {}", path, e))
+                .ok(),
+            None => {
+                tracing::error!("Upload countries are restricted without a GEOIP_DATABASE, refusing all uploads");
+                None
+            },
+        };
+        Some(Self {
+            reader,
+            allowed: config.upload_countries_allowed.clone(),
+            blocked: config.upload_countries_blocked.clone(),
+        })
+    }
+
+    /// The ISO code of the country `ip` is in, if the database knows.
+    fn country(reader: &Reader<Vec<u8>>, ip: IpAddr) -> Result<Option<String>, MaxMindDBError> {
+        match reader.lookup::<geoip2::Country>(ip) {
+            Ok(found) => Ok(found.country.and_then(|c| c.iso_code).map(str::to_uppercase)),
+            Err(MaxMindDBError::AddressNotFoundError(_)) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+#[async_trait]
+impl Hook for CountryFilter {
+    fn name(&self) -> &'static str {
+        "country-filter"
+    }
+
+    async fn on_upload(&self, upload: &Upload) -> Result<(), StatusCode> {
+        let reader = self.reader.as_ref().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+        let country = Self::country(reader, upload.ip).map_err(|e| {
+            tracing::error!("Couldn't look up {}: {}", upload.ip, e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+        let refused = match &country {
+            Some(c) => self.blocked.contains(c) || (!self.allowed.is_empty() && !self.allowed.contains(c)),
+            None => !self.allowed.is_empty(),
+        };
+        if refused {
+            tracing::info!("Refused an upload from {} in {}", upload.ip, country.as_deref().unwrap_or("an unknown country"));
+            return Err(StatusCode::UNAVAILABLE_FOR_LEGAL_REASONS);
+        }
+        Ok(())
+    }
+}
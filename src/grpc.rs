@@ -0,0 +1,195 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::Arc,
+};
+
+use axum::{body::Bytes, http::StatusCode};
+use futures::StreamExt;
+use tonic::{Request, Response, Status, Streaming};
+
+use crate::{
+    auth::authenticate_client,
+    content,
+    db::TokenInfo,
+    ipfs,
+    maintenance,
+    metadata,
+    paste::{self, Created, UploadOptions},
+    AppState,
+};
+
+mod proto {
+    tonic::include_proto!("smolpaste");
+}
+
+use proto::{
+    pastes_server::{Pastes, PastesServer},
+    upload_request::Part,
+    DeleteRequest, DeleteResponse, ListRequest, ListResponse, MetadataRequest, PasteMetadata, UploadRequest,
+};
+
+type Row = (String, String, i64, i64, Option<i64>, Option<String>, Option<String>, Option<String>);
+
+/// Selects a [`Row`], to be followed by a `WHERE` clause.
+const ROW_QUERY: &str = "SELECT id, pastes.filename, size, timestamp, owner, ipfs_pins.cid, metadata, title FROM pastes
+    LEFT JOIN ipfs_pins ON ipfs_pins.filename = pastes.filename";
+
+/// The gRPC API, sharing its state with the HTTP one.
+pub struct GrpcService {
+    state: Arc<AppState>,
+}
+
+pub fn server(state: Arc<AppState>) -> PastesServer<GrpcService> {
+    PastesServer::new(GrpcService { state })
+}
+
+impl GrpcService {
+    /// Authenticates like the HTTP API does, though there's never a client
+    /// certificate to go by.
+    async fn authenticate(&self, token: &str, ip: IpAddr) -> Result<TokenInfo, Status> {
+        authenticate_client(&self.state, ip, None, Some(token)).await.map_err(to_status)
+    }
+
+    fn to_metadata(&self, (id, filename, size, timestamp, owner, ipfs_cid, metadata, title): Row) -> PasteMetadata {
+        PasteMetadata {
+            url: self.state.config().url_template.render(&self.state.config().fallback_base_url(), &filename),
+            id,
+            filename,
+            size: size.max(0) as u64,
+            timestamp,
+            owner,
+            ipfs_cid,
+            metadata,
+            title,
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl Pastes for GrpcService {
+    async fn upload(&self, request: Request<Streaming<UploadRequest>>) -> Result<Response<PasteMetadata>, Status> {
+        // pulled out before awaiting, since streaming requests aren't `Sync`
+        let (token, addr) = credentials(&request)?;
+        let (force, unlisted, immutable) = (flag(&request, "force"), flag(&request, "unlisted"), flag(&request, "immutable"));
+        let user = self.authenticate(&token, addr.ip()).await?;
+        if maintenance::is_read_only(&self.state) {
+            return Err(Status::unavailable("read-only for maintenance"));
+        }
+        let mut stream = request.into_inner();
+
+        let header = match stream.next().await {
+            Some(Ok(UploadRequest { part: Some(Part::Header(h)) })) => h,
+            Some(Err(e)) => return Err(e),
+            _ => return Err(Status::invalid_argument("the first message must be a header")),
+        };
+        let options = UploadOptions {
+            force,
+            unlisted,
+            immutable,
+            metadata: header.metadata.as_deref()
+                .map(|m| metadata::parse(m, self.state.config().metadata_max_size)).transpose()
+                .map_err(|_| Status::invalid_argument("metadata must be a small JSON object"))?,
+            title: header.title.as_deref().map(metadata::title).transpose()
+                .map_err(|_| Status::invalid_argument("title too long"))?.flatten(),
+            ..Default::default()
+        };
+
+        let chunks = stream.map(|r| match r {
+            Ok(UploadRequest { part: Some(Part::Chunk(c)) }) => Ok(Bytes::from(c)),
+            Ok(_) => Err(Status::invalid_argument("expected a chunk")),
+            Err(e) => Err(e),
+        });
+
+        let filename = match paste::store_stream(&self.state, &user, addr, options, &header.filename, chunks).await.map_err(to_status)?.0 {
+            Created::Paste(filename) | Created::Duplicate(filename) => filename,
+            Created::Collection(_) => return Err(Status::internal("upload was expanded")),
+        };
+        content::schedule(&self.state.db, &filename);
+        ipfs::schedule(&self.state.db, &self.state.config(), &filename);
+
+        let row = sqlx::query_as::<_, Row>(&format!("{} WHERE pastes.filename = $1", ROW_QUERY))
+        .bind(&filename)
+        .fetch_one(&self.state.db).await.map_err(|_| Status::internal("database error"))?;
+        let mut metadata = self.to_metadata(row);
+        if let Some(base_url) = &user.base_url {
+            metadata.url = self.state.config().url_template.render(base_url, &metadata.filename);
+        }
+        Ok(Response::new(metadata))
+    }
+
+    async fn delete(&self, request: Request<DeleteRequest>) -> Result<Response<DeleteResponse>, Status> {
+        let (token, addr) = credentials(&request)?;
+        let user = self.authenticate(&token, addr.ip()).await?;
+        if maintenance::is_read_only(&self.state) {
+            return Err(Status::unavailable("read-only for maintenance"));
+        }
+
+        paste::delete(&self.state, &user, addr.ip(), &request.get_ref().id).await.map_err(to_status)?;
+        Ok(Response::new(DeleteResponse {}))
+    }
+
+    async fn list(&self, request: Request<ListRequest>) -> Result<Response<ListResponse>, Status> {
+        let (token, addr) = credentials(&request)?;
+        let user = self.authenticate(&token, addr.ip()).await?;
+        let query = request.get_ref();
+
+        // admins see everything, everyone else only their own
+        let owner = if user.is_admin() { None } else { Some(user.id.ok_or_else(|| Status::permission_denied("no owner"))?) };
+        let limit = if query.limit == 0 { 100 } else { query.limit };
+
+        let wanted = (!query.metadata.is_empty()).then(|| serde_json::to_string(&query.metadata)).transpose()
+            .map_err(|_| Status::internal("couldn't encode filter"))?;
+
+        let rows = sqlx::query_as::<_, Row>(&format!("{} WHERE ($1 IS NULL OR owner = $1) AND {}
+        ORDER BY timestamp DESC LIMIT $2 OFFSET $3", ROW_QUERY, metadata::filter(4)))
+        .bind(owner)
+        .bind(limit)
+        .bind(query.offset)
+        .bind(wanted)
+        .fetch_all(&self.state.db).await.map_err(|_| Status::internal("database error"))?;
+
+        Ok(Response::new(ListResponse { pastes: rows.into_iter().map(|r| self.to_metadata(r)).collect() }))
+    }
+
+    async fn metadata(&self, request: Request<MetadataRequest>) -> Result<Response<PasteMetadata>, Status> {
+        let (token, addr) = credentials(&request)?;
+        let user = self.authenticate(&token, addr.ip()).await?;
+        paste::check_owner(&self.state, &user, &request.get_ref().id).await.map_err(to_status)?;
+
+        let row = sqlx::query_as::<_, Row>(&format!("{} WHERE id = $1", ROW_QUERY))
+        .bind(&request.get_ref().id)
+        .fetch_optional(&self.state.db).await.map_err(|_| Status::internal("database error"))?
+        .ok_or_else(|| Status::not_found("no such paste"))?;
+
+        Ok(Response::new(self.to_metadata(row)))
+    }
+}
+
+/// Reads the bearer token from the `authorization` metadata.
+fn credentials<T>(request: &Request<T>) -> Result<(String, SocketAddr), Status> {
+    let addr = request.remote_addr().unwrap_or(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0));
+    let token = request.metadata().get("authorization")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .ok_or_else(|| Status::unauthenticated("missing bearer token"))?;
+    Ok((token.to_string(), addr))
+}
+
+/// Whether the metadata entry `name` is set to `true`.
+fn flag<T>(request: &Request<T>, name: &str) -> bool {
+    request.metadata().get(name).and_then(|v| v.to_str().ok()) == Some("true")
+}
+
+fn to_status(code: StatusCode) -> Status {
+    match code {
+        StatusCode::UNAUTHORIZED => Status::unauthenticated("invalid token"),
+        StatusCode::FORBIDDEN => Status::permission_denied("forbidden"),
+        StatusCode::TOO_MANY_REQUESTS => Status::resource_exhausted("too many failed attempts"),
+        StatusCode::PAYLOAD_TOO_LARGE => Status::resource_exhausted("upload too large"),
+        StatusCode::BAD_REQUEST | StatusCode::UNSUPPORTED_MEDIA_TYPE | StatusCode::UNPROCESSABLE_ENTITY => Status::invalid_argument("upload rejected"),
+        StatusCode::CONFLICT => Status::failed_precondition("upload looks like it contains credentials, retry with force: true"),
+        StatusCode::NOT_FOUND => Status::not_found("no such paste"),
+        StatusCode::LOCKED => Status::failed_precondition("paste is under a legal hold"),
+        _ => Status::internal("internal error"),
+    }
+}
@@ -0,0 +1,45 @@
+use std::{io::SeekFrom, path::Path};
+
+use serde::Serialize;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+/// Bytes shown per row, as in `xxd` and `hexdump -C`.
+const ROW_LEN: usize = 16;
+/// Bytes shown per page, so large files are never read whole.
+pub const PAGE_LEN: u64 = 4096;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Row {
+    offset: String,
+    hex: String,
+    ascii: String,
+}
+
+/// Reads page `page` of the file at `path` and formats it as hex and ASCII.
+pub async fn page(path: &Path, page: u64) -> std::io::Result<Vec<Row>> {
+    let offset = page.saturating_mul(PAGE_LEN);
+    let mut file = tokio::fs::File::open(path).await?;
+    file.seek(SeekFrom::Start(offset)).await?;
+
+    let mut bytes = Vec::with_capacity(PAGE_LEN as usize);
+    file.take(PAGE_LEN).read_to_end(&mut bytes).await?;
+
+    Ok(bytes.chunks(ROW_LEN).enumerate().map(|(i, chunk)| row(offset + (i * ROW_LEN) as u64, chunk)).collect())
+}
+
+fn row(offset: u64, chunk: &[u8]) -> Row {
+    let mut hex = String::with_capacity(ROW_LEN * 3 + 1);
+    for i in 0..ROW_LEN {
+        // an extra space halfway, to make columns easier to count
+        if i == ROW_LEN / 2 {
+            hex.push(' ');
+        }
+        match chunk.get(i) {
+            Some(b) => hex.push_str(&format!("{:02x} ", b)),
+            None => hex.push_str("   "),
+        }
+    }
+
+    let ascii = chunk.iter().map(|&b| if b.is_ascii_graphic() || b == b' ' { b as char } else { '.' }).collect();
+    Row { offset: format!("{:08x}", offset), hex, ascii }
+}
@@ -0,0 +1,132 @@
+use std::{
+    fmt::Debug,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+};
+
+use axum::{async_trait, http::StatusCode};
+use sqlx::SqlitePool;
+
+use crate::{config::Config, geoip, nsfw};
+
+/// A freshly stored upload, before it's recorded in the database.
+#[derive(Debug, Clone)]
+pub struct Upload {
+    /// What it's going to be stored as.
+    pub filename: String,
+    /// Where it's staged until then, which says nothing about its type.
+    pub path: PathBuf,
+    pub size: u64,
+    /// Row id of the uploading token, if it has one.
+    pub owner: Option<i64>,
+    pub ip: IpAddr,
+}
+
+/// Extension points for compiled-in plugins, like virus scanners, webhooks or
+/// custom validation. Every method does nothing by default.
+#[async_trait]
+pub trait Hook: Debug + Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// Runs before an upload is recorded; an error rejects it with that status.
+    async fn on_upload(&self, _upload: &Upload) -> Result<(), StatusCode> {
+        Ok(())
+    }
+
+    /// Runs before a paste is served; an error refuses it with that status.
+    async fn on_serve(&self, _filename: &str, _ip: IpAddr) -> Result<(), StatusCode> {
+        Ok(())
+    }
+
+    /// Runs after a paste has been deleted.
+    async fn on_delete(&self, _filename: &str) {}
+}
+
+/// The registered hooks, run in registration order. They're replaced as a
+/// whole when the config is reloaded, without disturbing runs in progress.
+#[derive(Debug)]
+pub struct Hooks {
+    hooks: RwLock<Arc<Vec<Box<dyn Hook>>>>,
+    /// For plugins that record what they found.
+    db: SqlitePool,
+}
+
+impl Hooks {
+    pub fn from_config(config: &Config, db: &SqlitePool) -> Self {
+        let hooks = Self { hooks: RwLock::default(), db: db.clone() };
+        hooks.reconfigure(config);
+        hooks
+    }
+
+    /// Registers the plugins that are compiled in and enabled by `config`,
+    /// in place of the ones registered before.
+    pub fn reconfigure(&self, config: &Config) {
+        let mut hooks: Vec<Box<dyn Hook>> = Vec::new();
+        if !config.blocked_extensions.is_empty() {
+            hooks.push(Box::new(ExtensionFilter { blocked: config.blocked_extensions.clone() }));
+        }
+        if let Some(filter) = geoip::CountryFilter::from_config(config) {
+            hooks.push(Box::new(filter));
+        }
+        if let Some(url) = &config.nsfw_classifier_url {
+            hooks.push(Box::new(nsfw::Classifier::new(url, config.nsfw_policy, config.nsfw_threshold, self.db.clone())));
+        }
+
+        for hook in &hooks {
+            tracing::info!("Registered hook {}", hook.name());
+        }
+        *self.hooks.write().unwrap_or_else(|e| e.into_inner()) = Arc::new(hooks);
+    }
+
+    fn current(&self) -> Arc<Vec<Box<dyn Hook>>> {
+        self.hooks.read().map(|h| h.clone()).unwrap_or_else(|e| e.into_inner().clone())
+    }
+
+    pub async fn upload(&self, upload: &Upload) -> Result<(), StatusCode> {
+        for hook in self.current().iter() {
+            if let Err(status) = hook.on_upload(upload).await {
+                tracing::info!("Hook {} rejected upload {}", hook.name(), upload.filename);
+                return Err(status);
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn serve(&self, filename: &str, ip: IpAddr) -> Result<(), StatusCode> {
+        for hook in self.current().iter() {
+            if let Err(status) = hook.on_serve(filename, ip).await {
+                tracing::info!("Hook {} refused to serve {} to {}", hook.name(), filename, ip);
+                return Err(status);
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn delete(&self, filename: &str) {
+        for hook in self.current().iter() {
+            hook.on_delete(filename).await;
+        }
+    }
+}
+
+/// Rejects uploads with any of the configured extensions.
+#[derive(Debug)]
+struct ExtensionFilter {
+    blocked: Vec<String>,
+}
+
+#[async_trait]
+impl Hook for ExtensionFilter {
+    fn name(&self) -> &'static str {
+        "extension-filter"
+    }
+
+    async fn on_upload(&self, upload: &Upload) -> Result<(), StatusCode> {
+        let ext = Path::new(&upload.filename).extension().and_then(|e| e.to_str()).unwrap_or_default().to_lowercase();
+        if self.blocked.contains(&ext) {
+            return Err(StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        }
+        Ok(())
+    }
+}
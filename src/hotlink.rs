@@ -0,0 +1,186 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Arc, OnceLock},
+};
+
+use axum::{
+    body::Body,
+    extract::{ConnectInfo, State},
+    http::{header, HeaderValue, Method, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use image::{imageops::{self, FilterType}, ImageFormat};
+use sqlx::SqlitePool;
+use tokio::sync::Semaphore;
+use tower::ServiceExt;
+use tower_http::services::ServeFile;
+
+use crate::{access, bandwidth, config::Config, paste::ServedPaste, thumbnail, AppState};
+
+/// Where watermarked copies of image pastes are cached, as
+/// `watermarked/<filename>.png`.
+const WATERMARKED_DIRECTORY: &str = "watermarked";
+/// Widest a watermark gets, as a share of the image's width.
+const MAX_WATERMARK_SHARE: u32 = 3;
+
+/// What image pastes embedded on pages outside `HOTLINK_ALLOWED_REFERERS` get.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Hotlink {
+    /// `403`.
+    Block,
+    /// A copy with `HOTLINK_WATERMARK` in its bottom right corner.
+    Watermark,
+}
+
+impl FromStr for Hotlink {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "block" => Ok(Self::Block),
+            "watermark" => Ok(Self::Watermark),
+            _ => anyhow::bail!("unknown hotlink action {:?}, expected block or watermark", s),
+        }
+    }
+}
+
+fn watermarked_path(filename: &str) -> PathBuf {
+    Path::new(WATERMARKED_DIRECTORY).join(format!("{}.png", filename))
+}
+
+/// Whether `req` comes from a page embedding the paste somewhere it isn't
+/// allowed to. Requests without a `Referer` are someone opening the link
+/// directly and never are, neither are pages on our own hosts.
+fn is_hotlinked(config: &Config, req: &Request<Body>) -> bool {
+    let referer = match req.headers().get(header::REFERER).and_then(|v| v.to_str().ok()) {
+        Some(r) if !r.is_empty() => r,
+        _ => return false,
+    };
+    let host = match reqwest::Url::parse(referer).ok().and_then(|u| u.host_str().map(str::to_lowercase)) {
+        Some(h) => h,
+        // not a URL we can make sense of, so treat it like none was sent
+        None => return false,
+    };
+
+    // HTTP/2 requests carry it in the URI instead
+    let own = req.headers().get(header::HOST)
+        .and_then(|v| v.to_str().ok())
+        .or_else(|| req.uri().authority().map(|a| a.as_str()))
+        .map(|h| without_port(h).to_lowercase());
+    if own.as_deref() == Some(host.as_str()) || config.allowed_hosts.contains(&host) {
+        return false;
+    }
+    // an allowed domain lets its subdomains embed too
+    !config.hotlink_allowed_referers.iter().any(|a| host == *a || host.ends_with(&format!(".{}", a)))
+}
+
+fn without_port(host: &str) -> &str {
+    match host.rsplit_once(':') {
+        Some((host, port)) if port.bytes().all(|b| b.is_ascii_digit()) => host,
+        _ => host,
+    }
+}
+
+/// Applies `HOTLINK_ACTION` to image pastes served from `/paste` to pages
+/// outside `HOTLINK_ALLOWED_REFERERS`, when that's set.
+pub async fn protect(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    req: Request<Body>,
+    next: Next<Body>,
+) -> Response {
+    let config = state.config();
+    let path = req.uri().path().trim_start_matches('/').to_string();
+    // only the paste itself, not its tail or versions
+    let protected = !config.hotlink_allowed_referers.is_empty()
+        && (req.method() == Method::GET || req.method() == Method::HEAD)
+        && !path.contains('/')
+        && mime_guess::from_path(&path).first().map_or(false, |m| m.type_() == mime_guess::mime::IMAGE);
+    if !protected {
+        return next.run(req).await;
+    }
+
+    let mut response = if !is_hotlinked(&config, &req) {
+        next.run(req).await
+    } else {
+        tracing::debug!("Hotlinked {} from {:?}", path, req.headers().get(header::REFERER));
+        match (config.hotlink_action, &config.hotlink_watermark) {
+            (Hotlink::Watermark, Some(mark)) => serve_watermarked(&state, &path, mark, addr.ip(), req).await,
+            (Hotlink::Watermark, None) => {
+                tracing::warn!("HOTLINK_ACTION is watermark but HOTLINK_WATERMARK isn't set, blocking {}", path);
+                blocked()
+            },
+            (Hotlink::Block, _) => blocked(),
+        }
+    };
+    // whoever caches this has to tell embeds and direct access apart
+    response.headers_mut().append(header::VARY, HeaderValue::from_static("referer"));
+    response
+}
+
+fn blocked() -> Response {
+    (StatusCode::FORBIDDEN, "This image can't be embedded here\n").into_response()
+}
+
+async fn serve_watermarked(state: &AppState, filename: &str, mark: &str, ip: IpAddr, req: Request<Body>) -> Response {
+    let path = watermarked_path(filename);
+    let source = match req.extensions().get::<ServedPaste>() {
+        Some(p) => p.path(),
+        None => return StatusCode::NOT_FOUND.into_response(),
+    };
+    if tokio::fs::metadata(&source).await.is_err() {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+
+    // made again when the paste or the watermark changed
+    if !thumbnail::is_fresh(&path, &source).await || !thumbnail::is_fresh(&path, Path::new(mark)).await {
+        if let Err(e) = generate(source, filename, mark).await {
+            tracing::warn!("Couldn't watermark {}, blocking it instead: {}", filename, e);
+            return blocked();
+        }
+    }
+
+    let method = req.method().clone();
+    let response = match ServeFile::new_with_mime(path, &mime_guess::mime::IMAGE_PNG).oneshot(req).await {
+        Ok(r) => r.into_response(),
+        Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    };
+    access::record(state, filename, ip, &method, &response);
+    bandwidth::record(state, filename, &method, response)
+}
+
+async fn generate(source: PathBuf, filename: &str, mark: &str) -> anyhow::Result<()> {
+    // decoding large images takes a lot of memory, so only do one at a time
+    static PERMITS: OnceLock<Semaphore> = OnceLock::new();
+    let _permit = PERMITS.get_or_init(|| Semaphore::new(1)).acquire().await?;
+
+    tokio::fs::create_dir_all(WATERMARKED_DIRECTORY).await?;
+    let target = watermarked_path(filename);
+    let mark = PathBuf::from(mark);
+    tokio::task::spawn_blocking(move || -> anyhow::Result<()> {
+        let mut img = image::open(source)?.to_rgba8();
+        let mut mark = image::open(mark)?;
+        let max_width = (img.width() / MAX_WATERMARK_SHARE).max(1);
+        if mark.width() > max_width || mark.height() > img.height() {
+            mark = mark.resize(max_width, img.height(), FilterType::Triangle);
+        }
+        let x = img.width().saturating_sub(mark.width());
+        let y = img.height().saturating_sub(mark.height());
+        imageops::overlay(&mut img, &mark.to_rgba8(), x as i64, y as i64);
+
+        // written aside first, so nobody is served half an image
+        let mut tmp = target.as_os_str().to_owned();
+        tmp.push(".tmp");
+        img.save_with_format(&tmp, ImageFormat::Png)?;
+        std::fs::rename(&tmp, &target)?;
+        Ok(())
+    }).await?
+}
+
+/// Removes the watermarked copies of pastes that are gone, returning how many.
+pub async fn remove_orphans(db: &SqlitePool) -> anyhow::Result<usize> {
+    thumbnail::remove_orphans_in(db, WATERMARKED_DIRECTORY).await
+}
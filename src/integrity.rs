@@ -0,0 +1,136 @@
+use std::{net::SocketAddr, sync::Arc};
+
+use axum::{
+    extract::{ConnectInfo, Path, Query, State},
+    http::StatusCode,
+    Extension, Json,
+};
+use chrono::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{audit, auth::authenticate_client, content, storage::paste_path_in, tls::ClientCert, AppState};
+
+/// What re-hashing a paste found.
+#[derive(Debug, Clone, Serialize)]
+pub struct Check {
+    pub filename: String,
+    /// The hash recorded when it was stored, if it's been hashed since it
+    /// last changed.
+    pub expected: Option<String>,
+    /// The hash of what's on disk now, unless the file is gone.
+    pub actual: Option<String>,
+    pub size: i64,
+    pub actual_size: Option<u64>,
+    pub ok: bool,
+    /// Whether there was a recorded hash to compare with. Pastes without one
+    /// are only checked for being there at the right size.
+    pub verified: bool,
+}
+
+/// Re-hashes the paste stored as `filename` and compares it to what was
+/// recorded, flagging it when they differ. Without a recorded hash, the one
+/// found is recorded as the baseline and the paste is reported unverified.
+pub async fn check_paste(state: &AppState, filename: &str) -> anyhow::Result<Check> {
+    // so the paste isn't flagged for being read halfway through a change
+    let _lock = state.locks.lock(filename).await;
+    let (expected, size, prefix) = sqlx::query_as::<_, (Option<String>, i64, Option<String>)>("SELECT sha256, size, storage_prefix FROM pastes WHERE filename = $1")
+    .bind(filename)
+    .fetch_one(&state.db).await?;
+
+    let path = paste_path_in(prefix.as_deref(), filename);
+    let actual_size = tokio::fs::metadata(&path).await.ok().map(|m| m.len());
+    let actual = match actual_size {
+        Some(_) => Some(content::hash_file(path).await?),
+        None => None,
+    };
+
+    // sizes used to be stored in 32 bits, so pastes over 4 GiB stored back
+    // then have theirs cut short
+    let size = match actual_size {
+        Some(a) if a > u64::from(u32::MAX) && i64::from(a as u32) == size => {
+            tracing::info!("Fixing the recorded size of {}: {} bytes, not {}", filename, a, size);
+            sqlx::query("UPDATE pastes SET size = $1 WHERE filename = $2")
+            .bind(a as i64)
+            .bind(filename)
+            .execute(&state.db).await?;
+            a as i64
+        },
+        _ => size,
+    };
+
+    let ok = actual_size == Some(size.max(0) as u64) && match (&expected, &actual) {
+        (Some(e), Some(a)) => e == a,
+        (None, Some(_)) => true,
+        (_, None) => false,
+    };
+    let verified = ok && expected.is_some();
+
+    let now = Utc::now().timestamp();
+    if ok {
+        sqlx::query("UPDATE pastes SET sha256 = COALESCE(sha256, $1), verified_at = $2, corrupted_at = NULL WHERE filename = $3")
+        .bind(&actual)
+        .bind(now)
+        .bind(filename)
+        .execute(&state.db).await?;
+    } else {
+        tracing::error!("Paste {} is corrupted: expected {:?} ({} bytes), found {:?} ({:?} bytes)", filename, expected, size, actual, actual_size);
+        sqlx::query("UPDATE pastes SET verified_at = $1, corrupted_at = COALESCE(corrupted_at, $1) WHERE filename = $2")
+        .bind(now)
+        .bind(filename)
+        .execute(&state.db).await?;
+    }
+
+    Ok(Check { filename: filename.to_string(), expected, actual, size, actual_size, ok, verified })
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct VerifyParam {
+    token: Option<String>,
+}
+
+/// Handles `POST /api/paste/<id>/verify`, which re-hashes the paste on the
+/// spot.
+#[axum::debug_handler]
+pub async fn verify(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    cert: Option<Extension<ClientCert>>,
+    Path(id): Path<String>,
+    Query(query): Query<VerifyParam>,
+) -> Result<Json<Check>, StatusCode> {
+    let user = authenticate_client(&state, addr.ip(), cert.as_deref(), query.token.as_deref()).await?;
+    let (filename, owner) = sqlx::query_as::<_, (String, Option<i64>)>("SELECT filename, owner FROM pastes WHERE id = $1")
+    .bind(&id)
+    .fetch_optional(&state.db).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    .ok_or(StatusCode::NOT_FOUND)?;
+    if !user.can_manage(owner) {
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    let check = check_paste(&state, &filename).await.map_err(|e| {
+        tracing::error!("Couldn't verify {}: {}", filename, e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    audit::record(&state, user.id, addr.ip(), "verify", &filename).await;
+    Ok(Json(check))
+}
+
+/// Re-hashes the `SCRUB_SAMPLE` pastes that went longest without being
+/// checked, so bit rot is found even in pastes nobody asks about.
+pub async fn scrub(state: &AppState) -> anyhow::Result<()> {
+    let filenames = sqlx::query_scalar::<_, String>("SELECT filename FROM pastes ORDER BY verified_at IS NOT NULL, verified_at LIMIT $1")
+    .bind(state.config().scrub_sample as i64)
+    .fetch_all(&state.db).await?;
+
+    let (mut corrupted, mut unverified) = (0, 0);
+    for filename in &filenames {
+        let check = check_paste(state, filename).await?;
+        if !check.ok {
+            corrupted += 1;
+        } else if !check.verified {
+            unverified += 1;
+        }
+    }
+    tracing::info!("Scrubbed {} pastes, {} corrupted, {} without a hash to verify", filenames.len(), corrupted, unverified);
+    Ok(())
+}
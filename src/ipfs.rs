@@ -0,0 +1,155 @@
+use std::sync::OnceLock;
+
+use serde::Deserialize;
+use sqlx::SqlitePool;
+use tokio_util::io::ReaderStream;
+
+use crate::{config::Config, storage, takedown};
+
+fn client() -> &'static reqwest::Client {
+    static CLIENT: OnceLock<reqwest::Client> = OnceLock::new();
+    CLIENT.get_or_init(reqwest::Client::new)
+}
+
+#[derive(Debug, Deserialize)]
+struct Added {
+    #[serde(rename = "Hash")]
+    hash: String,
+}
+
+/// Adds the paste stored as `filename` to the IPFS node, if one is
+/// configured, and pins it there so it outlives this instance. Returns its
+/// CID. Failures are only logged, the paste is stored either way.
+pub async fn pin(db: &SqlitePool, config: &Config, filename: &str) -> Option<String> {
+    let api = config.ipfs_api.as_deref()?;
+    match mirror(db, api, filename).await {
+        Ok(cid) => Some(cid),
+        Err(e) => {
+            tracing::warn!("Couldn't pin {} to IPFS: {}", filename, e);
+            None
+        },
+    }
+}
+
+/// Like [`pin`], in the background, for when nobody waits on the CID.
+pub fn schedule(db: &SqlitePool, config: &Config, filename: &str) {
+    let api = match &config.ipfs_api {
+        Some(api) => api.clone(),
+        None => return,
+    };
+
+    let db = db.clone();
+    let filename = filename.to_string();
+    tokio::spawn(async move {
+        if let Err(e) = mirror(&db, &api, &filename).await {
+            tracing::warn!("Couldn't pin {} to IPFS: {}", filename, e);
+        }
+    });
+}
+
+/// Adds and pins the current contents of `filename` through the node's HTTP
+/// API at `api` and records the CID, unpinning what it replaces. Pastes that
+/// are taken down aren't.
+pub async fn mirror(db: &SqlitePool, api: &str, filename: &str) -> anyhow::Result<String> {
+    if takedown::active(db, filename).await?.is_some() {
+        anyhow::bail!("it's taken down");
+    }
+    let file = tokio::fs::File::open(storage::locate(db, filename).await?).await?;
+    let part = reqwest::multipart::Part::stream(reqwest::Body::wrap_stream(ReaderStream::new(file)))
+        .file_name(filename.to_string());
+    let form = reqwest::multipart::Form::new().part("file", part);
+
+    let added: Added = client().post(format!("{}/api/v0/add?pin=true&cid-version=1&quieter=true", api.trim_end_matches('/')))
+        .multipart(form)
+        .send().await?
+        .error_for_status()?
+        .json().await?;
+
+    let replaced = cid(db, filename).await?;
+    sqlx::query("INSERT OR REPLACE INTO ipfs_pins (filename, cid) VALUES ($1, $2)")
+    .bind(filename)
+    .bind(&added.hash)
+    .execute(db).await?;
+
+    if let Some(old) = replaced.filter(|old| *old != added.hash) {
+        unpin_unused(db, api, &old).await?;
+    }
+
+    tracing::info!("Pinned {} to IPFS as {}", filename, added.hash);
+    Ok(added.hash)
+}
+
+/// Unpins the paste stored as `filename` and forgets its CID, for when it's
+/// taken down and shouldn't be served from IPFS either.
+pub async fn unpin(db: &SqlitePool, config: &Config, filename: &str) -> anyhow::Result<()> {
+    let api = match &config.ipfs_api {
+        Some(api) => api,
+        None => return Ok(()),
+    };
+
+    let cid = sqlx::query_scalar::<_, String>("DELETE FROM ipfs_pins WHERE filename = $1 RETURNING cid")
+    .bind(filename)
+    .fetch_optional(db).await?;
+    if let Some(cid) = cid {
+        unpin_unused(db, api, &cid).await?;
+    }
+    Ok(())
+}
+
+/// Unpins the CIDs of pastes that have been deleted or taken down, returning
+/// how many were forgotten. Pins are kept until this runs, so a node that was
+/// down when a paste went away is caught up with later.
+pub async fn unpin_removed(db: &SqlitePool, config: &Config) -> anyhow::Result<usize> {
+    let api = match &config.ipfs_api {
+        Some(api) => api,
+        None => return Ok(0),
+    };
+
+    let removed = sqlx::query_as::<_, (String, String)>("SELECT filename, cid FROM ipfs_pins
+    WHERE filename NOT IN (SELECT filename FROM pastes)
+    OR filename IN (SELECT filename FROM takedowns WHERE lifted_at IS NULL)")
+    .fetch_all(db).await?;
+
+    let mut failed = 0;
+    for (filename, cid) in &removed {
+        let res = async {
+            sqlx::query("DELETE FROM ipfs_pins WHERE filename = $1")
+            .bind(filename)
+            .execute(db).await?;
+            unpin_unused(db, api, cid).await
+        }.await;
+        if let Err(e) = res {
+            tracing::error!("Couldn't unpin {} ({}): {}", filename, cid, e);
+            failed += 1;
+        }
+    }
+    if failed > 0 {
+        anyhow::bail!("{} of {} pastes couldn't be unpinned", failed, removed.len());
+    }
+    Ok(removed.len())
+}
+
+/// Unpins `cid` unless another paste has the same contents.
+async fn unpin_unused(db: &SqlitePool, api: &str, cid: &str) -> anyhow::Result<()> {
+    let users = sqlx::query_scalar::<_, i64>("SELECT COUNT(*) FROM ipfs_pins WHERE cid = $1")
+    .bind(cid)
+    .fetch_one(db).await?;
+    if users > 0 {
+        return Ok(());
+    }
+
+    let res = client().post(format!("{}/api/v0/pin/rm?arg={}", api.trim_end_matches('/'), cid))
+        .send().await?;
+    // the node answers 500 for CIDs that were unpinned by hand in the meantime
+    if res.status() != reqwest::StatusCode::INTERNAL_SERVER_ERROR {
+        res.error_for_status()?;
+    }
+    Ok(())
+}
+
+/// The CID the paste stored as `filename` was pinned as, if it was.
+pub async fn cid(db: &SqlitePool, filename: &str) -> sqlx::Result<Option<String>> {
+    sqlx::query_scalar::<_, String>("SELECT cid FROM ipfs_pins WHERE filename = $1")
+    .bind(filename)
+    .fetch_optional(db).await
+}
@@ -0,0 +1,480 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    path::Path,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
+
+use chrono::prelude::*;
+use rand::Rng;
+use serde::Serialize;
+use sqlx::{SqliteConnection, SqlitePool};
+
+use crate::{access, config::Config, hooks::Hooks, hotlink, integrity, ipfs, maintenance, namespace, staging, stats, storage, thumbnail, AppState};
+
+type JobFuture = Pin<Box<dyn Future<Output = anyhow::Result<()>> + Send>>;
+
+/// A task the scheduler knows how to run.
+struct Job {
+    name: &'static str,
+    run: fn(Arc<AppState>) -> JobFuture,
+}
+
+fn registry() -> Vec<Job> {
+    vec![
+        Job { name: "gc", run: |s| Box::pin(gc(s)) },
+        Job { name: "stats", run: |s| Box::pin(snapshot_stats(s)) },
+        Job { name: "vacuum", run: |s| Box::pin(vacuum(s)) },
+        Job { name: "backup", run: |s| Box::pin(backup(s)) },
+        Job { name: "scrub", run: |s| Box::pin(scrub(s)) },
+        Job { name: "staging", run: |s| Box::pin(clean_staging(s)) },
+        Job { name: "histogram", run: |s| Box::pin(count_uploads(s)) },
+    ]
+}
+
+/// Files this young might still be uploading, so GC leaves them alone.
+const GC_GRACE: Duration = Duration::from_secs(3600);
+
+/// Number of database backups kept around.
+const BACKUPS_KEPT: usize = 7;
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct JobStats {
+    pub interval_secs: u64,
+    /// Time of day the job runs at, in UTC, if it runs daily at a set time.
+    pub at: Option<String>,
+    pub runs: u64,
+    pub failures: u64,
+    pub last_run: Option<i64>,
+    pub last_duration_ms: Option<u128>,
+    pub last_error: Option<String>,
+}
+
+/// Per-job run metrics, shown on `/admin/jobs`.
+#[derive(Debug, Default)]
+pub struct JobMetrics {
+    jobs: Mutex<HashMap<&'static str, JobStats>>,
+}
+
+impl JobMetrics {
+    pub fn snapshot(&self) -> HashMap<&'static str, JobStats> {
+        self.jobs.lock().unwrap().clone()
+    }
+
+    fn update(&self, name: &'static str, f: impl FnOnce(&mut JobStats)) {
+        f(self.jobs.lock().unwrap().entry(name).or_default());
+    }
+}
+
+/// When a job runs.
+#[derive(Debug, Clone, Copy)]
+enum Every {
+    /// Over and over, this long apart.
+    Interval(Duration),
+    /// Once a day at this time, in UTC.
+    Daily(NaiveTime),
+}
+
+impl Every {
+    /// Parses an interval like `12h`, or a time of day like `@03:30`.
+    fn parse(s: &str) -> anyhow::Result<Self> {
+        match s.strip_prefix('@') {
+            Some(at) => NaiveTime::parse_from_str(at, "%H:%M")
+                .map(Self::Daily)
+                .map_err(|_| anyhow::anyhow!("invalid time of day {:?}, expected HH:MM", at)),
+            None => Ok(Self::Interval(parse_interval(s)?)),
+        }
+    }
+
+    /// How long to wait for the next run.
+    fn until_next(&self) -> Duration {
+        match *self {
+            Self::Interval(interval) => {
+                // up to 10% jitter, so jobs on the same interval don't all fire together
+                let jitter = rand::thread_rng().gen_range(0..=interval.as_millis() as u64 / 10);
+                interval + Duration::from_millis(jitter)
+            },
+            Self::Daily(at) => {
+                let now = Utc::now();
+                let mut next = now.date_naive().and_time(at).and_utc();
+                if next <= now {
+                    next += chrono::Duration::days(1);
+                }
+                (next - now).to_std().unwrap_or_default()
+            },
+        }
+    }
+}
+
+/// Parses a schedule like `gc=1h;vacuum=1d;backup=@03:30` into jobs and when
+/// they run.
+fn parse_schedule(schedule: &str) -> anyhow::Result<Vec<(Job, Every)>> {
+    let mut registry = registry();
+    let mut jobs = Vec::new();
+
+    for entry in schedule.split(';').map(str::trim).filter(|e| !e.is_empty()) {
+        let (name, every) = entry.split_once('=')
+            .ok_or_else(|| anyhow::anyhow!("invalid schedule entry {:?}", entry))?;
+        let position = registry.iter().position(|j| j.name == name.trim())
+            .ok_or_else(|| anyhow::anyhow!("unknown job {:?}", name))?;
+        jobs.push((registry.swap_remove(position), Every::parse(every.trim())?));
+    }
+
+    Ok(jobs)
+}
+
+/// Parses a duration like `90`, `30m`, `12h` or `90d`.
+pub fn parse_interval(s: &str) -> anyhow::Result<Duration> {
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (n, unit) = s.split_at(split);
+    let n: u64 = n.parse().map_err(|_| anyhow::anyhow!("invalid interval {:?}", s))?;
+    let secs = match unit {
+        "" | "s" => n,
+        "m" => n * 60,
+        "h" => n * 3600,
+        "d" => n * 86400,
+        _ => anyhow::bail!("invalid interval unit in {:?}", s),
+    };
+    if secs == 0 {
+        anyhow::bail!("interval must not be zero");
+    }
+    Ok(Duration::from_secs(secs))
+}
+
+/// Hours of the day, in UTC, like `2-5` for 02:00 to 04:59; `22-4` wraps
+/// around midnight and `0-24` is the whole day.
+#[derive(Debug, Clone, Copy)]
+pub struct Hours {
+    start: u32,
+    end: u32,
+}
+
+impl Hours {
+    pub fn contains(&self, hour: u32) -> bool {
+        if self.start <= self.end {
+            (self.start..self.end).contains(&hour)
+        } else {
+            hour >= self.start || hour < self.end
+        }
+    }
+}
+
+impl std::str::FromStr for Hours {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (start, end) = s.split_once('-').ok_or_else(|| anyhow::anyhow!("invalid hours {:?}", s))?;
+        let (start, end): (u32, u32) = (start.trim().parse()?, end.trim().parse()?);
+        if start > 23 || end > 24 {
+            anyhow::bail!("invalid hours {:?}", s);
+        }
+        Ok(Self { start, end })
+    }
+}
+
+/// Starts a task for every job in the configured schedule.
+pub fn start(state: Arc<AppState>) -> anyhow::Result<()> {
+    for (job, every) in parse_schedule(&state.config().schedule)? {
+        match every {
+            Every::Interval(interval) => {
+                tracing::info!("Scheduling job {} every {} seconds", job.name, interval.as_secs());
+                state.jobs.update(job.name, |s| s.interval_secs = interval.as_secs());
+            },
+            Every::Daily(at) => {
+                tracing::info!("Scheduling job {} daily at {} UTC", job.name, at.format("%H:%M"));
+                state.jobs.update(job.name, |s| {
+                    s.interval_secs = 86400;
+                    s.at = Some(at.format("%H:%M").to_string());
+                });
+            },
+        }
+        tokio::spawn(run_periodically(state.clone(), job, every));
+    }
+    Ok(())
+}
+
+async fn run_periodically(state: Arc<AppState>, job: Job, every: Every) {
+    loop {
+        tokio::time::sleep(every.until_next()).await;
+
+        let started = Instant::now();
+        // run on its own task so a panic is caught by the join handle
+        let res = tokio::spawn((job.run)(state.clone())).await;
+        let elapsed = started.elapsed();
+
+        let error = match res {
+            Ok(Ok(())) => None,
+            Ok(Err(e)) => Some(e.to_string()),
+            Err(e) if e.is_panic() => Some("job panicked".to_string()),
+            Err(e) => Some(e.to_string()),
+        };
+
+        if let Some(e) = &error {
+            tracing::error!("Job {} failed: {}", job.name, e);
+        }
+
+        state.jobs.update(job.name, |s| {
+            s.runs += 1;
+            s.last_run = Some(Utc::now().timestamp());
+            s.last_duration_ms = Some(elapsed.as_millis());
+            if error.is_some() {
+                s.failures += 1;
+                s.last_error = error;
+            }
+        });
+    }
+}
+
+async fn gc(state: Arc<AppState>) -> anyhow::Result<()> {
+    if maintenance::is_read_only(&state) {
+        tracing::info!("Skipping GC while read-only");
+        return Ok(());
+    }
+    collect_garbage(&state.db, &state.config(), &state.hooks).await
+}
+
+/// Removes used-up download links, expired sessions, pastes past their
+/// namespace's retention and files that no paste refers to, and unpins
+/// deleted pastes from IPFS. A step failing is logged and the others still
+/// run, but GC as a whole fails then.
+pub async fn collect_garbage(db: &SqlitePool, config: &Config, hooks: &Hooks) -> anyhow::Result<()> {
+    let steps = [
+        ("stale links", remove_stale_links(db).await),
+        ("expired sessions", remove_expired_sessions(db).await),
+        ("pastes past retention", expire_pastes(db, hooks).await),
+        ("orphaned files", remove_orphaned_files(db).await),
+        ("orphaned thumbnails", remove_orphaned_thumbnails(db).await),
+        ("the access log", prune_accesses(db, config).await),
+        ("watermarked copies", remove_orphaned_watermarks(db).await),
+        ("IPFS pins", unpin_removed(db, config).await),
+    ];
+
+    let mut failed = 0;
+    for (step, res) in &steps {
+        if let Err(e) = res {
+            tracing::error!("GC couldn't clean up {}: {}", step, e);
+            failed += 1;
+        }
+    }
+    if failed > 0 {
+        anyhow::bail!("{} of {} GC steps failed", failed, steps.len());
+    }
+    Ok(())
+}
+
+async fn remove_stale_links(db: &SqlitePool) -> anyhow::Result<()> {
+    sqlx::query("DELETE FROM download_links WHERE uses_left <= 0 OR paste NOT IN (SELECT id FROM pastes)")
+    .execute(db).await?;
+
+    sqlx::query("DELETE FROM redirects WHERE target NOT IN (SELECT filename FROM pastes)")
+    .execute(db).await?;
+    Ok(())
+}
+
+async fn remove_expired_sessions(db: &SqlitePool) -> anyhow::Result<()> {
+    sqlx::query("DELETE FROM sessions WHERE expires_at <= $1")
+    .bind(Utc::now().timestamp())
+    .execute(db).await?;
+    Ok(())
+}
+
+async fn expire_pastes(db: &SqlitePool, hooks: &Hooks) -> anyhow::Result<()> {
+    let expired = namespace::expire(db, hooks).await?;
+    if expired > 0 {
+        tracing::info!("GC removed {} pastes past their namespace's retention", expired);
+    }
+    Ok(())
+}
+
+/// Removes files no paste refers to. One that can't be looked at or removed
+/// is logged and skipped.
+async fn remove_orphaned_files(db: &SqlitePool) -> anyhow::Result<()> {
+    let (mut removed, mut failed) = (0, 0);
+    for (name, path) in storage::walk().await? {
+        let res = async {
+            let age = tokio::fs::metadata(&path).await?.modified()?.elapsed().unwrap_or_default();
+            if age < GC_GRACE {
+                return Ok(false);
+            }
+
+            let known = sqlx::query_scalar::<_, i64>("SELECT COUNT(*) FROM pastes WHERE filename = $1")
+            .bind(&name)
+            .fetch_one(db).await?;
+
+            if known == 0 {
+                tokio::fs::remove_file(&path).await?;
+            }
+            Ok::<_, anyhow::Error>(known == 0)
+        }.await;
+
+        match res {
+            Ok(true) => removed += 1,
+            Ok(false) => {},
+            Err(e) => {
+                tracing::error!("GC couldn't check {}: {}", path.display(), e);
+                failed += 1;
+            },
+        }
+    }
+
+    if removed > 0 {
+        tracing::info!("GC removed {} orphaned files", removed);
+    }
+    if failed > 0 {
+        anyhow::bail!("{} files couldn't be checked", failed);
+    }
+    Ok(())
+}
+
+async fn remove_orphaned_thumbnails(db: &SqlitePool) -> anyhow::Result<()> {
+    let thumbnails = thumbnail::remove_orphans(db).await?;
+    if thumbnails > 0 {
+        tracing::info!("GC removed {} thumbnails of deleted pastes", thumbnails);
+    }
+    Ok(())
+}
+
+async fn prune_accesses(db: &SqlitePool, config: &Config) -> anyhow::Result<()> {
+    let accesses = access::prune(db, config.access_log_retention).await?;
+    if accesses > 0 {
+        tracing::info!("GC forgot {} logged downloads", accesses);
+    }
+    Ok(())
+}
+
+async fn remove_orphaned_watermarks(db: &SqlitePool) -> anyhow::Result<()> {
+    let watermarked = hotlink::remove_orphans(db).await?;
+    if watermarked > 0 {
+        tracing::info!("GC removed {} watermarked copies of deleted pastes", watermarked);
+    }
+    Ok(())
+}
+
+async fn unpin_removed(db: &SqlitePool, config: &Config) -> anyhow::Result<()> {
+    let unpinned = ipfs::unpin_removed(db, config).await?;
+    if unpinned > 0 {
+        tracing::info!("GC unpinned {} deleted or taken down pastes from IPFS", unpinned);
+    }
+    Ok(())
+}
+
+/// Records how much is stored right now, so the history survives deletions.
+async fn snapshot_stats(state: Arc<AppState>) -> anyhow::Result<()> {
+    sqlx::query("INSERT INTO stats_snapshots (timestamp, pastes, bytes)
+    SELECT $1, COUNT(*), COALESCE(SUM(size), 0) FROM pastes")
+    .bind(Utc::now().timestamp())
+    .execute(&state.db).await?;
+    Ok(())
+}
+
+/// Removes uploads left in the staging directory for longer than
+/// `STAGING_MAX_AGE_SECONDS`, along with the records of those sent in parts.
+async fn clean_staging(state: Arc<AppState>) -> anyhow::Result<()> {
+    let (removed, freed) = staging::clean(state.config().staging_max_age).await?;
+    for name in &removed {
+        sqlx::query("DELETE FROM multipart_uploads WHERE id = $1")
+        .bind(name)
+        .execute(&state.db).await?;
+        sqlx::query("DELETE FROM multipart_parts WHERE upload = $1")
+        .bind(name)
+        .execute(&state.db).await?;
+    }
+
+    if !removed.is_empty() {
+        tracing::info!("Removed {} abandoned uploads from staging, {} bytes", removed.len(), freed);
+    }
+    Ok(())
+}
+
+async fn count_uploads(state: Arc<AppState>) -> anyhow::Result<()> {
+    Ok(stats::aggregate_uploads(&state.db).await?)
+}
+
+async fn scrub(state: Arc<AppState>) -> anyhow::Result<()> {
+    integrity::scrub(&state).await
+}
+
+/// Tidies the database up during `DB_MAINTENANCE_HOURS`: frees the pages
+/// deleted rows left behind, checkpoints the WAL and refreshes the query
+/// planner's statistics. Skipped at any other time, so schedule it often
+/// enough to hit the window, like `vacuum=1h`.
+async fn vacuum(state: Arc<AppState>) -> anyhow::Result<()> {
+    if !state.config().db_maintenance_hours.contains(Utc::now().hour()) {
+        return Ok(());
+    }
+
+    // pragmas apply per connection, so everything runs on the same one
+    let mut conn = state.db.acquire().await?;
+    let before = db_size(&mut conn).await?;
+
+    // incremental vacuum only works once the file is set up for it, which
+    // takes one full vacuum
+    let auto_vacuum = sqlx::query_scalar::<_, i64>("PRAGMA auto_vacuum").fetch_one(&mut *conn).await?;
+    if auto_vacuum != 2 {
+        tracing::info!("Switching the database to incremental vacuum");
+        sqlx::query("PRAGMA auto_vacuum = INCREMENTAL").execute(&mut *conn).await?;
+        sqlx::query("VACUUM").execute(&mut *conn).await?;
+    } else {
+        sqlx::query("PRAGMA incremental_vacuum").execute(&mut *conn).await?;
+    }
+    // a replicated WAL is only checkpointed by the replicator, once it's
+    // been shipped
+    if state.config().replica_url.is_none() {
+        sqlx::query("PRAGMA wal_checkpoint(TRUNCATE)").execute(&mut *conn).await?;
+    }
+    sqlx::query("PRAGMA optimize").execute(&mut *conn).await?;
+
+    let after = db_size(&mut conn).await?;
+    tracing::info!("Database maintenance done, {} bytes before, {} after", before, after);
+    Ok(())
+}
+
+/// Bytes the main database takes on disk, along with its WAL.
+async fn db_size(conn: &mut SqliteConnection) -> anyhow::Result<u64> {
+    let file = sqlx::query_scalar::<_, String>("SELECT file FROM pragma_database_list WHERE name = 'main'")
+    .fetch_one(&mut *conn).await?;
+    if file.is_empty() {
+        // in memory
+        return Ok(0);
+    }
+
+    let mut size = 0;
+    for path in [file.clone(), format!("{}-wal", file)] {
+        if let Ok(m) = tokio::fs::metadata(&path).await {
+            size += m.len();
+        }
+    }
+    Ok(size)
+}
+
+/// Writes a consistent copy of the database to the backup directory and
+/// prunes old ones.
+async fn backup(state: Arc<AppState>) -> anyhow::Result<()> {
+    let config = state.config();
+    let dir = Path::new(&config.backup_dir);
+    tokio::fs::create_dir_all(dir).await?;
+
+    let path = dir.join(format!("smolpaste-{}.sqlite", Utc::now().format("%Y%m%d%H%M%S")));
+    sqlx::query("VACUUM INTO $1")
+    .bind(path.to_string_lossy().into_owned())
+    .execute(&state.db).await?;
+
+    let mut backups = Vec::new();
+    let mut entries = tokio::fs::read_dir(dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name.starts_with("smolpaste-") && name.ends_with(".sqlite") {
+            backups.push((entry.metadata().await?.modified().unwrap_or(SystemTime::UNIX_EPOCH), entry.path()));
+        }
+    }
+
+    backups.sort();
+    let excess = backups.len().saturating_sub(BACKUPS_KEPT);
+    for (_, path) in backups.into_iter().take(excess) {
+        tokio::fs::remove_file(path).await?;
+    }
+
+    Ok(())
+}
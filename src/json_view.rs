@@ -0,0 +1,94 @@
+use std::fmt::Write;
+
+use serde_json::Value;
+
+/// Nesting below which objects and arrays start out collapsed.
+const OPEN_DEPTH: usize = 2;
+
+/// How a JSON paste is shown.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// A pretty-printed tree with collapsible objects and arrays.
+    Tree,
+    /// On one line.
+    Minified,
+    /// As it was uploaded.
+    Raw,
+}
+
+impl Mode {
+    pub fn parse(mode: Option<&str>) -> Self {
+        match mode {
+            Some("minified") => Self::Minified,
+            Some("raw") => Self::Raw,
+            _ => Self::Tree,
+        }
+    }
+}
+
+/// Whether the paste stored as `filename` with `content` should be viewed as
+/// JSON: anything named `.json`, or text that looks like it and parses.
+pub fn parse(filename: &str, content: &str) -> Option<Value> {
+    let named = filename.rsplit_once('.').map_or(false, |(_, ext)| ext.eq_ignore_ascii_case("json"));
+    let trimmed = content.trim_start();
+    if !named && !(trimmed.starts_with('{') || trimmed.starts_with('[')) {
+        return None;
+    }
+    serde_json::from_str(content).ok()
+}
+
+/// Renders `value` as nested `<details>` elements, so it can be folded
+/// without any script, with spans to colour each kind of token.
+pub fn to_html(value: &Value) -> String {
+    let mut html = String::new();
+    render(value, 0, &mut html);
+    html
+}
+
+fn render(value: &Value, depth: usize, html: &mut String) {
+    match value {
+        Value::Null => html.push_str("<span class=\"kw\">null</span>"),
+        Value::Bool(b) => { let _ = write!(html, "<span class=\"kw\">{}</span>", b); },
+        Value::Number(n) => { let _ = write!(html, "<span class=\"num\">{}</span>", n); },
+        Value::String(s) => { let _ = write!(html, "<span class=\"str\">{}</span>", quoted(s)); },
+        Value::Array(items) if items.is_empty() => html.push_str("[]"),
+        Value::Object(members) if members.is_empty() => html.push_str("{}"),
+        Value::Array(items) => {
+            open(html, depth, '[', items.len(), "item");
+            for (i, item) in items.iter().enumerate() {
+                html.push_str("<div>");
+                render(item, depth + 1, html);
+                close_member(html, i + 1 < items.len());
+            }
+            html.push_str("</div>]</details>");
+        },
+        Value::Object(members) => {
+            open(html, depth, '{', members.len(), "key");
+            for (i, (key, member)) in members.iter().enumerate() {
+                let _ = write!(html, "<div><span class=\"key\">{}</span>: ", quoted(key));
+                render(member, depth + 1, html);
+                close_member(html, i + 1 < members.len());
+            }
+            html.push_str("</div>}</details>");
+        },
+    }
+}
+
+fn open(html: &mut String, depth: usize, bracket: char, len: usize, noun: &str) {
+    let open = if depth < OPEN_DEPTH { " open" } else { "" };
+    let plural = if len == 1 { "" } else { "s" };
+    let _ = write!(
+        html,
+        "<details{}><summary>{}<span class=\"count\">{} {}{}</span></summary><div class=\"members\">",
+        open, bracket, len, noun, plural,
+    );
+}
+
+fn close_member(html: &mut String, comma: bool) {
+    html.push_str(if comma { ",</div>" } else { "</div>" });
+}
+
+/// `s` as a JSON string literal, escaped for HTML.
+fn quoted(s: &str) -> String {
+    tera::escape_html(&serde_json::to_string(s).unwrap_or_default())
+}
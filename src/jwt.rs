@@ -0,0 +1,176 @@
+use std::{
+    str::FromStr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use jsonwebtoken::{
+    decode, decode_header,
+    jwk::{AlgorithmParameters, EllipticCurve, Jwk, JwkSet},
+    Algorithm, DecodingKey, Validation,
+};
+use serde_json::Value;
+use tokio::sync::RwLock;
+
+use crate::{config::Config, db::TokenInfo};
+
+/// How long to wait before fetching the JWKS again, so tokens with made-up
+/// key ids can't have us hammer the issuer.
+const JWKS_MIN_REFRESH: Duration = Duration::from_secs(60);
+
+enum KeySource {
+    Secret(DecodingKey),
+    Jwks { url: String, keys: RwLock<JwkSet>, fetched: Mutex<Instant> },
+}
+
+impl std::fmt::Debug for KeySource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Secret(_) => f.write_str("Secret"),
+            Self::Jwks { url, .. } => f.debug_struct("Jwks").field("url", url).finish(),
+        }
+    }
+}
+
+/// Validates signed JWTs as an alternative to tokens stored in the database.
+#[derive(Debug)]
+pub struct JwtVerifier {
+    source: KeySource,
+    issuer: Option<String>,
+    audience: Option<String>,
+    scope_claim: String,
+    quota_claim: String,
+}
+
+impl JwtVerifier {
+    /// Returns `None` when neither a shared secret nor a JWKS URL is configured.
+    pub async fn from_config(config: &Config) -> anyhow::Result<Option<Self>> {
+        let source = match (&config.jwt_secret, &config.jwt_jwks_url) {
+            (Some(secret), _) => KeySource::Secret(DecodingKey::from_secret(secret.as_bytes())),
+            (None, Some(url)) => KeySource::Jwks {
+                url: url.clone(),
+                keys: RwLock::new(fetch_jwks(url).await?),
+                fetched: Mutex::new(Instant::now()),
+            },
+            (None, None) => return Ok(None),
+        };
+
+        Ok(Some(Self {
+            source,
+            issuer: config.jwt_issuer.clone(),
+            audience: config.jwt_audience.clone(),
+            scope_claim: config.jwt_scope_claim.clone(),
+            quota_claim: config.jwt_quota_claim.clone(),
+        }))
+    }
+
+    /// Verifies `token` and maps its claims to a token identity, or returns
+    /// `None` if it isn't a valid JWT.
+    pub async fn verify(&self, token: &str) -> Option<TokenInfo> {
+        let header = decode_header(token).ok()?;
+
+        let claims = match &self.source {
+            KeySource::Secret(key) => {
+                if !matches!(header.alg, Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512) {
+                    return None;
+                }
+                decode::<Value>(token, key, &self.validation(header.alg)).ok()?.claims
+            },
+            KeySource::Jwks { url, keys, fetched } => {
+                let kid = header.kid?;
+                if keys.read().await.find(&kid).is_none() {
+                    // the issuer may have rotated its keys since we last looked
+                    if !due(fetched) {
+                        return None;
+                    }
+                    let fresh = fetch_jwks(url).await.map_err(|e| tracing::error!("Couldn't refresh JWKS: {}", e)).ok()?;
+                    *keys.write().await = fresh;
+                }
+
+                let key = {
+                    let keys = keys.read().await;
+                    let jwk = keys.find(&kid)?;
+                    if !key_allows(jwk, header.alg) {
+                        return None;
+                    }
+                    DecodingKey::from_jwk(jwk).ok()?
+                };
+                decode::<Value>(token, &key, &self.validation(header.alg)).ok()?.claims
+            },
+        };
+
+        Some(token_info(claims.get(&self.scope_claim), claims.get(&self.quota_claim), claims.get("exp").and_then(Value::as_i64)))
+    }
+
+    fn validation(&self, alg: Algorithm) -> Validation {
+        let mut validation = Validation::new(alg);
+        if let Some(issuer) = &self.issuer {
+            validation.set_issuer(&[issuer]);
+        }
+        match &self.audience {
+            Some(audience) => validation.set_audience(&[audience]),
+            None => validation.validate_aud = false,
+        }
+        validation
+    }
+}
+
+/// Whether the JWKS may be fetched again, marking it fetched if so.
+fn due(fetched: &Mutex<Instant>) -> bool {
+    let mut fetched = fetched.lock().unwrap();
+    if fetched.elapsed() < JWKS_MIN_REFRESH {
+        return false;
+    }
+    *fetched = Instant::now();
+    true
+}
+
+/// Whether a token signed with `alg` may be checked against `jwk`: the
+/// algorithm the key is pinned to if it names one, or else one that fits
+/// its type. The header is the token's own say, so it never gets to pick
+/// HMAC, which would use a public key as the secret.
+fn key_allows(jwk: &Jwk, alg: Algorithm) -> bool {
+    if matches!(alg, Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512) {
+        return false;
+    }
+    if let Some(pinned) = jwk.common.key_algorithm {
+        return Algorithm::from_str(&pinned.to_string()).map_or(false, |pinned| pinned == alg);
+    }
+    match &jwk.algorithm {
+        AlgorithmParameters::RSA(_) => matches!(alg,
+            Algorithm::RS256 | Algorithm::RS384 | Algorithm::RS512 | Algorithm::PS256 | Algorithm::PS384 | Algorithm::PS512),
+        AlgorithmParameters::EllipticCurve(params) => matches!((&params.curve, alg),
+            (EllipticCurve::P256, Algorithm::ES256) | (EllipticCurve::P384, Algorithm::ES384)),
+        AlgorithmParameters::OctetKeyPair(_) => alg == Algorithm::EdDSA,
+        AlgorithmParameters::OctetKey(_) => false,
+    }
+}
+
+/// Maps the scope and quota claims of a self-contained token, which expires
+/// at `expires_at`, to a token identity.
+pub fn token_info(scope: Option<&Value>, quota: Option<&Value>, expires_at: Option<i64>) -> TokenInfo {
+    let scopes: Vec<&str> = match scope {
+        Some(Value::String(s)) => s.split_whitespace().collect(),
+        Some(Value::Array(a)) => a.iter().filter_map(Value::as_str).collect(),
+        _ => Vec::new(),
+    };
+
+    TokenInfo {
+        id: None,
+        scope: if scopes.contains(&"admin") { "admin" } else { "upload" }.to_string(),
+        max_upload_size: quota.and_then(Value::as_i64),
+        namespace: None,
+        base_url: None,
+        expires_at,
+    }
+}
+
+/// JWTs are three base64 segments separated by dots, which our own tokens never contain.
+pub fn looks_like_jwt(token: &str) -> bool {
+    token.split('.').count() == 3
+}
+
+async fn fetch_jwks(url: &str) -> anyhow::Result<JwkSet> {
+    tracing::info!("Fetching JWKS from {}...", url);
+    Ok(reqwest::get(url).await?.error_for_status()?.json().await?)
+}
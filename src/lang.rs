@@ -0,0 +1,131 @@
+use std::sync::OnceLock;
+
+use regex::Regex;
+
+/// Lines looked at when guessing, which is plenty for anything distinctive.
+const DETECT_LINES: usize = 200;
+/// Score a guess needs before it's trusted over plain text.
+const MIN_SCORE: u32 = 3;
+
+/// The language the viewer should treat a text paste as: the one asked for
+/// with `?lang=`, the one its extension names, or a guess from its contents
+/// when the extension says nothing.
+pub fn language(filename: &str, content: &str, requested: Option<&str>) -> Option<String> {
+    if let Some(lang) = requested.filter(|l| valid(l)) {
+        return Some(lang.to_ascii_lowercase());
+    }
+
+    let ext = filename.rsplit_once('.').map(|(_, e)| e.to_ascii_lowercase());
+    match ext.as_deref().and_then(from_extension) {
+        Some(lang) => Some(lang.to_string()),
+        None => detect(content).map(str::to_string),
+    }
+}
+
+fn valid(lang: &str) -> bool {
+    !lang.is_empty() && lang.len() <= 32 && lang.bytes().all(|b| b.is_ascii_alphanumeric() || b"+-#".contains(&b))
+}
+
+/// Extensions that name a language; `.txt` and friends don't.
+fn from_extension(ext: &str) -> Option<&'static str> {
+    Some(match ext {
+        "rs" => "rust",
+        "py" => "python",
+        "js" | "mjs" | "cjs" => "javascript",
+        "ts" => "typescript",
+        "go" => "go",
+        "c" | "h" => "c",
+        "cc" | "cpp" | "cxx" | "hpp" => "cpp",
+        "java" => "java",
+        "rb" => "ruby",
+        "php" => "php",
+        "sh" | "bash" => "bash",
+        "json" => "json",
+        "yaml" | "yml" => "yaml",
+        "toml" => "toml",
+        "ini" | "conf" | "cfg" => "ini",
+        "xml" => "xml",
+        "html" | "htm" => "html",
+        "css" => "css",
+        "sql" => "sql",
+        "md" => "markdown",
+        "diff" | "patch" => "diff",
+        "nix" => "nix",
+        "lua" => "lua",
+        _ => return None,
+    })
+}
+
+/// Guesses the language of `content` by scoring tell-tale lines, or returns
+/// `None` if nothing stands out.
+pub fn detect(content: &str) -> Option<&'static str> {
+    let first = content.lines().next().unwrap_or_default();
+    if let Some(interpreter) = first.strip_prefix("#!") {
+        // `/usr/bin/env python3` and `/bin/bash -e` both name the program last
+        let program = interpreter.split_whitespace().rev().find(|w| !w.starts_with('-')).unwrap_or_default();
+        let program = program.rsplit('/').next().unwrap_or(program).trim_end_matches(|c: char| c.is_ascii_digit() || c == '.');
+        match program {
+            "python" => return Some("python"),
+            "bash" | "sh" | "zsh" => return Some("bash"),
+            "node" => return Some("javascript"),
+            "ruby" => return Some("ruby"),
+            "perl" => return Some("perl"),
+            _ => {},
+        }
+    }
+
+    let trimmed = content.trim_start();
+    if trimmed.starts_with("<?php") {
+        return Some("php");
+    }
+    if trimmed.starts_with("<?xml") {
+        return Some("xml");
+    }
+    if trimmed.get(..9).map_or(false, |s| s.eq_ignore_ascii_case("<!doctype")) || trimmed.starts_with("<html") {
+        return Some("html");
+    }
+    if (trimmed.starts_with('{') || trimmed.starts_with('[')) && serde_json::from_str::<serde_json::Value>(content).is_ok() {
+        return Some("json");
+    }
+
+    let mut scores = vec![0; rules().len()];
+    for line in content.lines().take(DETECT_LINES) {
+        for (i, (_, patterns)) in rules().iter().enumerate() {
+            scores[i] += patterns.iter().filter(|(re, _)| re.is_match(line)).map(|(_, weight)| weight).sum::<u32>();
+        }
+    }
+
+    let (best, score) = scores.into_iter().enumerate().max_by_key(|&(_, s)| s)?;
+    (score >= MIN_SCORE).then(|| rules()[best].0)
+}
+
+type Rules = Vec<(&'static str, Vec<(Regex, u32)>)>;
+
+/// Lines typical for each language, weighted by how sure they make us.
+fn rules() -> &'static Rules {
+    static RULES: OnceLock<Rules> = OnceLock::new();
+    RULES.get_or_init(|| {
+        let table: &[(&str, &[(&str, u32)])] = &[
+            ("rust", &[(r"^\s*(pub )?fn \w+", 2), (r"^\s*use \w+::", 2), (r"^\s*impl\b", 2), (r"\blet mut\b", 2), (r"^\s*#\[derive", 3)]),
+            ("python", &[(r"^\s*def \w+\(.*\):\s*$", 2), (r"^\s*(from \w+ )?import \w+", 1), (r"^\s*class \w+.*:\s*$", 1), (r"^if __name__ == ", 3), (r"\bself\.", 1)]),
+            ("javascript", &[(r"\bfunction\s*\w*\(", 1), (r"^\s*(const|let|var) \w+ = ", 1), (r"=>\s*\{", 1), (r"\bconsole\.log\(", 2), (r"\brequire\(", 2)]),
+            ("go", &[(r"^package \w+", 3), (r"^func ", 2), (r":= ", 1), (r"^import \(", 2)]),
+            ("c", &[(r"^#include\s*[<\x22]", 3), (r"^\s*int main\(", 2), (r"\bprintf\(", 1)]),
+            ("bash", &[(r"^\s*(if|while) \[\[? ", 2), (r"^\s*(fi|done|esac)\s*$", 2), (r"^\s*export \w+=", 2), (r"\$\{\w+\}", 1)]),
+            ("sql", &[(r"(?i)^\s*select\b.*\bfrom\b", 2), (r"(?i)^\s*(insert into|create table|update \w+ set)\b", 3)]),
+            ("yaml", &[(r"^---\s*$", 1), (r"^\s*[\w-]+:\s+\S", 1), (r"^\s*- [\w-]+:", 1)]),
+            ("toml", &[(r"^\[[\w.-]+\]\s*$", 2), (r"^\s*[\w-]+ = ", 1)]),
+            ("diff", &[(r"^diff --git ", 3), (r"^(---|\+\+\+) [ab]/", 2), (r"^@@ -\d+", 3)]),
+            ("dockerfile", &[(r"^FROM \S+", 2), (r"^(RUN|COPY|CMD|ENTRYPOINT|WORKDIR) ", 2)]),
+        ];
+
+        table.iter()
+            .map(|(lang, patterns)| {
+                let patterns = patterns.iter()
+                    .map(|(re, weight)| (Regex::new(re).expect("language patterns are valid"), *weight))
+                    .collect();
+                (*lang, patterns)
+            })
+            .collect()
+    })
+}
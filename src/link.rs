@@ -0,0 +1,106 @@
+use std::{net::SocketAddr, sync::Arc};
+
+use axum::{
+    body::Body,
+    extract::{ConnectInfo, Path, Query, State},
+    http::{Method, Request, StatusCode},
+    response::Response,
+    Extension,
+};
+use chrono::prelude::*;
+use serde::Deserialize;
+
+use crate::{access, audit, auth::authenticate_client, base_url::BaseUrl, namespace::RequestNamespace, paste, tls::ClientCert, AppState};
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct LinkParam {
+    token: Option<String>,
+    /// How many downloads the link allows before it stops working.
+    uses: Option<u32>,
+}
+
+#[derive(Debug, Clone, sqlx::FromRow)]
+struct PasteOwner {
+    filename: String,
+    owner: Option<i64>,
+}
+
+/// Creates a download link for a paste that stops working after a number of
+/// uses, while the paste itself stays around.
+#[axum::debug_handler]
+pub async fn new_link(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    cert: Option<Extension<ClientCert>>,
+    BaseUrl(base_url): BaseUrl,
+    namespace: RequestNamespace,
+    Path(id): Path<String>,
+    Query(query): Query<LinkParam>,
+) -> Result<String, StatusCode> {
+    let user = authenticate_client(&state, addr.ip(), cert.as_deref(), query.token.as_deref()).await?;
+    namespace.check(&user)?;
+
+    let paste = sqlx::query_as::<_, PasteOwner>("SELECT filename, owner FROM pastes WHERE id = $1 AND namespace IS $2")
+    .bind(&id)
+    .bind(namespace.name())
+    .fetch_optional(&state.db).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    .ok_or(StatusCode::NOT_FOUND)?;
+
+    if !user.can_manage(paste.owner) {
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    let uses = query.uses.unwrap_or(1);
+    if uses == 0 {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let link = uuid::Uuid::new_v4().simple().to_string();
+
+    sqlx::query("INSERT INTO download_links (id, paste, uses_left, created_at) VALUES ($1, $2, $3, $4)")
+    .bind(&link)
+    .bind(&id)
+    .bind(uses)
+    .bind(Utc::now().timestamp())
+    .execute(&state.db).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    audit::record(&state, user.id, addr.ip(), "new_link", &paste.filename).await;
+
+    Ok(format!("{}/d/{}", user.base_url.as_deref().unwrap_or(&base_url), link))
+}
+
+/// Serves `/d/<link>` like `/paste` serves the paste it was made for.
+#[axum::debug_handler]
+pub async fn download(
+    State(state): State<Arc<AppState>>,
+    namespace: RequestNamespace,
+    Path(link): Path<String>,
+    req: Request<Body>,
+) -> Result<Response, StatusCode> {
+    // claim a use before serving, so concurrent requests can't both get the
+    // last one; links to pastes of other namespaces are as good as missing
+    let id = sqlx::query_scalar::<_, String>("UPDATE download_links SET uses_left = uses_left - 1
+    WHERE id = $1 AND uses_left > 0 AND paste IN (SELECT id FROM pastes WHERE namespace IS $2) RETURNING paste")
+    .bind(&link)
+    .bind(namespace.name())
+    .fetch_optional(&state.db).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    .ok_or(StatusCode::NOT_FOUND)?;
+
+    let filename = sqlx::query_scalar::<_, String>("SELECT filename FROM pastes WHERE id = $1")
+    .bind(&id)
+    .fetch_optional(&state.db).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    .ok_or(StatusCode::NOT_FOUND)?;
+
+    let method = req.method().clone();
+    let response = paste::serve(state.clone(), &filename, false, req).await;
+
+    // only a download of the whole paste uses the link up, so HEAD requests,
+    // failures and ranges picking up halfway through give the use back
+    if method != Method::GET || !access::is_start(&response) {
+        sqlx::query("UPDATE download_links SET uses_left = uses_left + 1 WHERE id = $1")
+        .bind(&link)
+        .execute(&state.db).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    }
+
+    Ok(response)
+}
@@ -0,0 +1,190 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    time::Duration,
+};
+
+use axum::{
+    extract::{ConnectInfo, Host},
+    http::{Request, Uri},
+    middleware,
+    response::Redirect,
+    Extension, Router,
+};
+use hyper::server::conn::Http;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::TlsAcceptor;
+
+use crate::{config::Config, tls};
+
+/// How long an HTTP/2 ping may go unanswered before the connection is closed.
+const KEEP_ALIVE_TIMEOUT: Duration = Duration::from_secs(20);
+/// How long a listener waits before accepting again after accepting failed.
+const ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Where and how to accept connections.
+#[derive(Debug, Clone)]
+pub enum Listener {
+    Http(String),
+    /// Plain HTTP that only redirects to the HTTPS listener.
+    Redirect(String),
+    Https(String),
+    Unix(String),
+}
+
+impl Listener {
+    /// Parses `http://addr`, `http+redirect://addr`, `https://addr` or `unix:path`.
+    pub fn parse(spec: &str) -> anyhow::Result<Self> {
+        if let Some(addr) = spec.strip_prefix("http://") {
+            Ok(Self::Http(addr.to_string()))
+        } else if let Some(addr) = spec.strip_prefix("http+redirect://") {
+            Ok(Self::Redirect(addr.to_string()))
+        } else if let Some(addr) = spec.strip_prefix("https://") {
+            Ok(Self::Https(addr.to_string()))
+        } else if let Some(path) = spec.strip_prefix("unix:") {
+            Ok(Self::Unix(path.to_string()))
+        } else {
+            anyhow::bail!("invalid listener {:?}", spec)
+        }
+    }
+}
+
+/// The protocol settings every connection is served with. Both HTTP/1.1 and
+/// HTTP/2 are spoken everywhere: over TLS as negotiated through ALPN, and in
+/// plain text to clients that start with the HTTP/2 preface (h2c).
+pub fn http(config: &Config) -> Http {
+    let keep_alive = Some(config.http_keep_alive).filter(|k| !k.is_zero());
+    let mut http = Http::new();
+    http.http1_keep_alive(keep_alive.is_some())
+        // hyper panics below 8 KiB, which Config never goes under
+        .max_buf_size(config.http_max_header_size)
+        .http2_max_concurrent_streams(config.http2_max_concurrent_streams)
+        .http2_max_header_list_size(u32::try_from(config.http_max_header_size).unwrap_or(u32::MAX))
+        // lets many parallel downloads on one connection use the bandwidth
+        .http2_adaptive_window(true)
+        .http2_keep_alive_interval(keep_alive)
+        .http2_keep_alive_timeout(KEEP_ALIVE_TIMEOUT);
+    http
+}
+
+/// Serves `app` on every listener until one of them fails.
+pub async fn serve_all(listeners: Vec<Listener>, app: Router, tls: Option<TlsAcceptor>, http: Http) -> anyhow::Result<()> {
+    let https_port = listeners.iter().find_map(|l| match l {
+        Listener::Https(addr) => addr.parse::<SocketAddr>().ok().map(|a| a.port()),
+        _ => None,
+    });
+
+    let servers = listeners.into_iter().map(|listener| {
+        let app = app.clone();
+        let tls = tls.clone();
+        let http = http.clone();
+        tokio::spawn(async move { serve(listener, app, tls, http, https_port).await })
+    });
+
+    for res in futures::future::try_join_all(servers).await? {
+        res?;
+    }
+    Ok(())
+}
+
+async fn serve(listener: Listener, app: Router, tls: Option<TlsAcceptor>, http: Http, https_port: Option<u16>) -> anyhow::Result<()> {
+    match listener {
+        Listener::Http(addr) => {
+            let listener = tokio::net::TcpListener::bind(addr).await?;
+            tracing::info!("Listening on http://{}...", listener.local_addr()?);
+            loop {
+                let (stream, addr) = match listener.accept().await {
+                    Ok(a) => a,
+                    Err(e) => {
+                        accept_failed(e).await;
+                        continue;
+                    },
+                };
+                // small responses shouldn't wait on the ACK of the last one
+                let _ = stream.set_nodelay(true);
+                tokio::spawn(serve_connection(stream, addr, app.clone(), http.clone()));
+            }
+        },
+        Listener::Redirect(addr) => {
+            let listener = std::net::TcpListener::bind(addr)?;
+            tracing::info!("Redirecting http://{} to HTTPS...", listener.local_addr()?);
+            let redirect = Router::new().fallback(move |Host(host): Host, uri: Uri| async move {
+                // drop the port we were reached on, and use the HTTPS one unless it's the default
+                let host = host.rsplit_once(':').map_or(host.as_str(), |(h, _)| h).to_string();
+                let authority = match https_port {
+                    Some(443) | None => host,
+                    Some(port) => format!("{}:{}", host, port),
+                };
+                let path = uri.path_and_query().map_or("/", |p| p.as_str());
+                Redirect::permanent(&format!("https://{}{}", authority, path))
+            });
+            axum::Server::from_tcp(listener)?
+                .serve(redirect.into_make_service())
+                .await?;
+        },
+        Listener::Https(addr) => {
+            let acceptor = tls.ok_or_else(|| anyhow::anyhow!("HTTPS listener needs TLS_CERT and TLS_KEY"))?;
+            let listener = tokio::net::TcpListener::bind(addr).await?;
+            tracing::info!("Listening on https://{}...", listener.local_addr()?);
+            tls::serve(listener, acceptor, app, http).await?;
+        },
+        Listener::Unix(path) => {
+            // a stale socket from a previous run would make binding fail
+            let _ = std::fs::remove_file(&path);
+            let listener = tokio::net::UnixListener::bind(&path)?;
+            tracing::info!("Listening on unix:{}...", path);
+            let app = app.layer(middleware::map_request(forwarded_peer));
+            loop {
+                let (stream, _) = match listener.accept().await {
+                    Ok(a) => a,
+                    Err(e) => {
+                        accept_failed(e).await;
+                        continue;
+                    },
+                };
+                // unix sockets have no peer address, the proxy says who it's
+                // for with each request
+                tokio::spawn(serve_connection(stream, SocketAddr::new(UNKNOWN_PEER, 0), app.clone(), http.clone()));
+            }
+        },
+    }
+    Ok(())
+}
+
+/// Waits a little after accepting a connection failed. That's usually
+/// temporary, like running out of file descriptors, so listeners keep going
+/// rather than stopping for good or retrying in a tight loop.
+pub async fn accept_failed(e: std::io::Error) {
+    tracing::error!("Couldn't accept a connection, retrying in {:?}: {}", ACCEPT_BACKOFF, e);
+    tokio::time::sleep(ACCEPT_BACKOFF).await;
+}
+
+/// The peer of requests on a unix socket that don't say who they're for. It's
+/// not loopback, so they don't pass for local clients.
+const UNKNOWN_PEER: IpAddr = IpAddr::V4(Ipv4Addr::UNSPECIFIED);
+
+/// Makes the client the reverse proxy in front of a unix socket forwarded
+/// `req` for its peer: the last address in `X-Forwarded-For`, which is the
+/// one the proxy added, or [`UNKNOWN_PEER`] without one. Only the proxy can
+/// reach the socket, so unlike on TCP listeners the header is trusted.
+async fn forwarded_peer<B>(mut req: Request<B>) -> Request<B> {
+    let ip = req.headers().get_all("x-forwarded-for").iter().last()
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.rsplit(',').next())
+        .and_then(|ip| ip.trim().parse::<IpAddr>().ok())
+        .unwrap_or(UNKNOWN_PEER);
+    req.extensions_mut().insert(ConnectInfo(SocketAddr::new(ip, 0)));
+    req
+}
+
+/// Serves a single accepted connection, making `addr` available to handlers
+/// the way `into_make_service_with_connect_info` would.
+pub async fn serve_connection<I>(io: I, addr: SocketAddr, app: Router, http: Http)
+where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let app = app.layer(Extension(ConnectInfo(addr)));
+    // upgrades are what WebSockets start with
+    if let Err(e) = http.serve_connection(io, app).with_upgrades().await {
+        tracing::debug!("Error serving connection from {}: {}", addr, e);
+    }
+}
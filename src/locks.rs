@@ -0,0 +1,27 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use tokio::sync::{Mutex as AsyncMutex, OwnedMutexGuard};
+
+/// A lock per paste, for changes to its file that must not overlap, like
+/// replacing it, appending to it or checking its hash.
+#[derive(Debug, Default)]
+pub struct PasteLocks {
+    locks: Mutex<HashMap<String, Arc<AsyncMutex<()>>>>,
+}
+
+impl PasteLocks {
+    /// Waits for the lock of the paste stored as `filename`, which is held
+    /// until the guard is dropped.
+    pub async fn lock(&self, filename: &str) -> OwnedMutexGuard<()> {
+        let lock = {
+            let mut locks = self.locks.lock().unwrap();
+            // nobody holds or waits for these anymore
+            locks.retain(|_, l| Arc::strong_count(l) > 1);
+            locks.entry(filename.to_string()).or_default().clone()
+        };
+        lock.lock_owned().await
+    }
+}
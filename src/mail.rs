@@ -0,0 +1,259 @@
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use mail_parser::{MessageParser, MimeHeaders};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    sync::Semaphore,
+    time::timeout,
+};
+
+use crate::{config::Config, maintenance, paste::store_anonymous, AppState};
+
+/// How long a client may take for any single command or line of data.
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(60);
+/// Longest command line accepted, as RFC 5321 asks for.
+const MAX_LINE: u64 = 1000;
+/// Longest line of data read at once; longer ones are read in pieces.
+const MAX_DATA_LINE: u64 = 64 << 10;
+/// Sessions handled at once; more wait to be accepted.
+const MAX_CONNECTIONS: usize = 64;
+
+/// Accepts mail for `MAIL_RECIPIENT` over SMTP or LMTP on `addr` and turns
+/// its attachments, or its text when there are none, into pastes. The URLs go
+/// back to the sender by mail when `MAIL_RELAY` is set and the MTA named by
+/// `MAIL_AUTHSERV_ID` found SPF to pass for them.
+pub async fn serve(state: Arc<AppState>, addr: &str) -> anyhow::Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    let permits = Arc::new(Semaphore::new(MAX_CONNECTIONS));
+    tracing::info!("Accepting mail on {}...", addr);
+
+    loop {
+        let permit = permits.clone().acquire_owned().await?;
+        let (stream, peer) = match listener.accept().await {
+            Ok(c) => c,
+            Err(e) => {
+                tracing::warn!("Couldn't accept a mail connection: {}", e);
+                continue;
+            },
+        };
+
+        let state = state.clone();
+        tokio::spawn(async move {
+            if let Err(e) = session(&state, stream, peer).await {
+                tracing::debug!("Mail session with {} failed: {}", peer, e);
+            }
+            drop(permit);
+        });
+    }
+}
+
+/// One SMTP or LMTP session. Only a single recipient is taken per message,
+/// which keeps the LMTP replies after `DATA` the same as SMTP's.
+async fn session(state: &AppState, stream: TcpStream, peer: SocketAddr) -> anyhow::Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    let config = state.config();
+    let recipient = config.mail_recipient.clone().unwrap_or_default();
+
+    writer.write_all(b"220 smolpaste ESMTP ready\r\n").await?;
+
+    let mut sender: Option<String> = None;
+    let mut accepted = false;
+    loop {
+        let line = match read_line(&mut reader).await? {
+            Some(l) => l,
+            None => return Ok(()),
+        };
+        let upper = line.to_ascii_uppercase();
+        let verb = upper.split_whitespace().next().unwrap_or_default();
+
+        let reply = match verb {
+            "EHLO" | "LHLO" => format!("250-smolpaste\r\n250-8BITMIME\r\n250 SIZE {}\r\n", config.mail_max_size),
+            "HELO" => "250 smolpaste\r\n".to_string(),
+            "MAIL" => match address(&line, "FROM:") {
+                _ if maintenance::is_read_only(state) => "421 4.3.0 Read-only for maintenance\r\n".to_string(),
+                Some(from) if sender_allowed(&config, &from) => {
+                    sender = Some(from);
+                    accepted = false;
+                    "250 2.1.0 OK\r\n".to_string()
+                },
+                Some(_) => "550 5.7.1 Sender not allowed\r\n".to_string(),
+                None => "501 5.5.4 Syntax: MAIL FROM:<address>\r\n".to_string(),
+            },
+            "RCPT" => match address(&line, "TO:") {
+                _ if sender.is_none() => "503 5.5.1 MAIL first\r\n".to_string(),
+                _ if accepted => "452 4.5.3 One recipient per message\r\n".to_string(),
+                Some(to) if to.eq_ignore_ascii_case(&recipient) => {
+                    accepted = true;
+                    "250 2.1.5 OK\r\n".to_string()
+                },
+                Some(_) => "550 5.1.1 No such mailbox\r\n".to_string(),
+                None => "501 5.5.4 Syntax: RCPT TO:<address>\r\n".to_string(),
+            },
+            "DATA" if !accepted => "503 5.5.1 RCPT first\r\n".to_string(),
+            "DATA" => {
+                writer.write_all(b"354 End data with <CR><LF>.<CR><LF>\r\n").await?;
+                let from = sender.take().unwrap_or_default();
+                accepted = false;
+                match read_data(&mut reader, config.mail_max_size).await? {
+                    Some(message) => deliver(state, &config, &message, &from, peer).await,
+                    None => "552 5.3.4 Message too big\r\n".to_string(),
+                }
+            },
+            "RSET" => {
+                sender = None;
+                accepted = false;
+                "250 2.0.0 OK\r\n".to_string()
+            },
+            "NOOP" => "250 2.0.0 OK\r\n".to_string(),
+            "QUIT" => {
+                writer.write_all(b"221 2.0.0 Bye\r\n").await?;
+                return Ok(());
+            },
+            _ => "502 5.5.2 Command not implemented\r\n".to_string(),
+        };
+        writer.write_all(reply.as_bytes()).await?;
+    }
+}
+
+async fn read_line<R: AsyncBufReadExt + Unpin>(reader: &mut R) -> anyhow::Result<Option<String>> {
+    let mut line = Vec::new();
+    let n = timeout(COMMAND_TIMEOUT, reader.take(MAX_LINE).read_until(b'\n', &mut line)).await??;
+    if n == 0 {
+        return Ok(None);
+    }
+    Ok(Some(String::from_utf8_lossy(&line).trim_end().to_string()))
+}
+
+/// Reads a message up to the lone `.` line, undoing dot-stuffing. `None` if
+/// it's larger than `limit`, in which case the rest is still read and dropped.
+async fn read_data<R: AsyncBufReadExt + Unpin>(reader: &mut R, limit: u64) -> anyhow::Result<Option<Vec<u8>>> {
+    let mut message = Vec::new();
+    let mut too_big = false;
+    // whether the next piece read starts a line, rather than going on with
+    // one longer than `MAX_DATA_LINE`
+    let mut line_start = true;
+    loop {
+        let mut line = Vec::new();
+        let n = timeout(COMMAND_TIMEOUT, (&mut *reader).take(MAX_DATA_LINE).read_until(b'\n', &mut line)).await??;
+        if n == 0 {
+            anyhow::bail!("connection closed during DATA");
+        }
+        let starts = std::mem::replace(&mut line_start, line.ends_with(b"\n"));
+        if starts && (line == b".\r\n" || line == b".\n") {
+            break;
+        }
+        let line = if starts { line.strip_prefix(b".").unwrap_or(&line) } else { &line };
+        if message.len() as u64 + line.len() as u64 > limit {
+            too_big = true;
+            message.clear();
+        }
+        if !too_big {
+            message.extend_from_slice(line);
+        }
+    }
+    Ok((!too_big).then_some(message))
+}
+
+/// The address in `MAIL FROM:<a@b>` or `RCPT TO:<a@b>`, without parameters.
+fn address(line: &str, prefix: &str) -> Option<String> {
+    let rest = line.get(5..)?.trim_start();
+    if !rest.get(..prefix.len())?.eq_ignore_ascii_case(prefix) {
+        return None;
+    }
+    let rest = rest[prefix.len()..].trim_start();
+    let address = rest.strip_prefix('<')?.split('>').next()?;
+    Some(address.to_string())
+}
+
+/// Whether mail from `sender` is turned into pastes. Entries are addresses or
+/// `@domain`, and an empty list keeps everyone out. Envelope senders are easy
+/// to forge, so this only keeps out the casual spam.
+fn sender_allowed(config: &Config, sender: &str) -> bool {
+    let sender = sender.to_ascii_lowercase();
+    config.mail_allowed_senders.iter().any(|allowed| {
+        if allowed.starts_with('@') { sender.ends_with(allowed.as_str()) } else { sender == *allowed }
+    })
+}
+
+/// Stores the attachments of `message`, returning the reply to `DATA` with
+/// their URLs. They're mailed back to `from` as well when the sender is known
+/// not to be forged, so nobody gets mail they didn't ask for.
+async fn deliver(state: &AppState, config: &Config, message: &[u8], from: &str, peer: SocketAddr) -> String {
+    let parsed = match MessageParser::default().parse(message) {
+        Some(m) => m,
+        None => return "554 5.6.0 Couldn't parse the message\r\n".to_string(),
+    };
+
+    let text = parsed.body_text(0);
+    let mut files: Vec<(String, &[u8])> = parsed.attachments()
+        .map(|a| (a.attachment_name().unwrap_or_default().to_string(), a.contents()))
+        .collect();
+    if files.is_empty() {
+        if let Some(text) = text.as_deref() {
+            files.push(("message.txt".to_string(), text.as_bytes()));
+        }
+    }
+
+    let mut urls = Vec::new();
+    for (name, contents) in files.iter().filter(|(_, c)| !c.is_empty()) {
+        match store_anonymous(state, contents, name, peer.ip()).await {
+            Ok(filename) => urls.push(config.url_template.render(&config.fallback_base_url(), &filename)),
+            Err(e) => tracing::warn!("Couldn't store {:?} mailed by {}: {}", name, from, e),
+        }
+    }
+    if urls.is_empty() {
+        return "554 5.6.0 Nothing in the message could be stored\r\n".to_string();
+    }
+    tracing::info!("Stored {} pastes mailed by {}", urls.len(), from);
+
+    match &config.mail_authserv_id {
+        Some(id) if spf_passed(&parsed, id, from) => {
+            if let Err(e) = reply(config, from, parsed.subject().unwrap_or_default(), &urls).await {
+                tracing::warn!("Couldn't mail the URLs back to {}: {}", from, e);
+            }
+        },
+        _ => tracing::debug!("Not mailing the URLs back to unverified sender {}", from),
+    }
+
+    let lines: String = urls.iter().map(|url| format!("250-{}\r\n", url)).collect();
+    format!("{}250 2.0.0 Stored {} pastes\r\n", lines, urls.len())
+}
+
+/// Whether the MTA in front of us, `authserv_id`, found SPF to pass for
+/// `sender`, going by the `Authentication-Results` headers it adds. It drops
+/// the ones claiming to come from it on mail from outside, so only its own
+/// are left.
+fn spf_passed(message: &mail_parser::Message, authserv_id: &str, sender: &str) -> bool {
+    message.headers_raw()
+    .filter(|(name, _)| name.eq_ignore_ascii_case("Authentication-Results"))
+    .any(|(_, value)| {
+        let mut results = value.split(';');
+        let id = results.next().unwrap_or_default().split_whitespace().next().unwrap_or_default();
+        id.eq_ignore_ascii_case(authserv_id) && results.any(|result| {
+            let mut words = result.split_whitespace();
+            words.next().map_or(false, |w| w.eq_ignore_ascii_case("spf=pass")) && words.any(|w| match w.split_once('=') {
+                Some((key, value)) => key.eq_ignore_ascii_case("smtp.mailfrom") && value.eq_ignore_ascii_case(sender),
+                None => false,
+            })
+        })
+    })
+}
+
+/// Mails `urls` back to `to` through `MAIL_RELAY`, if there is one.
+async fn reply(config: &Config, to: &str, subject: &str, urls: &[String]) -> anyhow::Result<()> {
+    let (relay, from) = match (&config.mail_relay, &config.mail_recipient) {
+        (Some(relay), Some(from)) if !to.is_empty() => (relay, from),
+        _ => return Ok(()),
+    };
+
+    let email = Message::builder()
+        .from(from.parse()?)
+        .to(to.parse()?)
+        .subject(format!("Re: {}", subject))
+        .body(format!("Your pastes:\n\n{}\n", urls.join("\n")))?;
+    AsyncSmtpTransport::<Tokio1Executor>::from_url(relay)?.build().send(email).await?;
+    Ok(())
+}
@@ -1,251 +1,381 @@
-use std::{sync::Arc, time::Duration, path};
+use std::{
+    convert::Infallible,
+    future::Future,
+    str::FromStr,
+    sync::{atomic::AtomicBool, Arc},
+    time::Duration,
+};
 
 use axum::{
-    extract::{Multipart, State, Query, Path},
-    http::StatusCode,
-    routing::{get, post, delete},
-    Router, body::Bytes,
+    body::Body,
+    http::Request,
+    middleware,
+    response::Response,
+    routing::{any, delete, get, post, put},
+    Router,
 };
-use chrono::prelude::*;
 
-use serde::Deserialize;
-use sqlx::{sqlite::SqlitePoolOptions, SqlitePool};
+use sqlx::{sqlite::{SqliteConnectOptions, SqlitePoolOptions}, SqlitePool};
+use tokio::sync::broadcast;
+use tower::{Service, ServiceBuilder};
 use tower_http::services::ServeDir;
-use uuid::Uuid;
-use futures::{Stream, TryStreamExt};
-use std::io;
-use tokio::{fs::File, io::BufWriter};
-use tokio_util::io::StreamReader;
 
+mod abuse;
+mod access;
+mod admin;
+mod ansi;
+mod archive;
+mod assets;
+mod audit;
+mod auth;
+mod bandwidth;
+mod base_url;
+mod cli;
+mod config;
+mod content;
+mod db;
+mod diff;
+mod encrypted;
+mod errors;
+mod exif;
+mod geoip;
+mod grpc;
+mod hexdump;
+mod hooks;
+mod hotlink;
+mod integrity;
+mod ipfs;
+mod jobs;
+mod json_view;
+mod jwt;
+mod lang;
+mod link;
+mod listen;
+mod locks;
+mod mail;
+mod maintenance;
+mod metadata;
+mod moderation;
+mod name;
+mod namespace;
+mod netcat;
+mod notify;
+mod nsfw;
+mod oembed;
+mod optimize;
+mod pages;
+mod parts;
+mod paseto;
+mod paste;
+mod pdf;
+mod policy;
+mod progress;
+mod redirects;
+mod reload;
+mod rename;
+mod replica;
+mod reputation;
+mod robots;
+mod s3;
+mod secrets;
+mod session;
+mod signature;
+mod sniff;
+mod staging;
+mod stats;
+mod storage;
+mod table;
+mod tail;
+mod takedown;
+mod templates;
+mod text;
+mod throttle;
+mod thumbnail;
+mod tls;
+mod totp;
+mod versions;
+mod webdav;
+
+use auth::AuthGuard;
+use bandwidth::Bandwidth;
+use config::{Config, LiveConfig};
+use hooks::Hooks;
+use jobs::JobMetrics;
+use jwt::JwtVerifier;
+use listen::Listener;
+use locks::PasteLocks;
+use paseto::PasetoVerifier;
+use reload::LogHandle;
+use progress::Uploads;
+use reputation::Reputation;
+use session::SessionKey;
+use templates::Templates;
+use throttle::Throttle;
 
 const PASTES_DIRECTORY: &str = "pastes";
 #[tokio::main]
 async fn main() {
-    tracing_subscriber::fmt::init();
+    // subcommands print their results to stdout and log to stderr
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if !args.is_empty() {
+        if let Err(e) = cli::run(&args).await {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    let log_handle = reload::init_logging();
     tracing::info!("Starting server...");
 
-    match run().await {
+    match run(log_handle).await {
         Ok(_) => tracing::info!("Program exited successfully."),
         Err(e) => tracing::error!("Error: {}", e),
     }
 }
 
-async fn run() -> anyhow::Result<()> {
-    let base_url: &'static str = std::env::var("BASE_URL")
-    .map(|s| Box::leak(s.into_boxed_str()) as &str)
-    .unwrap_or("http://127.0.0.1:3001");
+async fn run(log_handle: LogHandle) -> anyhow::Result<()> {
+    let config = Config::from_env()?;
+    reload::set_log_level(&log_handle, &config.log_level)?;
+    name::configure(config.id_scheme.clone());
 
     tokio::fs::create_dir_all(PASTES_DIRECTORY).await?;
+    storage::migrate_flat().await?;
+
+    tracing::info!("Opening database at \"{}\"...", &config.database_url);
+    let db = connect(&config).await?;
+    db::init_db(&db).await?;
+    let tls = tls::acceptor(&config)?;
+    let http = listen::http(&config);
+    let listeners = if !config.listeners.is_empty() {
+        config.listeners.iter().map(|l| Listener::parse(l)).collect::<anyhow::Result<_>>()?
+    } else if tls.is_some() {
+        vec![Listener::Https(config.addr.clone())]
+    } else {
+        vec![Listener::Http(config.addr.clone())]
+    };
+    let jwt = JwtVerifier::from_config(&config).await?;
+    let paseto = PasetoVerifier::from_config(&config)?;
+    let session_key = SessionKey::from_config(&config);
+    let reputation = Reputation::from_config(&config);
+    let throttle = Throttle::from_config(&config);
+    let hooks = Hooks::from_config(&config, &db);
+    let templates = Templates::load(&config)?;
+    let (appends, _) = broadcast::channel(64);
+    let (events, _) = broadcast::channel(256);
+    let state = Arc::new(AppState {
+        db,
+        read_only: AtomicBool::new(config.read_only),
+        config: LiveConfig::new(config),
+        log_handle,
+        auth_guard: AuthGuard::default(),
+        reputation,
+        uploads: Uploads::default(),
+        throttle,
+        bandwidth: Bandwidth::default(),
+        locks: PasteLocks::default(),
+        jwt,
+        paseto,
+        session_key,
+        appends,
+        events,
+        jobs: JobMetrics::default(),
+        hooks,
+        templates,
+    });
+
+    jobs::start(state.clone())?;
+    reload::on_sighup(state.clone())?;
+    notify::start(state.clone());
+    bandwidth::start(state.clone());
+    replica::start(&state.db, &state.config()).await?;
+
+    if let Some(grpc_addr) = &state.config().grpc_addr {
+        let grpc_addr = grpc_addr.parse()?;
+        let service = grpc::server(state.clone());
+        tracing::info!("Serving gRPC on {}...", grpc_addr);
+        tokio::spawn(async move {
+            if let Err(e) = tonic::transport::Server::builder().add_service(service).serve(grpc_addr).await {
+                tracing::error!("gRPC server failed: {}", e);
+            }
+        });
+    }
 
-    let db_connection_str =
-        std::env::var("DATABASE_URL").unwrap_or_else(|_| "smolpaste.sqlite".to_string());
-
-    tracing::info!("Opening database at \"{}\"...", &db_connection_str);
-    let db = SqlitePoolOptions::new()
-        .max_connections(5)
-        .acquire_timeout(Duration::from_secs(3))
-        .connect(&db_connection_str)
-        .await?;
-
+    if let Some(netcat_addr) = state.config().netcat_addr.clone() {
+        let state = state.clone();
+        tokio::spawn(async move {
+            if let Err(e) = netcat::serve(state, &netcat_addr).await {
+                tracing::error!("Netcat listener failed: {}", e);
+            }
+        });
+    }
 
-    init_db(&db).await?;
-    let state = Arc::new(AppState { db, base_url });
+    if let Some(mail_addr) = state.config().mail_addr.clone() {
+        let state = state.clone();
+        tokio::spawn(async move {
+            if let Err(e) = mail::serve(state, &mail_addr).await {
+                tracing::error!("Mail listener failed: {}", e);
+            }
+        });
+    }
 
-    let addr = std::env::var("SMOLPASTE_ADDR").unwrap_or_else(|_| "127.0.0.1:3001".to_string());
     let app = Router::new()
-        .route("/new", post(new_paste))
-        .route("/delete", delete(delete_paste))
-        .nest_service("/paste",ServeDir::new(PASTES_DIRECTORY))
+        .route("/", get(pages::index))
+        .route("/mine", get(pages::mine))
+        .route("/view/:filename", get(pages::view))
+        .route("/view/:filename/diff", get(diff::versions))
+        .route("/oembed", get(oembed::oembed))
+        .route("/robots.txt", get(robots::robots_txt))
+        .route("/static/*path", get(assets::serve))
+        .route("/new", post(paste::new_paste))
+        .route("/delete", delete(paste::delete_paste))
+        .route("/login", get(session::login_page).post(session::login))
+        .route("/logout", post(session::logout))
+        .route("/ui/upload", post(session::upload))
+        .route("/ui/upload/progress/:id", get(progress::progress))
+        .route("/ui/delete", post(session::delete))
+        .route("/collection/:id", get(paste::get_collection))
+        .route("/admin/auth-attempts", get(admin::auth_attempts))
+        .route("/admin/audit", get(admin::audit_log))
+        .route("/admin/cert", post(admin::map_cert))
+        .route("/admin/base-url", post(admin::set_base_url))
+        .route("/admin/bandwidth-quota", post(admin::set_bandwidth_quota))
+        .route("/admin/reload", post(admin::reload_config))
+        .route("/admin/read-only", post(admin::set_read_only))
+        .route("/admin/legal-hold", post(admin::set_legal_hold))
+        .route("/admin/takedown", post(takedown::take_down).delete(takedown::lift))
+        .route("/admin/takedowns", get(takedown::list))
+        .route("/admin/redirect", post(redirects::create).delete(redirects::remove))
+        .route("/admin/redirects", get(redirects::list))
+        .route("/admin/flags", get(moderation::queue))
+        .route("/admin/flags/:id/dismiss", post(moderation::dismiss))
+        .route("/admin", get(stats::dashboard))
+        .route("/api/stats", get(stats::stats))
+        .route("/api/stats/uploads", get(stats::uploads))
+        .route("/api/stats/bandwidth", get(stats::bandwidth))
+        .route("/api/admin/storage", get(stats::storage))
+        .route("/api/pastes", get(metadata::search).delete(paste::bulk_delete))
+        .route("/api/collection/:id/archive", get(paste::archive_collection))
+        .route("/admin/archive", get(admin::archive_all))
+        .route("/admin/events", get(admin::events))
+        .route("/admin/jobs", get(admin::jobs))
+        .route("/admin/staging", get(staging::report))
+        .route("/admin/db-backup", get(admin::db_backup))
+        .route("/admin/totp", post(admin::totp_enroll).delete(admin::totp_disable))
+        .route("/admin/totp/confirm", post(admin::totp_confirm))
+        .route("/api/paste/:id", get(metadata::info).patch(rename::rename))
+        .route("/api/uploads", post(parts::initiate))
+        .route("/api/uploads/:id", delete(parts::abort))
+        .route("/api/uploads/:id/complete", post(parts::complete))
+        .route("/api/uploads/:id/:part", put(parts::put_part))
+        .route("/api/paste/:id/accesses", get(access::list))
+        .route("/api/paste/:id/link", post(link::new_link))
+        .route("/api/paste/:id/signature", get(signature::serve).put(signature::attach))
+        .route("/api/paste/:id/verify", post(integrity::verify))
+        .route("/api/paste/:id/versions", get(versions::list_versions))
+        .route("/d/:link", get(link::download))
+        .route("/p/:hash", get(content::serve))
+        .route("/thumb/:filename", get(thumbnail::serve))
+        .route(webdav::PREFIX, any(webdav::handle))
+        .route(&format!("{}/*path", webdav::PREFIX), any(webdav::handle))
+        .route(&format!("{}/:bucket", s3::PREFIX), any(s3::bucket))
+        .route(&format!("{}/:bucket/*key", s3::PREFIX), any(s3::object))
+        .route("/api/diff", get(diff::diff))
+        .route("/api/tokens/:id/usage", get(admin::token_usage))
+        .route("/api/tokens/:id/purge", post(admin::purge_token))
+        .nest_service("/paste", paste_service(state.clone()))
+        .fallback(pages::not_found)
+        .layer(middleware::from_fn_with_state(state.clone(), robots::noindex_all))
+        .layer(middleware::from_fn_with_state(state.clone(), maintenance::reject_writes))
+        .layer(middleware::from_fn_with_state(state.clone(), errors::error_pages))
         .with_state(state);
 
-    let listener = std::net::TcpListener::bind(addr)?;
-    tracing::info!("Listening on {}...", listener.local_addr()?);
-
-    axum::Server::from_tcp(listener)?
-        .serve(app.into_make_service())
-        .await?;
-
-    Ok(())
-}
+    // namespace prefixes have to be stripped before the router sees the path
+    let app = Router::new().fallback_service(ServiceBuilder::new()
+        .layer(middleware::map_request(namespace::strip_prefix))
+        .service(app));
 
-#[derive(Debug, Clone)]
-pub struct AppState {
-    db: SqlitePool,
-    base_url: &'static str
-}
+    listen::serve_all(listeners, app, tls, http).await?;
 
-pub async fn init_db(db: &SqlitePool) -> anyhow::Result<()> {
-    sqlx::query("CREATE TABLE IF NOT EXISTS pastes (
-        id TEXT PRIMARY KEY NOT NULL,
-        size INTEGER,
-        filename TEXT,
-        timestamp INTEGER
-    )")
-    .execute(db).await?;
-
-    sqlx::query("CREATE TABLE IF NOT EXISTS tokens (
-        value TEXT,
-        created_at INTEGER
-)")
-    .execute(db).await?;
-
-    /*sqlx::query("INSERT INTO tokens (value, created_at) VALUES ($1, $2)")
-    .bind("test")
-    .bind(1699645888)
-    .execute(db).await?;*/
     Ok(())
 }
 
-#[derive(Debug, Clone, sqlx::FromRow)]
-pub struct PasteInfo {
-    id: Uuid,
-    size: u32,
-    filename: String,
-    timestamp: i64,
-}
-
-#[derive(Debug, Clone, sqlx::FromRow)]
-pub struct FileNameWrapper {
-    filename: String,
-}
-
-#[derive(Debug, Clone, sqlx::FromRow)]
-pub struct TokenInfo {
-    value: Uuid,
-    created_at: i64
-}
-
-#[derive(Debug, Clone, Deserialize)]
-pub struct TokenParam {
-    token: String
-}
-
-#[derive(Debug, Clone, Deserialize)]
-pub struct IdTokenParam {
-    token: String,
-    id: String
+/// Everything pastes are served through: nested at `/paste`, and used by the
+/// routes that find a paste some other way, so they all apply the same checks
+/// and headers.
+fn paste_service(state: Arc<AppState>) -> impl Service<Request<Body>, Response = Response, Error = Infallible, Future = impl Future<Output = Result<Response, Infallible>> + Send> + Clone + Send + 'static {
+    ServiceBuilder::new()
+        .layer(middleware::map_request(name::normalize_uri))
+        .layer(middleware::from_fn_with_state(state.clone(), redirects::follow))
+        .layer(middleware::from_fn_with_state(state.clone(), paste::load_paste))
+        .layer(middleware::from_fn_with_state(state.clone(), namespace::hide_foreign))
+        .layer(middleware::from_fn_with_state(state.clone(), takedown::enforce))
+        .layer(middleware::from_fn_with_state(state.clone(), bandwidth::enforce))
+        .layer(middleware::from_fn_with_state(state.clone(), paste::serve_hooks))
+        .layer(middleware::from_fn_with_state(state.clone(), policy::enforce))
+        .layer(middleware::from_fn_with_state(state.clone(), throttle::limit))
+        .layer(middleware::from_fn_with_state(state.clone(), hotlink::protect))
+        .layer(middleware::from_fn_with_state(state.clone(), paste::append_paste))
+        .layer(middleware::from_fn_with_state(state.clone(), versions::update_paste))
+        .layer(middleware::from_fn_with_state(state.clone(), versions::serve_version))
+        .layer(middleware::from_fn_with_state(state.clone(), paste::count_bandwidth))
+        .layer(middleware::from_fn(paste::mark_immutable))
+        .layer(middleware::from_fn(robots::noindex_unlisted))
+        .layer(middleware::from_fn(pdf::serve_inline))
+        .layer(middleware::from_fn_with_state(state, tail::tail_paste))
+        .layer(middleware::from_fn(text::slice_lines))
+        .layer(middleware::map_request(storage::shard_uri))
+        .service(ServeDir::new(PASTES_DIRECTORY).with_buf_chunk_size(storage::READ_CHUNK_SIZE))
 }
 
-
-#[axum::debug_handler]
-async fn new_paste(
-    State(state): State<Arc<AppState>>,
-    Query(token): Query<TokenParam>,
-    mut multipart: Multipart,
-) -> Result<String, StatusCode> {
-    let res = sqlx::query_scalar::<_, i32>("SELECT COUNT(*) as count FROM tokens WHERE value = $1")
-    .bind(token.token)
-    .fetch_one(&state.db).await;
-
-    match res {
-        Ok(1) => {},
-        Ok(0) => return Err(StatusCode::UNAUTHORIZED),
-        Err(sqlx::Error::RowNotFound) => return Err(StatusCode::UNAUTHORIZED),
-        _ => return Err(StatusCode::INTERNAL_SERVER_ERROR)
-    };
-    
-    let id = uuid::Uuid::new_v4();
-    let field = match multipart.next_field().await {
-        Ok(Some(f)) => f,
-        _ => return Err(StatusCode::BAD_REQUEST)
-    };
-
-    let upload_name = match field.file_name() {
-        None => return Err(StatusCode::BAD_REQUEST),
-        Some(n) => path::Path::new(n)
-    };
-
-    let filename = match upload_name.extension() {
-        Some(e) => match e.to_str() {
-            Some(e) => format!("{}.{}", id, e),
-            None => return Err(StatusCode::BAD_REQUEST)
-        },
-        None => format!("{}", id)
-    };
-
-    let written = stream_to_file(&filename, field).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-
-    tracing::info!("Created a {} byte file.", written);
-
-    let utc: DateTime<Utc> = Utc::now();
-
-    let info = PasteInfo {
-        id,
-        size: written,
-        filename,
-        timestamp: utc.timestamp(),
-    };
-
-    sqlx::query("INSERT INTO pastes (
-        id,
-        size,
-        filename,
-        timestamp
-    )VALUES (
-        $1, $2, $3, $4
-    )")
-    .bind(info.id.to_string())
-    .bind(info.size)
-    .bind(&info.filename)
-    .bind(info.timestamp)
-    .execute(&state.db).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-
-    tracing::info!("{}/paste/{}", state.base_url, info.filename);
-    return Ok(format!("{}/paste/{}", state.base_url, info.filename))
+async fn connect(config: &Config) -> anyhow::Result<SqlitePool> {
+    let mut options = SqliteConnectOptions::from_str(&config.database_url)?;
+    if config.replica_url.is_some() {
+        // the replicator checkpoints once the WAL is shipped
+        options = options.pragma("wal_autocheckpoint", "0");
+    }
+    let db = SqlitePoolOptions::new()
+        .max_connections(5)
+        .acquire_timeout(Duration::from_secs(3))
+        .connect_with(options)
+        .await?;
+    Ok(db)
 }
 
-#[axum::debug_handler]
-async fn delete_paste(
-    State(state): State<Arc<AppState>>,
-    Query(query): Query<IdTokenParam>,
-) -> Result<StatusCode, StatusCode> {
-    let res = sqlx::query_scalar::<_, i32>("SELECT COUNT(*) as count FROM tokens WHERE value = $1")
-    .bind(query.token)
-    .fetch_one(&state.db).await;
-
-    match res {
-        Ok(1) => {},
-        Ok(0) => return Err(StatusCode::UNAUTHORIZED),
-        Err(sqlx::Error::RowNotFound) => return Err(StatusCode::UNAUTHORIZED),
-        _ => return Err(StatusCode::INTERNAL_SERVER_ERROR)
-    };
-
-    let paste = match sqlx::query_as::<_, FileNameWrapper>("DELETE FROM pastes WHERE id = $1 RETURNING filename")
-    .bind(&query.id)
-    .fetch_one(&state.db)
-    .await {
-        Ok(f) => f,
-        Err(sqlx::Error::RowNotFound) => return Err(StatusCode::NOT_FOUND),
-        Err(_) => return Err(StatusCode::INTERNAL_SERVER_ERROR)
-    };
-
-    tracing::info!("Deleting paste {}", &paste.filename);
-
-    tokio::fs::remove_file(format!("{}/{}", PASTES_DIRECTORY, paste.filename))
-    .await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-
-    Ok(StatusCode::OK)
+#[derive(Debug)]
+pub struct AppState {
+    db: SqlitePool,
+    config: LiveConfig,
+    log_handle: LogHandle,
+    /// Set while uploads and deletes are refused, see [`maintenance`].
+    read_only: AtomicBool,
+    auth_guard: AuthGuard,
+    /// Verdicts on anonymous uploaders' addresses, see [`reputation`].
+    reputation: Reputation,
+    /// Web UI uploads whose progress a page follows.
+    uploads: Uploads,
+    /// Download bandwidth in use, see [`throttle`].
+    throttle: Throttle,
+    /// Bytes served not yet written out, see [`bandwidth`].
+    bandwidth: Bandwidth,
+    /// Changes to a paste's file in progress, see [`locks`].
+    locks: PasteLocks,
+    jwt: Option<JwtVerifier>,
+    paseto: Option<PasetoVerifier>,
+    session_key: SessionKey,
+    /// Filenames of pastes that were just appended to, for anyone tailing them.
+    appends: broadcast::Sender<String>,
+    /// Every audited operation, for `/admin/events` subscribers.
+    events: broadcast::Sender<audit::AuditEvent>,
+    jobs: JobMetrics,
+    hooks: Hooks,
+    templates: Templates,
 }
 
-async fn stream_to_file<S, E>(path: &str, stream: S) -> anyhow::Result<u32>
-where
-    S: Stream<Item = Result<Bytes, E>>,
-    E: Into<anyhow::Error>,
-{
-
-    async {
-        // Convert the stream into an `AsyncRead`.
-        let body_with_io_error = stream.map_err(|_| io::Error::new(io::ErrorKind::Other, ""));
-        let body_reader = StreamReader::new(body_with_io_error);
-        futures::pin_mut!(body_reader);
-
-        // Create the file. `File` implements `AsyncWrite`.
-        let path = std::path::Path::new(PASTES_DIRECTORY).join(path);
-        let mut file = BufWriter::new(File::create(path).await?);
-
-        // Copy the body into the file.
-        let total = tokio::io::copy(&mut body_reader, &mut file).await?;
-        Ok(total as u32)
+impl AppState {
+    /// The config as of now; a reload may swap it out at any time, so hold on
+    /// to it when several values have to agree.
+    fn config(&self) -> Arc<Config> {
+        self.config.get()
     }
-    .await
-}
\ No newline at end of file
+}
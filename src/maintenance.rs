@@ -0,0 +1,38 @@
+use std::sync::{atomic::Ordering, Arc};
+
+use axum::{
+    extract::State,
+    http::{header, Method, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+
+use crate::AppState;
+
+/// Paths that keep working while read-only, so an admin can still log in and
+/// turn it off again.
+const ALWAYS_WRITABLE: &[&str] = &["/admin", "/login", "/logout"];
+
+/// Whether uploads and deletes are currently refused.
+pub fn is_read_only(state: &AppState) -> bool {
+    state.read_only.load(Ordering::Relaxed)
+}
+
+pub fn set_read_only(state: &AppState, read_only: bool) {
+    if state.read_only.swap(read_only, Ordering::Relaxed) != read_only {
+        tracing::warn!("Read-only mode {}", if read_only { "enabled" } else { "disabled" });
+    }
+}
+
+/// Refuses anything but reads with `503 Service Unavailable` while the
+/// instance is read-only, telling clients when to try again.
+pub async fn reject_writes<B>(State(state): State<Arc<AppState>>, req: Request<B>, next: Next<B>) -> Response {
+    let read = matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS) || req.method().as_str() == "PROPFIND";
+    let exempt = ALWAYS_WRITABLE.iter().any(|p| req.uri().path().starts_with(p));
+    if read || exempt || !is_read_only(&state) {
+        return next.run(req).await;
+    }
+
+    let retry_after = state.config().maintenance_retry_after.as_secs().to_string();
+    (StatusCode::SERVICE_UNAVAILABLE, [(header::RETRY_AFTER, retry_after)]).into_response()
+}
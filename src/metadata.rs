@@ -0,0 +1,150 @@
+use std::{net::SocketAddr, sync::Arc};
+
+use axum::{
+    extract::{ConnectInfo, Path, Query, State},
+    http::StatusCode,
+    Extension, Json,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::{auth::authenticate_client, base_url::BaseUrl, namespace::RequestNamespace, tls::ClientCert, AppState};
+
+/// Longest title a paste can have, in characters.
+const MAX_TITLE_LEN: usize = 200;
+
+/// Selects the [`Info`] of pastes, to be narrowed down with `WHERE`.
+const INFO_QUERY: &str = "SELECT id, filename, title, size, COALESCE(updated_at, timestamp) AS timestamp, metadata, recipients,
+    signature IS NOT NULL AS signed, signed_by,
+    CASE WHEN legal_hold THEN NULL ELSE timestamp + (SELECT retention_seconds FROM namespaces WHERE name = pastes.namespace) END AS expires_at
+    FROM pastes";
+
+/// Matches pastes whose metadata has every key of the JSON object bound as
+/// `$<param>` with the same value, compared as text so `42` and `"42"` match.
+/// An unbound or empty filter matches everything.
+pub fn filter(param: usize) -> String {
+    format!("NOT EXISTS (SELECT 1 FROM json_each(${}) AS wanted
+        WHERE CAST(json_extract(pastes.metadata, '$.\"' || wanted.key || '\"') AS TEXT) IS NOT CAST(wanted.value AS TEXT))", param)
+}
+
+/// Checks that `raw` is a JSON object of at most `max_size` bytes, whose keys
+/// can be searched for, and returns it compacted.
+pub fn parse(raw: &str, max_size: usize) -> Result<String, StatusCode> {
+    let object: Map<String, Value> = serde_json::from_str(raw).map_err(|_| StatusCode::BAD_REQUEST)?;
+    if object.keys().any(|k| k.is_empty() || k.contains('"')) {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let compact = serde_json::to_string(&object).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if compact.len() > max_size {
+        return Err(StatusCode::PAYLOAD_TOO_LARGE);
+    }
+    Ok(compact)
+}
+
+/// Trims `raw` and flattens line breaks and other control characters into
+/// spaces, for use as a title. Empty titles are none at all.
+pub fn title(raw: &str) -> Result<Option<String>, StatusCode> {
+    let title: String = raw.trim().chars().map(|c| if c.is_control() { ' ' } else { c }).collect();
+    if title.chars().count() > MAX_TITLE_LEN {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    Ok(Some(title).filter(|t| !t.is_empty()))
+}
+
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct Info {
+    pub id: String,
+    pub filename: String,
+    pub title: Option<String>,
+    pub size: i64,
+    pub timestamp: i64,
+    pub metadata: Option<sqlx::types::Json<Value>>,
+    /// Who the paste is encrypted to, when it's an age file.
+    pub recipients: Option<sqlx::types::Json<Vec<String>>>,
+    /// Whether a PGP signature is attached.
+    pub signed: bool,
+    /// Fingerprint of the configured key that made it, if any did.
+    pub signed_by: Option<String>,
+    /// When it's deleted for being older than its namespace's retention.
+    pub expires_at: Option<i64>,
+    #[sqlx(skip)]
+    pub url: String,
+}
+
+/// Describes paste `id`, along with the metadata it was uploaded with.
+#[axum::debug_handler]
+pub async fn info(
+    State(state): State<Arc<AppState>>,
+    BaseUrl(base_url): BaseUrl,
+    namespace: RequestNamespace,
+    Path(id): Path<String>,
+) -> Result<Json<Info>, StatusCode> {
+    let mut info = sqlx::query_as::<_, Info>(&format!("{} WHERE id = $1 AND namespace IS $2", INFO_QUERY))
+    .bind(&id)
+    .bind(namespace.name())
+    .fetch_optional(&state.db).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    .ok_or(StatusCode::NOT_FOUND)?;
+
+    info.url = state.config().url_template.render(&base_url, &info.filename);
+    Ok(Json(info))
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SearchParam {
+    token: Option<String>,
+    /// A JSON object the metadata of every paste listed has to agree with.
+    metadata: Option<String>,
+    limit: Option<u32>,
+    #[serde(default)]
+    offset: u32,
+}
+
+/// Lists the caller's pastes, or everyone's for admins, newest first,
+/// narrowed down by their metadata.
+#[axum::debug_handler]
+pub async fn search(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    cert: Option<Extension<ClientCert>>,
+    BaseUrl(base_url): BaseUrl,
+    namespace: RequestNamespace,
+    Query(query): Query<SearchParam>,
+) -> Result<Json<Vec<Info>>, StatusCode> {
+    let user = authenticate_client(&state, addr.ip(), cert.as_deref(), query.token.as_deref()).await?;
+    namespace.check(&user)?;
+    let wanted = query.metadata.as_deref().map(|m| parse(m, state.config().metadata_max_size)).transpose()?;
+
+    // admins see everything, everyone else only their own
+    let owner = if user.is_admin() { None } else { Some(user.id.ok_or(StatusCode::FORBIDDEN)?) };
+    let pastes = list(&state, &base_url, owner, namespace.name(), wanted.as_deref(), query.limit.unwrap_or(100), query.offset).await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(Json(pastes))
+}
+
+/// Pastes in `namespace`, of `owner` or everyone's, whose metadata agrees
+/// with `wanted`, newest first.
+pub async fn list(
+    state: &AppState,
+    base_url: &str,
+    owner: Option<i64>,
+    namespace: Option<&str>,
+    wanted: Option<&str>,
+    limit: u32,
+    offset: u32,
+) -> sqlx::Result<Vec<Info>> {
+    let mut pastes = sqlx::query_as::<_, Info>(&format!("{} WHERE ($1 IS NULL OR owner = $1) AND namespace IS $2 AND {}
+    ORDER BY timestamp DESC LIMIT $4 OFFSET $5", INFO_QUERY, filter(3)))
+    .bind(owner)
+    .bind(namespace)
+    .bind(wanted)
+    .bind(limit)
+    .bind(offset)
+    .fetch_all(&state.db).await?;
+
+    let template = &state.config().url_template;
+    for paste in &mut pastes {
+        paste.url = template.render(base_url, &paste.filename);
+    }
+    Ok(pastes)
+}
@@ -0,0 +1,102 @@
+use std::{net::SocketAddr, sync::Arc};
+
+use axum::{
+    extract::{ConnectInfo, Path, Query, State},
+    http::StatusCode,
+    Json,
+};
+use chrono::prelude::*;
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+
+use crate::{audit, auth::authenticate_admin, AppState};
+
+/// A paste something flagged for an admin to look at. Flags don't change
+/// how it's served; taking it down is up to whoever reviews it.
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct Flag {
+    pub id: i64,
+    pub filename: String,
+    /// What flagged it, like `nsfw`.
+    pub kind: String,
+    /// How sure whatever flagged it was, from 0 to 1, if it said.
+    pub score: Option<f64>,
+    pub created_at: i64,
+    pub reviewed_at: Option<i64>,
+    pub reviewed_by: Option<i64>,
+}
+
+/// Adds the paste stored as `filename` to the moderation queue.
+pub async fn flag(db: &SqlitePool, filename: &str, kind: &str, score: Option<f64>) -> sqlx::Result<()> {
+    sqlx::query("INSERT INTO flags (filename, kind, score, created_at) VALUES ($1, $2, $3, $4)")
+    .bind(filename)
+    .bind(kind)
+    .bind(score)
+    .bind(Utc::now().timestamp())
+    .execute(db).await?;
+    Ok(())
+}
+
+/// Marks the flags on the paste stored as `filename` reviewed by `actor`.
+pub async fn resolve(db: &SqlitePool, filename: &str, actor: Option<i64>) -> sqlx::Result<()> {
+    sqlx::query("UPDATE flags SET reviewed_at = $1, reviewed_by = $2 WHERE filename = $3 AND reviewed_at IS NULL")
+    .bind(Utc::now().timestamp())
+    .bind(actor)
+    .bind(filename)
+    .execute(db).await?;
+    Ok(())
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct QueueParam {
+    token: String,
+    totp: Option<String>,
+    limit: Option<u32>,
+}
+
+/// Lists the flags nobody reviewed yet on pastes that are still there,
+/// oldest first.
+#[axum::debug_handler]
+pub async fn queue(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Query(query): Query<QueueParam>,
+) -> Result<Json<Vec<Flag>>, StatusCode> {
+    authenticate_admin(&state, addr.ip(), &query.token, query.totp.as_deref()).await?;
+
+    let flags = sqlx::query_as::<_, Flag>("SELECT * FROM flags
+    WHERE reviewed_at IS NULL AND filename IN (SELECT filename FROM pastes)
+    ORDER BY id LIMIT $1")
+    .bind(query.limit.map(i64::from).unwrap_or(-1))
+    .fetch_all(&state.db).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Json(flags))
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ReviewParam {
+    token: String,
+    totp: Option<String>,
+}
+
+/// Dismisses flag `id`, leaving the paste as it is.
+#[axum::debug_handler]
+pub async fn dismiss(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path(id): Path<i64>,
+    Query(query): Query<ReviewParam>,
+) -> Result<StatusCode, StatusCode> {
+    let admin = authenticate_admin(&state, addr.ip(), &query.token, query.totp.as_deref()).await?;
+
+    let filename = sqlx::query_scalar::<_, String>("UPDATE flags SET reviewed_at = $1, reviewed_by = $2
+    WHERE id = $3 AND reviewed_at IS NULL RETURNING filename")
+    .bind(Utc::now().timestamp())
+    .bind(admin.id)
+    .bind(id)
+    .fetch_optional(&state.db).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    .ok_or(StatusCode::NOT_FOUND)?;
+
+    audit::record(&state, admin.id, addr.ip(), "flag_dismiss", &filename).await;
+    Ok(StatusCode::OK)
+}
@@ -0,0 +1,340 @@
+use std::{fmt, sync::OnceLock};
+
+use axum::http::{uri::PathAndQuery, Request, Uri};
+use rand::Rng;
+use sqlx::SqlitePool;
+use uuid::Uuid;
+
+use crate::storage::paste_path;
+
+/// Longest extension kept from the name a file was uploaded as.
+const MAX_EXTENSION_LEN: usize = 16;
+/// Longest id accepted, whichever scheme it was generated with.
+const MAX_ID_LEN: usize = 64;
+/// How many fresh ids are tried before giving up on finding an unused one.
+pub const MAX_ATTEMPTS: usize = 16;
+
+/// Ids that would be mistaken for a route, compared regardless of case.
+const RESERVED: &[&str] = &[
+    "admin", "api", "collection", "d", "dav", "delete", "favicon", "login", "logout",
+    "new", "ns", "oembed", "p", "paste", "robots", "s3", "static", "thumb", "ui", "view",
+];
+
+static SCHEME: OnceLock<IdScheme> = OnceLock::new();
+
+/// How ids for new pastes are generated, from `ID_LENGTH`, `ID_ALPHABET` and
+/// `ID_CASE_SENSITIVE`.
+#[derive(Debug, Clone)]
+pub struct IdScheme {
+    /// Ids of `length` random characters from the alphabet, or UUIDs when
+    /// `None`.
+    random: Option<(Vec<u8>, usize)>,
+    /// Whether `abc` and `ABC` are different pastes. When they aren't, ids
+    /// are generated and looked up in lowercase.
+    case_sensitive: bool,
+}
+
+impl Default for IdScheme {
+    fn default() -> Self {
+        Self { random: None, case_sensitive: true }
+    }
+}
+
+impl IdScheme {
+    pub fn new(alphabet: &str, length: Option<usize>, case_sensitive: bool) -> anyhow::Result<Self> {
+        let length = match length {
+            Some(l) if l == 0 || l > MAX_ID_LEN => anyhow::bail!("ID_LENGTH must be between 1 and {}", MAX_ID_LEN),
+            Some(l) => l,
+            None => return Ok(Self { random: None, case_sensitive }),
+        };
+
+        let mut chars = Vec::new();
+        for b in alphabet.bytes() {
+            if !valid_id_byte(b) {
+                anyhow::bail!("ID_ALPHABET may only contain ASCII letters, digits, '-' and '_'");
+            }
+            let b = if case_sensitive { b } else { b.to_ascii_lowercase() };
+            if !chars.contains(&b) {
+                chars.push(b);
+            }
+        }
+        if chars.len() < 2 {
+            anyhow::bail!("ID_ALPHABET needs at least two distinct characters");
+        }
+
+        Ok(Self { random: Some((chars, length)), case_sensitive })
+    }
+
+    /// A fresh id, which may already be taken.
+    fn generate(&self) -> String {
+        let (alphabet, length) = match &self.random {
+            Some(r) => r,
+            None => return Uuid::new_v4().to_string(),
+        };
+
+        let mut rng = rand::thread_rng();
+        loop {
+            let id: String = (0..*length).map(|_| alphabet[rng.gen_range(0..alphabet.len())] as char).collect();
+            if !RESERVED.iter().any(|r| r.eq_ignore_ascii_case(&id)) {
+                return id;
+            }
+        }
+    }
+
+    /// See [`PasteName::parse`].
+    fn parse(&self, name: &str) -> Option<PasteName> {
+        let (id, ext) = match name.split_once('.') {
+            Some((id, ext)) => (id, Some(ext)),
+            None => (name, None),
+        };
+
+        // the UUIDs handed out before ids were configurable fit this too
+        if id.is_empty() || id.len() > MAX_ID_LEN || !id.bytes().all(valid_id_byte) {
+            return None;
+        }
+        if !ext.map_or(true, valid_extension) {
+            return None;
+        }
+        if self.case_sensitive {
+            return Some(PasteName(name.to_string()));
+        }
+        Some(PasteName(match ext {
+            Some(ext) => format!("{}.{}", id.to_ascii_lowercase(), ext),
+            None => id.to_ascii_lowercase(),
+        }))
+    }
+}
+
+/// Makes `scheme` the one paste names are generated and parsed with. Only the
+/// first call counts; until then, names are UUIDs.
+pub fn configure(scheme: IdScheme) {
+    let _ = SCHEME.set(scheme);
+}
+
+fn scheme() -> &'static IdScheme {
+    SCHEME.get_or_init(IdScheme::default)
+}
+
+/// The name a paste is stored and served as: its id, optionally followed by a
+/// `.` and an extension.
+///
+/// It only ever contains ASCII letters, digits, `-`, `_` and a single `.`, so
+/// it's safe to use as a path component or in a URL without further escaping.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct PasteName(String);
+
+impl PasteName {
+    /// Names a new paste with a fresh id, keeping the extension of the name
+    /// the client uploaded it as when it's a sensible one. The id may be
+    /// taken already, see [`unused`] for one that isn't.
+    pub fn new(upload_name: &str) -> Self {
+        let id = scheme().generate();
+        match extension(upload_name) {
+            Some(e) => Self(format!("{}.{}", id, e)),
+            None => Self(id),
+        }
+    }
+
+    /// Validates a name taken from a request, without checking that a paste
+    /// by that name exists. When ids aren't case sensitive, the id part comes
+    /// back in lowercase.
+    pub fn parse(name: &str) -> Option<Self> {
+        scheme().parse(name)
+    }
+
+    /// This name with `ext` appended, if it doesn't have an extension yet.
+    pub fn with_extension(&self, ext: &str) -> Option<Self> {
+        (!self.has_extension() && valid_extension(ext)).then(|| Self(format!("{}.{}", self.0, ext)))
+    }
+
+    pub fn has_extension(&self) -> bool {
+        self.0.contains('.')
+    }
+
+    /// The id part, without the extension.
+    pub fn id(&self) -> &str {
+        self.0.split('.').next().unwrap_or(&self.0)
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    pub fn into_string(self) -> String {
+        self.0
+    }
+}
+
+impl fmt::Display for PasteName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// Names a new paste like [`PasteName::new`], with an id no paste has, had
+/// or could get by sniffing an extension, retrying on collisions.
+pub async fn unused(db: &SqlitePool, upload_name: &str) -> anyhow::Result<PasteName> {
+    for _ in 0..MAX_ATTEMPTS {
+        let name = PasteName::new(upload_name);
+        if !is_taken(db, &name, None).await? {
+            return Ok(name);
+        }
+        tracing::debug!("Id {} is taken, trying another", name.id());
+    }
+    anyhow::bail!("no unused id after {} attempts, ID_LENGTH is likely too short", MAX_ATTEMPTS)
+}
+
+/// Whether the id of `name` belongs to a paste other than the one stored as
+/// `except`, or did to one that expired or was renamed away from it.
+pub async fn is_taken(db: &SqlitePool, name: &PasteName, except: Option<&str>) -> anyhow::Result<bool> {
+    // LIKE ignores case and takes `_` for any character, which only errs
+    // on the side of caution
+    let taken = sqlx::query_scalar::<_, i64>("SELECT
+        EXISTS (SELECT 1 FROM pastes WHERE (filename = $1 OR filename LIKE $1 || '.%') AND filename IS NOT $2)
+        OR EXISTS (SELECT 1 FROM expired_pastes WHERE filename = $1 OR filename LIKE $1 || '.%')
+        OR EXISTS (SELECT 1 FROM redirects WHERE (source = $1 OR source LIKE $1 || '.%') AND target IS NOT $2)")
+    .bind(name.id())
+    .bind(except)
+    .fetch_one(db).await?;
+
+    // files nothing knows of, left behind by a crash, aren't overwritten either
+    Ok(taken != 0 || tokio::fs::try_exists(paste_path(name.as_str())).await?)
+}
+
+/// Rewrites requests for `/<name>/...` to the name as [`PasteName::parse`]
+/// gives it, so ids that aren't case sensitive are found whatever case
+/// they're asked for in.
+pub async fn normalize_uri<B>(mut req: Request<B>) -> Request<B> {
+    if scheme().case_sensitive {
+        return req;
+    }
+
+    let path = req.uri().path().trim_start_matches('/');
+    let (first, rest) = match path.split_once('/') {
+        Some((first, rest)) => (first, Some(rest)),
+        None => (path, None),
+    };
+    let name = match PasteName::parse(first) {
+        Some(n) if n.as_str() != first => n,
+        _ => return req,
+    };
+
+    let mut path = format!("/{}", name);
+    if let Some(rest) = rest {
+        path = format!("{}/{}", path, rest);
+    }
+    if let Some(q) = req.uri().query() {
+        path = format!("{}?{}", path, q);
+    }
+
+    let mut parts = req.uri().clone().into_parts();
+    parts.path_and_query = path.parse::<PathAndQuery>().ok();
+    if let Ok(uri) = Uri::from_parts(parts) {
+        *req.uri_mut() = uri;
+    }
+    req
+}
+
+/// The extension of a client-supplied file name, if it's safe to keep.
+fn extension(upload_name: &str) -> Option<&str> {
+    // only the last component counts, whichever separator the client used
+    let base = upload_name.rsplit(['/', '\\']).next()?;
+    match base.rsplit_once('.') {
+        // dotfiles like `.bashrc` have no extension
+        Some((stem, ext)) if !stem.is_empty() && valid_extension(ext) => Some(ext),
+        _ => None,
+    }
+}
+
+fn valid_extension(ext: &str) -> bool {
+    !ext.is_empty() && ext.len() <= MAX_EXTENSION_LEN && ext.bytes().all(|b| b.is_ascii_alphanumeric())
+}
+
+fn valid_id_byte(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b == b'-' || b == b'_'
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn uuid_ids() {
+        let scheme = IdScheme::default();
+        let id = scheme.generate();
+        assert!(Uuid::parse_str(&id).is_ok());
+        assert_eq!(scheme.parse(&id), Some(PasteName(id)));
+    }
+
+    #[test]
+    fn random_ids() {
+        let scheme = IdScheme::new("abc123", Some(12), true).unwrap();
+        for _ in 0..100 {
+            let id = scheme.generate();
+            assert_eq!(id.len(), 12);
+            assert!(id.bytes().all(|b| b"abc123".contains(&b)));
+            assert_eq!(scheme.parse(&id).map(PasteName::into_string), Some(id));
+        }
+    }
+
+    #[test]
+    fn case_insensitive_ids() {
+        // `A` and `a` are the same character, so this is only two of them
+        let scheme = IdScheme::new("aAbB", Some(8), false).unwrap();
+        assert_eq!(scheme.random, Some((b"ab".to_vec(), 8)));
+        assert!(scheme.generate().bytes().all(|b| b == b'a' || b == b'b'));
+        assert!(IdScheme::new("aA", Some(8), false).is_err());
+        assert!(IdScheme::new("aA", Some(8), true).is_ok());
+
+        // only the id is folded, never the extension
+        assert_eq!(scheme.parse("AbBa.TXT").map(PasteName::into_string), Some("abba.TXT".to_string()));
+        let sensitive = IdScheme::new("aAbB", Some(8), true).unwrap();
+        assert_eq!(sensitive.parse("AbBa.TXT").map(PasteName::into_string), Some("AbBa.TXT".to_string()));
+    }
+
+    #[test]
+    fn reserved_ids_are_never_generated() {
+        // `p` is reserved, whatever its case
+        for alphabet in ["ap", "aP"] {
+            let scheme = IdScheme::new(alphabet, Some(1), true).unwrap();
+            assert!((0..100).all(|_| scheme.generate() == "a"));
+        }
+    }
+
+    #[test]
+    fn invalid_schemes() {
+        assert!(IdScheme::new("ab", Some(0), true).is_err());
+        assert!(IdScheme::new("ab", Some(MAX_ID_LEN + 1), true).is_err());
+        assert!(IdScheme::new("ab", Some(MAX_ID_LEN), true).is_ok());
+        assert!(IdScheme::new("aaaa", Some(8), true).is_err());
+        assert!(IdScheme::new("", Some(8), true).is_err());
+        for alphabet in ["ab.", "ab/", "ab ", "ab\\", "abé", "ab\0"] {
+            assert!(IdScheme::new(alphabet, Some(8), true).is_err(), "{:?}", alphabet);
+        }
+        // the alphabet doesn't matter for UUIDs
+        assert!(IdScheme::new("", None, true).is_ok());
+    }
+
+    #[test]
+    fn id_bytes() {
+        for b in [b'a', b'z', b'A', b'Z', b'0', b'9', b'-', b'_'] {
+            assert!(valid_id_byte(b), "{:?}", b as char);
+        }
+        // the neighbours of the ranges above, and what paths are made of
+        for b in [b'`', b'{', b'@', b'[', b'/', b':', b'^', b',', b'.', b' ', b'\\', b'%', b'\0', 0x7f, 0x80, 0xff] {
+            assert!(!valid_id_byte(b), "{:?}", b);
+        }
+    }
+
+    #[test]
+    fn parsed_names() {
+        let scheme = IdScheme::default();
+        let longest = "a".repeat(MAX_ID_LEN);
+        assert!(scheme.parse(&longest).is_some());
+        assert!(scheme.parse(&format!("{}a", longest)).is_none());
+        for name in ["", ".txt", "abc.", "a.b.c", "a/b", "../etc", "abc.t-t", "abc.abcdefghijklmnopq"] {
+            assert!(scheme.parse(name).is_none(), "{:?}", name);
+        }
+        assert!(scheme.parse("abc.abcdefghijklmnop").is_some());
+    }
+}
@@ -0,0 +1,155 @@
+use std::sync::Arc;
+
+use axum::{
+    async_trait,
+    body::Body,
+    extract::{FromRequestParts, Host},
+    http::{request::Parts, uri::PathAndQuery, Request, StatusCode, Uri},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use chrono::prelude::*;
+use sqlx::SqlitePool;
+
+use crate::{db::{StoredPaste, TokenInfo}, hooks::Hooks, paste::ServedPaste, versions, AppState};
+
+/// Paths under this prefix are served as if they were requested on the host
+/// of the namespace named right after it, like `/ns/<name>/new`.
+const PREFIX: &str = "/ns/";
+
+/// A tenant with its own tokens and pastes, which the other tenants on the
+/// instance can't see.
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct Namespace {
+    pub name: String,
+    /// Requests for this host belong to the namespace.
+    pub host: Option<String>,
+    /// Where the namespace's pastes are linked from, instead of `BASE_URL`.
+    pub base_url: Option<String>,
+    /// Largest upload any token of the namespace may make, in bytes.
+    pub max_upload_size: Option<i64>,
+    /// Pastes older than this are deleted by the `gc` job.
+    pub retention_seconds: Option<i64>,
+    /// Directory under `pastes` the namespace's pastes are stored in, apart
+    /// from everyone else's; see [`crate::storage::paste_path_in`].
+    pub storage_prefix: Option<String>,
+}
+
+/// Name of the namespace picked by a path prefix, set by [`strip_prefix`].
+#[derive(Debug, Clone)]
+struct PrefixedNamespace(String);
+
+/// The namespace a request was made in, `None` for the default one.
+#[derive(Debug, Clone)]
+pub struct RequestNamespace(pub Option<Namespace>);
+
+impl RequestNamespace {
+    pub fn name(&self) -> Option<&str> {
+        self.0.as_ref().map(|n| n.name.as_str())
+    }
+
+    /// Tokens only work in the namespace they were issued for.
+    pub fn check(&self, user: &TokenInfo) -> Result<(), StatusCode> {
+        if user.namespace.as_deref() == self.name() { Ok(()) } else { Err(StatusCode::FORBIDDEN) }
+    }
+
+    /// Whether the paste stored as `filename` was uploaded in this namespace.
+    pub async fn owns(&self, state: &AppState, filename: &str) -> Result<bool, StatusCode> {
+        let namespace = sqlx::query_scalar::<_, Option<String>>("SELECT namespace FROM pastes WHERE filename = $1")
+        .bind(filename)
+        .fetch_optional(&state.db).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+        Ok(namespace.map_or(false, |n| n.as_deref() == self.name()))
+    }
+}
+
+#[async_trait]
+impl FromRequestParts<Arc<AppState>> for RequestNamespace {
+    type Rejection = StatusCode;
+
+    async fn from_request_parts(parts: &mut Parts, state: &Arc<AppState>) -> Result<Self, Self::Rejection> {
+        if let Some(PrefixedNamespace(name)) = parts.extensions.get::<PrefixedNamespace>() {
+            return sqlx::query_as::<_, Namespace>("SELECT * FROM namespaces WHERE name = $1")
+            .bind(name)
+            .fetch_optional(&state.db).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+            .map(|n| Self(Some(n)))
+            .ok_or(StatusCode::NOT_FOUND);
+        }
+
+        let host = match Host::from_request_parts(parts, state).await {
+            Ok(Host(host)) => host.to_ascii_lowercase(),
+            Err(_) => return Ok(Self(None)),
+        };
+        let namespace = sqlx::query_as::<_, Namespace>("SELECT * FROM namespaces WHERE host = $1")
+        .bind(host)
+        .fetch_optional(&state.db).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+        Ok(Self(namespace))
+    }
+}
+
+/// Name of the namespace the request picked through a path prefix.
+pub fn prefix(parts: &Parts) -> Option<&str> {
+    parts.extensions.get::<PrefixedNamespace>().map(|p| p.0.as_str())
+}
+
+/// Rewrites `/ns/<name>/<path>` to `/<path>`, remembering the namespace, so
+/// every route works under the prefix. This has to run before routing.
+pub async fn strip_prefix<B>(mut req: Request<B>) -> Request<B> {
+    let (name, rest) = match req.uri().path().strip_prefix(PREFIX).and_then(|p| p.split_once('/')) {
+        Some((name, rest)) if !name.is_empty() => (name.to_string(), format!("/{}", rest)),
+        _ => return req,
+    };
+
+    let path = match req.uri().query() {
+        Some(q) => format!("{}?{}", rest, q),
+        None => rest,
+    };
+
+    let mut parts = req.uri().clone().into_parts();
+    parts.path_and_query = path.parse::<PathAndQuery>().ok();
+    if let Ok(uri) = Uri::from_parts(parts) {
+        *req.uri_mut() = uri;
+        req.extensions_mut().insert(PrefixedNamespace(name));
+    }
+    req
+}
+
+/// Hides pastes from other namespaces, as if they didn't exist.
+pub async fn hide_foreign(
+    namespace: RequestNamespace,
+    req: Request<Body>,
+    next: Next<Body>,
+) -> Response {
+    match req.extensions().get::<ServedPaste>() {
+        Some(p) if p.namespace.as_deref() == namespace.name() => next.run(req).await,
+        _ => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+/// Deletes pastes that outlived their namespace's retention, unless they're
+/// held, returning how many were removed.
+pub async fn expire(db: &SqlitePool, hooks: &Hooks) -> anyhow::Result<usize> {
+    let expired = sqlx::query_as::<_, StoredPaste>("DELETE FROM pastes WHERE rowid IN (
+        SELECT pastes.rowid FROM pastes JOIN namespaces ON pastes.namespace = namespaces.name
+        WHERE namespaces.retention_seconds IS NOT NULL AND pastes.timestamp < $1 - namespaces.retention_seconds
+        AND NOT pastes.legal_hold
+    ) RETURNING filename, storage_prefix")
+    .bind(Utc::now().timestamp())
+    .fetch_all(db).await?;
+
+    for paste in &expired {
+        let filename = &paste.filename;
+        sqlx::query("INSERT OR IGNORE INTO expired_pastes (filename, timestamp) VALUES ($1, $2)")
+        .bind(filename)
+        .bind(Utc::now().timestamp())
+        .execute(db).await?;
+
+        if let Err(e) = tokio::fs::remove_file(paste.path()).await {
+            tracing::error!("Couldn't remove expired {}: {}", filename, e);
+        }
+        versions::remove(db, filename).await;
+        hooks.delete(filename).await;
+    }
+    Ok(expired.len())
+}
@@ -0,0 +1,94 @@
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::Semaphore,
+    time::{timeout, Instant},
+};
+
+use crate::{maintenance, paste::store_anonymous, AppState};
+
+/// Connections handled at once; more wait to be accepted.
+const MAX_CONNECTIONS: usize = 64;
+
+/// Accepts termbin-style uploads on `addr`: whatever a client sends until it
+/// closes the connection or goes quiet becomes a paste, and the URL is sent
+/// back, so `cat file | nc host port` works.
+pub async fn serve(state: Arc<AppState>, addr: &str) -> anyhow::Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    let permits = Arc::new(Semaphore::new(MAX_CONNECTIONS));
+    tracing::info!("Accepting netcat uploads on {}...", addr);
+
+    loop {
+        let permit = permits.clone().acquire_owned().await?;
+        let (stream, peer) = match listener.accept().await {
+            Ok(c) => c,
+            Err(e) => {
+                tracing::warn!("Couldn't accept a netcat connection: {}", e);
+                continue;
+            },
+        };
+
+        let state = state.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle(&state, stream, peer).await {
+                tracing::debug!("Netcat upload from {} failed: {}", peer, e);
+            }
+            drop(permit);
+        });
+    }
+}
+
+async fn handle(state: &AppState, mut stream: TcpStream, peer: SocketAddr) -> anyhow::Result<()> {
+    if maintenance::is_read_only(state) {
+        stream.write_all(b"read-only for maintenance, try again later\n").await?;
+        return Ok(());
+    }
+
+    let config = state.config();
+    let data = match receive(&mut stream, config.netcat_max_size, config.netcat_idle_timeout, config.netcat_timeout).await? {
+        Some(d) if !d.is_empty() => d,
+        Some(_) => return Ok(()),
+        None => {
+            stream.write_all(b"upload too large\n").await?;
+            return Ok(());
+        },
+    };
+
+    let reply = match store_anonymous(state, &data, "", peer.ip()).await {
+        Ok(filename) => format!("{}\n", config.url_template.render(&config.fallback_base_url(), &filename)),
+        Err(e) => {
+            tracing::warn!("Couldn't store a netcat upload from {}: {}", peer, e);
+            "upload failed\n".to_string()
+        },
+    };
+    stream.write_all(reply.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+/// Reads until the client closes its side or sends nothing for `idle`, since
+/// plain `nc` often keeps the connection open. `None` if it sent more than
+/// `limit` bytes.
+async fn receive(stream: &mut TcpStream, limit: u64, idle: Duration, total: Duration) -> anyhow::Result<Option<Vec<u8>>> {
+    let deadline = Instant::now() + total;
+    let mut data = Vec::new();
+    let mut buf = [0; 8192];
+
+    loop {
+        let wait = idle.min(deadline.saturating_duration_since(Instant::now()));
+        let n = match timeout(wait, stream.read(&mut buf)).await {
+            Ok(read) => read?,
+            Err(_) => break,
+        };
+        if n == 0 {
+            break;
+        }
+        data.extend_from_slice(&buf[..n]);
+        if data.len() as u64 > limit {
+            return Ok(None);
+        }
+    }
+    Ok(Some(data))
+}
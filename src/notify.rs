@@ -0,0 +1,113 @@
+use std::sync::Arc;
+
+use serde_json::json;
+use tokio::{io::AsyncReadExt, sync::broadcast::error::RecvError};
+
+use crate::{config::Config, storage::paste_path, text::is_text, AppState};
+
+/// Bytes of a text paste quoted in its announcement.
+const PREVIEW_BYTES: u64 = 500;
+
+/// Announces new pastes in the configured Discord channel and Matrix room,
+/// following the audit events so only uploads that went through are posted.
+/// Unlisted pastes and those in namespaces are never announced.
+pub fn start(state: Arc<AppState>) {
+    let mut events = state.events.subscribe();
+    let client = reqwest::Client::new();
+
+    tokio::spawn(async move {
+        loop {
+            let event = match events.recv().await {
+                Ok(e) => e,
+                Err(RecvError::Lagged(n)) => {
+                    tracing::warn!("Notifications fell behind, skipped {} events", n);
+                    continue;
+                },
+                Err(RecvError::Closed) => return,
+            };
+            if event.action != "upload" {
+                continue;
+            }
+
+            let config = state.config();
+            if config.discord_webhook_url.is_none() && config.matrix_room_id.is_none() {
+                continue;
+            }
+            if let Err(e) = announce(&state, &config, &client, &event.target).await {
+                tracing::warn!("Couldn't announce {}: {}", event.target, e);
+            }
+        }
+    });
+}
+
+async fn announce(state: &AppState, config: &Config, client: &reqwest::Client, filename: &str) -> anyhow::Result<()> {
+    let paste = sqlx::query_as::<_, (i64, bool, Option<String>)>("SELECT size, unlisted, namespace FROM pastes WHERE filename = $1")
+    .bind(filename)
+    .fetch_optional(&state.db).await?;
+    let size = match paste {
+        Some((size, false, None)) => size,
+        _ => return Ok(()),
+    };
+
+    let base_url = config.fallback_base_url();
+    let page_url = format!("{}/view/{}", base_url, filename);
+    let raw_url = config.url_template.render(&base_url, filename);
+    let image = mime_guess::from_path(filename).first().map_or(false, |m| m.type_() == "image");
+    let preview = if is_text(filename) { Some(preview(filename).await?) } else { None };
+
+    if let Some(webhook) = &config.discord_webhook_url {
+        let mut embed = json!({
+            "title": filename,
+            "url": page_url,
+            "footer": { "text": format!("{} bytes", size) },
+        });
+        if image {
+            embed["image"] = json!({ "url": raw_url });
+        }
+        if let Some(text) = &preview {
+            embed["description"] = json!(format!("```\n{}\n```", text.replace("```", "`\u{200b}``")));
+        }
+
+        client.post(webhook)
+            .json(&json!({ "content": "New paste uploaded", "embeds": [embed] }))
+            .send().await?.error_for_status()?;
+    }
+
+    if let (Some(homeserver), Some(room), Some(token)) = (&config.matrix_homeserver, &config.matrix_room_id, &config.matrix_access_token) {
+        let mut html = format!("New paste uploaded: <a href=\"{}\">{}</a> ({} bytes)", tera::escape_html(&page_url), tera::escape_html(filename), size);
+        if let Some(text) = &preview {
+            html.push_str(&format!("<pre><code>{}</code></pre>", tera::escape_html(text)));
+        }
+        let txn = uuid::Uuid::new_v4().simple();
+        let url = format!("{}/_matrix/client/v3/rooms/{}/send/m.room.message/{}", homeserver.trim_end_matches('/'), urlencode(room), txn);
+
+        client.put(url)
+            .bearer_auth(token)
+            .json(&json!({
+                "msgtype": "m.text",
+                "body": format!("New paste uploaded: {} ({} bytes)", page_url, size),
+                "format": "org.matrix.custom.html",
+                "formatted_body": html,
+            }))
+            .send().await?.error_for_status()?;
+    }
+    Ok(())
+}
+
+/// The start of the paste stored as `filename`, as text. Only pastes of the
+/// default namespace are announced, which have no storage prefix.
+async fn preview(filename: &str) -> std::io::Result<String> {
+    let mut head = Vec::new();
+    tokio::fs::File::open(paste_path(filename)).await?.take(PREVIEW_BYTES).read_to_end(&mut head).await?;
+    Ok(String::from_utf8_lossy(&head).into_owned())
+}
+
+/// Room ids like `!abc:example.org` have characters that can't go in a path.
+fn urlencode(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
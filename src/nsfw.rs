@@ -0,0 +1,107 @@
+use std::{str::FromStr, time::Duration};
+
+use axum::{async_trait, http::StatusCode};
+use reqwest::header;
+use serde::Deserialize;
+use sqlx::SqlitePool;
+use tokio_util::io::ReaderStream;
+
+use crate::{hooks::{Hook, Upload}, moderation};
+
+/// How long an upload waits for a score before it's let through unscored.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// What to do with images the classifier finds NSFW.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NsfwPolicy {
+    /// Store the paste, and add it to the moderation queue.
+    Flag,
+    Block,
+}
+
+impl FromStr for NsfwPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "flag" => Ok(Self::Flag),
+            "block" => Ok(Self::Block),
+            _ => anyhow::bail!("unknown NSFW policy {:?}, expected flag or block", s),
+        }
+    }
+}
+
+/// What the classifier answers.
+#[derive(Debug, Clone, Deserialize)]
+struct Verdict {
+    /// How likely the image is NSFW, from 0 to 1.
+    score: f32,
+}
+
+/// Sends image uploads to `NSFW_CLASSIFIER_URL`, as the body of a `POST`
+/// with their type, and expects `{"score": <0 to 1>}` back. Anything that
+/// serves that will do, like a small wrapper around an ONNX model. Uploads
+/// are let through when the classifier can't be reached, so an outage
+/// doesn't stop them.
+#[derive(Debug)]
+pub struct Classifier {
+    url: String,
+    policy: NsfwPolicy,
+    threshold: f32,
+    client: reqwest::Client,
+    db: SqlitePool,
+}
+
+impl Classifier {
+    pub fn new(url: &str, policy: NsfwPolicy, threshold: f32, db: SqlitePool) -> Self {
+        let client = reqwest::Client::builder().timeout(TIMEOUT).build().unwrap_or_default();
+        Self { url: url.to_string(), policy, threshold, client, db }
+    }
+
+    async fn score(&self, upload: &Upload, mime: &str) -> anyhow::Result<f32> {
+        let file = tokio::fs::File::open(&upload.path).await?;
+        let verdict = self.client.post(&self.url)
+            .header(header::CONTENT_TYPE, mime)
+            .header(header::CONTENT_LENGTH, upload.size)
+            .body(reqwest::Body::wrap_stream(ReaderStream::new(file)))
+            .send().await?
+            .error_for_status()?
+            .json::<Verdict>().await?;
+        Ok(verdict.score)
+    }
+}
+
+#[async_trait]
+impl Hook for Classifier {
+    fn name(&self) -> &'static str {
+        "nsfw-classifier"
+    }
+
+    async fn on_upload(&self, upload: &Upload) -> Result<(), StatusCode> {
+        let mime = mime_guess::from_path(&upload.filename).first_or_octet_stream();
+        if mime.type_() != mime_guess::mime::IMAGE {
+            return Ok(());
+        }
+
+        let score = match self.score(upload, mime.essence_str()).await {
+            Ok(s) => s,
+            Err(e) => {
+                tracing::warn!("Couldn't classify {}: {}", upload.filename, e);
+                return Ok(());
+            },
+        };
+        if score < self.threshold {
+            return Ok(());
+        }
+
+        tracing::info!("Classifier scored {} as NSFW ({:.2})", upload.filename, score);
+        match self.policy {
+            NsfwPolicy::Block => Err(StatusCode::UNPROCESSABLE_ENTITY),
+            NsfwPolicy::Flag => {
+                moderation::flag(&self.db, &upload.filename, "nsfw", Some(f64::from(score))).await
+                    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+                Ok(())
+            },
+        }
+    }
+}
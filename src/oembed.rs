@@ -0,0 +1,132 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncReadExt;
+
+use crate::{base_url::BaseUrl, name::PasteName, namespace::RequestNamespace, storage, text::is_text, AppState};
+
+/// Lines of a text paste included in its embed.
+const SNIPPET_LINES: usize = 20;
+/// Bytes read from a text paste for its embed.
+const SNIPPET_BYTES: u64 = 4096;
+/// Size of text embeds when the consumer doesn't ask for one.
+const DEFAULT_WIDTH: u32 = 600;
+const DEFAULT_HEIGHT: u32 = 400;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct OEmbedParam {
+    url: String,
+    format: Option<String>,
+    maxwidth: Option<u32>,
+    maxheight: Option<u32>,
+}
+
+/// Response as described in the oEmbed spec, <https://oembed.com>.
+#[derive(Debug, Clone, Serialize)]
+pub struct OEmbed {
+    version: &'static str,
+    #[serde(rename = "type")]
+    kind: &'static str,
+    title: String,
+    provider_name: String,
+    provider_url: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    html: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    width: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    height: Option<u32>,
+}
+
+/// Describes the paste behind a `/view` or `/paste` URL, as a photo for
+/// images, a snippet for text and a plain link for anything else.
+#[axum::debug_handler]
+pub async fn oembed(
+    State(state): State<Arc<AppState>>,
+    BaseUrl(base_url): BaseUrl,
+    namespace: RequestNamespace,
+    Query(query): Query<OEmbedParam>,
+) -> Result<Json<OEmbed>, StatusCode> {
+    if query.format.as_deref().map_or(false, |f| f != "json") {
+        return Err(StatusCode::NOT_IMPLEMENTED);
+    }
+
+    let name = query.url.strip_prefix(base_url.as_str())
+        .and_then(|path| path.strip_prefix("/view/").or_else(|| path.strip_prefix("/paste/")))
+        .and_then(PasteName::parse)
+        .ok_or(StatusCode::NOT_FOUND)?;
+    let filename = name.into_string();
+
+    if !namespace.owns(&state, &filename).await? {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    let title = sqlx::query_scalar::<_, Option<String>>("SELECT title FROM pastes WHERE filename = $1")
+    .bind(&filename)
+    .fetch_optional(&state.db).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    .flatten();
+
+    let path = storage::locate(&state.db, &filename).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let raw_url = state.config().url_template.render(&base_url, &filename);
+    let mut embed = OEmbed {
+        version: "1.0",
+        kind: "link",
+        title: title.unwrap_or_else(|| filename.clone()),
+        provider_name: state.config().site_name.clone(),
+        provider_url: base_url.clone(),
+        url: None,
+        html: None,
+        width: None,
+        height: None,
+    };
+
+    let dimensions = {
+        let path = path.clone();
+        tokio::task::spawn_blocking(move || image::image_dimensions(path).ok()).await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    };
+
+    if let Some((width, height)) = dimensions {
+        let (width, height) = fit(width, height, query.maxwidth, query.maxheight);
+        embed.kind = "photo";
+        embed.url = Some(raw_url);
+        embed.width = Some(width);
+        embed.height = Some(height);
+    } else if is_text(&filename) {
+        let mut head = Vec::new();
+        tokio::fs::File::open(&path).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+            .take(SNIPPET_BYTES).read_to_end(&mut head).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        let text = String::from_utf8_lossy(&head);
+        let snippet: Vec<&str> = text.lines().take(SNIPPET_LINES).collect();
+
+        embed.kind = "rich";
+        embed.html = Some(format!(
+            "<pre>{}</pre><p><a href=\"{}\">{}</a></p>",
+            tera::escape_html(&snippet.join("\n")), tera::escape_html(&raw_url), tera::escape_html(&filename),
+        ));
+        embed.width = Some(query.maxwidth.unwrap_or(DEFAULT_WIDTH).min(DEFAULT_WIDTH));
+        embed.height = Some(query.maxheight.unwrap_or(DEFAULT_HEIGHT).min(DEFAULT_HEIGHT));
+    }
+
+    Ok(Json(embed))
+}
+
+/// Scales `width` by `height` down to fit the consumer's limits, keeping the
+/// aspect ratio.
+fn fit(width: u32, height: u32, max_width: Option<u32>, max_height: Option<u32>) -> (u32, u32) {
+    let scale = [
+        max_width.map(|m| f64::from(m) / f64::from(width.max(1))),
+        max_height.map(|m| f64::from(m) / f64::from(height.max(1))),
+    ]
+    .into_iter()
+    .flatten()
+    .fold(1.0, f64::min);
+
+    ((f64::from(width) * scale) as u32, (f64::from(height) * scale) as u32)
+}
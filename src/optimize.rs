@@ -0,0 +1,137 @@
+use std::{io::Cursor, path::Path, sync::OnceLock};
+
+use image::{
+    codecs::{
+        jpeg::JpegEncoder,
+        png::{CompressionType, FilterType, PngEncoder},
+        webp::WebPEncoder,
+    },
+    imageops, ColorType, DynamicImage, ImageEncoder, ImageFormat,
+};
+use sqlx::SqlitePool;
+use tokio::sync::Semaphore;
+
+use crate::{config::Config, content, ipfs, storage};
+
+/// How to re-encode images, taken from the config so the task doesn't need
+/// the whole state.
+#[derive(Debug, Clone, Copy)]
+struct Settings {
+    max_dimension: u32,
+    quality: u8,
+    png_to_webp: bool,
+}
+
+/// Re-encodes the image stored as `filename` in the background if it's big
+/// enough to be worth it, updating its size once done. Anything that goes
+/// wrong just leaves the original in place.
+pub fn schedule(db: &SqlitePool, config: &Config, filename: &str, size: u64) {
+    if !config.image_optimize || size < config.image_optimize_min_size {
+        return;
+    }
+
+    let db = db.clone();
+    let filename = filename.to_string();
+    let settings = Settings {
+        max_dimension: config.image_max_dimension,
+        quality: config.image_quality,
+        png_to_webp: config.image_png_to_webp,
+    };
+
+    let config = config.clone();
+    tokio::spawn(async move {
+        match optimize(&db, &filename, settings).await {
+            // what was hashed and pinned is the original
+            Ok(true) => {
+                content::address(&db, &config, &filename).await;
+                ipfs::pin(&db, &config, &filename).await;
+            },
+            Ok(false) => {},
+            Err(e) => tracing::warn!("Couldn't optimize {}: {}", filename, e),
+        }
+    });
+}
+
+/// Returns whether the file was replaced.
+async fn optimize(db: &SqlitePool, filename: &str, settings: Settings) -> anyhow::Result<bool> {
+    // decoding large images takes a lot of memory, so only do one at a time
+    static PERMITS: OnceLock<Semaphore> = OnceLock::new();
+    let _permit = PERMITS.get_or_init(|| Semaphore::new(1)).acquire().await?;
+
+    let path = storage::locate(db, filename).await?;
+    let encoded = {
+        let path = path.clone();
+        tokio::task::spawn_blocking(move || reencode(&path, settings)).await??
+    };
+    let (data, mime) = match encoded {
+        Some(e) => e,
+        None => return Ok(false),
+    };
+
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".optimize");
+    tokio::fs::write(&tmp, &data).await?;
+    tokio::fs::rename(&tmp, &path).await?;
+
+    let res = sqlx::query("UPDATE pastes SET size = $1, mime = $2, sha256 = NULL, signature = NULL, signed_by = NULL WHERE filename = $3")
+    .bind(data.len() as i64)
+    .bind(mime)
+    .bind(filename)
+    .execute(db).await?;
+
+    // deleted while we were busy, don't bring it back
+    if res.rows_affected() == 0 {
+        tokio::fs::remove_file(&path).await?;
+        return Ok(false);
+    }
+
+    tracing::info!("Optimized {} down to {} bytes.", filename, data.len());
+    Ok(true)
+}
+
+/// Returns the re-encoded image and its MIME type, if that came out smaller.
+/// This blocks, so call it from `spawn_blocking`.
+fn reencode(path: &Path, settings: Settings) -> anyhow::Result<Option<(Vec<u8>, &'static str)>> {
+    let data = std::fs::read(path)?;
+    let format = match image::guess_format(&data) {
+        Ok(f @ (ImageFormat::Jpeg | ImageFormat::Png | ImageFormat::WebP)) => f,
+        _ => return Ok(None),
+    };
+
+    let mut img = image::load_from_memory_with_format(&data, format)?;
+    let max = settings.max_dimension;
+    if img.width() > max || img.height() > max {
+        img = img.resize(max, max, imageops::FilterType::Lanczos3);
+    }
+
+    let mut out = Cursor::new(Vec::new());
+    let mime = match format {
+        ImageFormat::Jpeg => {
+            let rgb = img.to_rgb8();
+            JpegEncoder::new_with_quality(&mut out, settings.quality)
+                .encode(&rgb, rgb.width(), rgb.height(), ColorType::Rgb8)?;
+            "image/jpeg"
+        },
+        ImageFormat::Png if !settings.png_to_webp => {
+            PngEncoder::new_with_quality(&mut out, CompressionType::Best, FilterType::Adaptive)
+                .write_image(img.as_bytes(), img.width(), img.height(), img.color())?;
+            "image/png"
+        },
+        _ => {
+            encode_webp(&mut out, &img)?;
+            "image/webp"
+        },
+    };
+
+    let out = out.into_inner();
+    Ok((out.len() < data.len()).then_some((out, mime)))
+}
+
+/// Encodes `img` as lossless WebP, the only kind `image` keeps supporting;
+/// it's still kept only when it comes out smaller.
+fn encode_webp(out: &mut Cursor<Vec<u8>>, img: &DynamicImage) -> anyhow::Result<()> {
+    let rgba = img.to_rgba8();
+    WebPEncoder::new_lossless(out)
+        .encode(&rgba, rgba.width(), rgba.height(), ColorType::Rgba8)?;
+    Ok(())
+}
@@ -0,0 +1,262 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::{Html, IntoResponse, Response},
+};
+use chrono::prelude::*;
+use serde::{Deserialize, Serialize};
+use tera::Context;
+
+use crate::{ansi, base_url::BaseUrl, content, encrypted, hexdump, ipfs, json_view, lang, metadata, name::PasteName, namespace::RequestNamespace, pdf, policy::Serving, redirects, session::Session, storage, table, takedown, text::is_text, thumbnail, AppState};
+
+/// Text pastes larger than this are linked instead of shown inline.
+const MAX_INLINE_SIZE: u64 = 1024 * 1024;
+/// Pastes listed per page of `/mine`.
+const MINE_PAGE_LEN: u32 = 50;
+
+pub async fn index(State(state): State<Arc<AppState>>, session: Option<Session>) -> Result<Html<String>, StatusCode> {
+    let mut context = Context::new();
+    if let Some(session) = session {
+        context.insert("csrf", &session.csrf);
+    }
+    state.templates.render("upload.html", context)
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ViewParam {
+    /// Language to show a text paste as, instead of the detected one.
+    lang: Option<String>,
+    /// Render terminal colours, or show the escape sequences as they are;
+    /// decided by whether there are any when unset.
+    ansi: Option<bool>,
+    /// `tree` (the default), `minified` or `raw`, for JSON pastes.
+    json: Option<String>,
+    /// Page of the hex dump of a binary paste, from 0.
+    page: Option<u64>,
+}
+
+#[axum::debug_handler]
+pub async fn view(
+    State(state): State<Arc<AppState>>,
+    session: Option<Session>,
+    BaseUrl(base_url): BaseUrl,
+    namespace: RequestNamespace,
+    Path(filename): Path<String>,
+    Query(query): Query<ViewParam>,
+) -> Result<Response, StatusCode> {
+    let filename = PasteName::parse(&filename).ok_or(StatusCode::NOT_FOUND)?.into_string();
+    if !namespace.owns(&state, &filename).await? {
+        return redirects::to_sibling(&state, &namespace, &filename).await;
+    }
+    if let Err(response) = takedown::check(&state, &filename).await {
+        return Ok(response);
+    }
+
+    let (id, size, owner, sniffed, unlisted, version, sha256, title, recipients, signed, signed_by) = sqlx::query_as::<_, (String, i64, Option<i64>, Option<String>, bool, i64, Option<String>, Option<String>, Option<sqlx::types::Json<Vec<String>>>, bool, Option<String>)>("SELECT id, size, owner, mime, unlisted, version, sha256, title, recipients, signature IS NOT NULL, signed_by FROM pastes WHERE filename = $1")
+    .bind(&filename)
+    .fetch_optional(&state.db).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    .ok_or(StatusCode::NOT_FOUND)?;
+
+    let mime = mime_guess::from_path(&filename).first_or_octet_stream();
+    let kind = if encrypted::is_client_encrypted(&filename) {
+        "e2e"
+    } else if recipients.is_some() {
+        "encrypted"
+    } else if table::is_table(&filename) {
+        "table"
+    } else if pdf::is_pdf(&filename) {
+        "pdf"
+    } else if is_text(&filename) && (size.max(0) as u64) <= MAX_INLINE_SIZE {
+        "text"
+    } else {
+        match mime.type_().as_str() {
+            t @ ("image" | "video" | "audio") => t,
+            _ if !is_text(&filename) => "binary",
+            _ => "other",
+        }
+    };
+
+    let path = storage::locate(&state.db, &filename).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let mut context = Context::new();
+    if let Some(recipients) = &recipients {
+        context.insert("recipients", &recipients.0);
+    }
+    if kind == "text" {
+        let bytes = tokio::fs::read(&path).await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        // one element per line, so each can be linked to
+        let content = String::from_utf8_lossy(&bytes);
+        let json = json_view::parse(&filename, &content);
+        let json_mode = json_view::Mode::parse(query.json.as_deref());
+        context.insert("json", &json.is_some());
+
+        if let (Some(value), json_view::Mode::Tree) = (&json, json_mode) {
+            context.insert("json_tree", &json_view::to_html(value));
+        } else if let (Some(value), json_view::Mode::Minified) = (&json, json_mode) {
+            context.insert("ansi", &false);
+            context.insert("lines", &[value.to_string()]);
+        } else if query.ansi.unwrap_or_else(|| ansi::has_escapes(&content)) {
+            context.insert("ansi", &true);
+            context.insert("lines", &ansi::to_html_lines(&content));
+        } else {
+            context.insert("ansi", &false);
+            context.insert("lines", &content.lines().collect::<Vec<_>>());
+            context.insert("language", &lang::language(&filename, &content, query.lang.as_deref()));
+        }
+    } else if kind == "table" {
+        let parsed = {
+            let filename = filename.clone();
+            tokio::task::spawn_blocking(move || table::preview(&path, &filename)).await
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        };
+        match parsed {
+            Ok(t) => context.insert("table", &t),
+            // not every file named .csv is one
+            Err(e) => tracing::debug!("Couldn't parse {} as a table: {}", filename, e),
+        }
+    } else if kind == "pdf" {
+        // counted in the background after upload, so it may not be there yet
+        let pages = sqlx::query_scalar::<_, Option<i64>>("SELECT pdf_pages FROM pastes WHERE filename = $1")
+        .bind(&filename)
+        .fetch_one(&state.db).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        context.insert("pdf_pages", &pages);
+    } else if kind == "binary" {
+        let pages = (size.max(0) as u64).div_ceil(hexdump::PAGE_LEN).max(1);
+        let page = query.page.unwrap_or(0).min(pages - 1);
+        let rows = hexdump::page(&path, page).await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        context.insert("rows", &rows);
+        context.insert("page", &page);
+        context.insert("pages", &pages);
+    }
+    let cid = ipfs::cid(&state.db, &filename).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    context.insert("ipfs_cid", &cid);
+    context.insert("signed", &signed);
+    context.insert("signed_by", &signed_by);
+    if state.config().content_addressing {
+        context.insert("content_url", &sha256.map(|h| content::url("", &h)));
+    }
+    // `?raw`, or the paste would lead straight back here
+    let raw = match state.config().serving_policy.lookup(&filename) {
+        Some(Serving::Viewer) => "?raw",
+        _ => "",
+    };
+    context.insert("raw_url", &format!("/paste/{}{}", filename, raw));
+    context.insert("filename", &filename);
+    context.insert("title", &title);
+    context.insert("display_name", title.as_deref().unwrap_or(&filename));
+    context.insert("size", &size);
+    context.insert("kind", kind);
+    context.insert("id", &id);
+    context.insert("version", &version);
+    // for link previews, which need absolute URLs
+    let page_url = format!("{}/view/{}", base_url, filename);
+    let oembed_url = reqwest::Url::parse_with_params(&format!("{}/oembed", base_url), [("url", &page_url)])
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    context.insert("page_url", &page_url);
+    context.insert("oembed_url", oembed_url.as_str());
+    context.insert("raw_full_url", &state.config().url_template.render(&base_url, &filename));
+    context.insert("mime", &sniffed.unwrap_or_else(|| mime.essence_str().to_string()));
+
+    let can_delete = session.as_ref().map_or(false, |s| s.user.can_manage(owner));
+    context.insert("can_delete", &can_delete);
+    if let Some(session) = &session {
+        context.insert("csrf", &session.csrf);
+    }
+
+    let page = state.templates.render("view.html", context)?;
+    if unlisted {
+        return Ok(([("x-robots-tag", "noindex")], page).into_response());
+    }
+    Ok(page.into_response())
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct MineParam {
+    /// Page of the list, from 0.
+    #[serde(default)]
+    page: u32,
+}
+
+/// A paste as `/mine` lists it.
+#[derive(Debug, Clone, Serialize)]
+struct Listed {
+    id: String,
+    filename: String,
+    title: Option<String>,
+    size: String,
+    uploaded: String,
+    thumbnail_url: Option<String>,
+    expires_at: Option<i64>,
+    expires_in: Option<String>,
+}
+
+/// Lists the pastes of whoever is logged in, with buttons to delete them.
+#[axum::debug_handler]
+pub async fn mine(
+    State(state): State<Arc<AppState>>,
+    session: Session,
+    BaseUrl(base_url): BaseUrl,
+    namespace: RequestNamespace,
+    Query(query): Query<MineParam>,
+) -> Result<Html<String>, StatusCode> {
+    namespace.check(&session.user)?;
+    let owner = session.user.id.ok_or(StatusCode::FORBIDDEN)?;
+    // one more than shown, to know whether there's a next page
+    let mut pastes = metadata::list(&state, &base_url, Some(owner), namespace.name(), None, MINE_PAGE_LEN + 1, query.page.saturating_mul(MINE_PAGE_LEN)).await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let more = pastes.len() > MINE_PAGE_LEN as usize;
+    pastes.truncate(MINE_PAGE_LEN as usize);
+
+    let now = Utc::now().timestamp();
+    let listed: Vec<Listed> = pastes.into_iter().map(|p| Listed {
+        thumbnail_url: thumbnail::url(&base_url, &p.filename),
+        uploaded: Utc.timestamp_opt(p.timestamp, 0).single().map(|t| t.format("%Y-%m-%d %H:%M").to_string()).unwrap_or_default(),
+        size: human_size(p.size.max(0) as u64),
+        expires_in: p.expires_at.map(|t| human_duration(t - now)),
+        expires_at: p.expires_at,
+        id: p.id,
+        filename: p.filename,
+        title: p.title,
+    }).collect();
+
+    let mut context = Context::new();
+    context.insert("pastes", &listed);
+    context.insert("page", &query.page);
+    context.insert("more", &more);
+    context.insert("csrf", &session.csrf);
+    state.templates.render("mine.html", context)
+}
+
+fn human_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["bytes", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 { format!("{} bytes", bytes) } else { format!("{:.1} {}", size, UNITS[unit]) }
+}
+
+/// `seconds` from now in days, hours and minutes, the way `mine.js` keeps
+/// counting it down.
+fn human_duration(seconds: i64) -> String {
+    if seconds <= 0 {
+        return "any moment now".to_string();
+    }
+    let (days, hours, minutes) = (seconds / 86400, seconds % 86400 / 3600, seconds % 3600 / 60);
+    if days > 0 {
+        format!("in {}d {}h", days, hours)
+    } else if hours > 0 {
+        format!("in {}h {}m", hours, minutes)
+    } else {
+        format!("in {}m", minutes.max(1))
+    }
+}
+
+pub async fn not_found(State(state): State<Arc<AppState>>) -> Response {
+    state.templates.error(StatusCode::NOT_FOUND).into_response()
+}
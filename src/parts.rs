@@ -0,0 +1,310 @@
+use std::{
+    net::SocketAddr,
+    path::{Path as FsPath, PathBuf},
+    sync::Arc,
+};
+
+use axum::{
+    body::Body,
+    extract::{ConnectInfo, Path, Query, State},
+    http::{header, Request, StatusCode},
+    Extension, Json,
+};
+use chrono::prelude::*;
+use futures::TryStreamExt;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, BufWriter};
+use tokio_util::io::StreamReader;
+
+use crate::{
+    auth::authenticate_client,
+    base_url::BaseUrl,
+    db::TokenInfo,
+    name,
+    namespace::RequestNamespace,
+    paste::{record_upload, Inspector, UploadResult},
+    staging,
+    storage,
+    tls::ClientCert,
+    AppState,
+};
+
+/// Most parts an upload can have, as with S3.
+const MAX_PARTS: u32 = 10000;
+/// Most uploads a token can have started and not yet completed or aborted.
+const MAX_OPEN_UPLOADS: i64 = 16;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct InitiateParam {
+    token: Option<String>,
+    /// What the file is called, which its extension is taken from.
+    name: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct PartParam {
+    token: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Initiated {
+    pub upload_id: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Part {
+    pub part: u32,
+    pub size: u64,
+}
+
+/// Handles `POST /api/uploads`, which starts an upload sent in parts. Parts
+/// are then put to `/api/uploads/<id>/<n>`, numbered from 1 and in any
+/// order or at once, and `POST /api/uploads/<id>/complete` puts them
+/// together into a paste.
+#[axum::debug_handler]
+pub async fn initiate(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    cert: Option<Extension<ClientCert>>,
+    namespace: RequestNamespace,
+    Query(query): Query<InitiateParam>,
+) -> Result<Json<Initiated>, StatusCode> {
+    let user = authenticate_client(&state, addr.ip(), cert.as_deref(), query.token.as_deref()).await?;
+    namespace.check(&user)?;
+    // uploads are told apart by who started them
+    let owner = user.id.ok_or(StatusCode::FORBIDDEN)?;
+
+    let id = uuid::Uuid::new_v4().to_string();
+    let started = sqlx::query("INSERT INTO multipart_uploads (id, owner, name, created_at) SELECT $1, $2, $3, $4
+    WHERE (SELECT COUNT(*) FROM multipart_uploads WHERE owner = $2) < $5")
+    .bind(&id)
+    .bind(owner)
+    .bind(&query.name)
+    .bind(Utc::now().timestamp())
+    .bind(MAX_OPEN_UPLOADS)
+    .execute(&state.db).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    .rows_affected();
+    if started == 0 {
+        return Err(StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    tokio::fs::create_dir_all(upload_dir(&id)).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(Json(Initiated { upload_id: id }))
+}
+
+/// Handles `PUT /api/uploads/<id>/<n>`, storing part `n`, or replacing it
+/// when it's sent again. Parts need a `Content-Length`, which is counted
+/// against the upload limit before the part is written, so parts sent at
+/// once can't go over it together.
+#[axum::debug_handler]
+pub async fn put_part(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    cert: Option<Extension<ClientCert>>,
+    Path((id, part)): Path<(String, u32)>,
+    Query(query): Query<PartParam>,
+    req: Request<Body>,
+) -> Result<Json<Part>, StatusCode> {
+    let user = authenticate_client(&state, addr.ip(), cert.as_deref(), query.token.as_deref()).await?;
+    owned(&state, &user, &id).await?;
+    if part == 0 || part > MAX_PARTS {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let size = req.headers().get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok())
+        .ok_or(StatusCode::LENGTH_REQUIRED)?;
+    let limit = user.max_upload_size.map(|l| l.max(0) as u64).unwrap_or(u64::MAX);
+    if size > limit {
+        return Err(StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    // the other parts count towards the upload limit too, including the
+    // ones still being written
+    let reserved = sqlx::query("INSERT INTO multipart_parts (upload, part, size) SELECT $1, $2, $3
+    WHERE (SELECT COALESCE(SUM(size), 0) FROM multipart_parts WHERE upload = $1 AND part != $2) + $3 <= $4
+    ON CONFLICT (upload, part) DO UPDATE SET size = excluded.size")
+    .bind(&id)
+    .bind(part)
+    .bind(size as i64)
+    .bind(limit.min(i64::MAX as u64) as i64)
+    .execute(&state.db).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    .rows_affected();
+    if reserved == 0 {
+        return Err(StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    // written aside first, so a part cut short is never put together
+    let path = part_path(&id, part);
+    let partial = path.with_extension("partial");
+    let res = match write(&partial, req.into_body(), size).await {
+        Ok(s) if s == size => tokio::fs::rename(&partial, &path).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR),
+        Ok(_) => Err(StatusCode::BAD_REQUEST),
+        Err(_) => Err(StatusCode::INTERNAL_SERVER_ERROR),
+    };
+    if let Err(status) = res {
+        // the reservation replaced the old part's, so the old part goes too
+        let _ = tokio::fs::remove_file(&partial).await;
+        let _ = tokio::fs::remove_file(&path).await;
+        let _ = sqlx::query("DELETE FROM multipart_parts WHERE upload = $1 AND part = $2")
+        .bind(&id)
+        .bind(part)
+        .execute(&state.db).await;
+        return Err(status);
+    }
+
+    Ok(Json(Part { part, size }))
+}
+
+/// Handles `POST /api/uploads/<id>/complete`, which puts the parts together
+/// in order into a paste and returns its URL. They have to be numbered from
+/// 1 without gaps.
+#[axum::debug_handler]
+pub async fn complete(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    cert: Option<Extension<ClientCert>>,
+    BaseUrl(base_url): BaseUrl,
+    Path(id): Path<String>,
+    Query(query): Query<PartParam>,
+) -> Result<String, StatusCode> {
+    let user = authenticate_client(&state, addr.ip(), cert.as_deref(), query.token.as_deref()).await?;
+    let upload_name = owned(&state, &user, &id).await?;
+
+    let parts = parts(&id).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if parts.is_empty() || parts.iter().enumerate().any(|(i, (n, _))| *n as usize != i + 1) {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    let size: u64 = parts.iter().map(|(_, s)| *s).sum();
+    if user.max_upload_size.map_or(false, |l| size > l.max(0) as u64) {
+        return Err(StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    // claimed before putting it together, so a second request can't too
+    let claimed = sqlx::query("DELETE FROM multipart_uploads WHERE id = $1")
+    .bind(&id)
+    .execute(&state.db).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    .rows_affected();
+    if claimed == 0 {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    sqlx::query("DELETE FROM multipart_parts WHERE upload = $1")
+    .bind(&id)
+    .execute(&state.db).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let name = name::unused(&state.db, &upload_name).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let paste = uuid::Uuid::new_v4();
+    let staged = staging::upload(&paste);
+    let res = assemble(&staged, &id, &parts, state.config().durability).await;
+    let _ = tokio::fs::remove_dir_all(upload_dir(&id)).await;
+    let written = match res {
+        Ok(w) => w,
+        Err(e) => {
+            tracing::error!("Couldn't put upload {} together: {}", id, e);
+            let _ = tokio::fs::remove_file(staging::path(&staged)).await;
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        },
+    };
+
+    let filename = record_upload(&state, Some(&user), addr.ip(), paste, name, written).await?;
+    tracing::info!("Put {} parts of upload {} together as {}", parts.len(), id, filename);
+    Ok(state.config().url_template.render(user.base_url.as_deref().unwrap_or(&base_url), &filename))
+}
+
+/// Handles `DELETE /api/uploads/<id>`, dropping the upload and its parts.
+#[axum::debug_handler]
+pub async fn abort(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    cert: Option<Extension<ClientCert>>,
+    Path(id): Path<String>,
+    Query(query): Query<PartParam>,
+) -> Result<StatusCode, StatusCode> {
+    let user = authenticate_client(&state, addr.ip(), cert.as_deref(), query.token.as_deref()).await?;
+    owned(&state, &user, &id).await?;
+
+    sqlx::query("DELETE FROM multipart_uploads WHERE id = $1")
+    .bind(&id)
+    .execute(&state.db).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    sqlx::query("DELETE FROM multipart_parts WHERE upload = $1")
+    .bind(&id)
+    .execute(&state.db).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let _ = tokio::fs::remove_dir_all(upload_dir(&id)).await;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// The name upload `id` was started with, if `user` started it. Users
+/// without an id can't have started any.
+async fn owned(state: &AppState, user: &TokenInfo, id: &str) -> Result<String, StatusCode> {
+    if user.id.is_none() {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    let (owner, name) = sqlx::query_as::<_, (Option<i64>, String)>("SELECT owner, name FROM multipart_uploads WHERE id = $1")
+    .bind(id)
+    .fetch_optional(&state.db).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    .ok_or(StatusCode::NOT_FOUND)?;
+    if owner != user.id {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    Ok(name)
+}
+
+/// Where the parts of upload `id` wait until it's completed.
+pub fn upload_dir(id: &str) -> PathBuf {
+    staging::path(id)
+}
+
+fn part_path(id: &str, part: u32) -> PathBuf {
+    upload_dir(id).join(format!("{:05}.part", part))
+}
+
+/// The parts of upload `id` received so far and their sizes, in order.
+async fn parts(id: &str) -> std::io::Result<Vec<(u32, u64)>> {
+    let mut parts = Vec::new();
+    let mut entries = tokio::fs::read_dir(upload_dir(id)).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let name = entry.file_name();
+        let number = name.to_str()
+            .and_then(|n| n.strip_suffix(".part"))
+            .and_then(|n| n.parse::<u32>().ok());
+        if let Some(number) = number {
+            parts.push((number, entry.metadata().await?.len()));
+        }
+    }
+    parts.sort_unstable();
+    Ok(parts)
+}
+
+/// Writes `body` to `path`, stopping a byte past `limit` so going over it
+/// can be told apart.
+async fn write(path: &FsPath, body: Body, limit: u64) -> anyhow::Result<u64> {
+    let reader = StreamReader::new(body.map_err(std::io::Error::other)).take(limit.saturating_add(1));
+    futures::pin_mut!(reader);
+    let mut file = BufWriter::new(tokio::fs::File::create(path).await?);
+    let written = tokio::io::copy(&mut reader, &mut file).await?;
+    file.flush().await?;
+    Ok(written)
+}
+
+/// Concatenates `parts` of upload `id` into the staged file `staged`,
+/// hashing and sniffing it on the way.
+async fn assemble(staged: &str, id: &str, parts: &[(u32, u64)], durability: storage::Durability) -> std::io::Result<UploadResult> {
+    let mut file = BufWriter::new(staging::create(staged).await?);
+    let mut inspector = Inspector::default();
+    let mut buf = vec![0; storage::READ_CHUNK_SIZE];
+    for (part, _) in parts {
+        let mut source = tokio::fs::File::open(part_path(id, *part)).await?;
+        loop {
+            let n = source.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            inspector.update(&buf[..n]);
+            file.write_all(&buf[..n]).await?;
+        }
+    }
+    file.flush().await?;
+    staging::sync(file.get_ref(), durability).await?;
+    Ok(inspector.finish())
+}
@@ -0,0 +1,1302 @@
+use std::{net::{IpAddr, SocketAddr}, path::{Path as FsPath, PathBuf}, str::FromStr, sync::Arc};
+
+use axum::{
+    extract::{ConnectInfo, Multipart, Path, Query, State},
+    http::{header, uri::PathAndQuery, HeaderMap, HeaderValue, Method, Request, StatusCode, Uri},
+    body::{Body, Bytes},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use chrono::prelude::*;
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use futures::{Stream, StreamExt, TryStreamExt};
+use std::io;
+use tokio::io::{AsyncReadExt, AsyncWrite, AsyncWriteExt, BufWriter};
+use tokio_util::io::StreamReader;
+use tower::ServiceExt;
+
+use crate::{access, archive, audit, bandwidth, base_url::{BaseUrl, UrlTemplate}, content, auth::authenticate_client, encrypted, db::{FileNameWrapper, PasteInfo, StoredPaste, TokenInfo}, exif, hooks::Upload, ipfs, metadata, moderation, name::{self, PasteName}, namespace::RequestNamespace, optimize, pdf, progress::Tracker, reputation, secrets, session::hex, sniff::{self, SNIFF_LEN}, staging, storage::{self, paste_path_in, Durability}, thumbnail, tls::ClientCert, versions, AppState};
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct TokenParam {
+    token: Option<String>
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct IdTokenParam {
+    token: Option<String>,
+    id: String
+}
+
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct NewPasteParam {
+    token: Option<String>,
+    /// Extract a zip upload into one paste per file.
+    #[serde(default)]
+    expand: bool,
+    /// Store the paste even though it looks like it contains credentials.
+    #[serde(default)]
+    force: bool,
+    /// Leave EXIF and similar metadata in images.
+    #[serde(default)]
+    keep_metadata: bool,
+    /// Ask search engines not to index the paste.
+    #[serde(default)]
+    unlisted: bool,
+    /// Refuse any later change to the paste, so it can be cached forever.
+    #[serde(default)]
+    immutable: bool,
+    /// A JSON object to attach to the paste, like `{"build":"1234"}`.
+    metadata: Option<String>,
+    /// A human-readable title for the paste.
+    title: Option<String>,
+    /// Comma-separated age recipients to encrypt the paste to before it's
+    /// stored.
+    encrypt_to: Option<String>,
+}
+
+/// How an upload should be handled, beyond who made it.
+#[derive(Debug, Clone, Default)]
+pub struct UploadOptions {
+    pub expand: bool,
+    pub force: bool,
+    pub keep_metadata: bool,
+    pub unlisted: bool,
+    pub immutable: bool,
+    /// Already checked with [`metadata::parse`].
+    pub metadata: Option<String>,
+    /// Already cleaned up with [`metadata::title`].
+    pub title: Option<String>,
+    /// Already checked with [`encrypted::parse_recipients`].
+    pub encrypt_to: Option<Vec<String>>,
+    /// Where to report the bytes received, for a page showing progress.
+    pub progress: Option<Tracker>,
+}
+
+/// How much multipart framing a declared upload length may include on top of
+/// the file itself.
+const MULTIPART_OVERHEAD: u64 = 64 * 1024;
+
+/// What happens when a token uploads exactly what one of its pastes holds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Duplicates {
+    /// Store it as a new paste anyway.
+    Keep,
+    /// Hand back the paste it already has.
+    Reuse,
+}
+
+impl FromStr for Duplicates {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "keep" => Ok(Self::Keep),
+            "reuse" => Ok(Self::Reuse),
+            _ => anyhow::bail!("unknown duplicate upload mode {:?}, expected keep or reuse", s),
+        }
+    }
+}
+
+#[axum::debug_handler]
+pub async fn new_paste(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    cert: Option<Extension<ClientCert>>,
+    BaseUrl(base_url): BaseUrl,
+    namespace: RequestNamespace,
+    Query(query): Query<NewPasteParam>,
+    headers: HeaderMap,
+    mut multipart: Multipart,
+) -> Result<Response, StatusCode> {
+    let user = authenticate_client(&state, addr.ip(), cert.as_deref(), query.token.as_deref()).await?;
+    namespace.check(&user)?;
+
+    let options = UploadOptions {
+        expand: query.expand,
+        force: query.force,
+        keep_metadata: query.keep_metadata,
+        unlisted: query.unlisted,
+        immutable: query.immutable,
+        metadata: query.metadata.as_deref().map(|m| metadata::parse(m, state.config().metadata_max_size)).transpose()?,
+        title: query.title.as_deref().map(metadata::title).transpose()?.flatten(),
+        encrypt_to: query.encrypt_to.as_deref().map(encrypted::parse_recipients).transpose()?,
+        ..Default::default()
+    };
+    let (created, warnings) = store_upload(&state, &user, addr, &headers, options, &mut multipart).await?;
+    let base_url = user.base_url.clone().unwrap_or(base_url);
+    let url = created.url(&state.config().url_template, &base_url);
+    tracing::info!("{}", url);
+
+    let mut uploaded = Uploaded { url, duplicate: matches!(created, Created::Duplicate(_)), ..Default::default() };
+    match &created {
+        Created::Paste(filename) | Created::Duplicate(filename) => {
+            let hash = content::address(&state.db, &state.config(), filename).await;
+            uploaded.content_url = hash.as_deref().map(|h| content::url(&base_url, h));
+            // adding it to IPFS can take as long as the upload did, so only
+            // a CID it already has is reported
+            if matches!(created, Created::Paste(_)) {
+                ipfs::schedule(&state.db, &state.config(), filename);
+            }
+            uploaded.ipfs_cid = ipfs::cid(&state.db, filename).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+            uploaded.sha256 = hash;
+            uploaded.filename = Some(filename.clone());
+        },
+        Created::Collection(id) => uploaded.id = id.to_string(),
+    }
+
+    let mut response = if wants_json(&headers) {
+        if let Created::Paste(filename) | Created::Duplicate(filename) = &created {
+            uploaded.describe(&state, &base_url, filename).await?;
+        }
+        Json(&uploaded).into_response()
+    } else {
+        uploaded.url.clone().into_response()
+    };
+
+    if !warnings.is_empty() {
+        if let Ok(value) = HeaderValue::from_str(&warnings.join(", ")) {
+            response.headers_mut().insert("x-secret-warning", value);
+        }
+    }
+    if let Some(value) = uploaded.content_url.and_then(|u| HeaderValue::from_str(&u).ok()) {
+        response.headers_mut().insert("x-content-url", value);
+    }
+    if let Some(value) = uploaded.ipfs_cid.and_then(|c| HeaderValue::from_str(&c).ok()) {
+        response.headers_mut().insert("x-ipfs-cid", value);
+    }
+    if uploaded.duplicate {
+        response.headers_mut().insert("x-duplicate", HeaderValue::from_static("true"));
+    }
+    Ok(response)
+}
+
+/// Whether the client asked for a structured upload response rather than
+/// just the URL.
+fn wants_json(headers: &HeaderMap) -> bool {
+    headers.get(header::ACCEPT)
+        .and_then(|v| v.to_str().ok())
+        .map_or(false, |v| v.split(',').any(|t| t.trim().starts_with("application/json")))
+}
+
+/// Everything a client might want to link to after an upload, sent as JSON
+/// to those that ask for it.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Uploaded {
+    /// Id of the paste, or of the collection a zip was expanded into.
+    pub id: String,
+    pub filename: Option<String>,
+    /// The raw paste, or the collection's listing.
+    pub url: String,
+    pub view_url: Option<String>,
+    pub thumbnail_url: Option<String>,
+    /// Where to send `DELETE` with the uploader's token.
+    pub delete_url: Option<String>,
+    pub sha256: Option<String>,
+    pub content_url: Option<String>,
+    pub ipfs_cid: Option<String>,
+    /// Whether the token already had a paste with these contents, which is
+    /// what's returned instead of a new one.
+    pub duplicate: bool,
+}
+
+impl Uploaded {
+    /// Fills in the links derived from the paste stored as `filename`.
+    async fn describe(&mut self, state: &AppState, base_url: &str, filename: &str) -> Result<(), StatusCode> {
+        let (id, sha256, prefix) = sqlx::query_as::<_, (String, Option<String>, Option<String>)>("SELECT id, sha256, storage_prefix FROM pastes WHERE filename = $1")
+        .bind(filename)
+        .fetch_one(&state.db).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        self.id = id;
+
+        // usually recorded as it was uploaded
+        self.sha256 = self.sha256.take().or(sha256);
+        if self.sha256.is_none() {
+            self.sha256 = content::hash_file(paste_path_in(prefix.as_deref(), filename)).await.ok();
+        }
+        self.view_url = Some(format!("{}/view/{}", base_url, filename));
+        self.thumbnail_url = thumbnail::url(base_url, filename);
+        self.delete_url = Some(format!("{}/delete?id={}", base_url, self.id));
+        Ok(())
+    }
+}
+
+/// What an upload was stored as.
+#[derive(Debug, Clone)]
+pub enum Created {
+    Paste(String),
+    /// A paste of the same token with the same contents, see
+    /// `DUPLICATE_UPLOADS`.
+    Duplicate(String),
+    /// The contents of a zip, expanded into one paste per file.
+    Collection(uuid::Uuid),
+}
+
+impl Created {
+    pub fn url(&self, template: &UrlTemplate, base_url: &str) -> String {
+        match self {
+            Self::Paste(filename) | Self::Duplicate(filename) => template.render(base_url, filename),
+            Self::Collection(id) => format!("{}/collection/{}", base_url, id),
+        }
+    }
+}
+
+/// Stores the file in the next field of `multipart` as a paste owned by `user`,
+/// along with the kinds of credentials it seemed to contain.
+pub async fn store_upload(
+    state: &AppState,
+    user: &TokenInfo,
+    addr: SocketAddr,
+    headers: &HeaderMap,
+    options: UploadOptions,
+    multipart: &mut Multipart,
+) -> Result<(Created, Vec<&'static str>), StatusCode> {
+    // nothing of the body has been read yet, so a client waiting on
+    // `Expect: 100-continue` gets this without sending any of it
+    let limit = user.max_upload_size.map(|l| l.max(0) as u64).unwrap_or(u64::MAX);
+    if declared_length(headers).map_or(false, |l| l > limit.saturating_add(MULTIPART_OVERHEAD)) {
+        return Err(StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    let field = match multipart.next_field().await {
+        Ok(Some(f)) => f,
+        _ => return Err(StatusCode::BAD_REQUEST)
+    };
+    let upload_name = field.file_name().ok_or(StatusCode::BAD_REQUEST)?.to_string();
+    store_stream(state, user, addr, options, &upload_name, field).await
+}
+
+/// Stores `stream`, uploaded as `upload_name`, as a paste owned by `user`,
+/// along with the kinds of credentials it seemed to contain. Every API taking
+/// uploads from a token goes through here, so they're all checked the same.
+pub async fn store_stream<S, E>(
+    state: &AppState,
+    user: &TokenInfo,
+    addr: SocketAddr,
+    options: UploadOptions,
+    upload_name: &str,
+    stream: S,
+) -> Result<(Created, Vec<&'static str>), StatusCode>
+where
+    S: Stream<Item = Result<Bytes, E>>,
+    E: Into<anyhow::Error>,
+{
+    let limit = user.max_upload_size.map(|l| l.max(0) as u64).unwrap_or(u64::MAX);
+    let id = uuid::Uuid::new_v4();
+    let mut name = name::unused(&state.db, upload_name).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let progress = options.progress.clone();
+    let stream = stream.inspect_ok(move |chunk| {
+        if let Some(tracker) = &progress {
+            tracker.add(chunk.len());
+        }
+    });
+    let staged = staging::upload(&id);
+    let path = staging::path(&staged);
+    let written = match stream_to_staging(&staged, stream, limit, state.config().durability).await {
+        Ok(w) if w.size <= limit => w,
+        res => {
+            let _ = tokio::fs::remove_file(&path).await;
+            return Err(if res.is_ok() { StatusCode::PAYLOAD_TOO_LARGE } else { StatusCode::INTERNAL_SERVER_ERROR });
+        },
+    };
+
+    let mut mime = None;
+    if !name.has_extension() {
+        if let Some((renamed, sniffed)) = sniff::add_extension(&name, written.sniffed) {
+            name = renamed;
+            mime = Some(sniffed.to_string());
+        }
+    }
+    let mut filename = name.into_string();
+    let (mut size, mut sha256) = (written.size, Some(written.sha256));
+
+    tracing::info!("Created a {} byte file.", size);
+
+    // each file of a zip would have to be encrypted on its own
+    if options.expand && options.encrypt_to.is_some() {
+        tokio::fs::remove_file(&path).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    if options.expand {
+        return expand_upload(state, user, addr, &path, options).await.map(|c| (Created::Collection(c), Vec::new()));
+    }
+
+    if state.config().strip_metadata && !options.keep_metadata {
+        if let Some(stripped) = exif::strip(&path).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)? {
+            size = stripped;
+            sha256 = None;
+        }
+    }
+
+    // encrypting makes every upload different
+    if state.config().duplicate_uploads == Duplicates::Reuse && options.encrypt_to.is_none() {
+        if sha256.is_none() {
+            sha256 = Some(content::hash_file(path.clone()).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?);
+        }
+        if let Some(existing) = duplicate_of(state, user, sha256.as_deref().unwrap_or_default(), &options).await? {
+            tokio::fs::remove_file(&path).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+            tracing::info!("Upload is a duplicate of {}, not storing it again", existing);
+            return Ok((Created::Duplicate(existing), Vec::new()));
+        }
+    }
+    let warnings = match secrets::screen(state.config().secret_scan, options.force, &filename, &path).await {
+        Ok(w) => w,
+        Err(status) => {
+            tokio::fs::remove_file(&path).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+            return Err(status);
+        }
+    };
+
+    // after the checks above, which need the plaintext
+    let path = match &options.encrypt_to {
+        Some(recipients) => {
+            let sealed_path = staging::path(&format!("{}.age", staged));
+            let res = encrypted::encrypt(&filename, &path, &sealed_path, recipients).await;
+            let _ = tokio::fs::remove_file(&path).await;
+            let (sealed, sealed_size) = match res {
+                Ok(e) => e,
+                Err(e) => {
+                    tracing::error!("Couldn't encrypt {}: {}", filename, e);
+                    return Err(StatusCode::INTERNAL_SERVER_ERROR);
+                }
+            };
+            filename = sealed;
+            size = sealed_size;
+            sha256 = None;
+            mime = None;
+            sealed_path
+        },
+        None => path,
+    };
+    let recipients = encrypted::inspect(&path).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let upload = Upload { filename: filename.clone(), path, size, owner: user.id, ip: addr.ip() };
+    if let Err(status) = state.hooks.upload(&upload).await {
+        tokio::fs::remove_file(&upload.path).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        return Err(status);
+    }
+
+    let utc: DateTime<Utc> = Utc::now();
+
+    let mut info = PasteInfo {
+        id,
+        size,
+        filename,
+        timestamp: utc.timestamp(),
+        owner: user.id,
+        collection: None,
+        mime,
+        unlisted: options.unlisted,
+        namespace: user.namespace.clone(),
+        immutable: options.immutable,
+        metadata: options.metadata.clone(),
+        title: options.title.clone(),
+        recipients: recipients.map(sqlx::types::Json),
+        sha256,
+    };
+
+    publish(state, &mut info, &upload.path).await?;
+
+    audit::record(state, user.id, addr.ip(), "upload", &info.filename).await;
+
+    // re-encoding drops metadata too, and changes what an immutable paste
+    // promised to stay
+    if !options.keep_metadata && !options.immutable {
+        optimize::schedule(&state.db, &state.config(), &info.filename, info.size);
+    }
+    pdf::schedule(&state.db, &info.filename);
+
+    Ok((Created::Paste(info.filename), warnings))
+}
+
+/// The newest paste of `user` whose contents hash to `sha256`, if it has
+/// one that was uploaded with the same `options`, so handing it out instead
+/// doesn't change whether it's listed, can change, or what it's titled.
+/// Collections' pastes don't count, since they aren't uploaded alone, and
+/// neither do taken down ones, which would serve nothing.
+async fn duplicate_of(state: &AppState, user: &TokenInfo, sha256: &str, options: &UploadOptions) -> Result<Option<String>, StatusCode> {
+    let owner = match user.id {
+        Some(o) => o,
+        None => return Ok(None),
+    };
+    sqlx::query_scalar::<_, String>("SELECT filename FROM pastes
+        WHERE owner = $1 AND sha256 = $2 AND namespace IS $3 AND collection IS NULL
+        AND unlisted = $4 AND immutable = $5 AND metadata IS $6 AND title IS $7
+        AND filename NOT IN (SELECT filename FROM takedowns WHERE lifted_at IS NULL)
+        ORDER BY timestamp DESC LIMIT 1")
+    .bind(owner)
+    .bind(sha256)
+    .bind(&user.namespace)
+    .bind(options.unlisted)
+    .bind(options.immutable)
+    .bind(&options.metadata)
+    .bind(&options.title)
+    .fetch_optional(&state.db).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+/// Stores `data`, received as `upload_name` without any credentials, as a
+/// paste, going through the same checks as any other upload, and returns its
+/// name. For gateways like the netcat listener.
+pub async fn store_anonymous(state: &AppState, data: &[u8], upload_name: &str, ip: IpAddr) -> anyhow::Result<String> {
+    let id = uuid::Uuid::new_v4();
+    let name = name::unused(&state.db, upload_name).await?;
+
+    let mut file = staging::create(&staging::upload(&id)).await?;
+    file.write_all(data).await?;
+    staging::sync(&file, state.config().durability).await?;
+
+    record_upload(state, None, ip, id, name, UploadResult::of(data)).await
+        .map_err(|status| anyhow::anyhow!("upload refused: {}", status))
+}
+
+/// Finishes an upload for paste `id` already `written` to staging as
+/// [`staging::upload`] names it: names it after its contents if needed, runs
+/// the usual checks and hooks, records it as a paste owned by `user` and
+/// moves it into place. The file is removed if it's refused. Returns the name
+/// it's stored as.
+pub async fn record_upload(state: &AppState, user: Option<&TokenInfo>, ip: IpAddr, id: uuid::Uuid, mut name: PasteName, written: UploadResult) -> Result<String, StatusCode> {
+    let config = state.config();
+    let path = staging::path(&staging::upload(&id));
+    let listed = match user {
+        Some(_) => None,
+        None => match reputation::screen(state, ip).await {
+            Ok(l) => l,
+            Err(status) => {
+                let _ = tokio::fs::remove_file(&path).await;
+                return Err(status);
+            },
+        },
+    };
+    let mut mime = None;
+    if !name.has_extension() {
+        if let Some((renamed, sniffed)) = sniff::add_extension(&name, written.sniffed) {
+            name = renamed;
+            mime = Some(sniffed.to_string());
+        }
+    }
+    let filename = name.into_string();
+    let (mut size, mut sha256) = (written.size, Some(written.sha256));
+
+    if config.strip_metadata {
+        match exif::strip(&path).await {
+            Ok(Some(stripped)) => {
+                size = stripped;
+                sha256 = None;
+            },
+            Ok(None) => {},
+            Err(_) => {
+                let _ = tokio::fs::remove_file(&path).await;
+                return Err(StatusCode::INTERNAL_SERVER_ERROR);
+            },
+        }
+    }
+    if let Err(status) = secrets::screen(config.secret_scan, false, &filename, &path).await {
+        let _ = tokio::fs::remove_file(&path).await;
+        return Err(status);
+    }
+
+    let recipients = match encrypted::inspect(&path).await {
+        Ok(r) => r,
+        Err(_) => {
+            let _ = tokio::fs::remove_file(&path).await;
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        },
+    };
+
+    let owner = user.and_then(|u| u.id);
+    let upload = Upload { filename: filename.clone(), path, size, owner, ip };
+    if let Err(status) = state.hooks.upload(&upload).await {
+        let _ = tokio::fs::remove_file(&upload.path).await;
+        return Err(status);
+    }
+
+    let mut info = PasteInfo {
+        id,
+        size,
+        filename,
+        timestamp: Utc::now().timestamp(),
+        owner,
+        collection: None,
+        mime,
+        unlisted: listed.is_some(),
+        namespace: user.and_then(|u| u.namespace.clone()),
+        immutable: false,
+        metadata: None,
+        title: None,
+        recipients: recipients.map(sqlx::types::Json),
+        sha256,
+    };
+    publish(state, &mut info, &upload.path).await?;
+    audit::record(state, owner, ip, "upload", &info.filename).await;
+    if listed.is_some() {
+        moderation::flag(&state.db, &info.filename, "ip_reputation", None).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    }
+    optimize::schedule(&state.db, &config, &info.filename, size);
+    pdf::schedule(&state.db, &info.filename);
+    content::schedule(&state.db, &info.filename);
+    ipfs::schedule(&state.db, &config, &info.filename);
+
+    tracing::info!("Created a {} byte file.", info.size);
+    Ok(info.filename)
+}
+
+/// Replaces the zip upload staged at `staged` with a collection of its
+/// contents.
+async fn expand_upload(state: &AppState, user: &TokenInfo, addr: SocketAddr, staged: &FsPath, options: UploadOptions) -> Result<uuid::Uuid, StatusCode> {
+    let config = state.config();
+    let (max_entries, max_bytes) = (config.zip_max_entries, config.zip_max_bytes);
+    let collection = uuid::Uuid::new_v4();
+    let dir = staging::path(&staging::upload(&collection));
+
+    let res = {
+        let (path, dir) = (staged.to_path_buf(), dir.clone());
+        let db = state.db.clone();
+        let runtime = tokio::runtime::Handle::current();
+        tokio::task::spawn_blocking(move || {
+            archive::expand_zip(&path, &dir, max_entries, max_bytes, |n| runtime.block_on(name::unused(&db, n)))
+        }).await
+    };
+
+    // the zip itself isn't needed anymore either way, and a leftover is
+    // swept with the rest of staging
+    if let Err(e) = tokio::fs::remove_file(staged).await {
+        tracing::warn!("Couldn't remove staged zip {}: {}", staged.display(), e);
+    }
+
+    let mut extracted = match res {
+        Ok(Ok(e)) => e,
+        Ok(Err(e)) => {
+            tracing::info!("Rejected zip upload: {}", e);
+            let _ = tokio::fs::remove_dir_all(&dir).await;
+            return Err(StatusCode::UNPROCESSABLE_ENTITY);
+        },
+        Err(_) => {
+            let _ = tokio::fs::remove_dir_all(&dir).await;
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        },
+    };
+
+    if state.config().strip_metadata && !options.keep_metadata {
+        for file in &mut extracted {
+            if let Ok(Some(size)) = exif::strip(&dir.join(&file.filename)).await {
+                file.size = size;
+            }
+        }
+    }
+
+    let timestamp = Utc::now().timestamp();
+    let mut infos = Vec::with_capacity(extracted.len());
+    for file in &extracted {
+        let path = dir.join(&file.filename);
+        let upload = Upload { filename: file.filename.clone(), path, size: file.size, owner: user.id, ip: addr.ip() };
+        if let Err(status) = state.hooks.upload(&upload).await {
+            let _ = tokio::fs::remove_dir_all(&dir).await;
+            return Err(status);
+        }
+
+        let recipients = match encrypted::inspect(&upload.path).await {
+            Ok(r) => r,
+            Err(_) => {
+                let _ = tokio::fs::remove_dir_all(&dir).await;
+                return Err(StatusCode::INTERNAL_SERVER_ERROR);
+            },
+        };
+        infos.push(PasteInfo {
+            id: file.id,
+            size: file.size,
+            filename: file.filename.clone(),
+            timestamp,
+            owner: user.id,
+            collection: Some(collection),
+            mime: file.mime.map(str::to_string),
+            unlisted: options.unlisted,
+            namespace: user.namespace.clone(),
+            immutable: options.immutable,
+            metadata: options.metadata.clone(),
+            title: options.title.clone(),
+            recipients: recipients.map(sqlx::types::Json),
+            sha256: None,
+        });
+    }
+
+    let res = async {
+        let mut tx = state.db.begin().await?;
+
+        sqlx::query("INSERT INTO collections (id, timestamp, owner) VALUES ($1, $2, $3)")
+        .bind(collection.to_string())
+        .bind(timestamp)
+        .bind(user.id)
+        .execute(&mut *tx).await?;
+
+        let mut prefixes = Vec::new();
+        for info in &infos {
+            prefixes.push(insert_paste(&mut *tx, info).await?);
+        }
+
+        tx.commit().await?;
+        Ok::<_, sqlx::Error>(prefixes)
+    }.await;
+    let prefixes = match res {
+        Ok(p) => p,
+        Err(_) => {
+            let _ = tokio::fs::remove_dir_all(&dir).await;
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        },
+    };
+
+    for (i, (info, prefix)) in infos.iter().zip(&prefixes).enumerate() {
+        if let Err(e) = storage::place(&dir.join(&info.filename), prefix.as_deref(), &info.filename, config.durability).await {
+            tracing::error!("Couldn't move {} into place: {}", info.filename, e);
+            for (placed, prefix) in infos[..i].iter().zip(&prefixes) {
+                let _ = tokio::fs::remove_file(paste_path_in(prefix.as_deref(), &placed.filename)).await;
+            }
+            let _ = tokio::fs::remove_dir_all(&dir).await;
+            let _ = sqlx::query("DELETE FROM pastes WHERE collection = $1")
+            .bind(collection.to_string())
+            .execute(&state.db).await;
+            let _ = sqlx::query("DELETE FROM collections WHERE id = $1")
+            .bind(collection.to_string())
+            .execute(&state.db).await;
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    }
+    let _ = tokio::fs::remove_dir(&dir).await;
+
+    for info in &infos {
+        audit::record(state, user.id, addr.ip(), "upload", &info.filename).await;
+        if !options.keep_metadata && !options.immutable {
+            optimize::schedule(&state.db, &config, &info.filename, info.size);
+        }
+        pdf::schedule(&state.db, &info.filename);
+        content::schedule(&state.db, &info.filename);
+        ipfs::schedule(&state.db, &config, &info.filename);
+    }
+
+    tracing::info!("Expanded a zip into {} pastes.", infos.len());
+    Ok(collection)
+}
+
+/// Records `info` and moves its upload, staged at `staged`, to where it's
+/// served from. The row comes first, so nothing is ever served that isn't
+/// recorded; it's taken back if the file can't be moved. Should another
+/// upload have been given the same name in the meantime, a new one is drawn.
+pub async fn publish(state: &AppState, info: &mut PasteInfo, staged: &FsPath) -> Result<(), StatusCode> {
+    for _ in 0..name::MAX_ATTEMPTS {
+        let taken = match insert_paste(&state.db, info).await {
+            Ok(prefix) => match storage::place(staged, prefix.as_deref(), &info.filename, state.config().durability).await {
+                Ok(()) => return Ok(()),
+                Err(e) => {
+                    let _ = sqlx::query("DELETE FROM pastes WHERE id = $1")
+                    .bind(info.id.to_string())
+                    .execute(&state.db).await;
+                    if e.kind() != std::io::ErrorKind::AlreadyExists {
+                        tracing::error!("Couldn't move {} into place: {}", info.filename, e);
+                        break;
+                    }
+                    true
+                },
+            },
+            Err(e) => e.as_database_error().map_or(false, |e| e.is_unique_violation()),
+        };
+        if !taken {
+            break;
+        }
+
+        tracing::debug!("{} was taken while it was being uploaded, naming it anew", info.filename);
+        match name::unused(&state.db, &info.filename).await {
+            Ok(n) => info.filename = n.into_string(),
+            Err(_) => break,
+        }
+    }
+
+    let _ = tokio::fs::remove_file(staged).await;
+    Err(StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+/// Records `info`, returning the storage prefix its file goes under.
+pub async fn insert_paste<'e, E>(db: E, info: &PasteInfo) -> sqlx::Result<Option<String>>
+where
+    E: sqlx::Executor<'e, Database = sqlx::Sqlite>,
+{
+    sqlx::query_scalar::<_, Option<String>>("INSERT INTO pastes (
+        id,
+        size,
+        filename,
+        timestamp,
+        owner,
+        collection,
+        mime,
+        unlisted,
+        namespace,
+        immutable,
+        metadata,
+        title,
+        recipients,
+        sha256,
+        storage_prefix
+    )VALUES (
+        $1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14,
+        (SELECT storage_prefix FROM namespaces WHERE name = $9)
+    ) RETURNING storage_prefix")
+    .bind(info.id.to_string())
+    .bind(info.size as i64)
+    .bind(&info.filename)
+    .bind(info.timestamp)
+    .bind(info.owner)
+    .bind(info.collection.map(|c| c.to_string()))
+    .bind(&info.mime)
+    .bind(info.unlisted)
+    .bind(&info.namespace)
+    .bind(info.immutable)
+    .bind(&info.metadata)
+    .bind(&info.title)
+    .bind(&info.recipients)
+    .bind(&info.sha256)
+    .fetch_one(db).await
+}
+
+#[axum::debug_handler]
+pub async fn get_collection(
+    State(state): State<Arc<AppState>>,
+    BaseUrl(base_url): BaseUrl,
+    namespace: RequestNamespace,
+    Path(id): Path<String>,
+) -> Result<String, StatusCode> {
+    let pastes = sqlx::query_as::<_, FileNameWrapper>("SELECT filename FROM pastes WHERE collection = $1 AND namespace IS $2 ORDER BY filename")
+    .bind(&id)
+    .bind(namespace.name())
+    .fetch_all(&state.db).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    if pastes.is_empty() {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    let template = &state.config().url_template;
+    Ok(pastes.iter().map(|p| format!("{}\n", template.render(&base_url, &p.filename))).collect())
+}
+
+#[axum::debug_handler]
+pub async fn delete_paste(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    cert: Option<Extension<ClientCert>>,
+    namespace: RequestNamespace,
+    Query(query): Query<IdTokenParam>,
+) -> Result<StatusCode, StatusCode> {
+    let user = authenticate_client(&state, addr.ip(), cert.as_deref(), query.token.as_deref()).await?;
+    namespace.check(&user)?;
+    delete(&state, &user, addr.ip(), &query.id).await?;
+    Ok(StatusCode::OK)
+}
+
+/// Checks that `user` may manage the paste `id`: only admins and the paste's
+/// owner may, and pastes of other namespaces are as good as missing.
+pub async fn check_owner(state: &AppState, user: &TokenInfo, id: &str) -> Result<(), StatusCode> {
+    let (owner, namespace) = sqlx::query_as::<_, (Option<i64>, Option<String>)>("SELECT owner, namespace FROM pastes WHERE id = $1")
+    .bind(id)
+    .fetch_optional(&state.db).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    .ok_or(StatusCode::NOT_FOUND)?;
+    if !user.is_admin() && namespace != user.namespace {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    if !user.can_manage(owner) {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    Ok(())
+}
+
+/// Deletes the paste `id` on behalf of `user`, unless it's under a legal hold
+/// or, for anyone but admins, taken down. See [`check_owner`] for who may.
+pub async fn delete(state: &AppState, user: &TokenInfo, ip: IpAddr, id: &str) -> Result<(), StatusCode> {
+    check_owner(state, user, id).await?;
+
+    let paste = match sqlx::query_as::<_, StoredPaste>("DELETE FROM pastes WHERE id = $1 AND NOT legal_hold
+    AND ($2 OR filename NOT IN (SELECT filename FROM takedowns WHERE lifted_at IS NULL)) RETURNING filename, storage_prefix")
+    .bind(id)
+    .bind(user.is_admin())
+    .fetch_one(&state.db)
+    .await {
+        Ok(f) => f,
+        Err(sqlx::Error::RowNotFound) => return Err(held_or_missing(state, id).await),
+        Err(_) => return Err(StatusCode::INTERNAL_SERVER_ERROR)
+    };
+
+    tracing::info!("Deleting paste {}", &paste.filename);
+    audit::record(state, user.id, ip, "delete", &paste.filename).await;
+
+    tokio::fs::remove_file(paste.path())
+    .await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    versions::remove(&state.db, &paste.filename).await;
+
+    state.hooks.delete(&paste.filename).await;
+
+    Ok(())
+}
+
+/// Why paste `id` couldn't be deleted: it's held or taken down, or it isn't
+/// there.
+async fn held_or_missing(state: &AppState, id: &str) -> StatusCode {
+    let held = sqlx::query_scalar::<_, bool>("SELECT legal_hold OR filename IN (SELECT filename FROM takedowns WHERE lifted_at IS NULL) FROM pastes WHERE id = $1")
+    .bind(id)
+    .fetch_optional(&state.db).await;
+
+    match held {
+        Ok(Some(true)) => StatusCode::LOCKED,
+        Ok(_) => StatusCode::NOT_FOUND,
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct BulkDeleteFilter {
+    ids: Option<Vec<String>>,
+    /// Unix timestamp; only pastes uploaded before it are deleted.
+    older_than: Option<i64>,
+    /// Row id of the token that uploaded the pastes.
+    owner: Option<i64>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct BulkDeleteSummary {
+    /// Pastes under a legal hold aren't counted, they're left alone.
+    deleted: usize,
+    /// Pastes removed from the database whose files couldn't be removed.
+    failed: Vec<String>,
+    /// Pastes that matched but were kept, because they're under a legal hold
+    /// or, for anyone but admins, taken down.
+    skipped: Vec<String>,
+}
+
+#[axum::debug_handler]
+pub async fn bulk_delete(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    cert: Option<Extension<ClientCert>>,
+    Query(token): Query<TokenParam>,
+    Json(filter): Json<BulkDeleteFilter>,
+) -> Result<Json<BulkDeleteSummary>, StatusCode> {
+    let user = authenticate_client(&state, addr.ip(), cert.as_deref(), token.token.as_deref()).await?;
+
+    if filter.ids.is_none() && filter.older_than.is_none() && filter.owner.is_none() {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    // only admins get to delete other people's pastes
+    let owner = match (user.is_admin(), filter.owner) {
+        (true, owner) => owner,
+        (false, Some(owner)) if Some(owner) != user.id => return Err(StatusCode::FORBIDDEN),
+        (false, _) => Some(user.id.ok_or(StatusCode::FORBIDDEN)?),
+    };
+
+    let ids = filter.ids.map(|ids| serde_json::to_string(&ids)).transpose()
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let mut tx = state.db.begin().await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    // taken down pastes are kept as evidence, so only admins may delete
+    // them, as with single deletes
+    let skipped = sqlx::query_scalar::<_, String>("SELECT filename FROM pastes
+    WHERE ($1 IS NULL OR id IN (SELECT value FROM json_each($1)))
+    AND ($2 IS NULL OR timestamp < $2)
+    AND ($3 IS NULL OR owner = $3)
+    AND (legal_hold OR (NOT $4 AND filename IN (SELECT filename FROM takedowns WHERE lifted_at IS NULL)))")
+    .bind(&ids)
+    .bind(filter.older_than)
+    .bind(owner)
+    .bind(user.is_admin())
+    .fetch_all(&mut *tx).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let pastes = sqlx::query_as::<_, StoredPaste>("DELETE FROM pastes
+    WHERE ($1 IS NULL OR id IN (SELECT value FROM json_each($1)))
+    AND ($2 IS NULL OR timestamp < $2)
+    AND ($3 IS NULL OR owner = $3)
+    AND NOT legal_hold
+    AND ($4 OR filename NOT IN (SELECT filename FROM takedowns WHERE lifted_at IS NULL))
+    RETURNING filename, storage_prefix")
+    .bind(&ids)
+    .bind(filter.older_than)
+    .bind(owner)
+    .bind(user.is_admin())
+    .fetch_all(&mut *tx).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    tx.commit().await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    tracing::info!("Bulk deleting {} pastes", pastes.len());
+
+    let mut failed = Vec::new();
+    for paste in &pastes {
+        audit::record(&state, user.id, addr.ip(), "delete", &paste.filename).await;
+
+        if let Err(e) = tokio::fs::remove_file(paste.path()).await {
+            tracing::error!("Couldn't remove {}: {}", paste.filename, e);
+            failed.push(paste.filename.clone());
+        }
+        versions::remove(&state.db, &paste.filename).await;
+        state.hooks.delete(&paste.filename).await;
+    }
+
+    Ok(Json(BulkDeleteSummary { deleted: pastes.len(), failed, skipped }))
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ArchiveParam {
+    /// `zip` (the default) or `tar`.
+    format: Option<String>,
+}
+
+#[axum::debug_handler]
+pub async fn archive_collection(
+    State(state): State<Arc<AppState>>,
+    namespace: RequestNamespace,
+    Path(id): Path<String>,
+    Query(query): Query<ArchiveParam>,
+) -> Result<Response, StatusCode> {
+    let members = sqlx::query_as::<_, archive::Member>("SELECT filename, timestamp, storage_prefix FROM pastes WHERE collection = $1 AND namespace IS $2 ORDER BY filename")
+    .bind(&id)
+    .bind(namespace.name())
+    .fetch_all(&state.db).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    if members.is_empty() {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    archive::stream(members, query.format.as_deref(), &id)
+}
+
+/// Handles `POST /paste/<name>/append`, which can't be an ordinary route
+/// because the pastes directory is nested at `/paste`.
+pub async fn append_paste(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    cert: Option<Extension<ClientCert>>,
+    Query(token): Query<TokenParam>,
+    req: Request<Body>,
+    next: Next<Body>,
+) -> Response {
+    let filename = match req.uri().path().trim_start_matches('/').strip_suffix("/append") {
+        Some(f) if req.method() == Method::POST => match PasteName::parse(f) {
+            Some(name) => name.into_string(),
+            None => return StatusCode::NOT_FOUND.into_response(),
+        },
+        _ => return next.run(req).await,
+    };
+
+    let user = match authenticate_client(&state, addr.ip(), cert.as_deref(), token.token.as_deref()).await {
+        Ok(u) => u,
+        Err(e) => return e.into_response(),
+    };
+
+    let declared = declared_length(req.headers());
+    match append_to(&state, &user, &filename, declared, req.into_body()).await {
+        Ok(size) => {
+            // nobody tailing it isn't an error
+            let _ = state.appends.send(filename.clone());
+            audit::record(&state, user.id, addr.ip(), "append", &filename).await;
+            content::schedule(&state.db, &filename);
+            size.to_string().into_response()
+        },
+        Err(e) => e.into_response(),
+    }
+}
+
+/// Appends `body`, `declared` bytes long if the client said so, to the paste
+/// stored as `filename`, returning its new size.
+async fn append_to(state: &AppState, user: &TokenInfo, filename: &str, declared: Option<u64>, body: Body) -> Result<u64, StatusCode> {
+    // held until the new size is recorded, so appends happen one at a time:
+    // each checks the limit against the size the last one left, and rolling
+    // back a failed one can't cut off another's bytes
+    let _lock = state.locks.lock(filename).await;
+    let paste = sqlx::query_as::<_, (i64, Option<i64>, bool, Option<String>)>("SELECT size, owner, immutable, storage_prefix FROM pastes WHERE filename = $1")
+    .bind(filename)
+    .fetch_optional(&state.db).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let (size, owner, immutable, prefix) = paste.ok_or(StatusCode::NOT_FOUND)?;
+    if !user.can_manage(owner) {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    if immutable {
+        return Err(StatusCode::METHOD_NOT_ALLOWED);
+    }
+
+    let size = size.max(0) as u64;
+    let cap = user.max_upload_size.map(|l| l.max(0) as u64).unwrap_or(u64::MAX).min(state.config().append_max_size);
+    let remaining = cap.saturating_sub(size);
+    if declared.map_or(false, |l| l > remaining) {
+        return Err(StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    let path = paste_path_in(prefix.as_deref(), filename);
+    let mut file = tokio::fs::OpenOptions::new().append(true).open(&path).await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let body_reader = StreamReader::new(body.map_err(|_| io::Error::other(""))).take(remaining.saturating_add(1));
+    futures::pin_mut!(body_reader);
+    let written = tokio::io::copy(&mut body_reader, &mut file).await;
+
+    let written = match written {
+        Ok(w) if w <= remaining => w,
+        res => {
+            // roll back whatever made it to disk
+            file.set_len(size).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+            return Err(match res {
+                Ok(_) => StatusCode::PAYLOAD_TOO_LARGE,
+                Err(_) => StatusCode::BAD_REQUEST,
+            });
+        }
+    };
+
+    sqlx::query("UPDATE pastes SET size = size + $1, sha256 = NULL, signature = NULL, signed_by = NULL WHERE filename = $2")
+    .bind(written as i64)
+    .bind(filename)
+    .execute(&state.db).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(size + written)
+}
+
+/// Gives hooks a chance to refuse serving a paste.
+pub async fn serve_hooks<B>(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    req: Request<B>,
+    next: Next<B>,
+) -> Response {
+    if req.method() == Method::GET || req.method() == Method::HEAD {
+        let path = req.uri().path().trim_start_matches('/');
+        let filename = path.strip_suffix("/tail").unwrap_or(path);
+        if let Err(status) = state.hooks.serve(filename, addr.ip()).await {
+            return status.into_response();
+        }
+    }
+    next.run(req).await
+}
+
+/// How long caches may keep an immutable paste before asking again. It won't
+/// change, but it can still be deleted or taken down, and asking again is
+/// cheap with its `ETag`.
+const IMMUTABLE_CACHE_CONTROL: &str = "public, max-age=3600, must-revalidate";
+
+/// The row of the paste a request to `/paste` is for, loaded once by
+/// [`load_paste`] and shared by every layer under it.
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct ServedPaste {
+    pub filename: String,
+    pub owner: Option<i64>,
+    pub namespace: Option<String>,
+    pub unlisted: bool,
+    pub immutable: bool,
+    pub storage_prefix: Option<String>,
+}
+
+impl ServedPaste {
+    pub fn path(&self) -> PathBuf {
+        paste_path_in(self.storage_prefix.as_deref(), &self.filename)
+    }
+}
+
+/// Adds the [`ServedPaste`] `/<filename>/...` is for to the request, when
+/// there's such a paste.
+pub async fn load_paste<B>(
+    State(state): State<Arc<AppState>>,
+    mut req: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let path = req.uri().path().trim_start_matches('/');
+    let filename = path.split('/').next().unwrap_or(path);
+    let paste = sqlx::query_as::<_, ServedPaste>("SELECT filename, owner, namespace, unlisted, immutable, storage_prefix FROM pastes WHERE filename = $1")
+    .bind(filename)
+    .fetch_optional(&state.db).await;
+
+    match paste {
+        Ok(Some(paste)) => {
+            req.extensions_mut().insert(paste);
+        },
+        Ok(None) => {},
+        Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+    next.run(req).await
+}
+
+/// Tells caches that immutable pastes won't change, so they can keep them
+/// for a while without asking.
+pub async fn mark_immutable<B>(req: Request<B>, next: Next<B>) -> Response {
+    let immutable = req.extensions().get::<ServedPaste>().map_or(false, |p| p.immutable);
+    let mut response = next.run(req).await;
+
+    if immutable && (response.status().is_success() || response.status() == StatusCode::NOT_MODIFIED) {
+        response.headers_mut().insert(header::CACHE_CONTROL, HeaderValue::from_static(IMMUTABLE_CACHE_CONTROL));
+    }
+    response
+}
+
+/// Adds the bytes sent of every paste served to its `bytes_served` counter,
+/// and logs the download when `ACCESS_LOG` is on.
+pub async fn count_bandwidth<B>(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    req: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let path = req.uri().path().trim_start_matches('/');
+    let filename = path.split('/').next().unwrap_or(path).to_string();
+    let method = req.method().clone();
+    let response = next.run(req).await;
+    access::record(&state, &filename, addr.ip(), &method, &response);
+    bandwidth::record(&state, &filename, &method, response)
+}
+
+/// Serves the paste stored as `filename` in answer to `req`, made to a route
+/// that found it some other way, through the same layers as `/paste`. `raw`
+/// keeps it from being sent to the viewer, for clients that only want its
+/// bytes.
+pub async fn serve(state: Arc<AppState>, filename: &str, raw: bool, mut req: Request<Body>) -> Response {
+    let query = if raw { Some("raw") } else { req.uri().query() };
+    let path = match query {
+        Some(q) => format!("/{}?{}", filename, q),
+        None => format!("/{}", filename),
+    };
+
+    let mut parts = req.uri().clone().into_parts();
+    parts.path_and_query = path.parse::<PathAndQuery>().ok();
+    match Uri::from_parts(parts) {
+        Ok(uri) => *req.uri_mut() = uri,
+        Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+    crate::paste_service(state).oneshot(req).await.unwrap_or_else(|e| match e {})
+}
+
+/// The `Content-Length` a request declared, if any.
+pub fn declared_length(headers: &HeaderMap) -> Option<u64> {
+    headers.get(header::CONTENT_LENGTH)?.to_str().ok()?.parse().ok()
+}
+
+/// What an upload turned out to be, worked out while it was written so none
+/// of it has to be read back.
+#[derive(Debug, Clone)]
+pub struct UploadResult {
+    /// Bytes written, one more than the limit when the upload went over it.
+    pub size: u64,
+    /// SHA-256 of the bytes written, in hex.
+    pub sha256: String,
+    /// The extension and MIME type its first bytes look like.
+    pub sniffed: Option<(&'static str, &'static str)>,
+}
+
+impl UploadResult {
+    pub fn of(data: &[u8]) -> Self {
+        let mut inspector = Inspector::default();
+        inspector.update(data);
+        inspector.finish()
+    }
+}
+
+/// Works out an `UploadResult` from the bytes of an upload, fed in order.
+#[derive(Default)]
+pub struct Inspector {
+    size: u64,
+    hasher: Sha256,
+    head: Vec<u8>,
+}
+
+impl Inspector {
+    pub fn update(&mut self, bytes: &[u8]) {
+        self.size += bytes.len() as u64;
+        self.hasher.update(bytes);
+        let wanted = SNIFF_LEN.saturating_sub(self.head.len()).min(bytes.len());
+        self.head.extend_from_slice(&bytes[..wanted]);
+    }
+
+    pub fn finish(self) -> UploadResult {
+        UploadResult {
+            size: self.size,
+            sha256: hex(&self.hasher.finalize()),
+            sniffed: sniff::sniff(&self.head),
+        }
+    }
+}
+
+/// Copies at most `limit + 1` bytes into the staged file `name`, so callers
+/// can tell an oversized upload apart from one that fits exactly, and syncs
+/// them as `durability` asks. They're hashed and sniffed on the way.
+pub async fn stream_to_staging<S, E>(name: &str, stream: S, limit: u64, durability: Durability) -> anyhow::Result<UploadResult>
+where
+    S: Stream<Item = Result<Bytes, E>>,
+    E: Into<anyhow::Error>,
+{
+    let mut file = BufWriter::new(staging::create(name).await?);
+    let written = write_stream(&mut file, stream, limit).await?;
+    staging::sync(file.get_ref(), durability).await?;
+    Ok(written)
+}
+
+async fn write_stream<W, S, E>(file: &mut W, stream: S, limit: u64) -> anyhow::Result<UploadResult>
+where
+    W: AsyncWrite + Unpin,
+    S: Stream<Item = Result<Bytes, E>>,
+    E: Into<anyhow::Error>,
+{
+    let max = limit.saturating_add(1);
+    futures::pin_mut!(stream);
+    let mut inspector = Inspector::default();
+
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk.map_err(Into::into)?;
+        let left = usize::try_from(max - inspector.size).unwrap_or(usize::MAX);
+        let chunk = &chunk[..chunk.len().min(left)];
+        inspector.update(chunk);
+        file.write_all(chunk).await?;
+        if inspector.size >= max {
+            break;
+        }
+    }
+
+    file.flush().await?;
+    Ok(inspector.finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHUNK_SIZE: usize = 1 << 20;
+
+    /// `n` chunks of `a`s.
+    fn chunks(n: usize) -> impl Stream<Item = Result<Bytes, io::Error>> {
+        futures::stream::repeat(Bytes::from(vec![b'a'; CHUNK_SIZE])).take(n).map(Ok)
+    }
+
+    #[tokio::test]
+    async fn write_stream_stops_a_byte_past_a_limit_over_4_gib() {
+        let limit = (1 << 32) + 10;
+        let written = write_stream(&mut tokio::io::sink(), chunks(4100), limit).await.unwrap();
+        assert_eq!(written.size, limit + 1);
+        // `limit + 1` `a`s
+        assert_eq!(written.sha256, "b022a7d8931997d2ef805865dfa250fad0bad9d82abe5a54c6db662f86fb6549");
+    }
+
+    #[tokio::test]
+    async fn write_stream_takes_an_upload_that_fits_exactly() {
+        let limit = 3 * CHUNK_SIZE as u64;
+        let written = write_stream(&mut tokio::io::sink(), chunks(3), limit).await.unwrap();
+        assert_eq!(written.size, limit);
+    }
+
+    #[test]
+    fn inspector_sniffs_across_chunks() {
+        let png = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+        let mut split = Inspector::default();
+        for byte in png {
+            split.update(std::slice::from_ref(byte));
+        }
+        let mut whole = Inspector::default();
+        whole.update(png);
+
+        let (split, whole) = (split.finish(), whole.finish());
+        assert_eq!(split.size, png.len() as u64);
+        assert_eq!(split.sha256, whole.sha256);
+        assert_eq!(split.sniffed, Some(("png", "image/png")));
+    }
+}
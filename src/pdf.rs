@@ -0,0 +1,88 @@
+use std::{path::Path, sync::OnceLock};
+
+use axum::{
+    http::{header, HeaderValue, Request},
+    middleware::Next,
+    response::Response,
+};
+use regex::bytes::Regex;
+use sqlx::SqlitePool;
+
+use crate::storage;
+
+pub fn is_pdf(filename: &str) -> bool {
+    filename.rsplit_once('.').map_or(false, |(_, ext)| ext.eq_ignore_ascii_case("pdf"))
+}
+
+/// Counts the pages of the PDF stored as `filename` in the background and
+/// saves it for the viewer. Files that don't look like PDFs are left alone.
+pub fn schedule(db: &SqlitePool, filename: &str) {
+    if !is_pdf(filename) {
+        return;
+    }
+
+    let db = db.clone();
+    let filename = filename.to_string();
+    tokio::spawn(async move {
+        let path = match storage::locate(&db, &filename).await {
+            Ok(p) => p,
+            Err(e) => return tracing::warn!("Couldn't find {}: {}", filename, e),
+        };
+        let pages = match tokio::task::spawn_blocking(move || page_count(&path)).await {
+            Ok(Ok(Some(p))) => p,
+            Ok(Ok(None)) => return,
+            Ok(Err(e)) => return tracing::warn!("Couldn't read {}: {}", filename, e),
+            Err(e) => return tracing::warn!("Couldn't count the pages of {}: {}", filename, e),
+        };
+
+        let res = sqlx::query("UPDATE pastes SET pdf_pages = $1 WHERE filename = $2")
+        .bind(pages)
+        .bind(&filename)
+        .execute(&db).await;
+
+        if let Err(e) = res {
+            tracing::error!("Couldn't save the page count of {}: {}", filename, e);
+        }
+    });
+}
+
+/// Counts the page objects in the PDF at `path`, or, when they're hidden in
+/// compressed object streams, takes the largest `/Count` of a page tree.
+/// Returns `None` if it isn't a PDF or neither is found.
+/// This blocks, so call it from `spawn_blocking`.
+fn page_count(path: &Path) -> std::io::Result<Option<u32>> {
+    static PAGE: OnceLock<Regex> = OnceLock::new();
+    static COUNT: OnceLock<Regex> = OnceLock::new();
+
+    let data = std::fs::read(path)?;
+    if !data.starts_with(b"%PDF-") {
+        return Ok(None);
+    }
+
+    let page = PAGE.get_or_init(|| Regex::new(r"/Type\s*/Page(?:[^s\w]|$)").expect("page pattern is valid"));
+    let pages = page.find_iter(&data).count();
+    if pages > 0 {
+        return Ok(u32::try_from(pages).ok());
+    }
+
+    let count = COUNT.get_or_init(|| Regex::new(r"/Count\s+(\d+)").expect("count pattern is valid"));
+    Ok(count.captures_iter(&data)
+        .filter_map(|c| std::str::from_utf8(&c[1]).ok()?.parse::<u32>().ok())
+        .max())
+}
+
+/// Lets browsers show PDF pastes in place, including inside the viewer's
+/// frame, instead of offering them as downloads.
+pub async fn serve_inline<B>(req: Request<B>, next: Next<B>) -> Response {
+    let filename = req.uri().path().trim_start_matches('/').to_string();
+    let mut response = next.run(req).await;
+
+    if response.status().is_success() && is_pdf(&filename) {
+        let headers = response.headers_mut();
+        headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("application/pdf"));
+        if let Ok(disposition) = HeaderValue::from_str(&format!("inline; filename=\"{}\"", filename)) {
+            headers.insert(header::CONTENT_DISPOSITION, disposition);
+        }
+    }
+    response
+}
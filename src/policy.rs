@@ -0,0 +1,133 @@
+use std::{str::FromStr, sync::Arc};
+
+use axum::{
+    body::Body,
+    extract::State,
+    http::{header, HeaderValue, Method, Request},
+    middleware::Next,
+    response::{IntoResponse, Redirect, Response},
+};
+
+use crate::AppState;
+
+/// How browsers are told to handle a paste.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Serving {
+    /// Offer it as a download, so it's never rendered on our origin.
+    Attachment,
+    /// Show it in place.
+    Inline,
+    /// Send people to the viewer page instead, unless they ask for `?raw`.
+    Viewer,
+}
+
+impl FromStr for Serving {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "attachment" | "download" => Ok(Self::Attachment),
+            "inline" => Ok(Self::Inline),
+            "viewer" => Ok(Self::Viewer),
+            _ => anyhow::bail!("unknown serving behavior {:?}, expected attachment, inline or viewer", s),
+        }
+    }
+}
+
+/// Types browsers run scripts in when they're shown in place, with our
+/// origin's cookies and storage.
+const ACTIVE_TYPES: &[&str] = &["text/html", "application/xhtml+xml", "image/svg+xml", "text/xml", "application/xml"];
+
+/// Whether browsers would run scripts in the paste stored as `filename` if
+/// it were shown in place.
+pub fn is_active(filename: &str) -> bool {
+    let mime = mime_guess::from_path(filename).first_or_octet_stream();
+    ACTIVE_TYPES.contains(&mime.essence_str())
+}
+
+/// Serving behaviors by extension or MIME type, like
+/// `html=attachment;image/*=inline;md=viewer`.
+#[derive(Debug, Clone, Default)]
+pub struct ServingPolicy(Vec<(String, Serving)>);
+
+impl FromStr for ServingPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut rules = Vec::new();
+        for rule in s.split(';').map(str::trim).filter(|r| !r.is_empty()) {
+            let (key, serving) = rule.split_once('=')
+                .ok_or_else(|| anyhow::anyhow!("expected key=behavior, got {:?}", rule))?;
+            rules.push((key.trim().to_ascii_lowercase(), serving.trim().parse()?));
+        }
+        Ok(Self(rules))
+    }
+}
+
+impl ServingPolicy {
+    /// How the paste stored as `filename` is served, if the policy says.
+    /// Its extension wins over its type, and exact types over `type/*`.
+    pub fn lookup(&self, filename: &str) -> Option<Serving> {
+        let mime = mime_guess::from_path(filename).first_or_octet_stream();
+        let extension = filename.rsplit_once('.').map(|(_, e)| e.to_ascii_lowercase());
+
+        extension.and_then(|e| self.find(&e))
+            .or_else(|| self.find(mime.essence_str()))
+            .or_else(|| self.find(&format!("{}/*", mime.type_())))
+    }
+
+    /// Whether the paste stored as `filename` is kept from running scripts
+    /// on our origin: either it can't, or the policy never shows it in place.
+    pub fn is_safe(&self, filename: &str) -> bool {
+        !is_active(filename) || matches!(self.lookup(filename), Some(Serving::Attachment | Serving::Viewer))
+    }
+
+    fn find(&self, key: &str) -> Option<Serving> {
+        self.0.iter().find(|(k, _)| k == key).map(|(_, s)| *s)
+    }
+}
+
+/// Applies `SERVING_POLICY` to pastes being downloaded.
+pub async fn enforce(
+    State(state): State<Arc<AppState>>,
+    req: Request<Body>,
+    next: Next<Body>,
+) -> Response {
+    if req.method() != Method::GET && req.method() != Method::HEAD {
+        return next.run(req).await;
+    }
+
+    let path = req.uri().path().trim_start_matches('/');
+    let filename = path.split('/').next().unwrap_or(path).to_string();
+    let serving = state.config().serving_policy.lookup(&filename);
+
+    // only the paste itself moves to the viewer, not its tail or versions
+    let raw = req.uri().query().map_or(false, |q| q.split('&').any(|p| p == "raw" || p.starts_with("raw=")));
+    if serving == Some(Serving::Viewer) && !path.contains('/') && !raw {
+        // relative, so it stays under a namespace prefix
+        return Redirect::to(&format!("../view/{}", filename)).into_response();
+    }
+
+    let mut response = next.run(req).await;
+    apply(serving, &filename, &mut response);
+    response
+}
+
+/// Sets the headers `serving` calls for on a successful `response` serving
+/// `filename`. Sending people to the viewer is up to the caller.
+pub fn apply(serving: Option<Serving>, filename: &str, response: &mut Response) {
+    let disposition = match serving {
+        Some(Serving::Attachment) => "attachment",
+        Some(Serving::Inline) => "inline",
+        _ => return,
+    };
+    if !response.status().is_success() {
+        return;
+    }
+
+    let headers = response.headers_mut();
+    if let Ok(value) = HeaderValue::from_str(&format!("{}; filename=\"{}\"", disposition, filename)) {
+        headers.insert(header::CONTENT_DISPOSITION, value);
+    }
+    headers.insert(header::X_CONTENT_TYPE_OPTIONS, HeaderValue::from_static("nosniff"));
+}
@@ -0,0 +1,146 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use axum::{
+    extract::{ws::{Message, WebSocket, WebSocketUpgrade}, Path, State},
+    response::Response,
+};
+use serde::Serialize;
+use tokio::sync::watch;
+
+use crate::{session::Session, AppState};
+
+/// Longest upload id a page may pick.
+const MAX_ID_LEN: usize = 64;
+/// How long a progress socket waits for its upload to start, since the page
+/// connects before it submits the form.
+const START_TIMEOUT: Duration = Duration::from_secs(10);
+/// Least time between two updates sent to a page.
+const UPDATE_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How far along an upload is, as the server sees it.
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct Progress {
+    /// Bytes of the file received so far.
+    pub received: u64,
+    /// The length the request declared, framing included, if it did.
+    pub total: Option<u64>,
+    /// Set on the last update, once the upload is over, stored or not.
+    pub done: bool,
+}
+
+type InFlight = Arc<Mutex<HashMap<String, (String, watch::Receiver<Progress>)>>>;
+
+/// Uploads from the web UI that a page asked to follow, by the id it picked
+/// for them, along with the session that made them.
+#[derive(Debug, Default)]
+pub struct Uploads {
+    in_flight: InFlight,
+}
+
+impl Uploads {
+    /// Starts reporting on the upload `session` is making as `id`. `None`
+    /// when the id is invalid or already taken.
+    pub fn track(&self, session: &str, id: &str, total: Option<u64>) -> Option<Tracker> {
+        if id.is_empty() || id.len() > MAX_ID_LEN || !id.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-') {
+            return None;
+        }
+        let mut in_flight = self.in_flight.lock().unwrap();
+        if in_flight.contains_key(id) {
+            return None;
+        }
+
+        let (tx, rx) = watch::channel(Progress { total, ..Default::default() });
+        in_flight.insert(id.to_string(), (session.to_string(), rx));
+        Some(Tracker(Arc::new(TrackerInner { id: id.to_string(), tx, in_flight: self.in_flight.clone() })))
+    }
+
+    fn subscribe(&self, session: &str, id: &str) -> Option<watch::Receiver<Progress>> {
+        let in_flight = self.in_flight.lock().unwrap();
+        in_flight.get(id).filter(|(s, _)| s == session).map(|(_, rx)| rx.clone())
+    }
+}
+
+/// Counts the bytes of an upload as they arrive. It's over once the last
+/// clone is dropped.
+#[derive(Debug, Clone)]
+pub struct Tracker(Arc<TrackerInner>);
+
+#[derive(Debug)]
+struct TrackerInner {
+    id: String,
+    tx: watch::Sender<Progress>,
+    in_flight: InFlight,
+}
+
+impl Tracker {
+    pub fn add(&self, bytes: usize) {
+        self.0.tx.send_modify(|p| p.received += bytes as u64);
+    }
+}
+
+impl Drop for TrackerInner {
+    fn drop(&mut self) {
+        self.in_flight.lock().unwrap().remove(&self.id);
+    }
+}
+
+/// Handles the WebSocket at `/ui/upload/progress/<id>`, which sends the
+/// progress of the session's upload `id` as JSON until it's over.
+#[axum::debug_handler]
+pub async fn progress(
+    State(state): State<Arc<AppState>>,
+    session: Session,
+    Path(id): Path<String>,
+    ws: WebSocketUpgrade,
+) -> Response {
+    ws.on_upgrade(move |socket| report(state, session.id, id, socket))
+}
+
+async fn report(state: Arc<AppState>, session: String, id: String, mut socket: WebSocket) {
+    let mut rx = match wait_for(&state.uploads, &session, &id).await {
+        Some(rx) => rx,
+        None => {
+            let _ = socket.close().await;
+            return;
+        },
+    };
+
+    loop {
+        let progress = *rx.borrow_and_update();
+        if send(&mut socket, &progress).await.is_err() {
+            return;
+        }
+        tokio::time::sleep(UPDATE_INTERVAL).await;
+        // the tracker is gone, so the upload is over
+        if rx.changed().await.is_err() {
+            break;
+        }
+    }
+
+    let last = Progress { done: true, ..*rx.borrow() };
+    if send(&mut socket, &last).await.is_ok() {
+        let _ = socket.close().await;
+    }
+}
+
+async fn wait_for(uploads: &Uploads, session: &str, id: &str) -> Option<watch::Receiver<Progress>> {
+    let started = tokio::time::Instant::now();
+    loop {
+        if let Some(rx) = uploads.subscribe(session, id) {
+            return Some(rx);
+        }
+        if started.elapsed() > START_TIMEOUT {
+            return None;
+        }
+        tokio::time::sleep(UPDATE_INTERVAL).await;
+    }
+}
+
+async fn send(socket: &mut WebSocket, progress: &Progress) -> Result<(), axum::Error> {
+    let json = serde_json::to_string(progress).unwrap_or_default();
+    socket.send(Message::Text(json)).await
+}
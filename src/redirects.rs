@@ -0,0 +1,204 @@
+use std::{net::SocketAddr, sync::Arc};
+
+use axum::{
+    body::Body,
+    extract::{ConnectInfo, OriginalUri, Query, State},
+    http::{header, Method, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::prelude::*;
+use serde::{Deserialize, Serialize};
+use sqlx::{SqliteConnection, SqlitePool};
+
+use crate::{audit, auth::authenticate_admin, name::PasteName, namespace::RequestNamespace, AppState};
+
+/// An old name that leads to a paste, left behind when it was renamed or
+/// added by an admin for pastes that moved some other way.
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct Redirect {
+    pub source: String,
+    pub target: String,
+    pub namespace: Option<String>,
+    pub created_at: i64,
+}
+
+/// Makes the old name `source` of a paste in `namespace` lead to `target`.
+/// Redirects that led to `source` are pointed at `target` too, so nobody
+/// goes through more than one.
+pub async fn add(conn: &mut SqliteConnection, namespace: Option<&str>, source: &str, target: &str) -> sqlx::Result<()> {
+    sqlx::query("UPDATE redirects SET target = $1 WHERE target = $2")
+    .bind(target)
+    .bind(source)
+    .execute(&mut *conn).await?;
+
+    sqlx::query("INSERT OR REPLACE INTO redirects (source, target, namespace, created_at) VALUES ($1, $2, $3, $4)")
+    .bind(source)
+    .bind(target)
+    .bind(namespace)
+    .bind(Utc::now().timestamp())
+    .execute(&mut *conn).await?;
+    Ok(())
+}
+
+/// Where the paste once called `source` in `namespace` lives now, if it
+/// moved.
+pub async fn target(db: &SqlitePool, namespace: Option<&str>, source: &str) -> sqlx::Result<Option<String>> {
+    sqlx::query_scalar::<_, String>("SELECT target FROM redirects WHERE source = $1 AND namespace IS $2")
+    .bind(source)
+    .bind(namespace)
+    .fetch_optional(db).await
+}
+
+/// `301` to `location`.
+pub fn moved(location: &str) -> Response {
+    (StatusCode::MOVED_PERMANENTLY, [(header::LOCATION, location)]).into_response()
+}
+
+/// `301` to the new name of the paste once called `filename` in
+/// `namespace`, for pages and images whose URLs end with the name.
+pub async fn to_sibling(state: &AppState, namespace: &RequestNamespace, filename: &str) -> Result<Response, StatusCode> {
+    match target(&state.db, namespace.name(), filename).await {
+        // relative, so it stays under whatever prefix the request came in on
+        Ok(Some(t)) => Ok(moved(&t)),
+        Ok(None) => Err(StatusCode::NOT_FOUND),
+        Err(_) => Err(StatusCode::INTERNAL_SERVER_ERROR),
+    }
+}
+
+/// Sends requests for pastes under a name they moved away from to the new
+/// one, with `301`. Only what isn't found otherwise is looked up.
+pub async fn follow(
+    State(state): State<Arc<AppState>>,
+    namespace: RequestNamespace,
+    req: Request<Body>,
+    next: Next<Body>,
+) -> Response {
+    if req.method() != Method::GET && req.method() != Method::HEAD {
+        return next.run(req).await;
+    }
+
+    let uri = req.uri().clone();
+    let original = req.extensions().get::<OriginalUri>().map(|u| u.0.path().to_string());
+    let response = next.run(req).await;
+    if response.status() != StatusCode::NOT_FOUND {
+        return response;
+    }
+
+    let path = uri.path().trim_start_matches('/');
+    let (source, rest) = match path.split_once('/') {
+        Some((first, rest)) => (first, Some(rest)),
+        None => (path, None),
+    };
+    let target = match target(&state.db, namespace.name(), source).await {
+        Ok(Some(t)) => t,
+        _ => return response,
+    };
+
+    // wherever `/paste` is mounted, namespace prefixes included
+    let mount = original.as_deref()
+        .and_then(|o| o.get(..o.len().checked_sub(uri.path().len())?))
+        .unwrap_or("/paste")
+        .to_string();
+    let mut location = format!("{}/{}", mount, target);
+    if let Some(rest) = rest {
+        location = format!("{}/{}", location, rest);
+    }
+    if let Some(q) = uri.query() {
+        location = format!("{}?{}", location, q);
+    }
+    moved(&location)
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct RedirectParam {
+    token: String,
+    totp: Option<String>,
+    /// The old name.
+    source: String,
+    /// Name of the paste it leads to, when adding one.
+    target: Option<String>,
+}
+
+/// Handles `POST /admin/redirect`, which makes `source` lead to the existing
+/// paste `target`, for pastes that moved without being renamed here, like
+/// ones migrated from elsewhere. It replaces what `source` led to before.
+#[axum::debug_handler]
+pub async fn create(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Query(query): Query<RedirectParam>,
+) -> Result<Json<Redirect>, StatusCode> {
+    let admin = authenticate_admin(&state, addr.ip(), &query.token, query.totp.as_deref()).await?;
+    let source = PasteName::parse(&query.source).ok_or(StatusCode::BAD_REQUEST)?;
+    let target = query.target.as_deref().and_then(PasteName::parse).ok_or(StatusCode::BAD_REQUEST)?.into_string();
+
+    // it leads to the same namespace the paste is in
+    let namespace = sqlx::query_scalar::<_, Option<String>>("SELECT namespace FROM pastes WHERE filename = $1")
+    .bind(&target)
+    .fetch_optional(&state.db).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    .ok_or(StatusCode::NOT_FOUND)?;
+    // a paste by that name would never be redirected away from
+    let exists = sqlx::query_scalar::<_, i64>("SELECT COUNT(*) FROM pastes WHERE filename = $1")
+    .bind(source.as_str())
+    .fetch_one(&state.db).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if exists > 0 {
+        return Err(StatusCode::CONFLICT);
+    }
+
+    let mut conn = state.db.acquire().await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    add(&mut conn, namespace.as_deref(), source.as_str(), &target).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let redirect = sqlx::query_as::<_, Redirect>("SELECT * FROM redirects WHERE source = $1")
+    .bind(source.as_str())
+    .fetch_one(&mut *conn).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    audit::record(&state, admin.id, addr.ip(), "add_redirect", &format!("{} -> {}", redirect.source, redirect.target)).await;
+    Ok(Json(redirect))
+}
+
+/// Handles `DELETE /admin/redirect`, after which `source` is free to be
+/// given to a new paste again.
+#[axum::debug_handler]
+pub async fn remove(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Query(query): Query<RedirectParam>,
+) -> Result<StatusCode, StatusCode> {
+    let admin = authenticate_admin(&state, addr.ip(), &query.token, query.totp.as_deref()).await?;
+    let source = PasteName::parse(&query.source).ok_or(StatusCode::BAD_REQUEST)?;
+
+    let res = sqlx::query("DELETE FROM redirects WHERE source = $1")
+    .bind(source.as_str())
+    .execute(&state.db).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if res.rows_affected() == 0 {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    audit::record(&state, admin.id, addr.ip(), "remove_redirect", source.as_str()).await;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ListParam {
+    token: String,
+    totp: Option<String>,
+    /// Only the redirects leading to this paste.
+    target: Option<String>,
+}
+
+/// Handles `GET /admin/redirects`, listing redirects, newest first.
+#[axum::debug_handler]
+pub async fn list(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Query(query): Query<ListParam>,
+) -> Result<Json<Vec<Redirect>>, StatusCode> {
+    authenticate_admin(&state, addr.ip(), &query.token, query.totp.as_deref()).await?;
+
+    let redirects = sqlx::query_as::<_, Redirect>("SELECT * FROM redirects WHERE $1 IS NULL OR target = $1 ORDER BY created_at DESC")
+    .bind(&query.target)
+    .fetch_all(&state.db).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Json(redirects))
+}
@@ -0,0 +1,56 @@
+use std::sync::Arc;
+
+use tokio::signal::unix::{signal, SignalKind};
+use tracing_subscriber::{filter::EnvFilter, prelude::*, reload, Registry};
+
+use crate::{maintenance, AppState};
+
+/// Changes the log filter of the running process.
+pub type LogHandle = reload::Handle<EnvFilter, Registry>;
+
+/// Sets up logging at `info` until the config says otherwise.
+pub fn init_logging() -> LogHandle {
+    let (filter, handle) = reload::Layer::new(EnvFilter::new("info"));
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(tracing_subscriber::fmt::layer())
+        .init();
+    handle
+}
+
+pub fn set_log_level(handle: &LogHandle, level: &str) -> anyhow::Result<()> {
+    let filter = EnvFilter::try_new(level)?;
+    handle.reload(filter)?;
+    Ok(())
+}
+
+/// Re-reads the config and applies what can change without a restart. Work
+/// in progress, like uploads, carries on with the config it started with.
+pub async fn reload(state: &AppState) -> anyhow::Result<()> {
+    let old = state.config();
+    let config = old.reloaded()?;
+    set_log_level(&state.log_handle, &config.log_level)?;
+    state.hooks.reconfigure(&config);
+    state.reputation.reconfigure(&config);
+    // only when the setting changed, so it doesn't undo an admin's toggle
+    if config.read_only != old.read_only {
+        maintenance::set_read_only(state, config.read_only);
+    }
+    state.config.set(config);
+
+    tracing::info!("Reloaded the configuration");
+    Ok(())
+}
+
+/// Reloads the config whenever the process gets `SIGHUP`.
+pub fn on_sighup(state: Arc<AppState>) -> anyhow::Result<()> {
+    let mut hangups = signal(SignalKind::hangup())?;
+    tokio::spawn(async move {
+        while hangups.recv().await.is_some() {
+            if let Err(e) = reload(&state).await {
+                tracing::error!("Couldn't reload the configuration: {}", e);
+            }
+        }
+    });
+    Ok(())
+}
@@ -0,0 +1,148 @@
+use std::{net::SocketAddr, sync::Arc};
+
+use axum::{
+    extract::{ConnectInfo, Path, Query, State},
+    http::StatusCode,
+    Extension,
+};
+use serde::Deserialize;
+
+use crate::{
+    audit,
+    auth::authenticate_client,
+    base_url::BaseUrl,
+    name::{self, PasteName},
+    namespace::RequestNamespace,
+    redirects,
+    storage::paste_path_in,
+    tls::ClientCert,
+    versions,
+    AppState,
+};
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct RenameParam {
+    token: Option<String>,
+    /// What the paste is called from now on, extension included.
+    name: String,
+    /// Whether the old URL keeps working by redirecting to the new one,
+    /// which it does unless this is `false`.
+    redirect: Option<bool>,
+}
+
+/// Handles `PATCH /api/paste/<id>`, which gives the paste a new name for its
+/// URLs and returns the new URL. Its id stays the same.
+#[axum::debug_handler]
+pub async fn rename(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    cert: Option<Extension<ClientCert>>,
+    BaseUrl(base_url): BaseUrl,
+    namespace: RequestNamespace,
+    Path(id): Path<String>,
+    Query(query): Query<RenameParam>,
+) -> Result<String, StatusCode> {
+    let user = authenticate_client(&state, addr.ip(), cert.as_deref(), query.token.as_deref()).await?;
+    namespace.check(&user)?;
+
+    let (old, owner, immutable) = sqlx::query_as::<_, (String, Option<i64>, bool)>("SELECT filename, owner, immutable FROM pastes WHERE id = $1 AND namespace IS $2")
+    .bind(&id)
+    .bind(namespace.name())
+    .fetch_optional(&state.db).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    .ok_or(StatusCode::NOT_FOUND)?;
+    if !user.can_manage(owner) {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    // its URL was promised to always serve the same thing
+    if immutable {
+        return Err(StatusCode::METHOD_NOT_ALLOWED);
+    }
+
+    let name = PasteName::parse(&query.name).ok_or(StatusCode::BAD_REQUEST)?;
+    let new = name.as_str().to_string();
+    if new == old {
+        return Ok(state.config().url_template.render(user.base_url.as_deref().unwrap_or(&base_url), &new));
+    }
+    let ext = new.rsplit_once('.').map(|(_, e)| e.to_lowercase()).unwrap_or_default();
+    if state.config().blocked_extensions.contains(&ext) {
+        return Err(StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    }
+    // uploads are named by us, but this would let anyone turn a paste into
+    // a page that runs on our origin
+    if !state.config().serving_policy.is_safe(&new) {
+        return Err(StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    }
+    if name::is_taken(&state.db, &name, Some(&old)).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)? {
+        return Err(StatusCode::CONFLICT);
+    }
+
+    move_paste(&state, namespace.name(), &old, &new, query.redirect.unwrap_or(true)).await.map_err(|e| {
+        if is_conflict(&e) {
+            return StatusCode::CONFLICT;
+        }
+        tracing::error!("Couldn't rename {} to {}: {}", old, new, e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    audit::record(&state, user.id, addr.ip(), "rename", &format!("{} -> {}", old, new)).await;
+    tracing::info!("Renamed {} to {}", old, new);
+    Ok(state.config().url_template.render(user.base_url.as_deref().unwrap_or(&base_url), &new))
+}
+
+/// Moves the paste stored as `old` and everything kept about it to `new`.
+/// Thumbnails and watermarked copies are made again under the new name.
+/// Another paste that took `new` in the meantime is never replaced; that
+/// fails with a unique violation or `AlreadyExists`.
+async fn move_paste(state: &AppState, namespace: Option<&str>, old: &str, new: &str, redirect: bool) -> anyhow::Result<()> {
+    let mut tx = state.db.begin().await?;
+    let prefix = sqlx::query_scalar::<_, Option<String>>("UPDATE pastes SET filename = $1 WHERE filename = $2 RETURNING storage_prefix")
+    .bind(new)
+    .bind(old)
+    .fetch_one(&mut *tx).await?;
+    for table in ["versions", "bandwidth", "accesses"] {
+        sqlx::query(&format!("UPDATE {} SET paste = $1 WHERE paste = $2", table))
+        .bind(new)
+        .bind(old)
+        .execute(&mut *tx).await?;
+    }
+    for table in ["ipfs_pins", "s3_objects", "flags", "takedowns"] {
+        sqlx::query(&format!("UPDATE {} SET filename = $1 WHERE filename = $2", table))
+        .bind(new)
+        .bind(old)
+        .execute(&mut *tx).await?;
+    }
+    // a name it had before, so it doesn't lead anywhere anymore
+    sqlx::query("DELETE FROM redirects WHERE source = $1")
+    .bind(new)
+    .execute(&mut *tx).await?;
+    if redirect {
+        redirects::add(&mut *tx, namespace, old, new).await?;
+    }
+
+    // the database only changes once the files have moved, and the old
+    // name only goes away once it has
+    let (source, target) = (paste_path_in(prefix.as_deref(), old), paste_path_in(prefix.as_deref(), new));
+    if let Some(dir) = target.parent() {
+        tokio::fs::create_dir_all(dir).await?;
+    }
+    tokio::fs::hard_link(&source, &target).await?;
+    if let Err(e) = versions::rename_files(old, new).await {
+        let _ = tokio::fs::remove_file(&target).await;
+        return Err(e.into());
+    }
+    if let Err(e) = tx.commit().await {
+        let _ = versions::rename_files(new, old).await;
+        let _ = tokio::fs::remove_file(&target).await;
+        return Err(e.into());
+    }
+    tokio::fs::remove_file(&source).await?;
+    Ok(())
+}
+
+/// Whether moving a paste failed because its new name was taken after all.
+fn is_conflict(e: &anyhow::Error) -> bool {
+    if let Some(e) = e.downcast_ref::<sqlx::Error>() {
+        return e.as_database_error().map_or(false, |e| e.is_unique_violation());
+    }
+    e.downcast_ref::<std::io::Error>().map_or(false, |e| e.kind() == std::io::ErrorKind::AlreadyExists)
+}
@@ -0,0 +1,631 @@
+use std::{
+    collections::BTreeMap,
+    io::SeekFrom,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use chrono::prelude::*;
+use reqwest::Method;
+use sha2::{Digest, Sha256};
+use sqlx::{
+    sqlite::{SqliteConnectOptions, SqliteConnection},
+    ConnectOptions, Connection, SqlitePool,
+};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio_util::io::ReaderStream;
+
+use crate::{config::Config, s3::hmac, session::{hex, random_hex}};
+
+/// Bytes in the header at the start of a WAL.
+const WAL_HEADER_LEN: u64 = 32;
+/// Bytes in the header in front of every page in the WAL.
+const FRAME_HEADER_LEN: u64 = 24;
+/// Once this much WAL has been shipped, it's checkpointed into the database
+/// so it can start over.
+const CHECKPOINT_SIZE: u64 = 4 * 1024 * 1024;
+/// How many times a new generation waits for writers to stand still.
+const SNAPSHOT_ATTEMPTS: usize = 10;
+/// How many times a checkpoint ships what was written in the meantime before
+/// waiting for the next sync.
+const CHECKPOINT_ATTEMPTS: usize = 3;
+const SNAPSHOT_KEY: &str = "snapshot.sqlite";
+/// The payload hash of requests whose body is streamed.
+const UNSIGNED_PAYLOAD: &str = "UNSIGNED-PAYLOAD";
+
+/// The bucket the database is replicated to, from `REPLICA_URL` and friends,
+/// spoken to with path-style requests signed with SigV4.
+#[derive(Debug, Clone)]
+struct Bucket {
+    endpoint: String,
+    host: String,
+    bucket: String,
+    /// What every key starts with, empty or ending in `/`.
+    prefix: String,
+    region: String,
+    access_key: String,
+    secret_key: String,
+    client: reqwest::Client,
+}
+
+impl Bucket {
+    fn from_config(config: &Config) -> anyhow::Result<Option<Self>> {
+        let url = match &config.replica_url {
+            Some(u) => u,
+            None => return Ok(None),
+        };
+        let (bucket, prefix) = url.strip_prefix("s3://")
+            .ok_or_else(|| anyhow::anyhow!("REPLICA_URL must look like s3://bucket/prefix"))
+            .map(|rest| rest.split_once('/').unwrap_or((rest, "")))?;
+        let prefix = prefix.trim_matches('/');
+
+        let endpoint = config.replica_endpoint.clone()
+            .unwrap_or_else(|| format!("https://s3.{}.amazonaws.com", config.replica_region))
+            .trim_end_matches('/').to_string();
+        let parsed = reqwest::Url::parse(&endpoint)?;
+        let host = parsed.host_str().ok_or_else(|| anyhow::anyhow!("REPLICA_ENDPOINT has no host"))?;
+        let host = match parsed.port() {
+            Some(port) => format!("{}:{}", host, port),
+            None => host.to_string(),
+        };
+
+        Ok(Some(Self {
+            endpoint,
+            host,
+            bucket: bucket.to_string(),
+            prefix: if prefix.is_empty() { String::new() } else { format!("{}/", prefix) },
+            region: config.replica_region.clone(),
+            access_key: config.replica_access_key_id.clone().unwrap_or_default(),
+            secret_key: config.replica_secret_access_key.clone().unwrap_or_default(),
+            client: reqwest::Client::new(),
+        }))
+    }
+
+    async fn request(
+        &self,
+        method: Method,
+        key: Option<&str>,
+        query: &[(&str, &str)],
+        body: reqwest::Body,
+        payload_hash: &str,
+        length: Option<u64>,
+    ) -> anyhow::Result<reqwest::Response> {
+        let path = match key {
+            Some(key) => format!("/{}/{}", self.bucket, encode(&format!("{}{}", self.prefix, key), true)),
+            None => format!("/{}", self.bucket),
+        };
+        let mut query: Vec<String> = query.iter().map(|(k, v)| format!("{}={}", encode(k, false), encode(v, false))).collect();
+        query.sort();
+        let query = query.join("&");
+
+        let now = Utc::now();
+        let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let scope = format!("{}/{}/s3/aws4_request", now.format("%Y%m%d"), self.region);
+        let signed_headers = "host;x-amz-content-sha256;x-amz-date";
+        let canonical = format!(
+            "{}\n{}\n{}\nhost:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n\n{}\n{}",
+            method, path, query, self.host, payload_hash, amz_date, signed_headers, payload_hash,
+        );
+        let string_to_sign = format!("AWS4-HMAC-SHA256\n{}\n{}\n{}", amz_date, scope, hex(&Sha256::digest(canonical.as_bytes())));
+        let key = scope.split('/')
+            .fold(format!("AWS4{}", self.secret_key).into_bytes(), |key, part| hmac(&key, part));
+        let authorization = format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+            self.access_key, scope, signed_headers, hex(&hmac(&key, &string_to_sign)),
+        );
+
+        let url = if query.is_empty() { format!("{}{}", self.endpoint, path) } else { format!("{}{}?{}", self.endpoint, path, query) };
+        let mut request = self.client.request(method, url)
+            .header("x-amz-content-sha256", payload_hash)
+            .header("x-amz-date", amz_date)
+            .header(reqwest::header::AUTHORIZATION, authorization);
+        // streamed bodies need their length up front
+        if let Some(length) = length {
+            request = request.header(reqwest::header::CONTENT_LENGTH, length);
+        }
+        let response = request.body(body).send().await?;
+
+        if !response.status().is_success() {
+            let status = response.status();
+            anyhow::bail!("{} {}: {}", status, path, response.text().await.unwrap_or_default());
+        }
+        Ok(response)
+    }
+
+    async fn put(&self, key: &str, body: Vec<u8>) -> anyhow::Result<()> {
+        let hash = hex(&Sha256::digest(&body));
+        self.request(Method::PUT, Some(key), &[], body.into(), &hash, None).await?;
+        Ok(())
+    }
+
+    /// Uploads the file at `path` without holding it in memory.
+    async fn put_file(&self, key: &str, path: &Path) -> anyhow::Result<()> {
+        let file = tokio::fs::File::open(path).await?;
+        let len = file.metadata().await?.len();
+        let body = reqwest::Body::wrap_stream(ReaderStream::new(file));
+        self.request(Method::PUT, Some(key), &[], body, UNSIGNED_PAYLOAD, Some(len)).await?;
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> anyhow::Result<Vec<u8>> {
+        let response = self.request(Method::GET, Some(key), &[], reqwest::Body::from(Vec::new()), &empty_hash(), None).await?;
+        Ok(response.bytes().await?.to_vec())
+    }
+
+    async fn delete(&self, key: &str) -> anyhow::Result<()> {
+        self.request(Method::DELETE, Some(key), &[], reqwest::Body::from(Vec::new()), &empty_hash(), None).await?;
+        Ok(())
+    }
+
+    /// Every key under the prefix, without it.
+    async fn list(&self) -> anyhow::Result<Vec<String>> {
+        let mut keys = Vec::new();
+        let mut token: Option<String> = None;
+        loop {
+            let mut query = vec![("list-type", "2"), ("prefix", self.prefix.as_str())];
+            if let Some(t) = &token {
+                query.push(("continuation-token", t.as_str()));
+            }
+            let xml = self.request(Method::GET, None, &query, reqwest::Body::from(Vec::new()), &empty_hash(), None).await?
+                .text().await?;
+
+            // the response is simple enough not to need an XML parser
+            keys.extend(elements(&xml, "Key").into_iter().filter_map(|k| k.strip_prefix(&self.prefix).map(str::to_string)));
+            token = elements(&xml, "NextContinuationToken").first().map(|t| t.to_string());
+            if token.is_none() {
+                return Ok(keys);
+            }
+        }
+    }
+}
+
+fn empty_hash() -> String {
+    hex(&Sha256::digest(b""))
+}
+
+/// Percent-encodes `s` the way SigV4 wants, keeping `/` in paths.
+fn encode(s: &str, keep_slash: bool) -> String {
+    s.bytes().map(|b| match b {
+        b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (b as char).to_string(),
+        b'/' if keep_slash => "/".to_string(),
+        _ => format!("%{:02X}", b),
+    }).collect()
+}
+
+/// The text of every `<tag>` in `xml`, as is.
+fn elements<'a>(xml: &'a str, tag: &str) -> Vec<&'a str> {
+    let close = format!("</{}>", tag);
+    xml.split(&format!("<{}>", tag)).skip(1).filter_map(|s| s.split_once(&close).map(|(text, _)| text)).collect()
+}
+
+/// A WAL segment as stored, `<generation>/wal/<index>/<start>-<end>-<time>.wal`.
+/// Every time the WAL starts over, the index goes up, and `start` and `end`
+/// are offsets into the WAL as it was.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct Segment {
+    index: u32,
+    start: u64,
+    end: u64,
+    shipped_at: i64,
+}
+
+impl Segment {
+    fn key(&self, generation: &str) -> String {
+        format!("{}/wal/{:08x}/{:016x}-{:016x}-{}.wal", generation, self.index, self.start, self.end, self.shipped_at)
+    }
+
+    fn parse(key: &str) -> Option<(&str, Self)> {
+        let (generation, rest) = key.split_once("/wal/")?;
+        let (index, name) = rest.split_once('/')?;
+        let mut parts = name.strip_suffix(".wal")?.splitn(3, '-');
+        Some((generation, Self {
+            index: u32::from_str_radix(index, 16).ok()?,
+            start: u64::from_str_radix(parts.next()?, 16).ok()?,
+            end: u64::from_str_radix(parts.next()?, 16).ok()?,
+            shipped_at: parts.next()?.parse().ok()?,
+        }))
+    }
+}
+
+/// Generations are named after when they started, so they sort that way.
+fn new_generation() -> String {
+    format!("{}-{}", Utc::now().format("%Y%m%dT%H%M%SZ"), &random_hex()[..8])
+}
+
+fn generation_start(generation: &str) -> Option<DateTime<Utc>> {
+    let stamp = generation.split('-').next()?;
+    NaiveDateTime::parse_from_str(stamp, "%Y%m%dT%H%M%SZ").ok().map(|t| Utc.from_utc_datetime(&t))
+}
+
+/// The part of a WAL that has been committed past some offset.
+struct Committed {
+    salt: [u8; 8],
+    /// Where this read started, 0 when it includes the header.
+    start: u64,
+    bytes: Vec<u8>,
+}
+
+impl Committed {
+    fn end(&self) -> u64 {
+        self.start + self.bytes.len() as u64
+    }
+
+    /// Whether there's no frame in it to replay, just a header at most.
+    fn is_empty(&self) -> bool {
+        self.end() <= self.start.max(WAL_HEADER_LEN)
+    }
+}
+
+/// Reads what was committed to the WAL at `path` from `offset` on, or from
+/// the start when the WAL has started over since `salt`. Only complete
+/// transactions are returned.
+async fn read_committed(path: &Path, salt: Option<[u8; 8]>, offset: u64) -> anyhow::Result<Option<Committed>> {
+    let mut file = match tokio::fs::File::open(path).await {
+        Ok(f) => f,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let mut header = [0; WAL_HEADER_LEN as usize];
+    if file.read_exact(&mut header).await.is_err() {
+        // empty since the last truncating checkpoint
+        return Ok(None);
+    }
+    // the largest page size doesn't fit in 16 bits, so it's written as 1
+    let page_size = match u32::from_be_bytes(header[8..12].try_into()?) {
+        1 => 65536,
+        size => size as u64,
+    };
+    let wal_salt: [u8; 8] = header[16..24].try_into()?;
+
+    let start = if salt == Some(wal_salt) { offset } else { 0 };
+    file.seek(SeekFrom::Start(start.max(WAL_HEADER_LEN))).await?;
+    let mut frames = Vec::new();
+    file.read_to_end(&mut frames).await?;
+
+    // frames left over from before the WAL started over have another salt
+    let frame_len = (FRAME_HEADER_LEN + page_size) as usize;
+    let mut committed = 0;
+    for (i, frame) in frames.chunks_exact(frame_len).enumerate() {
+        if frame[8..16] != wal_salt {
+            break;
+        }
+        if frame[4..8] != [0; 4] {
+            committed = (i + 1) * frame_len;
+        }
+    }
+    frames.truncate(committed);
+
+    let bytes = if start == 0 { [&header[..], &frames].concat() } else { frames };
+    Ok(Some(Committed { salt: wal_salt, start, bytes }))
+}
+
+/// Ships the database and every change to it to the bucket, the way
+/// Litestream does: a snapshot starts a generation, then the WAL follows in
+/// segments. Automatic checkpoints are off so no change is folded into the
+/// database before it's shipped; checkpoints happen here instead.
+struct Replicator {
+    bucket: Bucket,
+    db: SqlitePool,
+    /// Held open so the WAL is never checkpointed away when the pool's last
+    /// connection closes, and used to keep writers out while reading.
+    conn: SqliteConnection,
+    db_path: PathBuf,
+    wal_path: PathBuf,
+    generation: Option<(String, Instant)>,
+    salt: Option<[u8; 8]>,
+    index: u32,
+    offset: u64,
+    checkpointed: u64,
+    sync_interval: Duration,
+    snapshot_interval: Duration,
+    retention: Duration,
+}
+
+/// Starts replicating the database to `REPLICA_URL`, if it's set.
+pub async fn start(db: &SqlitePool, config: &Config) -> anyhow::Result<()> {
+    let bucket = match Bucket::from_config(config)? {
+        Some(b) => b,
+        None => return Ok(()),
+    };
+    let options = SqliteConnectOptions::from_str(&config.database_url)?.pragma("wal_autocheckpoint", "0");
+    let mut conn = options.connect().await?;
+    // the file the connection actually opened, whatever form the URL took
+    let db_path = PathBuf::from(sqlx::query_scalar::<_, String>("SELECT file FROM pragma_database_list WHERE name = 'main'")
+    .fetch_one(&mut conn).await?);
+    if db_path.as_os_str().is_empty() {
+        anyhow::bail!("can't replicate an in-memory database");
+    }
+    let mut wal_path = db_path.clone().into_os_string();
+    wal_path.push("-wal");
+
+    let mut replicator = Replicator {
+        bucket,
+        db: db.clone(),
+        conn,
+        db_path,
+        wal_path: wal_path.into(),
+        generation: None,
+        salt: None,
+        index: 0,
+        offset: 0,
+        checkpointed: 0,
+        sync_interval: config.replica_sync_interval,
+        snapshot_interval: config.replica_snapshot_interval,
+        retention: config.replica_retention,
+    };
+
+    tracing::info!("Replicating the database to {}", config.replica_url.as_deref().unwrap_or_default());
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(replicator.sync_interval);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            if let Err(e) = replicator.sync().await {
+                tracing::error!("Replication failed: {}", e);
+            }
+        }
+    });
+    Ok(())
+}
+
+impl Replicator {
+    async fn sync(&mut self) -> anyhow::Result<()> {
+        let due = self.generation.as_ref().map_or(true, |(_, started)| started.elapsed() >= self.snapshot_interval);
+        if due {
+            self.snapshot().await?;
+        }
+
+        self.ship_pending().await?;
+        if self.offset >= CHECKPOINT_SIZE && self.offset != self.checkpointed {
+            self.checkpoint().await?;
+        }
+        Ok(())
+    }
+
+    /// Reads what was committed since the last segment, with writers kept out
+    /// just long enough to read a consistent WAL.
+    async fn read(&mut self) -> anyhow::Result<Option<Committed>> {
+        sqlx::query("BEGIN IMMEDIATE").execute(&mut self.conn).await?;
+        let res = read_committed(&self.wal_path, self.salt, self.offset).await;
+        sqlx::query("COMMIT").execute(&mut self.conn).await?;
+        res
+    }
+
+    async fn ship_pending(&mut self) -> anyhow::Result<()> {
+        match self.read().await? {
+            Some(committed) => self.ship(committed).await,
+            None => Ok(()),
+        }
+    }
+
+    async fn ship(&mut self, committed: Committed) -> anyhow::Result<()> {
+        let generation = match &self.generation {
+            Some((g, _)) => g.clone(),
+            None => return Ok(()),
+        };
+        let restarted = self.salt != Some(committed.salt);
+        // a WAL that only has its header has nothing to replay yet
+        if committed.is_empty() {
+            return Ok(());
+        }
+
+        let index = if restarted && self.salt.is_some() { self.index + 1 } else { self.index };
+        let segment = Segment { index, start: committed.start, end: committed.end(), shipped_at: Utc::now().timestamp() };
+        self.bucket.put(&segment.key(&generation), committed.bytes).await?;
+
+        if restarted {
+            self.checkpointed = 0;
+        }
+        self.salt = Some(committed.salt);
+        self.index = index;
+        self.offset = segment.end;
+        Ok(())
+    }
+
+    /// Folds the WAL into the database so it can start over, once everything
+    /// in it is shipped. Shipping happens while writers carry on; they're only
+    /// kept out to make sure nothing was written since, or their changes could
+    /// be checkpointed without being shipped.
+    async fn checkpoint(&mut self) -> anyhow::Result<()> {
+        for _ in 0..CHECKPOINT_ATTEMPTS {
+            self.ship_pending().await?;
+
+            sqlx::query("BEGIN IMMEDIATE").execute(&mut self.conn).await?;
+            let res = async {
+                let written = read_committed(&self.wal_path, self.salt, self.offset).await?;
+                if written.map_or(false, |c| !c.is_empty()) {
+                    return Ok(false);
+                }
+                // from another connection, this one's in a transaction
+                sqlx::query("PRAGMA wal_checkpoint(PASSIVE)").execute(&self.db).await?;
+                Ok::<_, anyhow::Error>(true)
+            }.await;
+            sqlx::query("COMMIT").execute(&mut self.conn).await?;
+
+            if res? {
+                self.checkpointed = self.offset;
+                return Ok(());
+            }
+        }
+        tracing::debug!("Writers kept the WAL busy, checkpointing at the next sync instead");
+        Ok(())
+    }
+
+    /// Starts a new generation with a copy of the database, taken while the
+    /// WAL is empty so its pages are exactly those the WAL will refer to.
+    /// `VACUUM INTO` would lay the pages out anew, so the file is copied as
+    /// is instead, from within a read transaction that began with an empty
+    /// WAL: nothing can be checkpointed into the file while it lasts, and
+    /// writers carry on in the WAL meanwhile.
+    async fn snapshot(&mut self) -> anyhow::Result<()> {
+        // the previous generation gets what it can still use first
+        self.ship_pending().await?;
+
+        let generation = new_generation();
+        let mut tmp = self.db_path.clone().into_os_string();
+        tmp.push(".replica");
+        let tmp = PathBuf::from(tmp);
+
+        for _ in 0..SNAPSHOT_ATTEMPTS {
+            let (busy, _, _) = sqlx::query_as::<_, (i64, i64, i64)>("PRAGMA wal_checkpoint(TRUNCATE)")
+            .fetch_one(&mut self.conn).await?;
+            if busy != 0 {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+
+            sqlx::query("BEGIN").execute(&mut self.conn).await?;
+            // the transaction only takes its read snapshot once it reads
+            let res = async {
+                sqlx::query("SELECT COUNT(*) FROM sqlite_master").execute(&mut self.conn).await?;
+                // the WAL only starts over once nobody reads from it, so if
+                // it's empty now, it was when the snapshot was taken
+                let wal_len = tokio::fs::metadata(&self.wal_path).await.map(|m| m.len()).unwrap_or(0);
+                if wal_len != 0 {
+                    return Ok(false);
+                }
+                tokio::fs::copy(&self.db_path, &tmp).await?;
+                Ok::<_, anyhow::Error>(true)
+            }.await;
+            sqlx::query("COMMIT").execute(&mut self.conn).await?;
+
+            let res = match res {
+                // someone wrote between the checkpoint and the snapshot
+                Ok(false) => continue,
+                Ok(true) => self.bucket.put_file(&format!("{}/{}", generation, SNAPSHOT_KEY), &tmp).await,
+                Err(e) => Err(e),
+            };
+            let _ = tokio::fs::remove_file(&tmp).await;
+            res?;
+
+            tracing::info!("Started replica generation {}", generation);
+            self.generation = Some((generation, Instant::now()));
+            self.salt = None;
+            self.index = 0;
+            self.offset = 0;
+            self.checkpointed = 0;
+
+            if let Err(e) = self.prune().await {
+                tracing::warn!("Couldn't remove old replica generations: {}", e);
+            }
+            return Ok(());
+        }
+        anyhow::bail!("the database was never idle long enough for a snapshot")
+    }
+
+    /// Removes generations that started longer than `REPLICA_RETENTION_DAYS`
+    /// ago, except the newest that did, which covers that far back.
+    async fn prune(&self) -> anyhow::Result<()> {
+        let keys = self.bucket.list().await?;
+        let cutoff = Utc::now() - chrono::Duration::from_std(self.retention)?;
+        let generations = by_generation(&keys);
+        let old: Vec<&str> = generations.keys()
+            .filter(|g| generation_start(g).map_or(false, |t| t < cutoff))
+            .copied()
+            .collect();
+
+        // the newest old one is the one the cutoff falls into
+        for generation in old.iter().rev().skip(1) {
+            for key in &generations[generation] {
+                self.bucket.delete(key).await?;
+            }
+            tracing::info!("Removed replica generation {}", generation);
+        }
+        Ok(())
+    }
+}
+
+/// Keys grouped by the generation they belong to, oldest first.
+fn by_generation(keys: &[String]) -> BTreeMap<&str, Vec<&str>> {
+    let mut generations: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+    for key in keys {
+        if let Some((generation, _)) = key.split_once('/') {
+            generations.entry(generation).or_default().push(key);
+        }
+    }
+    generations
+}
+
+/// Prints every generation in the bucket, with when it started and until
+/// when it can be restored to.
+pub async fn list_generations(config: &Config) -> anyhow::Result<()> {
+    let bucket = Bucket::from_config(config)?.ok_or_else(|| anyhow::anyhow!("REPLICA_URL isn't set"))?;
+    let keys = bucket.list().await?;
+    for (generation, keys) in by_generation(&keys) {
+        let started = generation_start(generation).map(|t| t.to_rfc3339()).unwrap_or_default();
+        let latest = keys.iter().filter_map(|k| Segment::parse(k)).map(|(_, s)| s.shipped_at).max()
+            .and_then(|t| Utc.timestamp_opt(t, 0).single())
+            .map(|t| t.to_rfc3339())
+            .unwrap_or_else(|| started.clone());
+        println!("{}\t{}\t{}", generation, started, latest);
+    }
+    Ok(())
+}
+
+/// Restores the database to `target` as it was at `until`, or as recent as
+/// possible, from `generation` or the latest one that started by then.
+pub async fn restore(config: &Config, target: &Path, generation: Option<&str>, until: Option<DateTime<Utc>>) -> anyhow::Result<()> {
+    if tokio::fs::try_exists(target).await? {
+        anyhow::bail!("{} already exists", target.display());
+    }
+    let bucket = Bucket::from_config(config)?.ok_or_else(|| anyhow::anyhow!("REPLICA_URL isn't set"))?;
+    let keys = bucket.list().await?;
+    let generations = by_generation(&keys);
+
+    let generation = match generation {
+        Some(g) => *generations.keys().find(|k| **k == g).ok_or_else(|| anyhow::anyhow!("no generation {}", g))?,
+        None => generations.keys().rev()
+            .filter(|g| generations[*g].iter().any(|k| k.ends_with(SNAPSHOT_KEY)))
+            .find(|g| until.map_or(true, |u| generation_start(g).map_or(false, |t| t <= u)))
+            .copied()
+            .ok_or_else(|| anyhow::anyhow!("no generation to restore from"))?,
+    };
+
+    let mut segments: Vec<Segment> = generations[generation].iter()
+        .filter_map(|k| Segment::parse(k))
+        .map(|(_, s)| s)
+        .filter(|s| until.map_or(true, |u| s.shipped_at <= u.timestamp()))
+        .collect();
+    segments.sort();
+
+    tracing::info!("Restoring generation {} to {}", generation, target.display());
+    let snapshot = bucket.get(&format!("{}/{}", generation, SNAPSHOT_KEY)).await?;
+    tokio::fs::write(target, snapshot).await?;
+
+    let mut wal_path = target.as_os_str().to_owned();
+    wal_path.push("-wal");
+    let mut applied = 0;
+    let mut restored_to = None;
+    let mut segments = segments.into_iter().peekable();
+    while let Some(first) = segments.peek().cloned() {
+        // each index is one run of the WAL, replayed by checkpointing it
+        let mut wal = Vec::new();
+        let mut gap = false;
+        while let Some(segment) = segments.next_if(|s| s.index == first.index) {
+            if segment.start != wal.len() as u64 {
+                tracing::warn!("Segment {} of WAL {} is missing, stopping there", wal.len(), first.index);
+                gap = true;
+                break;
+            }
+            wal.extend(bucket.get(&segment.key(generation)).await?);
+            restored_to = Some(segment.shipped_at);
+            applied += 1;
+        }
+
+        tokio::fs::write(&wal_path, &wal).await?;
+        let mut conn = SqliteConnectOptions::new().filename(target).connect().await?;
+        sqlx::query("PRAGMA wal_checkpoint(TRUNCATE)").execute(&mut conn).await?;
+        conn.close().await?;
+        if gap {
+            break;
+        }
+    }
+
+    let restored_to = restored_to.or_else(|| generation_start(generation).map(|t| t.timestamp()))
+        .and_then(|t| Utc.timestamp_opt(t, 0).single())
+        .map(|t| t.to_rfc3339())
+        .unwrap_or_default();
+    println!("Restored {} from generation {} and {} WAL segments, as of {}", target.display(), generation, applied, restored_to);
+    Ok(())
+}
@@ -0,0 +1,187 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    str::FromStr,
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, Instant},
+};
+
+use axum::http::StatusCode;
+
+use crate::{config::Config, AppState};
+
+/// How long a DNSBL gets to answer before the address counts as unlisted there.
+const LOOKUP_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// What to do with anonymous uploads from listed addresses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReputationPolicy {
+    Reject,
+    /// Store the paste unlisted and add it to the moderation queue, without
+    /// telling the uploader.
+    Flag,
+}
+
+impl FromStr for ReputationPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reject" => Ok(Self::Reject),
+            "flag" => Ok(Self::Flag),
+            _ => anyhow::bail!("unknown IP reputation policy {:?}, expected reject or flag", s),
+        }
+    }
+}
+
+/// An address or CIDR range from the blocklist file.
+#[derive(Debug, Clone, Copy)]
+struct Network {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Network {
+    fn parse(s: &str) -> Option<Self> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((a, p)) => (a.parse::<IpAddr>().ok()?, Some(p.parse::<u8>().ok()?)),
+            None => (s.parse::<IpAddr>().ok()?, None),
+        };
+        let bits = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = prefix.unwrap_or(bits);
+        (prefix <= bits).then_some(Self { addr, prefix })
+    }
+
+    fn contains(&self, ip: IpAddr) -> bool {
+        let (net, ip, bits) = match (self.addr, canonical(ip)) {
+            (IpAddr::V4(n), IpAddr::V4(i)) => (u128::from(u32::from(n)), u128::from(u32::from(i)), 32),
+            (IpAddr::V6(n), IpAddr::V6(i)) => (u128::from(n), u128::from(i), 128),
+            _ => return false,
+        };
+        let shift = bits - u32::from(self.prefix);
+        shift >= bits || net >> shift == ip >> shift
+    }
+}
+
+/// Where anonymous uploaders' addresses are looked up: the `IP_BLOCKLIST`
+/// file, then the `DNSBL` zones. Verdicts are cached for
+/// `DNSBL_CACHE_SECONDS`, listed or not.
+#[derive(Debug, Default)]
+pub struct Reputation {
+    blocklist: RwLock<Arc<Vec<Network>>>,
+    verdicts: Mutex<HashMap<IpAddr, (Instant, Option<String>)>>,
+}
+
+impl Reputation {
+    pub fn from_config(config: &Config) -> Self {
+        let reputation = Self::default();
+        reputation.reconfigure(config);
+        reputation
+    }
+
+    /// Re-reads the blocklist file and forgets cached verdicts, which may
+    /// have come from zones no longer configured. A blocklist that can't be
+    /// read leaves the one loaded before in place.
+    pub fn reconfigure(&self, config: &Config) {
+        let blocklist = match &config.ip_blocklist {
+            Some(path) => match load(path) {
+                Ok(b) => b,
+                Err(e) => {
+                    tracing::error!("Couldn't load the IP blocklist {}: {}", path, e);
+                    return;
+                },
+            },
+            None => Vec::new(),
+        };
+        *self.blocklist.write().unwrap_or_else(|e| e.into_inner()) = Arc::new(blocklist);
+        self.verdicts.lock().unwrap().clear();
+    }
+
+    fn cached(&self, ip: IpAddr, ttl: Duration) -> Option<Option<String>> {
+        let verdicts = self.verdicts.lock().unwrap();
+        verdicts.get(&ip).filter(|(at, _)| at.elapsed() < ttl).map(|(_, listed)| listed.clone())
+    }
+
+    fn remember(&self, ip: IpAddr, listed: Option<String>, ttl: Duration) {
+        let mut verdicts = self.verdicts.lock().unwrap();
+        verdicts.retain(|_, (at, _)| at.elapsed() < ttl);
+        verdicts.insert(ip, (Instant::now(), listed));
+    }
+
+    /// The list `ip` is on, if any.
+    async fn lookup(&self, ip: IpAddr, zones: &[String], ttl: Duration) -> Option<String> {
+        if let Some(listed) = self.cached(ip, ttl) {
+            return listed;
+        }
+
+        let blocklist = self.blocklist.read().map(|b| b.clone()).unwrap_or_else(|e| e.into_inner().clone());
+        let listed = if blocklist.iter().any(|n| n.contains(ip)) {
+            Some("blocklist".to_string())
+        } else {
+            let lookups = zones.iter().map(|zone| async move { is_listed(ip, zone).await.then(|| zone.clone()) });
+            futures::future::join_all(lookups).await.into_iter().flatten().next()
+        };
+
+        self.remember(ip, listed.clone(), ttl);
+        listed
+    }
+}
+
+/// Checks the address of an anonymous upload, failing with the status to
+/// reject it with, or returning the list it's on when it should be stored
+/// but flagged.
+pub async fn screen(state: &AppState, ip: IpAddr) -> Result<Option<String>, StatusCode> {
+    let config = state.config();
+    if config.dnsbl.is_empty() && config.ip_blocklist.is_none() {
+        return Ok(None);
+    }
+
+    let listed = match state.reputation.lookup(ip, &config.dnsbl, config.dnsbl_cache).await {
+        Some(l) => l,
+        None => return Ok(None),
+    };
+    tracing::info!("Anonymous upload from {}, which is listed on {}", ip, listed);
+    match config.ip_reputation_policy {
+        ReputationPolicy::Reject => Err(StatusCode::FORBIDDEN),
+        ReputationPolicy::Flag => Ok(Some(listed)),
+    }
+}
+
+fn load(path: &str) -> anyhow::Result<Vec<Network>> {
+    let mut networks = Vec::new();
+    for (i, line) in std::fs::read_to_string(path)?.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.is_empty() {
+            continue;
+        }
+        networks.push(Network::parse(line).ok_or_else(|| anyhow::anyhow!("line {}: invalid address {:?}", i + 1, line))?);
+    }
+    Ok(networks)
+}
+
+/// `ip`, unless it's an IPv4-mapped IPv6 address, which is looked up as the
+/// IPv4 address it maps.
+fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+        v4 => v4,
+    }
+}
+
+/// Whether `zone` lists `ip`, which it says by resolving the address'
+/// reversed octets, or nibbles for IPv6, under it.
+async fn is_listed(ip: IpAddr, zone: &str) -> bool {
+    let reversed = match canonical(ip) {
+        IpAddr::V4(v4) => v4.octets().iter().rev().map(u8::to_string).collect::<Vec<_>>().join("."),
+        IpAddr::V6(v6) => v6.octets().iter().rev().map(|b| format!("{:x}.{:x}", b & 0xf, b >> 4)).collect::<Vec<_>>().join("."),
+    };
+    let query = format!("{}.{}", reversed, zone.trim_end_matches('.'));
+
+    let res = tokio::time::timeout(LOOKUP_TIMEOUT, tokio::net::lookup_host((query.as_str(), 0))).await;
+    match res {
+        Ok(Ok(mut addrs)) => addrs.next().is_some(),
+        // NXDOMAIN, which is how a zone says the address isn't listed, or
+        // the zone didn't answer in time
+        _ => false,
+    }
+}
@@ -0,0 +1,47 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::State,
+    http::{header, HeaderValue, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+
+use crate::{paste::ServedPaste, AppState};
+
+const NOINDEX: HeaderValue = HeaderValue::from_static("noindex");
+
+/// Serves `ROBOTS_TXT` if set, or rules keeping crawlers out of the API and,
+/// when indexing is off, everything else too.
+pub async fn robots_txt(State(state): State<Arc<AppState>>) -> Result<Response, StatusCode> {
+    let body = match &state.config().robots_txt {
+        Some(path) => tokio::fs::read_to_string(path).await.map_err(|e| {
+            tracing::error!("Couldn't read {}: {}", path, e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?,
+        None if state.config().allow_indexing => "User-agent: *\nDisallow: /admin\nDisallow: /api/\nDisallow: /d/\n".to_string(),
+        None => "User-agent: *\nDisallow: /\n".to_string(),
+    };
+
+    Ok(([(header::CONTENT_TYPE, "text/plain; charset=utf-8")], body).into_response())
+}
+
+/// Marks every response as not to be indexed when indexing is off.
+pub async fn noindex_all<B>(State(state): State<Arc<AppState>>, req: Request<B>, next: Next<B>) -> Response {
+    let mut response = next.run(req).await;
+    if !state.config().allow_indexing {
+        response.headers_mut().insert("x-robots-tag", NOINDEX);
+    }
+    response
+}
+
+/// Marks unlisted pastes as not to be indexed.
+pub async fn noindex_unlisted<B>(req: Request<B>, next: Next<B>) -> Response {
+    let unlisted = req.extensions().get::<ServedPaste>().map_or(false, |p| p.unlisted);
+    let mut response = next.run(req).await;
+
+    if response.status().is_success() && unlisted {
+        response.headers_mut().insert("x-robots-tag", NOINDEX);
+    }
+    response
+}
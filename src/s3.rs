@@ -0,0 +1,311 @@
+use std::{net::SocketAddr, sync::Arc};
+
+use axum::{
+    body::Body,
+    extract::{ConnectInfo, Path, State},
+    http::{header, HeaderMap, Method, Request, StatusCode},
+    response::{IntoResponse, Response},
+};
+use chrono::prelude::*;
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    auth::authenticate_signed,
+    db::TokenInfo,
+    name,
+    paste::{self, declared_length, record_upload, stream_to_staging},
+    session::{hex, same},
+    staging,
+    AppState,
+};
+
+/// Where the API is mounted, so clients are pointed at `<base url>/s3` with
+/// path-style addressing.
+pub const PREFIX: &str = "/s3";
+
+/// How far a request's signing time may be from ours, as S3 allows.
+const MAX_SKEW_SECONDS: i64 = 15 * 60;
+
+/// Payload hash clients send when they don't sign the body.
+const UNSIGNED_PAYLOAD: &str = "UNSIGNED-PAYLOAD";
+
+/// Handles `/s3/<bucket>` itself, which clients poke to check the bucket
+/// exists before using it.
+pub async fn bucket(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path(bucket): Path<String>,
+    req: Request<Body>,
+) -> Response {
+    let res = match check(&state, addr, &bucket, &req).await {
+        Ok(_) if req.method() == Method::HEAD => Ok(StatusCode::OK.into_response()),
+        Ok(_) => Err(error(StatusCode::NOT_IMPLEMENTED, "NotImplemented", "Only objects can be put, got and deleted")),
+        Err(e) => Err(e),
+    };
+    res.unwrap_or_else(|e| e)
+}
+
+/// A minimal S3-compatible API over a single bucket: `PUT` stores an object
+/// as a paste, `GET` and `HEAD` serve it and `DELETE` removes it. Requests
+/// are signed with AWS Signature Version 4, the access key id being a token's
+/// id and the secret the token itself. Keys are private to the token that put
+/// them.
+pub async fn object(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path((bucket, key)): Path<(String, String)>,
+    req: Request<Body>,
+) -> Response {
+    let user = match check(&state, addr, &bucket, &req).await {
+        Ok(u) => u,
+        Err(e) => return e,
+    };
+    let owner = match user.id {
+        Some(id) => id,
+        None => return error(StatusCode::FORBIDDEN, "AccessDenied", "Access denied"),
+    };
+
+    match *req.method() {
+        Method::PUT => put(&state, &user, owner, addr, &key, req).await,
+        Method::GET | Method::HEAD => get(&state, owner, &key, req).await,
+        Method::DELETE => delete(&state, &user, owner, addr, &key).await,
+        _ => error(StatusCode::METHOD_NOT_ALLOWED, "MethodNotAllowed", "The method is not allowed for this resource"),
+    }
+}
+
+/// Makes sure the API is on, `bucket` is ours and the request is signed by a
+/// token, returning that token.
+async fn check(state: &AppState, addr: SocketAddr, bucket: &str, req: &Request<Body>) -> Result<TokenInfo, Response> {
+    if state.config().s3_bucket.as_deref() != Some(bucket) {
+        return Err(error(StatusCode::NOT_FOUND, "NoSuchBucket", "The specified bucket does not exist"));
+    }
+
+    let auth = req.headers().get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(Authorization::parse)
+        .ok_or_else(|| error(StatusCode::FORBIDDEN, "AccessDenied", "Requests have to be signed with AWS4-HMAC-SHA256"))?;
+
+    let signed_at = header_str(req.headers(), "x-amz-date")
+        .and_then(|d| NaiveDateTime::parse_from_str(d, "%Y%m%dT%H%M%SZ").ok())
+        .ok_or_else(|| error(StatusCode::BAD_REQUEST, "AuthorizationHeaderMalformed", "x-amz-date is missing or invalid"))?;
+    if (Utc::now().naive_utc() - signed_at).num_seconds().abs() > MAX_SKEW_SECONDS {
+        return Err(error(StatusCode::FORBIDDEN, "RequestTimeTooSkewed", "The difference between the request time and the server's time is too large"));
+    }
+
+    let payload_hash = header_str(req.headers(), "x-amz-content-sha256").unwrap_or(UNSIGNED_PAYLOAD);
+    if payload_hash.starts_with("STREAMING-") {
+        return Err(error(StatusCode::NOT_IMPLEMENTED, "NotImplemented", "Chunked payload signing isn't supported"));
+    }
+
+    let string_to_sign = format!(
+        "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+        signed_at.format("%Y%m%dT%H%M%SZ"), auth.scope(), hex(&Sha256::digest(canonical_request(req, &auth, payload_hash))),
+    );
+
+    let verify = |secret: &str| same(&auth.sign(secret, &string_to_sign), auth.signature);
+    match authenticate_signed(state, addr.ip(), auth.key_id, verify).await {
+        Ok(user) => Ok(user),
+        Err(StatusCode::TOO_MANY_REQUESTS) => Err(error(StatusCode::SERVICE_UNAVAILABLE, "SlowDown", "Too many failed attempts")),
+        Err(StatusCode::UNAUTHORIZED) => Err(error(StatusCode::FORBIDDEN, "SignatureDoesNotMatch", "The request signature we calculated does not match the signature you provided")),
+        Err(_) => Err(error(StatusCode::INTERNAL_SERVER_ERROR, "InternalError", "We encountered an internal error")),
+    }
+}
+
+/// The parts of an `AWS4-HMAC-SHA256 Credential=<key id>/<date>/<region>/s3/aws4_request,
+/// SignedHeaders=..., Signature=...` header.
+struct Authorization<'a> {
+    key_id: &'a str,
+    date: &'a str,
+    region: &'a str,
+    service: &'a str,
+    signed_headers: &'a str,
+    signature: &'a str,
+}
+
+impl<'a> Authorization<'a> {
+    fn parse(value: &'a str) -> Option<Self> {
+        let params = value.strip_prefix("AWS4-HMAC-SHA256 ")?;
+        let (mut credential, mut signed_headers, mut signature) = (None, None, None);
+        for param in params.split(',') {
+            match param.trim().split_once('=')? {
+                ("Credential", v) => credential = Some(v),
+                ("SignedHeaders", v) => signed_headers = Some(v),
+                ("Signature", v) => signature = Some(v),
+                _ => {},
+            }
+        }
+
+        let mut credential = credential?.split('/');
+        let auth = Self {
+            key_id: credential.next()?,
+            date: credential.next()?,
+            region: credential.next()?,
+            service: credential.next()?,
+            signed_headers: signed_headers?,
+            signature: signature?,
+        };
+        (credential.next()? == "aws4_request").then_some(auth)
+    }
+
+    fn scope(&self) -> String {
+        format!("{}/{}/{}/aws4_request", self.date, self.region, self.service)
+    }
+
+    /// Signs `string_to_sign` with the key derived from `secret` for this
+    /// request's scope.
+    fn sign(&self, secret: &str, string_to_sign: &str) -> String {
+        let key = [self.date, self.region, self.service, "aws4_request"].into_iter()
+            .fold(format!("AWS4{}", secret).into_bytes(), |key, part| hmac(&key, part));
+        hex(&hmac(&key, string_to_sign))
+    }
+}
+
+pub fn hmac(key: &[u8], data: &str) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any size");
+    mac.update(data.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// The request as SigV4 sees it. Path and query are taken as the client
+/// encoded them, which every SDK does the canonical way.
+fn canonical_request(req: &Request<Body>, auth: &Authorization, payload_hash: &str) -> String {
+    let mut query: Vec<String> = req.uri().query().unwrap_or_default()
+        .split('&')
+        .filter(|p| !p.is_empty())
+        .map(|p| if p.contains('=') { p.to_string() } else { format!("{}=", p) })
+        .collect();
+    query.sort();
+
+    let headers: String = auth.signed_headers.split(';')
+        .map(|name| {
+            let values: Vec<String> = req.headers().get_all(name).iter()
+                .map(|v| String::from_utf8_lossy(v.as_bytes()).split_whitespace().collect::<Vec<_>>().join(" "))
+                .collect();
+            format!("{}:{}\n", name, values.join(","))
+        })
+        .collect();
+
+    format!(
+        "{}\n{}\n{}\n{}\n{}\n{}",
+        req.method(), req.uri().path(), query.join("&"), headers, auth.signed_headers, payload_hash,
+    )
+}
+
+fn header_str<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers.get(name)?.to_str().ok()
+}
+
+/// An error response the way S3 clients expect it.
+fn error(status: StatusCode, code: &str, message: &str) -> Response {
+    let xml = format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<Error><Code>{}</Code><Message>{}</Message></Error>\n",
+        code, tera::escape_html(message),
+    );
+    (status, [(header::CONTENT_TYPE, "application/xml")], xml).into_response()
+}
+
+/// The paste `key` was put as by `owner`.
+async fn find(state: &AppState, owner: i64, key: &str) -> Result<Option<(String, String)>, Response> {
+    sqlx::query_as::<_, (String, String)>("SELECT pastes.id, pastes.filename FROM s3_objects
+        JOIN pastes ON pastes.filename = s3_objects.filename
+        WHERE s3_objects.owner = $1 AND s3_objects.key = $2")
+    .bind(owner)
+    .bind(key)
+    .fetch_optional(&state.db).await
+    .map_err(|_| error(StatusCode::INTERNAL_SERVER_ERROR, "InternalError", "We encountered an internal error"))
+}
+
+async fn put(state: &AppState, user: &TokenInfo, owner: i64, addr: SocketAddr, key: &str, req: Request<Body>) -> Response {
+    let limit = user.max_upload_size.map(|l| l.max(0) as u64).unwrap_or(u64::MAX);
+    if declared_length(req.headers()).map_or(false, |l| l > limit) {
+        return error(StatusCode::BAD_REQUEST, "EntityTooLarge", "Your proposed upload exceeds the maximum allowed size");
+    }
+    let expected = header_str(req.headers(), "x-amz-content-sha256").filter(|h| *h != UNSIGNED_PAYLOAD).map(str::to_string);
+
+    // keys can have slashes, the paste is named after the last part
+    let id = uuid::Uuid::new_v4();
+    let name = match name::unused(&state.db, key.rsplit('/').next().unwrap_or(key)).await {
+        Ok(n) => n,
+        Err(_) => return error(StatusCode::INTERNAL_SERVER_ERROR, "InternalError", "We encountered an internal error"),
+    };
+    let staged = staging::upload(&id);
+    let written = match stream_to_staging(&staged, req.into_body(), limit, state.config().durability).await {
+        Ok(w) if w.size <= limit => w,
+        res => {
+            let _ = tokio::fs::remove_file(staging::path(&staged)).await;
+            return match res {
+                Ok(_) => error(StatusCode::BAD_REQUEST, "EntityTooLarge", "Your proposed upload exceeds the maximum allowed size"),
+                Err(_) => error(StatusCode::INTERNAL_SERVER_ERROR, "InternalError", "We encountered an internal error"),
+            };
+        },
+    };
+
+    if expected.map_or(false, |e| e != written.sha256) {
+        let _ = tokio::fs::remove_file(staging::path(&staged)).await;
+        return error(StatusCode::BAD_REQUEST, "XAmzContentSHA256Mismatch", "The provided x-amz-content-sha256 header does not match what was computed");
+    }
+
+    let etag = format!("\"{}\"", written.sha256);
+    let replaced = match find(state, owner, key).await {
+        Ok(r) => r,
+        Err(e) => return e,
+    };
+    let filename = match record_upload(state, Some(user), addr.ip(), id, name, written).await {
+        Ok(f) => f,
+        Err(status) => return error(status, "InvalidRequest", status.canonical_reason().unwrap_or("Upload refused")),
+    };
+
+    let res = sqlx::query("INSERT OR REPLACE INTO s3_objects (owner, key, filename) VALUES ($1, $2, $3)")
+    .bind(owner)
+    .bind(key)
+    .bind(&filename)
+    .execute(&state.db).await;
+    if res.is_err() {
+        return error(StatusCode::INTERNAL_SERVER_ERROR, "InternalError", "We encountered an internal error");
+    }
+
+    // objects are overwritten, so the paste that was there goes away
+    if let Some((old, _)) = replaced {
+        if let Err(status) = paste::delete(state, user, addr.ip(), &old).await {
+            tracing::error!("Couldn't remove the paste replaced by S3 key {}: {}", key, status);
+        }
+    }
+
+    ([(header::ETAG, etag), (header::LOCATION, format!("/paste/{}", filename))]).into_response()
+}
+
+async fn get(state: &Arc<AppState>, owner: i64, key: &str, req: Request<Body>) -> Response {
+    let filename = match find(state, owner, key).await {
+        Ok(Some((_, f))) => f,
+        Ok(None) => return error(StatusCode::NOT_FOUND, "NoSuchKey", "The specified key does not exist"),
+        Err(e) => return e,
+    };
+    paste::serve(state.clone(), &filename, true, req).await
+}
+
+async fn delete(state: &AppState, user: &TokenInfo, owner: i64, addr: SocketAddr, key: &str) -> Response {
+    let res = sqlx::query_scalar::<_, String>("DELETE FROM s3_objects WHERE owner = $1 AND key = $2 RETURNING filename")
+    .bind(owner)
+    .bind(key)
+    .fetch_optional(&state.db).await;
+
+    let filename = match res {
+        Ok(Some(f)) => f,
+        // deleting a key that isn't there succeeds too
+        Ok(None) => return StatusCode::NO_CONTENT.into_response(),
+        Err(_) => return error(StatusCode::INTERNAL_SERVER_ERROR, "InternalError", "We encountered an internal error"),
+    };
+
+    let id = sqlx::query_scalar::<_, String>("SELECT id FROM pastes WHERE filename = $1")
+    .bind(&filename)
+    .fetch_optional(&state.db).await;
+    match id {
+        Ok(Some(id)) => match paste::delete(state, user, addr.ip(), &id).await {
+            Ok(()) | Err(StatusCode::NOT_FOUND) => StatusCode::NO_CONTENT.into_response(),
+            Err(_) => error(StatusCode::INTERNAL_SERVER_ERROR, "InternalError", "We encountered an internal error"),
+        },
+        Ok(None) => StatusCode::NO_CONTENT.into_response(),
+        Err(_) => error(StatusCode::INTERNAL_SERVER_ERROR, "InternalError", "We encountered an internal error"),
+    }
+}
@@ -0,0 +1,99 @@
+use std::{path::Path, str::FromStr, sync::OnceLock};
+
+use axum::http::StatusCode;
+use regex::Regex;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, BufReader};
+
+use crate::text::is_text;
+
+/// Text beyond this is not scanned, so huge logs don't hold up the upload.
+const MAX_SCAN_SIZE: u64 = 8 * 1024 * 1024;
+
+/// What to do with text uploads that look like they contain credentials.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SecretScan {
+    Off,
+    /// Store the paste, but tell the uploader what was found.
+    Warn,
+    /// Refuse the paste unless the upload is retried with `?force=true`.
+    Confirm,
+    Block,
+}
+
+impl FromStr for SecretScan {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(Self::Off),
+            "warn" => Ok(Self::Warn),
+            "confirm" => Ok(Self::Confirm),
+            "block" => Ok(Self::Block),
+            _ => anyhow::bail!("unknown secret scan mode {:?}, expected off, warn, confirm or block", s),
+        }
+    }
+}
+
+fn patterns() -> &'static [(&'static str, Regex)] {
+    static PATTERNS: OnceLock<Vec<(&'static str, Regex)>> = OnceLock::new();
+    PATTERNS.get_or_init(|| {
+        [
+            ("aws-access-key", r"\b(AKIA|ASIA)[0-9A-Z]{16}\b"),
+            ("private-key", r"-----BEGIN ([A-Z]+ )?PRIVATE KEY-----"),
+            ("bearer-token", r"(?i)\bbearer\s+[A-Za-z0-9\-._~+/]{20,}=*"),
+            ("github-token", r"\b(gh[pousr]_[A-Za-z0-9]{36}|github_pat_[A-Za-z0-9_]{82})\b"),
+            ("slack-token", r"\bxox[abprs]-[A-Za-z0-9-]{10,}"),
+            ("stripe-key", r"\b[sr]k_live_[A-Za-z0-9]{24,}\b"),
+        ]
+        .into_iter()
+        .map(|(name, re)| (name, Regex::new(re).expect("secret patterns are valid")))
+        .collect()
+    })
+}
+
+/// Names of the kinds of credentials found in the text file at `path`.
+async fn scan(path: &Path) -> std::io::Result<Vec<&'static str>> {
+    let mut reader = BufReader::new(tokio::fs::File::open(path).await?.take(MAX_SCAN_SIZE));
+    let mut found = Vec::new();
+    let mut line = Vec::new();
+
+    while reader.read_until(b'\n', &mut line).await? > 0 {
+        let text = String::from_utf8_lossy(&line);
+        for (name, re) in patterns() {
+            if !found.contains(name) && re.is_match(&text) {
+                found.push(*name);
+            }
+        }
+        line.clear();
+    }
+    Ok(found)
+}
+
+/// Scans the upload at `path`, to be stored as `filename`, if it's text and
+/// scanning is on, returning the warnings to pass on when it may be kept.
+pub async fn screen(mode: SecretScan, force: bool, filename: &str, path: &Path) -> Result<Vec<&'static str>, StatusCode> {
+    if mode == SecretScan::Off || !is_text(filename) {
+        return Ok(Vec::new());
+    }
+
+    let found = scan(path).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if !found.is_empty() {
+        tracing::info!("Found what looks like {} in {}", found.join(", "), filename);
+    }
+    check(mode, force, found)
+}
+
+/// Applies `mode` to what a scan `found`, returning the warnings to pass on
+/// when the paste may be stored.
+fn check(mode: SecretScan, force: bool, found: Vec<&'static str>) -> Result<Vec<&'static str>, StatusCode> {
+    if found.is_empty() {
+        return Ok(found);
+    }
+    match mode {
+        SecretScan::Off => Ok(Vec::new()),
+        SecretScan::Warn => Ok(found),
+        SecretScan::Confirm if force => Ok(found),
+        SecretScan::Confirm => Err(StatusCode::CONFLICT),
+        SecretScan::Block => Err(StatusCode::UNPROCESSABLE_ENTITY),
+    }
+}
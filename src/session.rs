@@ -0,0 +1,303 @@
+use std::{net::SocketAddr, sync::Arc};
+
+use axum::{
+    async_trait,
+    extract::{ConnectInfo, Form, FromRequestParts, Multipart, Query, State},
+    http::{header, request::Parts, HeaderMap, StatusCode},
+    response::{IntoResponse, Redirect, Response},
+};
+use chrono::prelude::*;
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use serde::Deserialize;
+use sha2::Sha256;
+use tera::Context;
+
+use crate::{
+    audit,
+    auth::{authenticate, check_totp},
+    base_url::BaseUrl,
+    config::Config,
+    content,
+    db::TokenInfo,
+    ipfs,
+    metadata,
+    namespace::RequestNamespace,
+    paste::{self, Created, UploadOptions},
+    AppState,
+};
+
+const COOKIE: &str = "smolpaste_session";
+
+/// Key the session cookies are signed with.
+pub struct SessionKey(Vec<u8>);
+
+impl std::fmt::Debug for SessionKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("SessionKey(..)")
+    }
+}
+
+impl SessionKey {
+    /// Uses `SESSION_SECRET`, or a random key that logs everyone out on restart.
+    pub fn from_config(config: &Config) -> Self {
+        match &config.session_secret {
+            Some(secret) => Self(secret.as_bytes().to_vec()),
+            None => {
+                let mut key = vec![0; 32];
+                rand::thread_rng().fill_bytes(&mut key);
+                Self(key)
+            },
+        }
+    }
+
+    fn sign(&self, id: &str) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.0).expect("HMAC takes keys of any size");
+        mac.update(id.as_bytes());
+        format!("{}.{}", id, hex(&mac.finalize().into_bytes()))
+    }
+
+    /// A keyed hash of `data` in hex, for values that have to be told apart
+    /// without being kept.
+    pub fn digest(&self, data: &[u8]) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.0).expect("HMAC takes keys of any size");
+        mac.update(data);
+        hex(&mac.finalize().into_bytes())
+    }
+
+    /// The session id in a cookie value, if it was signed by us.
+    fn verify<'a>(&self, value: &'a str) -> Option<&'a str> {
+        let (id, _) = value.split_once('.')?;
+        same(&self.sign(id), value).then_some(id)
+    }
+}
+
+/// A user logged in to the web UI, which uses cookies where the API takes tokens.
+#[derive(Debug, Clone)]
+pub struct Session {
+    pub id: String,
+    pub user: TokenInfo,
+    /// Has to come back with every form, so other sites can't submit them.
+    pub csrf: String,
+}
+
+impl Session {
+    fn check_csrf(&self, csrf: &str) -> Result<(), StatusCode> {
+        if same(&self.csrf, csrf) { Ok(()) } else { Err(StatusCode::FORBIDDEN) }
+    }
+}
+
+#[async_trait]
+impl FromRequestParts<Arc<AppState>> for Session {
+    type Rejection = Redirect;
+
+    async fn from_request_parts(parts: &mut Parts, state: &Arc<AppState>) -> Result<Self, Self::Rejection> {
+        let id = cookie(&parts.headers, COOKIE)
+            .and_then(|v| state.session_key.verify(v))
+            .ok_or_else(|| Redirect::to("/login"))?;
+
+        // sessions end early when the token they were opened with is revoked
+        // or expires, even if its expiry was brought forward since
+        let row = sqlx::query_as::<_, (Option<i64>, String, Option<i64>, Option<String>, String, i64)>("SELECT token, scope, max_upload_size, namespace, csrf, expires_at
+        FROM sessions WHERE id = $1 AND expires_at > $2
+        AND (token IS NULL OR token IN (SELECT rowid FROM tokens WHERE expires_at IS NULL OR expires_at > $2))")
+        .bind(id)
+        .bind(Utc::now().timestamp())
+        .fetch_optional(&state.db).await;
+
+        match row {
+            Ok(Some((token, scope, max_upload_size, namespace, csrf, expires_at))) => Ok(Self {
+                id: id.to_string(),
+                user: TokenInfo { id: token, scope, max_upload_size, namespace, base_url: None, expires_at: Some(expires_at) },
+                csrf,
+            }),
+            _ => Err(Redirect::to("/login")),
+        }
+    }
+}
+
+pub async fn login_page(State(state): State<Arc<AppState>>, session: Option<Session>) -> Response {
+    if session.is_some() {
+        return Redirect::to("/").into_response();
+    }
+    state.templates.render("login.html", Context::new()).into_response()
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct LoginForm {
+    token: String,
+    /// The current code, for tokens that enrolled a second factor.
+    totp: Option<String>,
+}
+
+/// Opens a session for whoever holds an API token, and its current TOTP code
+/// if it has one, like the admin API asks for.
+#[axum::debug_handler]
+pub async fn login(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    BaseUrl(base_url): BaseUrl,
+    namespace: RequestNamespace,
+    Form(form): Form<LoginForm>,
+) -> Result<Response, StatusCode> {
+    let totp = form.totp.as_deref().filter(|c| !c.is_empty());
+    let res = match authenticate(&state, addr.ip(), &form.token).await {
+        Ok(u) => check_totp(&state, addr.ip(), &form.token, &u, totp).await.map(|()| u),
+        Err(status) => Err(status),
+    };
+    let user = match res {
+        Ok(u) => u,
+        Err(status @ (StatusCode::UNAUTHORIZED | StatusCode::TOO_MANY_REQUESTS)) => {
+            let mut context = Context::new();
+            context.insert("error", if status == StatusCode::UNAUTHORIZED { "Invalid token or code." } else { "Too many failed attempts, try again later." });
+            return Ok((status, state.templates.render("login.html", context)?).into_response());
+        },
+        Err(status) => return Err(status),
+    };
+    namespace.check(&user)?;
+
+    let id = random_hex();
+    // the session ends with the credential it was opened with, like a JWT's
+    // `exp`, if that comes first
+    let now = Utc::now().timestamp();
+    let longest = now + state.config().session_max_age.as_secs() as i64;
+    let expires_at = user.expires_at.map_or(longest, |e| e.min(longest));
+    let max_age = (expires_at - now).max(0);
+    sqlx::query("INSERT INTO sessions (id, token, scope, max_upload_size, namespace, csrf, expires_at) VALUES ($1, $2, $3, $4, $5, $6, $7)")
+    .bind(&id)
+    .bind(user.id)
+    .bind(&user.scope)
+    .bind(user.max_upload_size)
+    .bind(&user.namespace)
+    .bind(random_hex())
+    .bind(expires_at)
+    .execute(&state.db).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    audit::record(&state, user.id, addr.ip(), "login", "").await;
+
+    let secure = if base_url.starts_with("https://") { "; Secure" } else { "" };
+    let cookie = format!("{}={}; Path=/; HttpOnly; SameSite=Lax; Max-Age={}{}", COOKIE, state.session_key.sign(&id), max_age, secure);
+    Ok(([(header::SET_COOKIE, cookie)], Redirect::to("/")).into_response())
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct CsrfForm {
+    csrf: String,
+}
+
+#[axum::debug_handler]
+pub async fn logout(
+    State(state): State<Arc<AppState>>,
+    session: Session,
+    Form(form): Form<CsrfForm>,
+) -> Result<Response, StatusCode> {
+    session.check_csrf(&form.csrf)?;
+
+    sqlx::query("DELETE FROM sessions WHERE id = $1")
+    .bind(&session.id)
+    .execute(&state.db).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let cookie = format!("{}=; Path=/; HttpOnly; SameSite=Lax; Max-Age=0", COOKIE);
+    Ok(([(header::SET_COOKIE, cookie)], Redirect::to("/")).into_response())
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct UploadParam {
+    /// Id the page picked to follow the upload's progress with, see
+    /// [`progress`](crate::progress).
+    progress: Option<String>,
+    /// Store the paste even though it looks like it contains credentials,
+    /// which the form asks for once the user confirms it.
+    #[serde(default)]
+    force: bool,
+}
+
+/// Handles the upload form, whose first field has to be the CSRF token and
+/// the second the title. An upload held back for confirmation, like one that
+/// looks like it contains credentials, shows the form again to confirm it.
+#[axum::debug_handler]
+pub async fn upload(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    session: Session,
+    namespace: RequestNamespace,
+    Query(query): Query<UploadParam>,
+    headers: HeaderMap,
+    mut multipart: Multipart,
+) -> Result<Response, StatusCode> {
+    namespace.check(&session.user)?;
+    let csrf = match multipart.next_field().await {
+        Ok(Some(f)) if f.name() == Some("csrf") => f.text().await.map_err(|_| StatusCode::BAD_REQUEST)?,
+        _ => return Err(StatusCode::FORBIDDEN),
+    };
+    session.check_csrf(&csrf)?;
+    let title = match multipart.next_field().await {
+        Ok(Some(f)) if f.name() == Some("title") => f.text().await.map_err(|_| StatusCode::BAD_REQUEST)?,
+        _ => return Err(StatusCode::BAD_REQUEST),
+    };
+
+    let progress = query.progress.as_deref().and_then(|id| state.uploads.track(&session.id, id, paste::declared_length(&headers)));
+    let options = UploadOptions { title: metadata::title(&title)?, force: query.force, progress, ..Default::default() };
+    let created = match paste::store_upload(&state, &session.user, addr, &headers, options, &mut multipart).await {
+        Ok((created, _)) => created,
+        Err(StatusCode::CONFLICT) => {
+            let mut context = Context::new();
+            context.insert("csrf", &session.csrf);
+            context.insert("title", &title);
+            context.insert("confirm", &true);
+            return Ok((StatusCode::CONFLICT, state.templates.render("upload.html", context)?).into_response());
+        },
+        Err(status) => return Err(status),
+    };
+    match created {
+        Created::Paste(filename) | Created::Duplicate(filename) => {
+            content::schedule(&state.db, &filename);
+            ipfs::schedule(&state.db, &state.config(), &filename);
+            Ok(Redirect::to(&format!("/view/{}", filename)).into_response())
+        },
+        Created::Collection(id) => Ok(Redirect::to(&format!("/collection/{}", id)).into_response()),
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct DeleteForm {
+    id: String,
+    csrf: String,
+}
+
+#[axum::debug_handler]
+pub async fn delete(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    session: Session,
+    Form(form): Form<DeleteForm>,
+) -> Result<Redirect, StatusCode> {
+    session.check_csrf(&form.csrf)?;
+
+    paste::delete(&state, &session.user, addr.ip(), &form.id).await?;
+    Ok(Redirect::to("/mine"))
+}
+
+fn cookie<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers.get_all(header::COOKIE).iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(';'))
+        .find_map(|c| c.trim().strip_prefix(name)?.strip_prefix('='))
+}
+
+pub fn random_hex() -> String {
+    let mut bytes = [0; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    hex(&bytes)
+}
+
+/// `bytes` in lowercase hex, the way hashes and fingerprints are stored and
+/// shown everywhere.
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Compares in constant time, so secrets can't be guessed byte by byte.
+pub fn same(a: &str, b: &str) -> bool {
+    a.len() == b.len() && a.bytes().zip(b.bytes()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
@@ -0,0 +1,187 @@
+use std::{
+    fs::File,
+    io::{self, BufReader, Cursor, Read},
+    net::SocketAddr,
+    path::Path as FsPath,
+    sync::Arc,
+};
+
+use axum::{
+    body::Bytes,
+    extract::{ConnectInfo, Path, Query, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use pgp::{
+    composed::{Deserializable, SignedPublicKey, StandaloneSignature},
+    types::{KeyTrait, PublicKeyTrait},
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{audit, auth::authenticate_client, namespace::RequestNamespace, paste, session::hex, storage, tls::ClientCert, AppState};
+
+/// Largest detached signature accepted. Real ones are well under a kilobyte,
+/// even for RSA keys.
+const MAX_SIGNATURE_SIZE: usize = 64 * 1024;
+const ARMOR_BEGIN: &[u8] = b"-----BEGIN PGP SIGNATURE-----";
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SignatureParam {
+    token: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Verdict {
+    /// Fingerprint of the configured key that made the signature, if any did.
+    pub signed_by: Option<String>,
+}
+
+/// Handles `PUT /api/paste/<id>/signature`, which attaches a detached PGP
+/// signature, armored or binary, to a paste and checks it against
+/// `PGP_KEYS`. Signatures no configured key made are kept too, without the
+/// badge.
+#[axum::debug_handler]
+pub async fn attach(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    cert: Option<Extension<ClientCert>>,
+    Path(id): Path<String>,
+    Query(query): Query<SignatureParam>,
+    body: Bytes,
+) -> Result<Json<Verdict>, StatusCode> {
+    let user = authenticate_client(&state, addr.ip(), cert.as_deref(), query.token.as_deref()).await?;
+    paste::check_owner(&state, &user, &id).await?;
+    let filename = sqlx::query_scalar::<_, String>("SELECT filename FROM pastes WHERE id = $1")
+    .bind(&id)
+    .fetch_optional(&state.db).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    .ok_or(StatusCode::NOT_FOUND)?;
+
+    if body.len() > MAX_SIGNATURE_SIZE {
+        return Err(StatusCode::PAYLOAD_TOO_LARGE);
+    }
+    let signature = parse(&body).map_err(|_| StatusCode::BAD_REQUEST)?;
+    let keys = state.config().pgp_keys.clone();
+    let path = storage::locate(&state.db, &filename).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let (signed_by, sha256) = tokio::task::spawn_blocking(move || -> anyhow::Result<(Option<String>, String)> {
+        verify(&keyring(&keys)?, &signature, &path)
+    }).await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    .map_err(|e| {
+        tracing::error!("Couldn't check the signature of {}: {}", filename, e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    // the verdict is about what was read, so it's only kept if the paste
+    // still hashes to that; one whose hash isn't known yet gets this one
+    let res = sqlx::query("UPDATE pastes SET signature = $1, signed_by = $2, sha256 = $3 WHERE id = $4 AND (sha256 = $3 OR sha256 IS NULL)")
+    .bind(&body[..])
+    .bind(&signed_by)
+    .bind(&sha256)
+    .bind(&id)
+    .execute(&state.db).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if res.rows_affected() == 0 {
+        return Err(StatusCode::CONFLICT);
+    }
+
+    audit::record(&state, user.id, addr.ip(), "sign", &filename).await;
+    Ok(Json(Verdict { signed_by }))
+}
+
+/// Serves the signature attached to paste `id`, as it was uploaded, to
+/// whoever can see the paste itself: it's hidden outside the paste's
+/// namespace and while the paste is taken down.
+#[axum::debug_handler]
+pub async fn serve(
+    State(state): State<Arc<AppState>>,
+    namespace: RequestNamespace,
+    Path(id): Path<String>,
+) -> Result<Response, StatusCode> {
+    let signature = sqlx::query_scalar::<_, Option<Vec<u8>>>("SELECT signature FROM pastes WHERE id = $1 AND namespace IS $2
+    AND filename NOT IN (SELECT filename FROM takedowns WHERE lifted_at IS NULL)")
+    .bind(&id)
+    .bind(namespace.name())
+    .fetch_optional(&state.db).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    .flatten()
+    .ok_or(StatusCode::NOT_FOUND)?;
+
+    let mime = if signature.starts_with(ARMOR_BEGIN) { "application/pgp-signature" } else { "application/octet-stream" };
+    Ok(([(header::CONTENT_TYPE, mime)], signature).into_response())
+}
+
+fn parse(raw: &[u8]) -> anyhow::Result<StandaloneSignature> {
+    if raw.starts_with(ARMOR_BEGIN) {
+        return Ok(StandaloneSignature::from_armor_single(Cursor::new(raw))?.0);
+    }
+    Ok(StandaloneSignature::from_bytes(Cursor::new(raw))?)
+}
+
+/// The public keys in the armored files listed in `PGP_KEYS`.
+fn keyring(paths: &[String]) -> anyhow::Result<Vec<SignedPublicKey>> {
+    let mut keys = Vec::new();
+    for path in paths {
+        let armored = std::fs::read(path)?;
+        for key in SignedPublicKey::from_armor_many(Cursor::new(armored))?.0 {
+            keys.push(key?);
+        }
+    }
+    Ok(keys)
+}
+
+/// Fingerprint of the key in `keys` that made `signature` over the file at
+/// `path`, by itself or one of its subkeys, along with the hash of what was
+/// read. The file is streamed rather than read into memory, once for each key
+/// the signature claims to be from, or once more to hash it if none made it.
+fn verify(keys: &[SignedPublicKey], signature: &StandaloneSignature, path: &FsPath) -> anyhow::Result<(Option<String>, String)> {
+    for key in keys {
+        let mut verified = verified_by(signature, key, path)?;
+        for sub in &key.public_subkeys {
+            if verified.is_some() {
+                break;
+            }
+            verified = verified_by(signature, sub, path)?;
+        }
+        if let Some(sha256) = verified {
+            return Ok((Some(hex(&key.fingerprint())), sha256));
+        }
+    }
+
+    let mut reader = Hashing::open(path)?;
+    io::copy(&mut reader, &mut io::sink())?;
+    Ok((None, reader.finish()))
+}
+
+/// The hash of the file at `path`, if `key` made `signature` over it. Keys
+/// other than the one the signature names as its issuer aren't tried.
+fn verified_by(signature: &StandaloneSignature, key: &impl PublicKeyTrait, path: &FsPath) -> io::Result<Option<String>> {
+    if signature.signature.issuer().is_some_and(|issuer| *issuer != key.key_id()) {
+        return Ok(None);
+    }
+    let mut reader = Hashing::open(path)?;
+    Ok(signature.signature.verify(key, &mut reader).ok().map(|_| reader.finish()))
+}
+
+/// Reads a file while hashing what was read.
+struct Hashing {
+    file: BufReader<File>,
+    hasher: Sha256,
+}
+
+impl Hashing {
+    fn open(path: &FsPath) -> io::Result<Self> {
+        Ok(Self { file: BufReader::new(File::open(path)?), hasher: Sha256::new() })
+    }
+
+    fn finish(self) -> String {
+        hex(&self.hasher.finalize())
+    }
+}
+
+impl Read for Hashing {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.file.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+}
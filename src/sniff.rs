@@ -0,0 +1,40 @@
+use crate::{encrypted, name::PasteName, policy};
+
+/// Bytes looked at to tell what a file is.
+pub const SNIFF_LEN: usize = 8192;
+
+/// Guesses an extension and MIME type from the first bytes of a file, for
+/// uploads whose name doesn't have an extension. Types browsers would run
+/// scripts in are never guessed: markup is plain text until its uploader
+/// names it otherwise.
+pub fn sniff(head: &[u8]) -> Option<(&'static str, &'static str)> {
+    if head.is_empty() {
+        return None;
+    }
+    // armored ones would pass for text otherwise
+    if encrypted::is_age(head) {
+        return Some(("age", "application/octet-stream"));
+    }
+    if let Some(kind) = infer::get(head) {
+        if !policy::is_active(&format!("sniffed.{}", kind.extension())) {
+            return Some((kind.extension(), kind.mime_type()));
+        }
+    }
+
+    // `head` may end in the middle of a character, which is still text
+    let utf8 = match std::str::from_utf8(head) {
+        Ok(_) => true,
+        Err(e) => e.error_len().is_none(),
+    };
+    if utf8 && !head.contains(&0) {
+        return Some(("txt", "text/plain"));
+    }
+    None
+}
+
+/// The name the paste `name`, which has no extension, gets from what its
+/// contents were `sniffed` as while it was written, along with its MIME type,
+/// or nothing if the contents weren't recognised.
+pub fn add_extension(name: &PasteName, sniffed: Option<(&'static str, &'static str)>) -> Option<(PasteName, &'static str)> {
+    sniffed.and_then(|(ext, mime)| Some((name.with_extension(ext)?, mime)))
+}
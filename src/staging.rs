@@ -0,0 +1,157 @@
+use std::{
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use axum::{
+    extract::{ConnectInfo, Query, State},
+    http::StatusCode,
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use tokio::fs::File;
+use uuid::Uuid;
+
+use crate::{auth::authenticate_admin, storage::Durability, AppState};
+
+/// Where uploads wait until they're complete, outside of what's served. It
+/// has to be on the same filesystem as the pastes, so they can be moved in.
+pub const STAGING_DIRECTORY: &str = "staging";
+
+/// Where the staged upload `name` lives, a file or a directory of parts.
+pub fn path(name: &str) -> PathBuf {
+    Path::new(STAGING_DIRECTORY).join(name)
+}
+
+/// Creates the staged file `name`.
+pub async fn create(name: &str) -> std::io::Result<File> {
+    tokio::fs::create_dir_all(STAGING_DIRECTORY).await?;
+    File::create(path(name)).await
+}
+
+/// The staged name of the upload that becomes the paste with row id `id`
+/// once it's recorded.
+pub fn upload(id: &Uuid) -> String {
+    format!("upload-{}", id.simple())
+}
+
+/// Flushes the staged `file` to disk as far as `durability` asks for. Where
+/// it ends up is synced by [`crate::storage::place`].
+pub async fn sync(file: &File, durability: Durability) -> std::io::Result<()> {
+    match durability {
+        Durability::None => Ok(()),
+        Durability::Data => file.sync_data().await,
+        Durability::Full => file.sync_all().await,
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct StagingParam {
+    token: String,
+    totp: Option<String>,
+}
+
+/// What's in the staging directory right now.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Usage {
+    /// Uploads staged, each a file or a directory of parts.
+    pub uploads: u64,
+    pub files: u64,
+    pub bytes: u64,
+    /// When the least recently written to upload last was, as a UNIX
+    /// timestamp.
+    pub oldest: Option<i64>,
+}
+
+/// A staged upload.
+struct Staged {
+    name: String,
+    is_dir: bool,
+    files: u64,
+    bytes: u64,
+    /// When any of its files was last written to.
+    modified: SystemTime,
+}
+
+async fn entries() -> std::io::Result<Vec<Staged>> {
+    let mut staged = Vec::new();
+    let mut entries = match tokio::fs::read_dir(STAGING_DIRECTORY).await {
+        Ok(e) => e,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(staged),
+        Err(e) => return Err(e),
+    };
+
+    while let Some(entry) = entries.next_entry().await? {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let metadata = entry.metadata().await?;
+        let (mut files, mut bytes, mut modified) = (0, 0, metadata.modified()?);
+        if metadata.is_dir() {
+            let mut parts = tokio::fs::read_dir(entry.path()).await?;
+            while let Some(part) = parts.next_entry().await? {
+                let metadata = part.metadata().await?;
+                files += 1;
+                bytes += metadata.len();
+                modified = modified.max(metadata.modified()?);
+            }
+        } else {
+            files = 1;
+            bytes = metadata.len();
+        }
+        staged.push(Staged { name, is_dir: metadata.is_dir(), files, bytes, modified });
+    }
+    Ok(staged)
+}
+
+pub async fn usage() -> std::io::Result<Usage> {
+    let mut usage = Usage::default();
+    for staged in entries().await? {
+        let at = staged.modified.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_secs() as i64;
+        usage.uploads += 1;
+        usage.files += staged.files;
+        usage.bytes += staged.bytes;
+        usage.oldest = Some(usage.oldest.map_or(at, |o| o.min(at)));
+    }
+    Ok(usage)
+}
+
+/// Removes staged uploads nothing was written to for `max_age`, returning
+/// their names and the bytes freed.
+pub async fn clean(max_age: Duration) -> std::io::Result<(Vec<String>, u64)> {
+    let mut removed = Vec::new();
+    let mut freed = 0;
+    for staged in entries().await? {
+        if staged.modified.elapsed().unwrap_or_default() < max_age {
+            continue;
+        }
+
+        let path = path(&staged.name);
+        let res = if staged.is_dir {
+            tokio::fs::remove_dir_all(&path).await
+        } else {
+            tokio::fs::remove_file(&path).await
+        };
+        match res {
+            Ok(()) => {
+                removed.push(staged.name);
+                freed += staged.bytes;
+            },
+            // finished in the meantime
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {},
+            Err(e) => return Err(e),
+        }
+    }
+    Ok((removed, freed))
+}
+
+/// Handles `GET /admin/staging`, reporting what the staging directory holds.
+#[axum::debug_handler]
+pub async fn report(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Query(query): Query<StagingParam>,
+) -> Result<Json<Usage>, StatusCode> {
+    authenticate_admin(&state, addr.ip(), &query.token, query.totp.as_deref()).await?;
+    usage().await.map(Json).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
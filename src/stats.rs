@@ -0,0 +1,336 @@
+use std::{collections::HashMap, net::SocketAddr, sync::Arc};
+
+use axum::{
+    extract::{ConnectInfo, Query, State},
+    http::StatusCode,
+    response::Html,
+    Json,
+};
+use chrono::prelude::*;
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+use tera::Context;
+
+use crate::{auth::authenticate_admin, staging, AppState};
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct StatsParam {
+    token: String,
+    totp: Option<String>,
+    /// How many days of history to return.
+    days: Option<u32>,
+}
+
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct DayStats {
+    pub day: String,
+    pub uploads: i64,
+    /// Bytes uploaded that day which are still stored.
+    pub bytes: i64,
+    /// Bytes stored at the end of that day.
+    pub total_bytes: i64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MimeCount {
+    pub mime: String,
+    pub count: i64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Stats {
+    pub days: Vec<DayStats>,
+    pub top_mime_types: Vec<MimeCount>,
+    /// Tokens that uploaded or deleted something within the period.
+    pub active_tokens: i64,
+}
+
+#[axum::debug_handler]
+pub async fn stats(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Query(query): Query<StatsParam>,
+) -> Result<Json<Stats>, StatusCode> {
+    authenticate_admin(&state, addr.ip(), &query.token, query.totp.as_deref()).await?;
+
+    let since = Utc::now().timestamp() - i64::from(query.days.unwrap_or(30)) * 86400;
+
+    // the running total has to include everything before `since`, so the
+    // window is applied over all days and filtered afterwards
+    let days = sqlx::query_as::<_, DayStats>("SELECT * FROM (
+        SELECT date(timestamp, 'unixepoch') AS day,
+        COUNT(*) AS uploads,
+        SUM(size) AS bytes,
+        SUM(SUM(size)) OVER (ORDER BY date(timestamp, 'unixepoch')) AS total_bytes
+        FROM pastes GROUP BY day
+    ) WHERE day >= date($1, 'unixepoch') ORDER BY day")
+    .bind(since)
+    .fetch_all(&state.db).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    // ids never contain a dot, so everything after the first one is the extension
+    let extensions = sqlx::query_as::<_, (String, i64)>("SELECT
+        CASE WHEN instr(filename, '.') > 0 THEN lower(substr(filename, instr(filename, '.') + 1)) ELSE '' END AS ext,
+        COUNT(*)
+        FROM pastes WHERE timestamp >= $1 GROUP BY ext")
+    .bind(since)
+    .fetch_all(&state.db).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let mut mimes: HashMap<String, i64> = HashMap::new();
+    for (ext, count) in extensions {
+        let mime = mime_guess::from_ext(&ext).first_or_octet_stream();
+        *mimes.entry(mime.essence_str().to_string()).or_default() += count;
+    }
+    let mut top_mime_types: Vec<MimeCount> = mimes.into_iter().map(|(mime, count)| MimeCount { mime, count }).collect();
+    top_mime_types.sort_by(|a, b| b.count.cmp(&a.count));
+    top_mime_types.truncate(10);
+
+    let active_tokens = sqlx::query_scalar::<_, i64>("SELECT COUNT(DISTINCT actor) FROM audit_log WHERE actor IS NOT NULL AND timestamp >= $1")
+    .bind(since)
+    .fetch_one(&state.db).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Json(Stats { days, top_mime_types, active_tokens }))
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct StorageParam {
+    token: String,
+    totp: Option<String>,
+    /// How many of the largest pastes to list.
+    top: Option<u32>,
+}
+
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct ExtensionUsage {
+    /// Empty for pastes without one.
+    pub extension: String,
+    pub pastes: i64,
+    pub bytes: i64,
+}
+
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct LargePaste {
+    pub id: String,
+    pub filename: String,
+    pub size: i64,
+    pub timestamp: i64,
+    pub owner: Option<i64>,
+}
+
+/// What was stored at the end of a day, from the `stats` job's snapshots.
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct StoredOnDay {
+    pub day: String,
+    pub pastes: i64,
+    pub bytes: i64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct StorageReport {
+    pub pastes: i64,
+    pub bytes: i64,
+    /// Kept by older versions of updated pastes.
+    pub version_bytes: i64,
+    /// Taken by uploads that aren't complete yet.
+    pub staging_bytes: u64,
+    pub extensions: Vec<ExtensionUsage>,
+    pub largest: Vec<LargePaste>,
+    /// The last 30 days; days the `stats` job didn't run are missing.
+    pub growth: Vec<StoredOnDay>,
+}
+
+/// Handles `GET /api/admin/storage`, which breaks down what's stored by
+/// extension and lists the largest pastes and how storage grew lately.
+#[axum::debug_handler]
+pub async fn storage(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Query(query): Query<StorageParam>,
+) -> Result<Json<StorageReport>, StatusCode> {
+    authenticate_admin(&state, addr.ip(), &query.token, query.totp.as_deref()).await?;
+
+    let (pastes, bytes) = sqlx::query_as::<_, (i64, i64)>("SELECT COUNT(*), COALESCE(SUM(size), 0) FROM pastes")
+    .fetch_one(&state.db).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let version_bytes = sqlx::query_scalar::<_, i64>("SELECT COALESCE(SUM(size), 0) FROM versions")
+    .fetch_one(&state.db).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let staging_bytes = staging::usage().await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?.bytes;
+
+    let extensions = sqlx::query_as::<_, ExtensionUsage>("SELECT
+        CASE WHEN instr(filename, '.') > 0 THEN lower(substr(filename, instr(filename, '.') + 1)) ELSE '' END AS extension,
+        COUNT(*) AS pastes,
+        SUM(size) AS bytes
+        FROM pastes GROUP BY extension ORDER BY bytes DESC")
+    .fetch_all(&state.db).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let largest = sqlx::query_as::<_, LargePaste>("SELECT id, filename, size, timestamp, owner FROM pastes ORDER BY size DESC LIMIT $1")
+    .bind(query.top.unwrap_or(20).min(1000))
+    .fetch_all(&state.db).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let since = Utc::now().timestamp() - 30 * 86400;
+    let growth = sqlx::query_as::<_, StoredOnDay>("SELECT date(timestamp, 'unixepoch') AS day, pastes, bytes
+        FROM stats_snapshots WHERE timestamp IN (
+            SELECT MAX(timestamp) FROM stats_snapshots WHERE timestamp >= $1 GROUP BY date(timestamp, 'unixepoch')
+        ) ORDER BY timestamp")
+    .bind(since)
+    .fetch_all(&state.db).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Json(StorageReport { pastes, bytes, version_bytes, staging_bytes, extensions, largest, growth }))
+}
+
+/// How wide the buckets of the upload histogram are.
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Granularity {
+    Hour,
+    #[default]
+    Day,
+}
+
+impl Granularity {
+    const ALL: [Self; 2] = [Self::Hour, Self::Day];
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Hour => "hour",
+            Self::Day => "day",
+        }
+    }
+
+    fn secs(self) -> i64 {
+        match self {
+            Self::Hour => 3600,
+            Self::Day => 86400,
+        }
+    }
+}
+
+/// Counts uploads into the histogram's buckets from the audit log, starting
+/// from the latest bucket of each granularity, which may have been counted
+/// before it was over.
+pub async fn aggregate_uploads(db: &SqlitePool) -> sqlx::Result<()> {
+    for granularity in Granularity::ALL {
+        let mut tx = db.begin().await?;
+        let start = sqlx::query_scalar::<_, Option<i64>>("SELECT MAX(bucket) FROM upload_histogram WHERE granularity = $1")
+        .bind(granularity.as_str())
+        .fetch_one(&mut *tx).await?
+        .unwrap_or(0);
+
+        sqlx::query("INSERT OR REPLACE INTO upload_histogram (granularity, bucket, uploads)
+        SELECT $1, timestamp - timestamp % $2 AS bucket, COUNT(*)
+        FROM audit_log WHERE action = 'upload' AND timestamp >= $3 GROUP BY bucket")
+        .bind(granularity.as_str())
+        .bind(granularity.secs())
+        .bind(start)
+        .execute(&mut *tx).await?;
+        tx.commit().await?;
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct HistogramParam {
+    token: String,
+    totp: Option<String>,
+    #[serde(default)]
+    granularity: Granularity,
+    /// How many days back to go.
+    days: Option<u32>,
+}
+
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct Bucket {
+    /// When the bucket starts, as a UNIX timestamp.
+    pub start: i64,
+    pub uploads: i64,
+}
+
+/// Handles `GET /api/stats/uploads`, the number of uploads per hour or day
+/// as of the last run of the `histogram` job. Buckets without any are left
+/// out.
+#[axum::debug_handler]
+pub async fn uploads(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Query(query): Query<HistogramParam>,
+) -> Result<Json<Vec<Bucket>>, StatusCode> {
+    authenticate_admin(&state, addr.ip(), &query.token, query.totp.as_deref()).await?;
+
+    let since = Utc::now().timestamp() - i64::from(query.days.unwrap_or(30)) * 86400;
+    let buckets = sqlx::query_as::<_, Bucket>("SELECT bucket AS start, uploads FROM upload_histogram
+        WHERE granularity = $1 AND bucket >= $2 ORDER BY bucket")
+    .bind(query.granularity.as_str())
+    .bind(since - since % query.granularity.secs())
+    .fetch_all(&state.db).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(Json(buckets))
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct BandwidthParam {
+    token: String,
+    totp: Option<String>,
+    /// How many days back to go, today included.
+    days: Option<u32>,
+    /// How many pastes and tokens to list.
+    top: Option<u32>,
+}
+
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct PasteBandwidth {
+    /// May no longer exist.
+    pub paste: String,
+    pub owner: Option<i64>,
+    pub bytes: i64,
+}
+
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct TokenBandwidth {
+    /// `None` for pastes uploaded without a token.
+    pub owner: Option<i64>,
+    pub bytes: i64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct BandwidthReport {
+    /// Bytes served over the period.
+    pub bytes: i64,
+    pub pastes: Vec<PasteBandwidth>,
+    pub tokens: Vec<TokenBandwidth>,
+}
+
+/// Handles `GET /api/stats/bandwidth`, what was downloaded over the last
+/// `days`, in total and by the pastes and tokens that used the most. Counts
+/// are a few seconds behind.
+#[axum::debug_handler]
+pub async fn bandwidth(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Query(query): Query<BandwidthParam>,
+) -> Result<Json<BandwidthReport>, StatusCode> {
+    authenticate_admin(&state, addr.ip(), &query.token, query.totp.as_deref()).await?;
+
+    let since = (Utc::now() - chrono::Duration::days(i64::from(query.days.unwrap_or(30).max(1)) - 1)).format("%Y-%m-%d").to_string();
+    let top = query.top.unwrap_or(20).min(1000);
+
+    let bytes = sqlx::query_scalar::<_, i64>("SELECT COALESCE(SUM(bytes), 0) FROM bandwidth WHERE day >= $1")
+    .bind(&since)
+    .fetch_one(&state.db).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let pastes = sqlx::query_as::<_, PasteBandwidth>("SELECT paste, owner, SUM(bytes) AS bytes FROM bandwidth
+        WHERE day >= $1 GROUP BY paste ORDER BY bytes DESC LIMIT $2")
+    .bind(&since)
+    .bind(top)
+    .fetch_all(&state.db).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let tokens = sqlx::query_as::<_, TokenBandwidth>("SELECT owner, SUM(bytes) AS bytes FROM bandwidth
+        WHERE day >= $1 GROUP BY owner ORDER BY bytes DESC LIMIT $2")
+    .bind(&since)
+    .bind(top)
+    .fetch_all(&state.db).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Json(BandwidthReport { bytes, pastes, tokens }))
+}
+
+pub async fn dashboard(State(state): State<Arc<AppState>>) -> Result<Html<String>, StatusCode> {
+    state.templates.render("admin.html", Context::new())
+}
@@ -0,0 +1,284 @@
+use std::{
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use axum::http::{uri::PathAndQuery, Request, Uri};
+use sqlx::SqlitePool;
+use tokio::fs::File;
+
+use crate::{paste::ServedPaste, PASTES_DIRECTORY};
+
+/// How much of a file is read at once when serving it. `ServeFile`'s default
+/// of 64 KiB costs a read and a body frame every 64 KiB, which is most of the
+/// CPU time spent sending large pastes; past a few hundred KiB, bigger reads
+/// barely help and every response holds a buffer this large.
+pub const READ_CHUNK_SIZE: usize = 256 << 10;
+
+/// Smallest read buffer a response is given, however small the file.
+const MIN_CHUNK_SIZE: usize = 8 << 10;
+
+/// How much of a file of `len` bytes is read at once when serving it: no
+/// more than it holds, so small pastes don't take a whole [`READ_CHUNK_SIZE`]
+/// buffer each.
+pub fn chunk_size(len: u64) -> usize {
+    len.clamp(MIN_CHUNK_SIZE as u64, READ_CHUNK_SIZE as u64) as usize
+}
+
+/// Where the paste stored as `filename` lives: `pastes/ab/cd/<filename>`,
+/// sharded by its first four characters so no directory grows too large.
+/// Pastes of namespaces with a storage prefix are found with [`locate`].
+pub fn paste_path(filename: &str) -> PathBuf {
+    paste_path_in(None, filename)
+}
+
+/// Where the paste stored as `filename` lives when it was stored under
+/// `prefix`: `pastes/<prefix>/ab/cd/<filename>`.
+pub fn paste_path_in(prefix: Option<&str>, filename: &str) -> PathBuf {
+    let root = match prefix.filter(|p| valid_prefix(p)) {
+        Some(prefix) => Path::new(PASTES_DIRECTORY).join(prefix),
+        None => PathBuf::from(PASTES_DIRECTORY),
+    };
+    match shard(filename) {
+        Some((outer, inner)) => root.join(outer).join(inner).join(filename),
+        None => root.join(filename),
+    }
+}
+
+/// Where the paste stored as `filename` lives, going by the storage prefix
+/// it was stored under.
+pub async fn locate(db: &SqlitePool, filename: &str) -> sqlx::Result<PathBuf> {
+    let prefix = stored_prefix(db, filename).await?;
+    Ok(paste_path_in(prefix.as_deref(), filename))
+}
+
+async fn stored_prefix(db: &SqlitePool, filename: &str) -> sqlx::Result<Option<String>> {
+    Ok(sqlx::query_scalar::<_, Option<String>>("SELECT storage_prefix FROM pastes WHERE filename = $1")
+    .bind(filename)
+    .fetch_optional(db).await?
+    .flatten()
+    .filter(|p| valid_prefix(p)))
+}
+
+/// Storage prefixes are alphanumeric and at least four characters long, so
+/// they can't clash with a shard, which is two, or with a paste that isn't
+/// sharded, whose first four characters aren't all alphanumeric. Others are
+/// ignored, storing the namespace's pastes with everyone else's.
+fn valid_prefix(prefix: &str) -> bool {
+    prefix.len() >= 4 && prefix.bytes().all(|b| b.is_ascii_alphanumeric())
+}
+
+/// How hard to try making a new paste survive a crash or power loss before
+/// reporting it as stored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Durability {
+    /// Leave it to the OS to write things out eventually.
+    None,
+    /// fsync the file's contents.
+    Data,
+    /// fsync the file and the directories leading to it, so its name is
+    /// durable too.
+    Full,
+}
+
+impl FromStr for Durability {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Self::None),
+            "data" => Ok(Self::Data),
+            "full" => Ok(Self::Full),
+            _ => anyhow::bail!("unknown durability {:?}, expected none, data or full", s),
+        }
+    }
+}
+
+/// Moves the upload staged at `staged`, already synced as `durability` asks,
+/// to where the paste `filename`, stored under `prefix`, is served from. It's
+/// linked there rather than renamed, so a file already there is never
+/// replaced: that fails with `AlreadyExists` instead.
+pub async fn place(staged: &Path, prefix: Option<&str>, filename: &str, durability: Durability) -> std::io::Result<()> {
+    let path = paste_path_in(prefix, filename);
+    if let Some(dir) = path.parent() {
+        tokio::fs::create_dir_all(dir).await?;
+    }
+    tokio::fs::hard_link(staged, &path).await?;
+    tokio::fs::remove_file(staged).await?;
+
+    if durability == Durability::Full {
+        sync_dirs(&path).await?;
+    }
+    Ok(())
+}
+
+/// Syncs the directories leading to the paste at `path`, which may have just
+/// been created, so its name is as durable as its contents.
+async fn sync_dirs(path: &Path) -> std::io::Result<()> {
+    for dir in path.ancestors().skip(1) {
+        if dir.as_os_str().is_empty() {
+            break;
+        }
+        File::open(dir).await?.sync_all().await?;
+        if dir == Path::new(PASTES_DIRECTORY) {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// The two directory levels `filename` is sharded into, if it has a prefix
+/// that's safe to use as directory names.
+fn shard(filename: &str) -> Option<(&str, &str)> {
+    let prefix = filename.get(..4)?;
+    if !prefix.bytes().all(|b| b.is_ascii_alphanumeric()) {
+        return None;
+    }
+    Some((&prefix[..2], &prefix[2..]))
+}
+
+/// Rewrites requests for `/<filename>` to its storage prefix and shard, so
+/// `ServeDir` finds it.
+pub async fn shard_uri<B>(mut req: Request<B>) -> Request<B> {
+    let name = match req.uri().path().strip_prefix('/') {
+        Some(n) if !n.contains('/') => n.to_string(),
+        _ => return req,
+    };
+    let prefix = req.extensions().get::<ServedPaste>()
+        .filter(|p| p.filename == name)
+        .and_then(|p| p.storage_prefix.clone())
+        .filter(|p| valid_prefix(p));
+
+    let mut path = String::new();
+    if let Some(prefix) = prefix {
+        path.push_str(&format!("/{}", prefix));
+    }
+    if let Some((outer, inner)) = shard(&name) {
+        path.push_str(&format!("/{}/{}", outer, inner));
+    }
+    path.push_str(&format!("/{}", name));
+    if let Some(q) = req.uri().query() {
+        path.push_str(&format!("?{}", q));
+    }
+
+    let mut parts = req.uri().clone().into_parts();
+    parts.path_and_query = path.parse::<PathAndQuery>().ok();
+    if let Ok(uri) = Uri::from_parts(parts) {
+        *req.uri_mut() = uri;
+    }
+    req
+}
+
+/// Moves pastes stored directly in the pastes directory, as they were before
+/// sharding, into their shards.
+pub async fn migrate_flat() -> anyhow::Result<()> {
+    let mut dir = tokio::fs::read_dir(PASTES_DIRECTORY).await?;
+    let mut moved = 0;
+    while let Some(entry) = dir.next_entry().await? {
+        if !entry.file_type().await?.is_file() {
+            continue;
+        }
+        let name = match entry.file_name().into_string() {
+            Ok(n) => n,
+            Err(_) => continue,
+        };
+
+        let target = paste_path(&name);
+        if target == entry.path() {
+            continue;
+        }
+        if let Some(dir) = target.parent() {
+            tokio::fs::create_dir_all(dir).await?;
+        }
+        tokio::fs::rename(entry.path(), target).await?;
+        moved += 1;
+    }
+
+    if moved > 0 {
+        tracing::info!("Moved {} pastes into sharded directories", moved);
+    }
+    Ok(())
+}
+
+/// Every file under the pastes directory, shards included.
+pub async fn walk() -> std::io::Result<Vec<(String, PathBuf)>> {
+    let mut files = Vec::new();
+    let mut dirs = vec![PathBuf::from(PASTES_DIRECTORY)];
+    while let Some(dir) = dirs.pop() {
+        let mut entries = tokio::fs::read_dir(dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let file_type = entry.file_type().await?;
+            if file_type.is_dir() {
+                dirs.push(entry.path());
+            } else if file_type.is_file() {
+                if let Ok(name) = entry.file_name().into_string() {
+                    files.push((name, entry.path()));
+                }
+            }
+        }
+    }
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use axum::{
+        body::{Body, HttpBody},
+        http::Request,
+    };
+    use tower::ServiceExt;
+    use tower_http::services::ServeDir;
+
+    use super::*;
+
+    #[test]
+    fn chunk_size_follows_the_file() {
+        assert_eq!(chunk_size(0), MIN_CHUNK_SIZE);
+        assert_eq!(chunk_size(100 << 10), 100 << 10);
+        assert_eq!(chunk_size(u64::MAX), READ_CHUNK_SIZE);
+    }
+
+    /// Compares sending a large paste and many small ones with `ServeDir`'s
+    /// default chunk size and with ours. Run it with
+    /// `cargo test --release serve_benchmark -- --ignored --nocapture`.
+    #[tokio::test]
+    #[ignore]
+    async fn serve_benchmark() {
+        let dir = std::env::temp_dir().join(format!("smolpaste-bench-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("large"), vec![0x5a; 512 << 20]).unwrap();
+        std::fs::write(dir.join("small"), vec![0x5a; 2 << 10]).unwrap();
+
+        let configs = [
+            ("ServeDir default", 64 << 10, 64 << 10),
+            ("READ_CHUNK_SIZE", READ_CHUNK_SIZE, READ_CHUNK_SIZE),
+            ("chunk_size", READ_CHUNK_SIZE, chunk_size(2 << 10)),
+        ];
+        for (label, large_chunk, small_chunk) in configs {
+            let start = Instant::now();
+            let len = drain(ServeDir::new(&dir).with_buf_chunk_size(large_chunk), "/large").await;
+            let large = start.elapsed();
+
+            let start = Instant::now();
+            for _ in 0..10_000 {
+                drain(ServeDir::new(&dir).with_buf_chunk_size(small_chunk), "/small").await;
+            }
+            let small = start.elapsed() / 10_000;
+
+            println!("{}: {:.0} MiB/s for a large paste, {:?} per small one", label, len as f64 / f64::from(1 << 20) / large.as_secs_f64(), small);
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    async fn drain(service: ServeDir, uri: &str) -> usize {
+        let req = Request::builder().uri(uri).body(Body::empty()).unwrap();
+        let mut body = service.oneshot(req).await.unwrap().into_body();
+        let mut len = 0;
+        while let Some(chunk) = body.data().await {
+            len += chunk.unwrap().len();
+        }
+        len
+    }
+}
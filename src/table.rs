@@ -0,0 +1,46 @@
+use std::path::Path;
+
+use serde::Serialize;
+
+/// Rows shown in the preview; the rest are only in the raw file.
+const MAX_ROWS: usize = 500;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Table {
+    pub header: Vec<String>,
+    pub rows: Vec<Vec<String>>,
+    /// Whether there were more rows than shown.
+    pub truncated: bool,
+}
+
+/// Whether the paste stored as `filename` should be previewed as a table.
+pub fn is_table(filename: &str) -> bool {
+    delimiter(filename).is_some()
+}
+
+fn delimiter(filename: &str) -> Option<u8> {
+    match filename.rsplit_once('.')?.1.to_ascii_lowercase().as_str() {
+        "csv" => Some(b','),
+        "tsv" | "tab" => Some(b'\t'),
+        _ => None,
+    }
+}
+
+/// Parses the first rows of the CSV or TSV file at `path`, reading no more of
+/// it than that. This blocks, so call it from `spawn_blocking`.
+pub fn preview(path: &Path, filename: &str) -> anyhow::Result<Table> {
+    let mut reader = csv::ReaderBuilder::new()
+        .delimiter(delimiter(filename).unwrap_or(b','))
+        // ragged rows are common in hand-written files
+        .flexible(true)
+        .from_path(path)?;
+
+    let header = reader.headers()?.iter().map(str::to_string).collect();
+    let mut rows = Vec::new();
+    let mut records = reader.records();
+    for record in records.by_ref().take(MAX_ROWS) {
+        rows.push(record?.iter().map(str::to_string).collect());
+    }
+
+    Ok(Table { header, rows, truncated: records.next().is_some() })
+}
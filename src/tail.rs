@@ -0,0 +1,132 @@
+use std::{
+    convert::Infallible,
+    io::{self, SeekFrom},
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
+
+use axum::{
+    body::{Body, Bytes, StreamBody},
+    extract::{Query, State},
+    http::{header, Method, Request, StatusCode},
+    middleware::Next,
+    response::{sse::{Event, KeepAlive, Sse}, IntoResponse, Response},
+};
+use serde::Deserialize;
+use tokio::{
+    fs::File,
+    io::{AsyncReadExt, AsyncSeekExt},
+    sync::broadcast::{self, error::RecvError},
+};
+
+use crate::{paste::ServedPaste, text::{is_text, tail_offset}, AppState};
+
+/// Largest chunk sent in one event.
+const CHUNK_SIZE: u64 = 64 * 1024;
+
+/// How long to wait for an append notification before checking the file anyway.
+const POLL_INTERVAL: Duration = Duration::from_secs(15);
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct TailParam {
+    /// Lines of existing content to send before following, 10 by default.
+    backlog: Option<u64>,
+    /// `sse` (the default) or `plain` for a chunked plaintext stream.
+    format: Option<String>,
+}
+
+struct Follower {
+    path: PathBuf,
+    filename: String,
+    offset: u64,
+    appends: broadcast::Receiver<String>,
+}
+
+impl Follower {
+    /// Waits for content past `offset` and returns it, or fails once the paste is gone.
+    async fn next_chunk(&mut self) -> io::Result<Bytes> {
+        loop {
+            let len = tokio::fs::metadata(&self.path).await?.len();
+            if len > self.offset {
+                let mut file = File::open(&self.path).await?;
+                file.seek(SeekFrom::Start(self.offset)).await?;
+
+                let mut buf = vec![0; (len - self.offset).min(CHUNK_SIZE) as usize];
+                file.read_exact(&mut buf).await?;
+                self.offset += buf.len() as u64;
+                return Ok(buf.into());
+            }
+
+            let _ = tokio::time::timeout(POLL_INTERVAL, self.wait_for_append()).await;
+        }
+    }
+
+    async fn wait_for_append(&mut self) {
+        loop {
+            match self.appends.recv().await {
+                Ok(f) if f == self.filename => return,
+                Ok(_) => continue,
+                // we may have missed ours, so go look
+                Err(RecvError::Lagged(_)) => return,
+                // nothing will ever wake us again, fall back to polling
+                Err(RecvError::Closed) => std::future::pending().await,
+            }
+        }
+    }
+}
+
+/// Handles `GET /paste/<name>/tail`, streaming content appended to a paste
+/// as it arrives.
+pub async fn tail_paste(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<TailParam>,
+    req: Request<Body>,
+    next: Next<Body>,
+) -> Response {
+    let paste = match req.uri().path().trim_start_matches('/').strip_suffix("/tail") {
+        Some(f) if req.method() == Method::GET => match req.extensions().get::<ServedPaste>() {
+            Some(p) if p.filename == f => p.clone(),
+            _ => return StatusCode::NOT_FOUND.into_response(),
+        },
+        _ => return next.run(req).await,
+    };
+    let filename = paste.filename.clone();
+
+    if !is_text(&filename) {
+        return StatusCode::UNSUPPORTED_MEDIA_TYPE.into_response();
+    }
+
+    // subscribe before looking at the file, so no append can slip in between
+    let appends = state.appends.subscribe();
+    let path = paste.path();
+
+    let offset = match File::open(&path).await {
+        Ok(mut file) => match tail_offset(&mut file, query.backlog.unwrap_or(10)).await {
+            Ok(o) => o,
+            Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        },
+        Err(_) => return StatusCode::NOT_FOUND.into_response(),
+    };
+
+    let follower = Follower { path, filename, offset, appends };
+    let chunks = futures::stream::unfold(follower, |mut f| async move {
+        let chunk = f.next_chunk().await.ok()?;
+        Some((chunk, f))
+    });
+
+    match query.format.as_deref() {
+        None | Some("sse") => {
+            let events = futures::StreamExt::map(chunks, |c| {
+                // SSE fields can't carry carriage returns
+                Ok::<_, Infallible>(Event::default().data(String::from_utf8_lossy(&c).replace('\r', "")))
+            });
+            Sse::new(events).keep_alive(KeepAlive::default()).into_response()
+        },
+        Some("plain") => (
+            [(header::CONTENT_TYPE, "text/plain; charset=utf-8")],
+            StreamBody::new(futures::StreamExt::map(chunks, Ok::<_, Infallible>)),
+        ).into_response(),
+        Some(_) => StatusCode::BAD_REQUEST.into_response(),
+    }
+}
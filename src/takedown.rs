@@ -0,0 +1,184 @@
+use std::{net::SocketAddr, sync::Arc};
+
+use axum::{
+    body::Body,
+    extract::{ConnectInfo, Query, State},
+    http::{header, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::prelude::*;
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+
+use crate::{audit, auth::authenticate_admin, ipfs, moderation, AppState};
+
+/// A paste disabled by an admin. Its file and row stay as they are, so the
+/// takedown can be lifted, or the paste handed over, later.
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct Takedown {
+    pub id: i64,
+    pub filename: String,
+    pub reason: String,
+    /// 451 or 410, what the paste is served as meanwhile.
+    pub status: u16,
+    pub actor: Option<i64>,
+    pub created_at: i64,
+    pub lifted_at: Option<i64>,
+    pub lifted_by: Option<i64>,
+}
+
+impl IntoResponse for Takedown {
+    fn into_response(self) -> Response {
+        let status = StatusCode::from_u16(self.status).unwrap_or(StatusCode::UNAVAILABLE_FOR_LEGAL_REASONS);
+        (status, [(header::CONTENT_TYPE, "text/plain; charset=utf-8")], format!("{}\n", self.reason)).into_response()
+    }
+}
+
+/// The takedown in force on the paste stored as `filename`, if there is one.
+pub async fn active(db: &SqlitePool, filename: &str) -> sqlx::Result<Option<Takedown>> {
+    sqlx::query_as::<_, Takedown>("SELECT * FROM takedowns WHERE filename = $1 AND lifted_at IS NULL")
+    .bind(filename)
+    .fetch_optional(db).await
+}
+
+/// Refuses to serve the paste stored as `filename` while it's taken down,
+/// with the status and reason it was taken down with.
+pub async fn check(state: &AppState, filename: &str) -> Result<(), Response> {
+    match active(&state.db, filename).await {
+        Ok(None) => Ok(()),
+        Ok(Some(takedown)) => Err(takedown.into_response()),
+        Err(_) => Err(StatusCode::INTERNAL_SERVER_ERROR.into_response()),
+    }
+}
+
+/// Keeps `/paste` from serving, updating or appending to pastes that are
+/// taken down.
+pub async fn enforce(
+    State(state): State<Arc<AppState>>,
+    req: Request<Body>,
+    next: Next<Body>,
+) -> Response {
+    let path = req.uri().path().trim_start_matches('/');
+    let filename = path.split('/').next().unwrap_or(path);
+
+    match check(&state, filename).await {
+        Ok(()) => next.run(req).await,
+        Err(response) => response,
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct TakedownParam {
+    token: String,
+    totp: Option<String>,
+    /// Paste id.
+    id: String,
+    reason: String,
+    /// 451 (the default) for legal reasons, 410 for anything else.
+    status: Option<u16>,
+}
+
+/// Takes paste `id` down: it's served as 451 or 410 with `reason` from then
+/// on, but nothing is deleted. Its owner can't delete it meanwhile either.
+#[axum::debug_handler]
+pub async fn take_down(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Query(query): Query<TakedownParam>,
+) -> Result<Json<Takedown>, StatusCode> {
+    let admin = authenticate_admin(&state, addr.ip(), &query.token, query.totp.as_deref()).await?;
+
+    let status = query.status.unwrap_or(451);
+    if !matches!(status, 410 | 451) || query.reason.trim().is_empty() {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let filename = sqlx::query_scalar::<_, String>("SELECT filename FROM pastes WHERE id = $1")
+    .bind(&query.id)
+    .fetch_optional(&state.db).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    .ok_or(StatusCode::NOT_FOUND)?;
+
+    if active(&state.db, &filename).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?.is_some() {
+        return Err(StatusCode::CONFLICT);
+    }
+
+    let takedown = sqlx::query_as::<_, Takedown>("INSERT INTO takedowns (filename, reason, status, actor, created_at)
+    VALUES ($1, $2, $3, $4, $5) RETURNING *")
+    .bind(&filename)
+    .bind(query.reason.trim())
+    .bind(status)
+    .bind(admin.id)
+    .bind(Utc::now().timestamp())
+    .fetch_one(&state.db).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    if let Err(e) = moderation::resolve(&state.db, &filename, admin.id).await {
+        tracing::warn!("Couldn't resolve the flags on {}: {}", filename, e);
+    }
+    // when this fails, the `gc` job unpins it later
+    if let Err(e) = ipfs::unpin(&state.db, &state.config(), &filename).await {
+        tracing::warn!("Couldn't unpin {} from IPFS: {}", filename, e);
+    }
+    audit::record(&state, admin.id, addr.ip(), "takedown", &filename).await;
+    tracing::info!("Took down {} ({}): {}", filename, status, takedown.reason);
+
+    Ok(Json(takedown))
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct LiftParam {
+    token: String,
+    totp: Option<String>,
+    /// Paste id.
+    id: String,
+}
+
+/// Lifts the takedown of paste `id`, so it's served again. The takedown
+/// stays on record.
+#[axum::debug_handler]
+pub async fn lift(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Query(query): Query<LiftParam>,
+) -> Result<Json<Takedown>, StatusCode> {
+    let admin = authenticate_admin(&state, addr.ip(), &query.token, query.totp.as_deref()).await?;
+
+    let takedown = sqlx::query_as::<_, Takedown>("UPDATE takedowns SET lifted_at = $1, lifted_by = $2
+    WHERE lifted_at IS NULL AND filename = (SELECT filename FROM pastes WHERE id = $3) RETURNING *")
+    .bind(Utc::now().timestamp())
+    .bind(admin.id)
+    .bind(&query.id)
+    .fetch_optional(&state.db).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    .ok_or(StatusCode::NOT_FOUND)?;
+
+    audit::record(&state, admin.id, addr.ip(), "takedown_lift", &takedown.filename).await;
+    ipfs::schedule(&state.db, &state.config(), &takedown.filename);
+
+    Ok(Json(takedown))
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ListParam {
+    token: String,
+    totp: Option<String>,
+    /// Include takedowns that were lifted.
+    #[serde(default)]
+    all: bool,
+}
+
+/// Lists takedowns, newest first.
+#[axum::debug_handler]
+pub async fn list(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Query(query): Query<ListParam>,
+) -> Result<Json<Vec<Takedown>>, StatusCode> {
+    authenticate_admin(&state, addr.ip(), &query.token, query.totp.as_deref()).await?;
+
+    let takedowns = sqlx::query_as::<_, Takedown>("SELECT * FROM takedowns WHERE $1 OR lifted_at IS NULL ORDER BY id DESC")
+    .bind(query.all)
+    .fetch_all(&state.db).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Json(takedowns))
+}
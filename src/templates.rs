@@ -0,0 +1,73 @@
+use std::path::Path;
+
+use axum::{
+    http::StatusCode,
+    response::{Html, IntoResponse, Response},
+};
+use rust_embed::RustEmbed;
+use tera::{Context, Tera};
+
+use crate::{config::Config, errors};
+
+#[derive(RustEmbed)]
+#[folder = "templates/"]
+struct Embedded;
+
+/// HTML templates, built in and optionally overridden from a directory so
+/// operators can change branding, the footer or add an imprint.
+#[derive(Debug)]
+pub struct Templates {
+    tera: Tera,
+    site_name: String,
+}
+
+impl Templates {
+    pub fn load(config: &Config) -> anyhow::Result<Self> {
+        let mut sources = Vec::new();
+        for name in Embedded::iter() {
+            if let Some(file) = Embedded::get(&name) {
+                sources.push((name.to_string(), String::from_utf8(file.data.into_owned())?));
+            }
+        }
+
+        if let Some(dir) = &config.template_dir {
+            for entry in std::fs::read_dir(dir)? {
+                let path = entry?.path();
+                let name = match path.file_name().and_then(|n| n.to_str()) {
+                    Some(n) if n.ends_with(".html") => n.to_string(),
+                    _ => continue,
+                };
+                tracing::info!("Overriding template {} from {}", name, Path::new(dir).display());
+                let source = std::fs::read_to_string(&path)?;
+                sources.retain(|(n, _)| *n != name);
+                sources.push((name, source));
+            }
+        }
+
+        let mut tera = Tera::default();
+        // added all at once, so inheritance between them resolves
+        tera.add_raw_templates(sources)?;
+
+        Ok(Self { tera, site_name: config.site_name.clone() })
+    }
+
+    /// Renders `name` with `context` plus the variables every page gets.
+    pub fn render(&self, name: &str, mut context: Context) -> Result<Html<String>, StatusCode> {
+        context.insert("site_name", &self.site_name);
+        self.tera.render(name, &context).map(Html).map_err(|e| {
+            tracing::error!("Couldn't render {}: {}", name, e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })
+    }
+
+    /// An error page for `status`.
+    pub fn error(&self, status: StatusCode) -> Response {
+        let mut context = Context::new();
+        context.insert("status", &status.as_u16());
+        context.insert("message", errors::message(status));
+        match self.render("error.html", context) {
+            Ok(page) => (status, page).into_response(),
+            Err(_) => status.into_response(),
+        }
+    }
+}
@@ -0,0 +1,167 @@
+use std::{
+    io::{self, SeekFrom},
+    path::PathBuf,
+};
+
+use axum::{
+    body::StreamBody,
+    extract::Query,
+    http::{header, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde::Deserialize;
+use tokio::{
+    fs::File,
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt, BufReader},
+};
+use tokio_util::io::ReaderStream;
+
+use crate::paste::ServedPaste;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SliceParam {
+    /// 1-based inclusive range like `100-200`, `100-` or `100`.
+    lines: Option<String>,
+    head: Option<u64>,
+    tail: Option<u64>,
+}
+
+enum Slice {
+    Lines(u64, u64),
+    Tail(u64),
+}
+
+/// Whether a paste should be treated as text, judging by its extension.
+/// Pastes without one are usually logs piped straight from a terminal.
+pub fn is_text(filename: &str) -> bool {
+    let ext = match std::path::Path::new(filename).extension().and_then(|e| e.to_str()) {
+        Some(e) => e,
+        None => return true,
+    };
+    let mime = mime_guess::from_ext(ext).first_or_octet_stream();
+    mime.type_() == mime_guess::mime::TEXT
+        || matches!(mime.subtype().as_str(), "json" | "xml" | "javascript" | "x-sh" | "toml" | "yaml")
+}
+
+/// Serves only part of a text paste when `?lines=`, `?head=` or `?tail=` is
+/// given, and passes everything else through to the static file service.
+pub async fn slice_lines<B>(
+    Query(query): Query<SliceParam>,
+    req: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let slice = match (&query.lines, query.head, query.tail) {
+        (None, None, None) => return next.run(req).await,
+        (Some(lines), None, None) => match parse_range(lines) {
+            Some((start, end)) => Slice::Lines(start, end),
+            None => return StatusCode::BAD_REQUEST.into_response(),
+        },
+        (None, Some(head), None) => Slice::Lines(1, head),
+        (None, None, Some(tail)) => Slice::Tail(tail),
+        _ => return StatusCode::BAD_REQUEST.into_response(),
+    };
+
+    // only serve names we handed out ourselves
+    let paste = match req.extensions().get::<ServedPaste>() {
+        Some(p) if p.filename == req.uri().path().trim_start_matches('/') => p.clone(),
+        _ => return StatusCode::NOT_FOUND.into_response(),
+    };
+
+    if !is_text(&paste.filename) {
+        return StatusCode::UNSUPPORTED_MEDIA_TYPE.into_response();
+    }
+
+    let (path, filename) = (paste.path(), paste.filename);
+    let (writer, reader) = tokio::io::duplex(64 * 1024);
+
+    tokio::spawn(async move {
+        let res = match slice {
+            Slice::Lines(start, end) => write_lines(path, writer, start, end).await,
+            Slice::Tail(n) => write_tail(path, writer, n).await,
+        };
+        if let Err(e) = res {
+            tracing::debug!("Stopped streaming {}: {}", filename, e);
+        }
+    });
+
+    (
+        [(header::CONTENT_TYPE, "text/plain; charset=utf-8")],
+        StreamBody::new(ReaderStream::new(reader)),
+    ).into_response()
+}
+
+fn parse_range(range: &str) -> Option<(u64, u64)> {
+    let (start, end) = match range.split_once('-') {
+        Some((start, "")) => (start.parse().ok()?, u64::MAX),
+        Some((start, end)) => (start.parse().ok()?, end.parse().ok()?),
+        None => {
+            let line = range.parse().ok()?;
+            (line, line)
+        }
+    };
+    (start >= 1 && start <= end).then_some((start, end))
+}
+
+async fn write_lines<W: AsyncWrite + Unpin>(path: PathBuf, mut w: W, start: u64, end: u64) -> io::Result<()> {
+    let mut reader = BufReader::new(File::open(path).await?);
+    let mut line = Vec::new();
+    let mut n = 0;
+
+    while n < end {
+        line.clear();
+        if reader.read_until(b'\n', &mut line).await? == 0 {
+            break;
+        }
+        n += 1;
+        if n >= start {
+            w.write_all(&line).await?;
+        }
+    }
+
+    w.shutdown().await
+}
+
+async fn write_tail<W: AsyncWrite + Unpin>(path: PathBuf, mut w: W, n: u64) -> io::Result<()> {
+    let mut file = File::open(path).await?;
+    let offset = tail_offset(&mut file, n).await?;
+
+    file.seek(SeekFrom::Start(offset)).await?;
+    tokio::io::copy(&mut file, &mut w).await?;
+
+    w.shutdown().await
+}
+
+/// Finds where the last `n` lines start by scanning backwards from the end,
+/// so huge logs don't have to be read in full.
+pub async fn tail_offset(file: &mut File, n: u64) -> io::Result<u64> {
+    let len = file.metadata().await?.len();
+    if n == 0 {
+        return Ok(len);
+    }
+
+    let mut buf = vec![0; 64 * 1024];
+    let mut pos = len;
+    let mut newlines = 0;
+
+    while pos > 0 {
+        let chunk = pos.min(buf.len() as u64);
+        pos -= chunk;
+        file.seek(SeekFrom::Start(pos)).await?;
+        file.read_exact(&mut buf[..chunk as usize]).await?;
+
+        for i in (0..chunk as usize).rev() {
+            let at = pos + i as u64;
+            // a trailing newline ends the last line rather than starting a new one
+            if buf[i] != b'\n' || at == len - 1 {
+                continue;
+            }
+            newlines += 1;
+            if newlines == n {
+                return Ok(at + 1);
+            }
+        }
+    }
+
+    Ok(0)
+}
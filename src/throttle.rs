@@ -0,0 +1,205 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{atomic::{AtomicUsize, Ordering}, Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    body::{self, BoxBody, HttpBody, StreamBody},
+    extract::{ConnectInfo, State},
+    http::{header, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use futures::StreamExt;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::{config::Config, paste::ServedPaste, AppState};
+
+/// How much sending a limit lets through at once after being idle, in
+/// seconds of its rate.
+const BURST: Duration = Duration::from_secs(1);
+
+/// A share of bandwidth that downloads wait their turn on.
+#[derive(Debug, Default)]
+struct Bucket {
+    /// When everything taken so far has gone out at the rate.
+    next: Mutex<Option<Instant>>,
+}
+
+impl Bucket {
+    /// Waits until `bytes` more can go out at `rate` bytes a second.
+    async fn take(&self, bytes: usize, rate: u64) {
+        let wait = {
+            let mut next = self.next.lock().unwrap();
+            let now = Instant::now();
+            let idle_since = now.checked_sub(BURST).unwrap_or(now);
+            let start = next.map_or(idle_since, |n| n.max(idle_since));
+            let end = start + Duration::from_secs_f64(bytes as f64 / rate.max(1) as f64);
+            *next = Some(end);
+            end.saturating_duration_since(now)
+        };
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    fn is_idle(&self) -> bool {
+        self.next.lock().unwrap().map_or(true, |n| n < Instant::now())
+    }
+}
+
+/// Download bandwidth shared by everyone, by everyone fetching pastes of the
+/// same token and by every download on the same connection, along with the
+/// slots large downloads take.
+#[derive(Debug, Default)]
+pub struct Throttle {
+    global: Arc<Bucket>,
+    owners: Mutex<HashMap<i64, Arc<Bucket>>>,
+    /// Keyed by the peer's address and port, which tell connections apart,
+    /// so requests kept alive or multiplexed on one share its rate.
+    connections: Mutex<HashMap<SocketAddr, Arc<Bucket>>>,
+    /// `DOWNLOAD_SLOTS` large downloads at a time, when it's set; it only
+    /// takes effect on restart.
+    slots: Option<Arc<Semaphore>>,
+    /// Large downloads waiting for a slot.
+    queued: AtomicUsize,
+}
+
+impl Throttle {
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            slots: config.download_slots.map(|n| Arc::new(Semaphore::new(n))),
+            ..Default::default()
+        }
+    }
+
+    /// Waits for a download slot for a response of `length` bytes, if it's
+    /// large enough to need one. Fails when the queue is full or the wait
+    /// too long.
+    async fn admit(&self, config: &Config, length: u64) -> Result<Option<OwnedSemaphorePermit>, ()> {
+        let slots = match &self.slots {
+            Some(s) if length >= config.download_slot_min_size => s.clone(),
+            _ => return Ok(None),
+        };
+        if let Ok(permit) = slots.clone().try_acquire_owned() {
+            return Ok(Some(permit));
+        }
+
+        if self.queued.fetch_add(1, Ordering::SeqCst) >= config.download_queue {
+            self.queued.fetch_sub(1, Ordering::SeqCst);
+            return Err(());
+        }
+        let res = tokio::time::timeout(config.download_queue_timeout, slots.acquire_owned()).await;
+        self.queued.fetch_sub(1, Ordering::SeqCst);
+        match res {
+            Ok(Ok(permit)) => Ok(Some(permit)),
+            _ => Err(()),
+        }
+    }
+
+    fn owner(&self, id: i64) -> Arc<Bucket> {
+        let mut owners = self.owners.lock().unwrap();
+        owners.retain(|_, b| Arc::strong_count(b) > 1 || !b.is_idle());
+        owners.entry(id).or_default().clone()
+    }
+
+    fn connection(&self, peer: SocketAddr) -> Arc<Bucket> {
+        let mut connections = self.connections.lock().unwrap();
+        connections.retain(|_, b| Arc::strong_count(b) > 1 || !b.is_idle());
+        connections.entry(peer).or_default().clone()
+    }
+}
+
+/// The limits one response is sent under.
+#[derive(Debug, Clone)]
+struct Limits {
+    global: Option<(Arc<Bucket>, u64)>,
+    owner: Option<(Arc<Bucket>, u64)>,
+    connection: Option<(Arc<Bucket>, u64)>,
+    /// The download slot, given back once the body is sent or dropped.
+    slot: Option<Arc<OwnedSemaphorePermit>>,
+}
+
+impl Limits {
+    async fn take(&self, bytes: usize) {
+        for (bucket, rate) in [&self.global, &self.owner, &self.connection].into_iter().flatten() {
+            bucket.take(bytes, *rate).await;
+        }
+    }
+}
+
+/// Slows `response`, serving the paste stored as `filename` and owned by
+/// `owner`, down to the configured download rates: `DOWNLOAD_RATE_LIMIT` for
+/// all downloads together, `DOWNLOAD_RATE_LIMIT_PER_TOKEN` for all downloads
+/// of pastes owned by the same token and `DOWNLOAD_RATE_LIMIT_PER_CONNECTION`
+/// for all downloads over the connection from `peer`, or for this one on its
+/// own when the peer isn't known. Responses of at least `DOWNLOAD_SLOT_MIN_SIZE`
+/// bytes wait for one of the `DOWNLOAD_SLOTS` first, and are refused with
+/// `503` and `Retry-After` when too many already wait.
+async fn apply(state: &AppState, peer: Option<SocketAddr>, filename: &str, owner: Option<i64>, response: Response) -> Response {
+    let config = state.config();
+    if !response.status().is_success() {
+        return response;
+    }
+
+    let length = response.headers().get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok())
+        .unwrap_or(0);
+    let slot = match state.throttle.admit(&config, length).await {
+        Ok(s) => s.map(Arc::new),
+        Err(()) => {
+            tracing::info!("No download slot free for {}", filename);
+            let retry_after = config.download_queue_timeout.as_secs().max(1).to_string();
+            return (StatusCode::SERVICE_UNAVAILABLE, [(header::RETRY_AFTER, retry_after)]).into_response();
+        },
+    };
+    if slot.is_none() && config.download_rate_limit.is_none()
+        && config.download_rate_limit_per_token.is_none()
+        && config.download_rate_limit_per_connection.is_none() {
+        return response;
+    }
+
+    let limits = Limits {
+        global: config.download_rate_limit.map(|rate| (state.throttle.global.clone(), rate)),
+        owner: config.download_rate_limit_per_token.zip(owner).map(|(rate, id)| (state.throttle.owner(id), rate)),
+        connection: config.download_rate_limit_per_connection
+            .map(|rate| (peer.map_or_else(Arc::default, |p| state.throttle.connection(p)), rate)),
+        slot,
+    };
+
+    let (parts, body) = response.into_parts();
+    Response::from_parts(parts, throttled(body, limits))
+}
+
+fn throttled(body: BoxBody, limits: Limits) -> BoxBody {
+    let chunks = futures::stream::unfold(body, |mut body| async move {
+        body.data().await.map(|chunk| (chunk, body))
+    });
+    let paced = chunks.then(move |chunk| {
+        let limits = limits.clone();
+        async move {
+            if let Ok(bytes) = &chunk {
+                limits.take(bytes.len()).await;
+            }
+            chunk
+        }
+    });
+    body::boxed(StreamBody::new(paced))
+}
+
+/// Applies the download rate limits to pastes served from `/paste`.
+pub async fn limit<B>(
+    State(state): State<Arc<AppState>>,
+    req: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let path = req.uri().path().trim_start_matches('/');
+    let filename = path.split('/').next().unwrap_or(path).to_string();
+    let peer = req.extensions().get::<ConnectInfo<SocketAddr>>().map(|c| c.0);
+    let owner = req.extensions().get::<ServedPaste>().and_then(|p| p.owner);
+    let response = next.run(req).await;
+    apply(&state, peer, &filename, owner, response).await
+}
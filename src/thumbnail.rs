@@ -0,0 +1,151 @@
+use std::{
+    net::SocketAddr,
+    path::{Path as FsPath, PathBuf},
+    sync::{Arc, OnceLock},
+};
+
+use axum::{
+    body::Body,
+    extract::{ConnectInfo, Path, State},
+    http::{Request, StatusCode},
+    response::{IntoResponse, Response},
+};
+use image::{imageops::FilterType, ImageFormat};
+use sqlx::SqlitePool;
+use tokio::sync::Semaphore;
+use tower::ServiceExt;
+use tower_http::services::ServeFile;
+
+use crate::{name::PasteName, namespace::RequestNamespace, redirects, storage, takedown, AppState};
+
+/// Where thumbnails are cached, as `thumbnails/<filename>.png`. Like kept
+/// versions, they're outside the pastes directory.
+const THUMBNAILS_DIRECTORY: &str = "thumbnails";
+/// Longest side of a thumbnail, in pixels.
+const SIZE: u32 = 256;
+
+fn thumbnail_path(filename: &str) -> PathBuf {
+    FsPath::new(THUMBNAILS_DIRECTORY).join(format!("{}.png", filename))
+}
+
+/// Whether a thumbnail can be made of the paste stored as `filename`.
+pub fn is_thumbnailable(filename: &str) -> bool {
+    matches!(ImageFormat::from_path(filename), Ok(ImageFormat::Png | ImageFormat::Jpeg | ImageFormat::WebP))
+}
+
+/// Where the thumbnail of the paste stored as `filename` can be found, if it
+/// can have one.
+pub fn url(base_url: &str, filename: &str) -> Option<String> {
+    is_thumbnailable(filename).then(|| format!("{}/thumb/{}", base_url, filename))
+}
+
+/// Serves `/thumb/<name>`, a small PNG of an image paste, made the first time
+/// it's asked for and whenever the paste changed since.
+#[axum::debug_handler]
+pub async fn serve(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    namespace: RequestNamespace,
+    Path(filename): Path<String>,
+    req: Request<Body>,
+) -> Result<Response, StatusCode> {
+    let filename = PasteName::parse(&filename).ok_or(StatusCode::NOT_FOUND)?.into_string();
+    if !is_thumbnailable(&filename) {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    if !namespace.owns(&state, &filename).await? {
+        return redirects::to_sibling(&state, &namespace, &filename).await;
+    }
+    if let Err(response) = takedown::check(&state, &filename).await {
+        return Ok(response);
+    }
+    state.hooks.serve(&filename, addr.ip()).await?;
+
+    let path = thumbnail_path(&filename);
+    let source = storage::locate(&state.db, &filename).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if !is_fresh(&path, &source).await {
+        generate(source, &filename).await.map_err(|e| {
+            tracing::warn!("Couldn't make a thumbnail of {}: {}", filename, e);
+            StatusCode::UNPROCESSABLE_ENTITY
+        })?;
+    }
+
+    ServeFile::new_with_mime(path, &mime_guess::mime::IMAGE_PNG).oneshot(req).await
+        .map(IntoResponse::into_response)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+/// Whether the thumbnail at `thumbnail` was made after `source` last changed.
+pub async fn is_fresh(thumbnail: &FsPath, source: &FsPath) -> bool {
+    let modified = |path: PathBuf| async move { tokio::fs::metadata(path).await.and_then(|m| m.modified()).ok() };
+    match (modified(thumbnail.to_path_buf()).await, modified(source.to_path_buf()).await) {
+        (Some(thumbnail), Some(source)) => thumbnail >= source,
+        _ => false,
+    }
+}
+
+async fn generate(source: PathBuf, filename: &str) -> anyhow::Result<()> {
+    // decoding large images takes a lot of memory, so only do one at a time
+    static PERMITS: OnceLock<Semaphore> = OnceLock::new();
+    let _permit = PERMITS.get_or_init(|| Semaphore::new(1)).acquire().await?;
+
+    tokio::fs::create_dir_all(THUMBNAILS_DIRECTORY).await?;
+    let target = thumbnail_path(filename);
+    tokio::task::spawn_blocking(move || -> anyhow::Result<()> {
+        let thumbnail = image::open(source)?.resize(SIZE, SIZE, FilterType::Triangle);
+        // written aside first, so nobody is served half a thumbnail
+        let mut tmp = target.as_os_str().to_owned();
+        tmp.push(".tmp");
+        thumbnail.save_with_format(&tmp, ImageFormat::Png)?;
+        std::fs::rename(&tmp, &target)?;
+        Ok(())
+    }).await?
+}
+
+/// Removes the thumbnails of pastes that are gone, returning how many.
+pub async fn remove_orphans(db: &SqlitePool) -> anyhow::Result<usize> {
+    remove_orphans_in(db, THUMBNAILS_DIRECTORY).await
+}
+
+/// Removes the `<filename>.png` files in `directory` made from pastes that
+/// are gone, returning how many. Files that can't be checked or removed are
+/// logged and skipped, and reported together at the end.
+pub async fn remove_orphans_in(db: &SqlitePool, directory: &str) -> anyhow::Result<usize> {
+    let mut entries = match tokio::fs::read_dir(directory).await {
+        Ok(e) => e,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e.into()),
+    };
+
+    let (mut removed, mut failed) = (0, 0);
+    while let Some(entry) = entries.next_entry().await? {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let filename = match name.strip_suffix(".png") {
+            Some(f) => f,
+            None => continue,
+        };
+
+        let res = async {
+            let known = sqlx::query_scalar::<_, i64>("SELECT COUNT(*) FROM pastes WHERE filename = $1")
+            .bind(filename)
+            .fetch_one(db).await?;
+            if known == 0 {
+                tokio::fs::remove_file(entry.path()).await?;
+            }
+            Ok::<_, anyhow::Error>(known == 0)
+        }.await;
+
+        match res {
+            Ok(true) => removed += 1,
+            Ok(false) => {},
+            Err(e) => {
+                tracing::error!("Couldn't check {}: {}", entry.path().display(), e);
+                failed += 1;
+            },
+        }
+    }
+    if failed > 0 {
+        anyhow::bail!("{} files in {} couldn't be checked", failed, directory);
+    }
+    Ok(removed)
+}
@@ -0,0 +1,115 @@
+use std::{fs::File, io::BufReader, sync::Arc};
+
+use anyhow::Context;
+use axum::{Extension, Router};
+use hyper::server::conn::Http;
+use sha2::{Digest, Sha256};
+use tokio_rustls::{
+    rustls::{server::AllowAnyAnonymousOrAuthenticatedClient, Certificate, PrivateKey, RootCertStore, ServerConfig},
+    TlsAcceptor,
+};
+
+use crate::{config::Config, listen::{accept_failed, serve_connection}, session::hex};
+
+/// SHA-256 fingerprint of the certificate a client presented during the TLS handshake.
+#[derive(Debug, Clone)]
+pub struct ClientCert {
+    pub fingerprint: String,
+}
+
+/// Marks requests that arrived over a TLS connection.
+#[derive(Debug, Clone, Copy)]
+pub struct Tls;
+
+/// Builds the TLS acceptor, or returns `None` when no certificate is configured.
+pub fn acceptor(config: &Config) -> anyhow::Result<Option<TlsAcceptor>> {
+    let (cert_path, key_path) = match (&config.tls_cert, &config.tls_key) {
+        (Some(c), Some(k)) => (c, k),
+        (None, None) => return Ok(None),
+        _ => anyhow::bail!("TLS_CERT and TLS_KEY must be set together"),
+    };
+
+    let certs = load_certs(cert_path)?;
+    let key = load_key(key_path)?;
+
+    let builder = ServerConfig::builder().with_safe_defaults();
+    let mut server_config = match &config.tls_client_ca {
+        Some(ca_path) => {
+            let mut roots = RootCertStore::empty();
+            for cert in load_certs(ca_path)? {
+                roots.add(&cert)?;
+            }
+            // certificates are only enforced on the routes that need them,
+            // so anonymous clients can still read pastes
+            builder
+                .with_client_cert_verifier(AllowAnyAnonymousOrAuthenticatedClient::new(roots).boxed())
+                .with_single_cert(certs, key)?
+        },
+        None => builder.with_no_client_auth().with_single_cert(certs, key)?,
+    };
+    server_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+    Ok(Some(TlsAcceptor::from(Arc::new(server_config))))
+}
+
+/// Accepts TLS connections and serves `app` on them, making the peer address
+/// and client certificate (if any) available to handlers.
+pub async fn serve(listener: tokio::net::TcpListener, acceptor: TlsAcceptor, app: Router, http: Http) -> anyhow::Result<()> {
+    loop {
+        let (stream, addr) = match listener.accept().await {
+            Ok(a) => a,
+            Err(e) => {
+                accept_failed(e).await;
+                continue;
+            },
+        };
+        let _ = stream.set_nodelay(true);
+        let acceptor = acceptor.clone();
+        let app = app.clone();
+        let http = http.clone();
+
+        tokio::spawn(async move {
+            let stream = match acceptor.accept(stream).await {
+                Ok(s) => s,
+                Err(e) => {
+                    tracing::debug!("TLS handshake with {} failed: {}", addr, e);
+                    return;
+                }
+            };
+
+            let cert = stream.get_ref().1
+                .peer_certificates()
+                .and_then(|c| c.first())
+                .map(|c| ClientCert { fingerprint: fingerprint(&c.0) });
+
+            let app = app.layer(Extension(Tls));
+            let app = match cert {
+                Some(cert) => app.layer(Extension(cert)),
+                None => app,
+            };
+            serve_connection(stream, addr, app, http).await;
+        });
+    }
+}
+
+fn fingerprint(der: &[u8]) -> String {
+    hex(&Sha256::digest(der))
+}
+
+fn load_certs(path: &str) -> anyhow::Result<Vec<Certificate>> {
+    let mut reader = BufReader::new(File::open(path).with_context(|| format!("couldn't open {}", path))?);
+    Ok(rustls_pemfile::certs(&mut reader)?.into_iter().map(Certificate).collect())
+}
+
+fn load_key(path: &str) -> anyhow::Result<PrivateKey> {
+    let mut reader = BufReader::new(File::open(path).with_context(|| format!("couldn't open {}", path))?);
+    for item in rustls_pemfile::read_all(&mut reader)? {
+        match item {
+            rustls_pemfile::Item::PKCS8Key(k) | rustls_pemfile::Item::RSAKey(k) | rustls_pemfile::Item::ECKey(k) => {
+                return Ok(PrivateKey(k))
+            },
+            _ => continue,
+        }
+    }
+    anyhow::bail!("no private key found in {}", path)
+}
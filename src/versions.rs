@@ -0,0 +1,324 @@
+use std::{
+    net::SocketAddr,
+    path::{Path as FsPath, PathBuf},
+    sync::Arc,
+};
+
+use axum::{
+    body::Body,
+    extract::{ConnectInfo, Path, Query, State},
+    http::{Method, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use chrono::prelude::*;
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+use tower::ServiceExt;
+use tower_http::services::ServeFile;
+
+use crate::{
+    audit,
+    auth::authenticate_client,
+    base_url::BaseUrl,
+    content,
+    ipfs,
+    name::PasteName,
+    namespace::RequestNamespace,
+    paste::{declared_length, stream_to_staging, UploadResult},
+    pdf,
+    staging,
+    storage,
+    tls::ClientCert,
+    AppState,
+};
+
+/// Where replaced revisions are kept, as `versions/<filename>/<version>`.
+/// It's outside the pastes directory so they're neither served directly nor
+/// collected as orphans.
+const VERSIONS_DIRECTORY: &str = "versions";
+
+fn version_path(filename: &str, version: i64) -> PathBuf {
+    FsPath::new(VERSIONS_DIRECTORY).join(filename).join(version.to_string())
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct UpdateParam {
+    token: Option<String>,
+}
+
+/// Handles `PUT /paste/<name>`, which replaces the paste's contents and keeps
+/// the old ones as a version.
+pub async fn update_paste(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    cert: Option<Extension<ClientCert>>,
+    Query(query): Query<UpdateParam>,
+    req: Request<Body>,
+    next: Next<Body>,
+) -> Response {
+    let path = req.uri().path().trim_start_matches('/');
+    let filename = match PasteName::parse(path) {
+        Some(name) if req.method() == Method::PUT => name.into_string(),
+        _ => return next.run(req).await,
+    };
+
+    let user = match authenticate_client(&state, addr.ip(), cert.as_deref(), query.token.as_deref()).await {
+        Ok(u) => u,
+        Err(e) => return e.into_response(),
+    };
+
+    let paste = sqlx::query_as::<_, (Option<i64>, i64, i64, i64, bool)>("SELECT owner, size, version, COALESCE(updated_at, timestamp), immutable FROM pastes WHERE filename = $1")
+    .bind(&filename)
+    .fetch_optional(&state.db).await;
+
+    let (owner, size, version, timestamp, immutable) = match paste {
+        Ok(Some(p)) => p,
+        Ok(None) => return StatusCode::NOT_FOUND.into_response(),
+        Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    };
+    if !user.can_manage(owner) {
+        return StatusCode::FORBIDDEN.into_response();
+    }
+    if immutable {
+        return StatusCode::METHOD_NOT_ALLOWED.into_response();
+    }
+
+    let limit = user.max_upload_size.map(|l| l.max(0) as u64).unwrap_or(u64::MAX);
+    if declared_length(req.headers()).map_or(false, |l| l > limit) {
+        return StatusCode::PAYLOAD_TOO_LARGE.into_response();
+    }
+
+    // staged first, so the current version stays intact until the new one
+    // is complete
+    let staged = format!("update-{}", uuid::Uuid::new_v4().simple());
+    let written = match stream_to_staging(&staged, req.into_body(), limit, state.config().durability).await {
+        Ok(w) if w.size <= limit => w,
+        res => {
+            let _ = tokio::fs::remove_file(staging::path(&staged)).await;
+            return match res {
+                Ok(_) => StatusCode::PAYLOAD_TOO_LARGE,
+                Err(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            }.into_response();
+        }
+    };
+
+    // concurrent updates each replace the version they started from, or
+    // fail instead of losing one another's changes
+    let _lock = state.locks.lock(&filename).await;
+    match replace(&state, &filename, &staged, (version, size, timestamp), &written).await {
+        Ok(false) => {
+            let _ = tokio::fs::remove_file(staging::path(&staged)).await;
+            StatusCode::CONFLICT.into_response()
+        },
+        Ok(true) => {
+            audit::record(&state, user.id, addr.ip(), "update", &filename).await;
+            pdf::schedule(&state.db, &filename);
+            content::schedule(&state.db, &filename);
+            ipfs::schedule(&state.db, &state.config(), &filename);
+            (version + 1).to_string().into_response()
+        },
+        Err(e) => {
+            tracing::error!("Couldn't update {}: {}", filename, e);
+            let _ = tokio::fs::remove_file(staging::path(&staged)).await;
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        },
+    }
+}
+
+/// Moves the `current` version of `filename` into history, as long as any is
+/// kept, and puts the `staged` upload in its place. Returns `false` without
+/// changing anything if the paste isn't at that version anymore. Hold its
+/// lock while calling this.
+async fn replace(state: &AppState, filename: &str, staged: &str, current: (i64, i64, i64), written: &UploadResult) -> anyhow::Result<bool> {
+    let (version, old_size, old_timestamp) = current;
+    let kept = state.config().paste_versions_kept;
+    let path = storage::locate(&state.db, filename).await?;
+
+    let mut tx = state.db.begin().await?;
+    let updated = sqlx::query("UPDATE pastes SET size = $1, version = $2, updated_at = $3, sha256 = $4, signature = NULL, signed_by = NULL
+        WHERE filename = $5 AND version = $6")
+    .bind(written.size as i64)
+    .bind(version + 1)
+    .bind(Utc::now().timestamp())
+    .bind(&written.sha256)
+    .bind(filename)
+    .bind(version)
+    .execute(&mut *tx).await?
+    .rows_affected();
+    if updated == 0 {
+        return Ok(false);
+    }
+
+    // the database only changes once the files have moved
+    let archived = version_path(filename, version);
+    if kept > 0 {
+        sqlx::query("INSERT INTO versions (paste, version, size, timestamp) VALUES ($1, $2, $3, $4)")
+        .bind(filename)
+        .bind(version)
+        .bind(old_size)
+        .bind(old_timestamp)
+        .execute(&mut *tx).await?;
+
+        if let Some(dir) = archived.parent() {
+            tokio::fs::create_dir_all(dir).await?;
+        }
+        tokio::fs::rename(&path, &archived).await?;
+    }
+    if let Err(e) = tokio::fs::rename(staging::path(staged), &path).await {
+        if kept > 0 {
+            let _ = tokio::fs::rename(&archived, &path).await;
+        }
+        return Err(e.into());
+    }
+    if let Err(e) = tx.commit().await {
+        let _ = tokio::fs::rename(&path, staging::path(staged)).await;
+        if kept > 0 {
+            let _ = tokio::fs::rename(&archived, &path).await;
+        }
+        return Err(e.into());
+    }
+
+    let pruned = sqlx::query_scalar::<_, i64>("DELETE FROM versions WHERE paste = $1 AND version NOT IN (
+        SELECT version FROM versions WHERE paste = $1 ORDER BY version DESC LIMIT $2
+    ) RETURNING version")
+    .bind(filename)
+    .bind(kept as i64)
+    .fetch_all(&state.db).await?;
+
+    for version in pruned {
+        let _ = tokio::fs::remove_file(version_path(filename, version)).await;
+    }
+    Ok(true)
+}
+
+/// Handles `GET /paste/<name>/v/<version>`, serving a kept revision or the
+/// current one.
+pub async fn serve_version(
+    State(state): State<Arc<AppState>>,
+    req: Request<Body>,
+    next: Next<Body>,
+) -> Response {
+    let (filename, version) = match req.uri().path().trim_start_matches('/').split_once("/v/") {
+        Some((f, v)) if req.method() == Method::GET || req.method() == Method::HEAD => {
+            match (PasteName::parse(f), v.parse::<i64>()) {
+                (Some(name), Ok(v)) => (name.into_string(), v),
+                _ => return StatusCode::NOT_FOUND.into_response(),
+            }
+        },
+        _ => return next.run(req).await,
+    };
+
+    let path = match find(&state, &filename, version).await {
+        Ok(p) => p,
+        Err(status) => return status.into_response(),
+    };
+
+    // served as whatever type the paste's own name says
+    let mime = mime_guess::from_path(&filename).first_or_octet_stream();
+    let len = tokio::fs::metadata(&path).await.map_or(0, |m| m.len());
+    match ServeFile::new_with_mime(path, &mime).with_buf_chunk_size(storage::chunk_size(len)).oneshot(req).await {
+        Ok(response) => response.into_response(),
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+}
+
+/// Where `version` of the paste stored as `filename` lives, whether it's the
+/// current one or kept.
+pub async fn find(state: &AppState, filename: &str, version: i64) -> Result<PathBuf, StatusCode> {
+    if current(state, filename).await? == version {
+        return storage::locate(&state.db, filename).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    let kept = sqlx::query_scalar::<_, i64>("SELECT COUNT(*) FROM versions WHERE paste = $1 AND version = $2")
+    .bind(filename)
+    .bind(version)
+    .fetch_one(&state.db).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    if kept == 0 {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    Ok(version_path(filename, version))
+}
+
+/// The current version of the paste stored as `filename`.
+pub async fn current(state: &AppState, filename: &str) -> Result<i64, StatusCode> {
+    sqlx::query_scalar::<_, i64>("SELECT version FROM pastes WHERE filename = $1")
+    .bind(filename)
+    .fetch_optional(&state.db).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    .ok_or(StatusCode::NOT_FOUND)
+}
+
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct Version {
+    pub version: i64,
+    pub size: i64,
+    pub timestamp: i64,
+    #[sqlx(skip)]
+    pub url: String,
+    #[sqlx(skip)]
+    pub current: bool,
+}
+
+/// Lists the kept revisions of paste `id`, oldest first, followed by the
+/// current one.
+#[axum::debug_handler]
+pub async fn list_versions(
+    State(state): State<Arc<AppState>>,
+    BaseUrl(base_url): BaseUrl,
+    namespace: RequestNamespace,
+    Path(id): Path<String>,
+) -> Result<Json<Vec<Version>>, StatusCode> {
+    let (filename, version, size, timestamp) = sqlx::query_as::<_, (String, i64, i64, i64)>("SELECT filename, version, size, COALESCE(updated_at, timestamp) FROM pastes
+    WHERE id = $1 AND namespace IS $2")
+    .bind(&id)
+    .bind(namespace.name())
+    .fetch_optional(&state.db).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    .ok_or(StatusCode::NOT_FOUND)?;
+
+    let mut versions = sqlx::query_as::<_, Version>("SELECT version, size, timestamp FROM versions WHERE paste = $1 ORDER BY version")
+    .bind(&filename)
+    .fetch_all(&state.db).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    versions.push(Version { version, size, timestamp, url: String::new(), current: true });
+    for v in &mut versions {
+        v.url = format!("{}/paste/{}/v/{}", base_url, filename, v.version);
+    }
+
+    Ok(Json(versions))
+}
+
+/// Forgets every kept revision of the paste stored as `filename`, once the
+/// paste itself is gone.
+pub async fn remove(db: &SqlitePool, filename: &str) {
+    let res = sqlx::query("DELETE FROM versions WHERE paste = $1")
+    .bind(filename)
+    .execute(db).await;
+
+    match res {
+        Ok(r) if r.rows_affected() == 0 => {},
+        Ok(_) => if let Err(e) = remove_files(filename).await {
+            tracing::error!("Couldn't remove the versions of {}: {}", filename, e);
+        },
+        Err(e) => tracing::error!("Couldn't forget the versions of {}: {}", filename, e),
+    }
+}
+
+/// Moves the kept revisions of the paste stored as `old` along with it to
+/// `new`.
+pub async fn rename_files(old: &str, new: &str) -> std::io::Result<()> {
+    match tokio::fs::rename(FsPath::new(VERSIONS_DIRECTORY).join(old), FsPath::new(VERSIONS_DIRECTORY).join(new)).await {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        res => res,
+    }
+}
+
+/// Removes the kept revisions of `filename` from storage, for when they've
+/// been forgotten already.
+pub async fn remove_files(filename: &str) -> std::io::Result<()> {
+    match tokio::fs::remove_dir_all(FsPath::new(VERSIONS_DIRECTORY).join(filename)).await {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        res => res,
+    }
+}
@@ -0,0 +1,175 @@
+use std::{net::SocketAddr, sync::Arc};
+
+use axum::{
+    body::Body,
+    extract::{ConnectInfo, State},
+    http::{header, HeaderMap, Method, Request, StatusCode},
+    response::{IntoResponse, Response},
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chrono::prelude::*;
+
+use crate::{
+    auth::authenticate,
+    db::TokenInfo,
+    name::{self, PasteName},
+    paste::{self, declared_length, record_upload, stream_to_staging},
+    staging,
+    AppState,
+};
+
+/// Where the WebDAV tree is mounted.
+pub const PREFIX: &str = "/dav";
+
+const ALLOW: &str = "OPTIONS, GET, HEAD, PUT, DELETE, PROPFIND";
+
+/// A flat WebDAV collection of the caller's pastes, so it can be mounted in a
+/// file manager. `PUT` uploads a new paste, which shows up under the name it's
+/// stored as rather than the one it was put as, `DELETE` removes one and
+/// `PROPFIND` lists them. Clients log in with HTTP Basic, the password being
+/// their token.
+pub async fn handle(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    req: Request<Body>,
+) -> Response {
+    if req.method() == Method::OPTIONS {
+        return ([("dav", "1"), ("allow", ALLOW), ("ms-author-via", "DAV")], "").into_response();
+    }
+
+    let user = match basic_token(req.headers()) {
+        Some(token) => match authenticate(&state, addr.ip(), &token).await {
+            Ok(u) => u,
+            Err(status) => return status.into_response(),
+        },
+        None => return (StatusCode::UNAUTHORIZED, [(header::WWW_AUTHENTICATE, "Basic realm=\"smolpaste\"")]).into_response(),
+    };
+
+    let name = req.uri().path().strip_prefix(PREFIX).unwrap_or_default().trim_matches('/').to_string();
+    let res = match (req.method().as_str(), name.as_str()) {
+        ("PROPFIND", "") => propfind(&state, &user, req.headers()).await,
+        ("PROPFIND", name) => propfind_one(&state, &user, name).await,
+        ("GET" | "HEAD", "") => Err(StatusCode::METHOD_NOT_ALLOWED),
+        ("GET" | "HEAD", name) => get(&state, &user, name, req).await,
+        ("PUT", "") => Err(StatusCode::METHOD_NOT_ALLOWED),
+        ("PUT", name) => put(&state, &user, addr, name, req).await,
+        ("DELETE", name) => delete(&state, &user, addr, name).await,
+        _ => Err(StatusCode::METHOD_NOT_ALLOWED),
+    };
+    res.unwrap_or_else(IntoResponse::into_response)
+}
+
+/// The token from `Authorization: Basic`, ignoring the user name.
+fn basic_token(headers: &HeaderMap) -> Option<String> {
+    let encoded = headers.get(header::AUTHORIZATION)?.to_str().ok()?.strip_prefix("Basic ")?;
+    let decoded = String::from_utf8(STANDARD.decode(encoded.trim()).ok()?).ok()?;
+    let (_, token) = decoded.split_once(':')?;
+    Some(token.to_string())
+}
+
+#[derive(Debug, sqlx::FromRow)]
+struct Entry {
+    id: String,
+    filename: String,
+    size: i64,
+    timestamp: i64,
+    mime: Option<String>,
+}
+
+const ENTRY_QUERY: &str = "SELECT id, filename, size, COALESCE(updated_at, timestamp) AS timestamp, mime FROM pastes
+    WHERE owner = $1 AND namespace IS $2";
+
+/// The paste stored as `name`, if `user` owns it.
+async fn owned(state: &AppState, user: &TokenInfo, name: &str) -> Result<Entry, StatusCode> {
+    let name = PasteName::parse(name).ok_or(StatusCode::NOT_FOUND)?;
+    sqlx::query_as::<_, Entry>(&format!("{} AND filename = $3", ENTRY_QUERY))
+    .bind(user.id)
+    .bind(&user.namespace)
+    .bind(name.as_str())
+    .fetch_optional(&state.db).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    .ok_or(StatusCode::NOT_FOUND)
+}
+
+async fn propfind(state: &AppState, user: &TokenInfo, headers: &HeaderMap) -> Result<Response, StatusCode> {
+    let mut xml = format!("{}{}", MULTISTATUS_START, collection_xml());
+    // `Depth: infinity` is treated like 1, there's nothing deeper
+    if headers.get("depth").map_or(true, |d| d != "0") {
+        let entries = sqlx::query_as::<_, Entry>(&format!("{} ORDER BY timestamp DESC", ENTRY_QUERY))
+        .bind(user.id)
+        .bind(&user.namespace)
+        .fetch_all(&state.db).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+        for entry in &entries {
+            xml.push_str(&entry_xml(entry));
+        }
+    }
+    xml.push_str(MULTISTATUS_END);
+    Ok(multistatus(xml))
+}
+
+async fn propfind_one(state: &AppState, user: &TokenInfo, name: &str) -> Result<Response, StatusCode> {
+    let entry = owned(state, user, name).await?;
+    Ok(multistatus(format!("{}{}{}", MULTISTATUS_START, entry_xml(&entry), MULTISTATUS_END)))
+}
+
+const MULTISTATUS_START: &str = "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<D:multistatus xmlns:D=\"DAV:\">\n";
+const MULTISTATUS_END: &str = "</D:multistatus>\n";
+
+fn multistatus(xml: String) -> Response {
+    (StatusCode::MULTI_STATUS, [(header::CONTENT_TYPE, "application/xml; charset=utf-8")], xml).into_response()
+}
+
+fn collection_xml() -> String {
+    format!(
+        "<D:response><D:href>{}/</D:href><D:propstat><D:prop><D:resourcetype><D:collection/></D:resourcetype></D:prop><D:status>HTTP/1.1 200 OK</D:status></D:propstat></D:response>\n",
+        PREFIX,
+    )
+}
+
+fn entry_xml(entry: &Entry) -> String {
+    let modified = Utc.timestamp_opt(entry.timestamp, 0).single().unwrap_or_default();
+    let mime = entry.mime.clone()
+        .unwrap_or_else(|| mime_guess::from_path(&entry.filename).first_or_octet_stream().essence_str().to_string());
+    // names are only ever letters, digits, `-`, `_` and `.`, so they need no escaping
+    format!(
+        "<D:response><D:href>{}/{}</D:href><D:propstat><D:prop>\
+        <D:displayname>{}</D:displayname><D:getcontentlength>{}</D:getcontentlength>\
+        <D:getcontenttype>{}</D:getcontenttype><D:getlastmodified>{}</D:getlastmodified>\
+        <D:creationdate>{}</D:creationdate><D:getetag>\"{}\"</D:getetag><D:resourcetype/>\
+        </D:prop><D:status>HTTP/1.1 200 OK</D:status></D:propstat></D:response>\n",
+        PREFIX, entry.filename, entry.filename, entry.size, tera::escape_html(&mime),
+        modified.format("%a, %d %b %Y %H:%M:%S GMT"), modified.to_rfc3339(), entry.id,
+    )
+}
+
+async fn get(state: &Arc<AppState>, user: &TokenInfo, name: &str, req: Request<Body>) -> Result<Response, StatusCode> {
+    let entry = owned(state, user, name).await?;
+    Ok(paste::serve(state.clone(), &entry.filename, true, req).await)
+}
+
+async fn put(state: &AppState, user: &TokenInfo, addr: SocketAddr, upload_name: &str, req: Request<Body>) -> Result<Response, StatusCode> {
+    let limit = user.max_upload_size.map(|l| l.max(0) as u64).unwrap_or(u64::MAX);
+    if declared_length(req.headers()).map_or(false, |l| l > limit) {
+        return Err(StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    let id = uuid::Uuid::new_v4();
+    let name = name::unused(&state.db, upload_name).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let staged = staging::upload(&id);
+    let written = match stream_to_staging(&staged, req.into_body(), limit, state.config().durability).await {
+        Ok(w) if w.size <= limit => w,
+        res => {
+            let _ = tokio::fs::remove_file(staging::path(&staged)).await;
+            return Err(if res.is_ok() { StatusCode::PAYLOAD_TOO_LARGE } else { StatusCode::INTERNAL_SERVER_ERROR });
+        },
+    };
+
+    let filename = record_upload(state, Some(user), addr.ip(), id, name, written).await?;
+    Ok((StatusCode::CREATED, [(header::LOCATION, format!("{}/{}", PREFIX, filename))]).into_response())
+}
+
+async fn delete(state: &AppState, user: &TokenInfo, addr: SocketAddr, name: &str) -> Result<Response, StatusCode> {
+    let entry = owned(state, user, name).await?;
+    paste::delete(state, user, addr.ip(), &entry.id).await?;
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
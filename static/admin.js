@@ -0,0 +1,54 @@
+function chart(svg, values) {
+    svg.innerHTML = "";
+    const max = Math.max(1, ...values);
+    const width = 100 / Math.max(1, values.length);
+    values.forEach((v, i) => {
+        const rect = document.createElementNS("http://www.w3.org/2000/svg", "rect");
+        const height = 100 * v / max;
+        rect.setAttribute("x", (i * width) + "%");
+        rect.setAttribute("y", (100 - height) + "%");
+        rect.setAttribute("width", (width * 0.9) + "%");
+        rect.setAttribute("height", height + "%");
+        svg.appendChild(rect);
+    });
+}
+
+document.getElementById("login").addEventListener("submit", async (e) => {
+    e.preventDefault();
+    const token = document.getElementById("token").value;
+    const totp = document.getElementById("totp").value;
+    const days = document.getElementById("days").value;
+    const res = await fetch(`/api/stats?token=${encodeURIComponent(token)}&totp=${encodeURIComponent(totp)}&days=${encodeURIComponent(days)}`);
+    if (!res.ok) {
+        document.getElementById("error").textContent = `Request failed: ${res.status}`;
+        return;
+    }
+    document.getElementById("error").textContent = "";
+    const stats = await res.json();
+
+    chart(document.getElementById("uploads"), stats.days.map(d => d.uploads));
+    chart(document.getElementById("bytes"), stats.days.map(d => d.total_bytes));
+
+    const table = document.getElementById("mimes");
+    table.innerHTML = "";
+    for (const m of stats.top_mime_types) {
+        const row = table.insertRow();
+        row.insertCell().textContent = m.mime;
+        row.insertCell().textContent = m.count;
+    }
+    document.getElementById("active").textContent = stats.active_tokens;
+
+    const recent = await fetch(`/api/pastes?token=${encodeURIComponent(token)}&limit=20`);
+    const pastes = recent.ok ? await recent.json() : [];
+    const list = document.getElementById("recent");
+    list.innerHTML = "";
+    for (const p of pastes) {
+        const row = list.insertRow();
+        const link = document.createElement("a");
+        link.href = `/view/${encodeURIComponent(p.filename)}`;
+        link.textContent = p.title || p.filename;
+        row.insertCell().appendChild(link);
+        row.insertCell().textContent = p.size;
+        row.insertCell().textContent = new Date(p.timestamp * 1000).toISOString();
+    }
+});
@@ -0,0 +1,75 @@
+// Pastes encrypted in the browser with AES-GCM. The server only ever gets
+// ciphertext; the key travels in the link's fragment, which browsers don't send.
+// A paste is a version byte, the 12 byte IV and the ciphertext.
+const VERSION = 1;
+
+function toBase64Url(bytes) {
+    return btoa(String.fromCharCode(...bytes)).replace(/\+/g, "-").replace(/\//g, "_").replace(/=+$/, "");
+}
+
+function fromBase64Url(s) {
+    const b64 = s.replace(/-/g, "+").replace(/_/g, "/").padEnd(Math.ceil(s.length / 4) * 4, "=");
+    return Uint8Array.from(atob(b64), c => c.charCodeAt(0));
+}
+
+const form = document.getElementById("e2e-form");
+if (form) {
+    form.addEventListener("submit", async (e) => {
+        e.preventDefault();
+        const status = document.getElementById("e2e-status");
+        if (!window.crypto || !crypto.subtle) {
+            status.textContent = "Your browser can only encrypt over HTTPS.";
+            return;
+        }
+
+        const key = crypto.getRandomValues(new Uint8Array(32));
+        const iv = crypto.getRandomValues(new Uint8Array(12));
+        const cryptoKey = await crypto.subtle.importKey("raw", key, "AES-GCM", false, ["encrypt"]);
+        const plaintext = new TextEncoder().encode(form.elements.text.value);
+        const ciphertext = await crypto.subtle.encrypt({ name: "AES-GCM", iv }, cryptoKey, plaintext);
+
+        // the same form the plain upload posts, with nothing readable in it
+        const body = new FormData();
+        body.append("csrf", form.elements.csrf.value);
+        body.append("title", "");
+        body.append("file", new Blob([new Uint8Array([VERSION]), iv, ciphertext]), "paste.e2e");
+        const res = await fetch("/ui/upload", { method: "POST", body });
+        if (!res.ok || !res.redirected) {
+            status.textContent = `Upload failed: ${res.status}`;
+            return;
+        }
+        location.href = `${new URL(res.url).pathname}#${toBase64Url(key)}`;
+    });
+}
+
+const viewer = document.getElementById("e2e-view");
+if (viewer) {
+    const status = viewer.querySelector(".status");
+    (async () => {
+        if (!location.hash) {
+            status.textContent = "This paste is encrypted, and the link you followed doesn't have its key.";
+            return;
+        }
+        if (!window.crypto || !crypto.subtle) {
+            status.textContent = "Your browser can only decrypt over HTTPS.";
+            return;
+        }
+
+        const res = await fetch(viewer.dataset.src);
+        const data = new Uint8Array(await res.arrayBuffer());
+        if (!res.ok || data[0] !== VERSION) {
+            status.textContent = "This paste couldn't be loaded.";
+            return;
+        }
+        try {
+            const cryptoKey = await crypto.subtle.importKey("raw", fromBase64Url(location.hash.slice(1)), "AES-GCM", false, ["decrypt"]);
+            const plaintext = await crypto.subtle.decrypt({ name: "AES-GCM", iv: data.slice(1, 13) }, cryptoKey, data.slice(13));
+            const pre = viewer.querySelector("pre");
+            pre.textContent = new TextDecoder().decode(plaintext);
+            pre.hidden = false;
+            status.textContent = "Decrypted in your browser.";
+        } catch (err) {
+            status.textContent = "The key in the link doesn't open this paste.";
+        }
+    })();
+}
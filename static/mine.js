@@ -0,0 +1,24 @@
+// counts down the time left before pastes expire, the way the server wrote
+// it, and asks before deleting one
+(function () {
+  function left(seconds) {
+    if (seconds <= 0) return 'any moment now';
+    var days = Math.floor(seconds / 86400), hours = Math.floor(seconds % 86400 / 3600), minutes = Math.floor(seconds % 3600 / 60);
+    if (days > 0) return 'in ' + days + 'd ' + hours + 'h';
+    if (hours > 0) return 'in ' + hours + 'h ' + minutes + 'm';
+    return 'in ' + Math.max(minutes, 1) + 'm';
+  }
+  function update() {
+    var now = Date.now() / 1000;
+    document.querySelectorAll('time.expires').forEach(function (t) {
+      t.textContent = left(+t.dataset.expires - now);
+    });
+  }
+  update();
+  setInterval(update, 30000);
+  document.querySelectorAll('form.delete').forEach(function (f) {
+    f.addEventListener('submit', function (e) {
+      if (!confirm(f.dataset.confirm)) e.preventDefault();
+    });
+  });
+})();
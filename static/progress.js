@@ -0,0 +1,30 @@
+// Shows how much of an upload the server has received, as it reports over a
+// WebSocket, rather than how much the browser thinks it has sent.
+const uploadForm = document.getElementById("upload-form");
+if (uploadForm && window.WebSocket) {
+    uploadForm.addEventListener("submit", () => {
+        const bar = document.getElementById("upload-progress");
+        const file = uploadForm.elements.file.files[0];
+        const id = Array.from(crypto.getRandomValues(new Uint8Array(16)), b => b.toString(16).padStart(2, "0")).join("");
+
+        // the form is submitted as usual, and the page keeps running until
+        // the response comes back; other parameters, like a confirmed
+        // ?force=true, are kept
+        const action = new URL(uploadForm.action);
+        action.searchParams.set("progress", id);
+        uploadForm.action = action;
+        const scheme = location.protocol === "https:" ? "wss" : "ws";
+        const socket = new WebSocket(`${scheme}://${location.host}/ui/upload/progress/${id}`);
+        socket.addEventListener("message", (e) => {
+            const progress = JSON.parse(e.data);
+            const total = file ? file.size : progress.total;
+            if (total) {
+                bar.value = Math.min(progress.received / total, 1);
+                bar.hidden = false;
+            }
+            if (progress.done) {
+                socket.close();
+            }
+        });
+    });
+}
@@ -0,0 +1,49 @@
+// #L42 or #L42-L58 highlights those lines; shift-click extends the selection
+(function () {
+  function range() {
+    var m = /^#L(\d+)(?:-L(\d+))?$/.exec(location.hash);
+    if (!m) return null;
+    var a = +m[1], b = m[2] ? +m[2] : a;
+    return [Math.min(a, b), Math.max(a, b)];
+  }
+  function highlight() {
+    document.querySelectorAll('.line.hl').forEach(function (l) { l.classList.remove('hl'); });
+    var r = range();
+    if (!r) return null;
+    for (var i = r[0]; i <= r[1]; i++) {
+      var line = document.getElementById('L' + i);
+      if (line) line.classList.add('hl');
+    }
+    return r;
+  }
+  document.querySelectorAll('.line .n').forEach(function (n) {
+    n.addEventListener('click', function (e) {
+      var r = range(), line = +n.textContent;
+      if (!e.shiftKey || !r) return;
+      e.preventDefault();
+      history.replaceState(null, '', '#L' + Math.min(r[0], line) + '-L' + Math.max(r[0], line));
+      highlight();
+    });
+  });
+  window.addEventListener('hashchange', highlight);
+  var r = highlight(), first = r && document.getElementById('L' + r[0]);
+  if (first) first.scrollIntoView();
+})();
+
+// click a header to sort by that column, again to reverse
+document.querySelectorAll('table.sortable th').forEach(function (th, column) {
+  th.addEventListener('click', function () {
+    var body = th.closest('table').tBodies[0];
+    var rows = Array.prototype.slice.call(body.rows);
+    var descending = th.dataset.sorted === 'asc';
+    th.closest('tr').querySelectorAll('th').forEach(function (h) { delete h.dataset.sorted; });
+    th.dataset.sorted = descending ? 'desc' : 'asc';
+    rows.sort(function (a, b) {
+      var x = a.cells[column] ? a.cells[column].textContent : '';
+      var y = b.cells[column] ? b.cells[column].textContent : '';
+      var order = (x !== '' && y !== '' && !isNaN(x) && !isNaN(y)) ? x - y : x.localeCompare(y);
+      return descending ? -order : order;
+    });
+    rows.forEach(function (r) { body.appendChild(r); });
+  });
+});